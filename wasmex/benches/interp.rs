@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// compare the interpreter (exec) and the compact IR (ir) on small workloads.
+// cargo bench (cargo bench --features jit adds the JIT)
+
+use std::time::{Duration, Instant};
+
+use wasmex::{IrModule, Module, Value};
+
+const FIB: &str = r#"
+(module
+  (func $fib (export "fib") (param i32) (result i32)
+    (if (result i32) (i32.lt_u (local.get 0) (i32.const 2))
+      (then (local.get 0))
+      (else (i32.add (call $fib (i32.sub (local.get 0) (i32.const 1)))
+                     (call $fib (i32.sub (local.get 0) (i32.const 2))))))))
+"#;
+
+const LOOP: &str = r#"
+(module
+  (func (export "sum") (param $n i32) (result i64)
+    (local $i i32) (local $s i64)
+    (block $done
+      (loop $l
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (local.set $s (i64.add (local.get $s)
+          (i64.mul (i64.extend_i32_u (local.get $i)) (i64.extend_i32_u (local.get $i)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $l)))
+    (local.get $s)))
+"#;
+
+const SIEVE: &str = r#"
+(module
+  (memory 2)
+  (func (export "sieve") (param $n i32) (result i32)
+    (local $i i32) (local $j i32) (local $count i32)
+    (memory.fill (i32.const 0) (i32.const 1) (local.get $n))
+    (local.set $i (i32.const 2))
+    (block $done
+      (loop $outer
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (if (i32.load8_u (local.get $i))
+          (then
+            (local.set $count (i32.add (local.get $count) (i32.const 1)))
+            (local.set $j (i32.mul (local.get $i) (local.get $i)))
+            (block $inner_done
+              (loop $inner
+                (br_if $inner_done (i32.ge_u (local.get $j) (local.get $n)))
+                (i32.store8 (local.get $j) (i32.const 0))
+                (local.set $j (i32.add (local.get $j) (local.get $i)))
+                (br $inner)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $outer)))
+    (local.get $count)))
+"#;
+
+const MATMUL: &str = r#"
+(module
+  (memory 1)
+  ;; c = a * b for n x n f64 matrices at 0, n*n*8 and 2*n*n*8
+  (func (export "matmul") (param $n i32) (result f64)
+    (local $i i32) (local $j i32) (local $k i32) (local $s f64) (local $sz i32)
+    (local.set $sz (i32.mul (i32.mul (local.get $n) (local.get $n)) (i32.const 8)))
+    (loop $init
+      (f64.store (i32.mul (local.get $i) (i32.const 8)) (f64.convert_i32_u (local.get $i)))
+      (f64.store (i32.add (local.get $sz) (i32.mul (local.get $i) (i32.const 8))) (f64.const 0.5))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $init (i32.lt_u (local.get $i) (i32.mul (local.get $n) (local.get $n)))))
+    (local.set $i (i32.const 0))
+    (loop $li
+      (local.set $j (i32.const 0))
+      (loop $lj
+        (local.set $s (f64.const 0))
+        (local.set $k (i32.const 0))
+        (loop $lk
+          (local.set $s (f64.add (local.get $s)
+            (f64.mul
+              (f64.load (i32.shl (i32.add (i32.mul (local.get $i) (local.get $n)) (local.get $k)) (i32.const 3)))
+              (f64.load offset=0 (i32.add (local.get $sz)
+                (i32.shl (i32.add (i32.mul (local.get $k) (local.get $n)) (local.get $j)) (i32.const 3)))))))
+          (local.set $k (i32.add (local.get $k) (i32.const 1)))
+          (br_if $lk (i32.lt_u (local.get $k) (local.get $n))))
+        (f64.store (i32.add (i32.mul (local.get $sz) (i32.const 2))
+                            (i32.shl (i32.add (i32.mul (local.get $i) (local.get $n)) (local.get $j)) (i32.const 3)))
+                   (local.get $s))
+        (local.set $j (i32.add (local.get $j) (i32.const 1)))
+        (br_if $lj (i32.lt_u (local.get $j) (local.get $n))))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $li (i32.lt_u (local.get $i) (local.get $n))))
+    (f64.load (i32.add (i32.mul (local.get $sz) (i32.const 2)) (i32.const 8)))))
+"#;
+
+// best of 3
+fn measure<F: FnMut() -> Vec<Value>>(mut f: F) -> (Duration, Vec<Value>) {
+    let mut best = Duration::MAX;
+    let mut results = Vec::new();
+    for _ in 0..3 {
+        let start = Instant::now();
+        results = f();
+        best = best.min(start.elapsed());
+    }
+    (best, results)
+}
+
+fn load(src: &str) -> Module {
+    let mut module = wasmex::parse_wat(src).unwrap();
+    module.validate().unwrap();
+    module
+}
+
+fn bench(name: &str, src: &str, args: &[Value]) {
+    let module = load(src);
+    let idx = 0;
+    let (interp, r1) = measure(|| {
+        let mut store = wasmex::make_store(&module).unwrap();
+        wasmex::invoke(idx, args, &module, &mut store).unwrap()
+    });
+    let start = Instant::now();
+    let ir = IrModule::new(&module);
+    let lower = start.elapsed();
+    assert!(ir.is_lowered(idx), "{} is not lowered", name);
+    let (compact, r2) = measure(|| {
+        let mut store = wasmex::make_store(&module).unwrap();
+        ir.invoke(idx, args, &module, &mut store).unwrap()
+    });
+    assert_eq!(format!("{:?}", r1), format!("{:?}", r2), "{}: results differ", name);
+    print!("{:<8} {:>10.2?} {:>10.2?} {:>8.2?} {:>7.1}x",
+           name, interp, compact, lower, interp.as_secs_f64() / compact.as_secs_f64());
+    #[cfg(feature = "jit")]
+    {
+        let jit = wasmex::Jit::new(&module, 0);
+        let (native, r3) = measure(|| {
+            let mut store = wasmex::make_store(&module).unwrap();
+            jit.invoke(idx, args, &module, &mut store).unwrap()
+        });
+        assert_eq!(format!("{:?}", r1), format!("{:?}", r3), "{}: results differ", name);
+        assert!(jit.compiled().contains(&idx), "{} is not compiled", name);
+        print!(" {:>10.2?} {:>7.1}x", native, interp.as_secs_f64() / native.as_secs_f64());
+    }
+    println!();
+}
+
+fn main() {
+    print!("{:<8} {:>10} {:>10} {:>8} {:>8}", "bench", "interp", "ir", "lower", "speedup");
+    #[cfg(feature = "jit")]
+    print!(" {:>10} {:>8}", "jit", "speedup");
+    println!();
+    bench("fib", FIB, &[Value::I32(25)]);
+    bench("loop", LOOP, &[Value::I32(2_000_000)]);
+    bench("sieve", SIEVE, &[Value::I32(100_000)]);
+    bench("matmul", MATMUL, &[Value::I32(48)]);
+}
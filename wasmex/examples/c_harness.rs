@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// compare the C translation (cgen) with the interpreter.
+// cargo run --example c_harness -- foo.wat calls.txt
+// each line of calls.txt is a call as --invoke (function and args). the C
+// files are compiled by $CC (default cc) and the results and the memory after
+// each call must be the same as the interpreter.
+
+use std::{env, fs, process};
+
+use wasmex::cgen::{harness_expected, harness_run};
+use wasmex::Module;
+
+fn load(path: &str) -> Result<Module, String> {
+    let mut module = if path.ends_with(".wat") {
+        let src = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        wasmex::parse_wat(&src).map_err(|e| format!("{}:{}", path, e))?
+    } else {
+        let buf = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        wasmex::init_module(buf).map_err(|e| format!("{}: {}", path, e))?
+    };
+    module.validate().map_err(|errs| format!("{}: {} validation errors", path, errs.len()))?;
+    Ok(module)
+}
+
+fn run(path: &str, calls_path: &str) -> Result<bool, String> {
+    let module = load(path)?;
+    let calls = fs::read_to_string(calls_path).map_err(|e| format!("{}: {}", calls_path, e))?;
+    let calls: Vec<Vec<&str>> = calls.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .filter(|args| !args.is_empty() && !args[0].starts_with('#'))
+        .collect();
+
+    let dir = env::temp_dir().join(format!("wasmex-c-harness-{}", process::id()));
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let cc = env::var("CC").unwrap_or("cc".to_string());
+    let lines = harness_run(&module, &calls, &cc, &dir)?;
+    let expected = harness_expected(&module, &calls);
+
+    let mut failed = 0;
+    for (i, (args, expected)) in calls.iter().zip(&expected).enumerate() {
+        let actual = lines.get(i).map_or("(no output)", |l| l.as_str());
+        if actual != expected {
+            println!("{}: interpreter '{}' C '{}'", args.join(" "), expected, actual);
+            failed += 1;
+        }
+    }
+    println!("{}/{} passed", calls.len() - failed, calls.len());
+    if failed == 0 {
+        let _ = fs::remove_dir_all(&dir);
+    }
+    Ok(failed == 0)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: c_harness FILE CALLS");
+        process::exit(2);
+    }
+    match run(&args[1], &args[2]) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(2);
+        },
+    }
+}
@@ -0,0 +1,26 @@
+;; greatest common divisor, recursive and loop versions
+(module
+  (func $gcd (export "gcd") (param $a i32) (param $b i32) (result i32)
+    (if (result i32) (i32.eqz (local.get $b))
+      (then (local.get $a))
+      (else (call $gcd (local.get $b) (i32.rem_u (local.get $a) (local.get $b))))))
+
+  (func $gcd_loop (export "gcd_loop") (param $a i32) (param $b i32) (result i32)
+    (local $t i32)
+    block $done
+      loop $next
+        local.get $b
+        i32.eqz
+        br_if $done
+        local.get $a
+        local.get $b
+        i32.rem_u
+        local.set $t
+        local.get $b
+        local.set $a
+        local.get $t
+        local.set $b
+        br $next
+      end
+    end
+    local.get $a))
@@ -6,31 +6,110 @@
 
 use std::fmt;
 
-pub trait GetType {
-    fn get(buf: &mut ByteCodeBuff) -> Self;
+use crate::inst::*;
+
+// error while decoding a binary module.
+// offset is the position in the module binary where the problem was found.
+#[derive(Debug, Clone)]
+pub enum DecodeError {
+    Magic,
+    Version(u32),
+    UnexpectedEnd {
+        sec_id: u8,
+        offset: usize,
+    },
+    Unexpected {
+        sec_id: u8,
+        offset: usize,
+        expected: &'static str,
+        found: u32,
+    },
+    Malformed {
+        sec_id: u8,
+        offset: usize,
+        reason: &'static str,
+    },
+    Unsupported {
+        sec_id: u8,
+        offset: usize,
+        what: &'static str,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Magic => write!(f, "invalid magic number"),
+            DecodeError::Version(ver) => write!(f, "version {} is invalid", ver),
+            DecodeError::UnexpectedEnd {sec_id, offset} =>
+                write!(f, "unexpected end of section {} at offset {:#x}", sec_id, offset),
+            DecodeError::Unexpected {sec_id, offset, expected, found} =>
+                write!(f, "expected {} but found {:#x} in section {} at offset {:#x}",
+                    expected, found, sec_id, offset),
+            DecodeError::Malformed {sec_id, offset, reason} =>
+                write!(f, "{} in section {} at offset {:#x}", reason, sec_id, offset),
+            DecodeError::Unsupported {sec_id, offset, what} =>
+                write!(f, "{} not supported in section {} at offset {:#x}", what, sec_id, offset),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub trait GetType: Sized {
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError>;
 }
 
 impl GetType for u32 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
         buf.get_u32()
     }
 }
 
-pub fn get_vector<T: GetType>(buf: &mut ByteCodeBuff) -> Vec<T> {
+pub fn get_vector<T: GetType>(buf: &mut ByteCodeBuff) -> Result<Vec<T>, DecodeError> {
     let mut vec: Vec<T> = Vec::new();
-    let n = buf.get_u32();
+    let n = buf.get_u32()?;
     for _ in 0..n {
-        vec.push(T::get(buf));
+        vec.push(T::get(buf)?);
     }
-    vec
+    Ok(vec)
 }
 
-#[derive(Clone)]
+// encode direction (inverse of GetType)
+pub trait PutType {
+    fn put(&self, buf: &mut ByteCodeBuff);
+}
+
+impl PutType for u32 {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_u32(*self);
+    }
+}
+
+pub fn put_vector<T: PutType>(vec: &[T], buf: &mut ByteCodeBuff) {
+    buf.put_u32(vec.len() as u32);
+    for v in vec {
+        v.put(buf);
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Valtype(pub u8);
 
 impl GetType for Valtype {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Valtype(buf.get_byte())
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let cur = buf.get_cur();
+        let t = buf.get_byte()?;
+        match t {
+            0x6f | 0x70 | 0x7b..=0x7f => Ok(Valtype(t)),
+            _ => Err(buf.err_unexpected(cur, "valtype", t as u32)),
+        }
+    }
+}
+
+impl PutType for Valtype {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_byte(self.0);
     }
 }
 
@@ -50,12 +129,18 @@ impl fmt::Display for Valtype {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Resulttype(pub Vec<Valtype>);
 
 impl GetType for Resulttype {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Resulttype(get_vector::<Valtype>(buf))
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Resulttype(get_vector::<Valtype>(buf)?))
+    }
+}
+
+impl PutType for Resulttype {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.0, buf);
     }
 }
 
@@ -73,19 +158,31 @@ impl fmt::Display for Resulttype {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Functype {
     pub input: Resulttype,
     pub output: Resulttype,
 }
 
 impl GetType for Functype {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        assert!(buf.get_byte() == 0x60);
-        Functype {
-            input: Resulttype::get(buf),
-            output: Resulttype::get(buf),
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let cur = buf.get_cur();
+        let t = buf.get_byte()?;
+        if t != 0x60 {
+            return Err(buf.err_unexpected(cur, "functype (0x60)", t as u32));
         }
+        Ok(Functype {
+            input: Resulttype::get(buf)?,
+            output: Resulttype::get(buf)?,
+        })
+    }
+}
+
+impl PutType for Functype {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_byte(0x60);
+        self.input.put(buf);
+        self.output.put(buf);
     }
 }
 
@@ -95,25 +192,71 @@ impl fmt::Display for Functype {
     }
 }
 
+#[derive(Clone)]
 pub enum Limits {
     Min(u32),
     Minmax(u32, u32),
 }
 
 impl GetType for Limits {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        let t = buf.get_byte();
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let cur = buf.get_cur();
+        let t = buf.get_byte()?;
         match t {
             0 => {
-                let min = buf.get_u32();
-                Limits::Min(min)
+                let min = buf.get_u32()?;
+                Ok(Limits::Min(min))
             },
             1 => {
-                let min = buf.get_u32();
-                let max = buf.get_u32();
-                Limits::Minmax(min, max)
+                let min = buf.get_u32()?;
+                let max = buf.get_u32()?;
+                Ok(Limits::Minmax(min, max))
+            },
+            _ => Err(buf.err_unexpected(cur, "limits type", t as u32)),
+        }
+    }
+}
+
+impl PutType for Limits {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        match self {
+            Limits::Min(min) => {
+                buf.put_byte(0);
+                buf.put_u32(*min);
+            },
+            Limits::Minmax(min, max) => {
+                buf.put_byte(1);
+                buf.put_u32(*min);
+                buf.put_u32(*max);
             },
-            _ => panic!("unknown limits type"),
+        }
+    }
+}
+
+impl Limits {
+    pub fn min(&self) -> u32 {
+        match self {
+            Limits::Min(min) => *min,
+            Limits::Minmax(min, _) => *min,
+        }
+    }
+
+    pub fn max(&self) -> Option<u32> {
+        match self {
+            Limits::Min(_) => None,
+            Limits::Minmax(_, max) => Some(*max),
+        }
+    }
+
+    // import matching: the limits provided satisfy the limits of the import
+    pub fn matches(&self, import: &Limits) -> bool {
+        if self.min() < import.min() {
+            return false;
+        }
+        match (self.max(), import.max()) {
+            (_, None) => true,
+            (Some(max), Some(import_max)) => max <= import_max,
+            (None, Some(_)) => false,
         }
     }
 }
@@ -133,11 +276,28 @@ pub struct Tabletype {
 }
 
 impl GetType for Tabletype {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Tabletype {
-            reftype: Valtype::get(buf),
-            limits: Limits::get(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Tabletype {
+            reftype: Valtype::get(buf)?,
+            limits: Limits::get(buf)?,
+        })
+    }
+}
+
+impl PutType for Tabletype {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        self.reftype.put(buf);
+        self.limits.put(buf);
+    }
+}
+
+impl Tabletype {
+    pub fn reftype(&self) -> u8 {
+        self.reftype.0
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
     }
 }
 
@@ -153,11 +313,31 @@ pub struct Globaltype {
 }
 
 impl GetType for Globaltype {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Globaltype {
-            valtype: Valtype::get(buf),
-            mutable: buf.get_byte(),
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let valtype = Valtype::get(buf)?;
+        let cur = buf.get_cur();
+        let mutable = buf.get_byte()?;
+        if mutable > 1 {
+            return Err(buf.err_unexpected(cur, "mut (0x00 or 0x01)", mutable as u32));
         }
+        Ok(Globaltype {valtype, mutable,})
+    }
+}
+
+impl PutType for Globaltype {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        self.valtype.put(buf);
+        buf.put_byte(self.mutable);
+    }
+}
+
+impl Globaltype {
+    pub fn valtype(&self) -> u8 {
+        self.valtype.0
+    }
+
+    pub fn is_mutable(&self) -> bool {
+        self.mutable == 1
     }
 }
 
@@ -175,16 +355,25 @@ impl fmt::Display for Globaltype {
 pub struct Expr(pub Vec<u8>);
 
 impl GetType for Expr {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        let mut expr = Vec::new();
-        loop {
-            let e = buf.get_byte();
-            expr.push(e);
-            if e == 0x0b {
-                break;
-            }
-        }
-        Expr(expr)
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        // decode instructions to find the terminating end
+        // (0x0b may also appear as an immediate value)
+        let start = buf.get_cur();
+        get_insts(buf)?;
+        Ok(Expr(buf.buf[start..buf.c].to_vec()))
+    }
+}
+
+impl PutType for Expr {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_bytes(&self.0);
+    }
+}
+
+impl Expr {
+    pub fn get_insts(&self) -> Result<Vec<Inst>, DecodeError> {
+        let mut buf = ByteCodeBuff::new(self.0.clone());
+        get_insts(&mut buf)
     }
 }
 
@@ -205,13 +394,18 @@ impl fmt::Display for Expr {
 pub struct ByteCodeBuff {
     buf: Vec<u8>,
     c: usize, // cursol
+    end: usize, // end of the current section (or function body)
+    sec_id: u8, // current section (for error report)
 }
 
 impl ByteCodeBuff {
     pub fn new(buf: Vec<u8>) -> Self {
+        let end = buf.len();
         Self {
             buf,
-            c: 0
+            c: 0,
+            end,
+            sec_id: 0,
         }
     }
 
@@ -219,6 +413,10 @@ impl ByteCodeBuff {
         self.buf.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
     pub fn get_cur(&self) -> usize {
         self.c
     }
@@ -232,113 +430,246 @@ impl ByteCodeBuff {
     }
 
     pub fn more(&self) -> bool {
-        self.c < self.buf.len()
+        self.c < self.end
+    }
+
+    pub fn get_end(&self) -> usize {
+        self.end
+    }
+
+    // restrict reading to [cur, end) and record the section id for errors
+    pub fn set_section(&mut self, sec_id: u8, end: usize) {
+        self.sec_id = sec_id;
+        self.end = end;
     }
 
-    pub fn get_byte(&mut self) -> u8 {
+    pub fn err_end(&self) -> DecodeError {
+        DecodeError::UnexpectedEnd {sec_id: self.sec_id, offset: self.c}
+    }
+
+    pub fn err_unexpected(&self, offset: usize, expected: &'static str, found: u32) -> DecodeError {
+        DecodeError::Unexpected {sec_id: self.sec_id, offset, expected, found}
+    }
+
+    pub fn err_malformed(&self, offset: usize, reason: &'static str) -> DecodeError {
+        DecodeError::Malformed {sec_id: self.sec_id, offset, reason}
+    }
+
+    pub fn err_unsupported(&self, offset: usize, what: &'static str) -> DecodeError {
+        DecodeError::Unsupported {sec_id: self.sec_id, offset, what}
+    }
+
+    fn get_slice(&mut self, n: usize) -> Result<&[u8], DecodeError> {
+        if n > self.end - self.c {
+            return Err(self.err_end());
+        }
+        let s = &self.buf[self.c..self.c + n];
+        self.c += n;
+        Ok(s)
+    }
+
+    pub fn get_byte(&mut self) -> Result<u8, DecodeError> {
+        if self.c >= self.end {
+            return Err(self.err_end());
+        }
         let byte = self.buf[self.c];
         self.c += 1;
-        byte
+        Ok(byte)
     }
 
-    pub fn get_u32(&mut self) -> u32 {
+    pub fn get_u32(&mut self) -> Result<u32, DecodeError> {
         // get u32: unsigned LEB128 encoding
+        let start = self.c;
         let mut num:u32 = 0;
         let mut shift = 0;
         loop {
-            let b = self.buf[self.c];
-            self.c += 1;
+            let b = self.get_byte()?;
+            if shift == 28 && b & 0x70 != 0 {
+                // 5th byte may have only 4 significant bits
+                return Err(self.err_malformed(start, "integer too large"));
+            }
             num |= ((b & 0x7f) as u32) << shift;
             if b & 0x80 == 0 {
                 break;
             }
             shift += 7;
+            if shift > 28 {
+                return Err(self.err_malformed(start, "integer representation too long"));
+            }
         }
 
-        num
+        Ok(num)
     }
 
-    pub fn get_i32(&mut self) -> i32 {
+    pub fn get_i32(&mut self) -> Result<i32, DecodeError> {
+        let start = self.c;
         let mut num:i32 = 0;
         let mut shift = 0;
         let mut b:u8;
         let size = 32;
 
         loop {
-            b = self.buf[self.c];
-            self.c += 1;
+            b = self.get_byte()?;
+            if shift == 28 {
+                // unused bits of the last byte must be a sign extension
+                let rest = b & 0x70;
+                if rest != 0 && rest != 0x70 || (rest == 0x70) != (b & 0x08 != 0) {
+                    return Err(self.err_malformed(start, "integer too large"));
+                }
+            }
             num |= ((b & 0x7f) as i32) << shift;
             shift += 7;
             if b & 0x80 == 0 {
                 break;
             }
+            if shift >= size + 3 {
+                return Err(self.err_malformed(start, "integer representation too long"));
+            }
         }
 
         if (shift < size) && (b & 0x40 != 0) {
             num |= !0 << shift;
         }
 
-        num
+        Ok(num)
     }
 
-    pub fn get_i64(&mut self) -> i64 {
+    pub fn get_i64(&mut self) -> Result<i64, DecodeError> {
+        let start = self.c;
         let mut num:i64 = 0;
         let mut shift = 0;
         let mut b:u8;
         let size = 64;
 
         loop {
-            b = self.buf[self.c];
-            self.c += 1;
+            b = self.get_byte()?;
+            if shift == 63 {
+                // last byte has only 1 significant bit, the rest is sign extension
+                let rest = b & 0x7f;
+                if rest != 0 && rest != 0x7f {
+                    return Err(self.err_malformed(start, "integer too large"));
+                }
+            }
             num |= ((b & 0x7f) as i64) << shift;
             shift += 7;
             if b & 0x80 == 0 {
                 break;
             }
+            if shift >= size + 6 {
+                return Err(self.err_malformed(start, "integer representation too long"));
+            }
         }
 
         if (shift < size) && (b & 0x40 != 0) {
             num |= !0 << shift;
         }
 
-        num
+        Ok(num)
     }
 
-    pub fn get_f32(&mut self) -> f32 {
+    pub fn get_f32(&mut self) -> Result<f32, DecodeError> {
         // IEEE 754 little endian
         let mut tmp: [u8; 4] = [0; 4];
-        tmp.copy_from_slice(&self.buf[self.c..self.c + 4]);
+        tmp.copy_from_slice(self.get_slice(4)?);
         let bin:u32 = u32::from_le_bytes(tmp);
         let num:f32 = f32::from_bits(bin);
-        self.c += 4;
 
-        num
+        Ok(num)
     }
 
-    pub fn get_f64(&mut self) -> f64 {
+    pub fn get_f64(&mut self) -> Result<f64, DecodeError> {
         // IEEE 754 little endian
         let mut tmp: [u8; 8] = [0; 8];
-        tmp.copy_from_slice(&self.buf[self.c..self.c + 8]);
+        tmp.copy_from_slice(self.get_slice(8)?);
         let bin:u64 = u64::from_le_bytes(tmp);
         let num:f64 = f64::from_bits(bin);
-        self.c += 8;
 
-        num
+        Ok(num)
+    }
+
+    pub fn get_name(&mut self) -> Result<String, DecodeError> {
+        let n = self.get_u32()? as usize;
+        let start = self.c;
+        let v = self.get_slice(n)?.to_vec();
+        match String::from_utf8(v) {
+            Ok(name) => Ok(name),
+            Err(_) => Err(self.err_malformed(start, "malformed UTF-8 encoding")),
+        }
+    }
+
+    pub fn get_data(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.get_u32()? as usize;
+        let data = self.get_slice(len)?.to_vec();
+        Ok(data)
+    }
+
+    pub fn get_bytes(&mut self, n: usize) -> Result<Vec<u8>, DecodeError> {
+        Ok(self.get_slice(n)?.to_vec())
+    }
+
+    // the put_* functions append to the end of the buffer.
+    // they always use the shortest (canonical) encoding.
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn put_byte(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn put_u32(&mut self, num: u32) {
+        // unsigned LEB128 encoding
+        let mut num = num;
+        loop {
+            let b = (num & 0x7f) as u8;
+            num >>= 7;
+            if num == 0 {
+                self.buf.push(b);
+                break;
+            }
+            self.buf.push(b | 0x80);
+        }
     }
 
-    pub fn get_name(&mut self) -> String {
-        let n = self.get_u32() as usize;
-        let v = self.buf[self.c..self.c + n].to_vec();
-        let name = String::from_utf8(v).unwrap();
-        self.add_cur(n);
+    pub fn put_i32(&mut self, num: i32) {
+        self.put_i64(num as i64);
+    }
+
+    pub fn put_i64(&mut self, num: i64) {
+        // signed LEB128 encoding
+        let mut num = num;
+        loop {
+            let b = (num & 0x7f) as u8;
+            num >>= 7; // arithmetic shift
+            if (num == 0 && b & 0x40 == 0) || (num == -1 && b & 0x40 != 0) {
+                self.buf.push(b);
+                break;
+            }
+            self.buf.push(b | 0x80);
+        }
+    }
+
+    pub fn put_f32(&mut self, num: f32) {
+        // IEEE 754 little endian
+        self.buf.extend_from_slice(&num.to_bits().to_le_bytes());
+    }
+
+    pub fn put_f64(&mut self, num: f64) {
+        // IEEE 754 little endian
+        self.buf.extend_from_slice(&num.to_bits().to_le_bytes());
+    }
 
-        name
+    pub fn put_name(&mut self, name: &str) {
+        self.put_data(name.as_bytes());
     }
 
-    pub fn get_data(&mut self) -> Vec<u8> {
-        let len = self.get_u32() as usize;
-        let data = self.buf[self.c..self.c + len].to_vec();
-        self.c += len;
-        data
+    pub fn put_data(&mut self, data: &[u8]) {
+        self.put_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
     }
 }
@@ -0,0 +1,1454 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// translate a module to C source (a header and a C file) in the style of wasm2c.
+// each local function becomes a static C function. the operand stack and the
+// locals are C variables of a union type (the stack height at each instruction
+// is static), blocks become labels and branches become gotos.
+// the module is a single instance: the memory, globals and tables are static
+// variables initialized by <prefix>_init(). the memory is a struct and every
+// access is bounds checked. traps longjmp() to <prefix>_trap_jmp.
+// imported functions are function pointers set by the host and exported
+// functions are C functions named <prefix>_<export name>.
+// globals defined in the module are supported. imports other than functions
+// (memories, tables and globals), multiple memories, reference types, table
+// instructions and SIMD are not supported (see unsupported()).
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::bytecode::*;
+use crate::exec::*;
+use crate::inst::*;
+use crate::module::*;
+
+pub struct CSource {
+    pub header: String,
+    pub source: String,
+}
+
+// trap codes (from 1) and the messages, same as the interpreter
+const TRAPS: [(&str, &str); 11] = [
+    ("UNREACHABLE", "trap: unreachable"),
+    ("MEMORY", "trap: out of bounds memory access"),
+    ("DIV_ZERO", "trap: integer divide by zero"),
+    ("OVERFLOW", "trap: integer overflow"),
+    ("CONVERSION", "trap: invalid conversion to integer"),
+    ("UNDEFINED_ELEMENT", "trap: undefined element"),
+    ("UNINITIALIZED_ELEMENT", "trap: uninitialized element"),
+    ("INDIRECT_TYPE", "trap: indirect call type mismatch"),
+    ("CALL_STACK", "trap: call stack exhausted"),
+    ("TABLE", "trap: out of bounds table access"),
+    ("IMPORT", "trap: import function is not provided"),
+];
+
+// $P is replaced by the prefix and $UP by the prefix in upper case
+const HEADER_START: &str = r#"// generated by wasmex from a WebAssembly module
+#ifndef $UP_H
+#define $UP_H
+
+#include <setjmp.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// linear memory. every access is checked against size
+typedef struct {
+    uint8_t *data;
+    uint64_t size; // in bytes
+    uint32_t max_pages;
+} $P_memory_t;
+
+// traps longjmp() to *$P_trap_jmp with the code. if it is NULL, the message is
+// printed and abort() is called.
+extern jmp_buf *$P_trap_jmp;
+const char *$P_trap_message(int code);
+
+// instantiate the module (memory, globals, tables, segments and the start
+// function). returns 0 or the trap code. the imports must be set before.
+int $P_init(void);
+void $P_free(void);
+"#;
+
+const SOURCE_START: &str = r#"// generated by wasmex from a WebAssembly module
+#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "$P.h"
+
+#define MAX_CALL_DEPTH 10000
+#define PAGE_SIZE 65536
+
+// values on the operand stack and locals. i64 is the first member so that
+// {0} clears all the bits. floats are read and written by their bits through
+// i32/i64 (reinterpret is nothing)
+typedef union {
+    uint64_t i64;
+    uint32_t i32;
+    float f32;
+    double f64;
+} Value;
+
+// element of a table. type is the index of the first equal function type
+typedef struct {
+    uint32_t type;
+    void (*func)(void);
+} Funcref;
+
+jmp_buf *$P_trap_jmp;
+static uint32_t depth;
+
+static void trap(int code) {
+    depth = 0;
+    if ($P_trap_jmp)
+        longjmp(*$P_trap_jmp, code);
+    fprintf(stderr, "%s\n", $P_trap_message(code));
+    abort();
+}
+
+$P_memory_t $P_memory;
+
+static inline uint8_t *mem_at(uint32_t addr, uint32_t offset, uint32_t n) {
+    uint64_t ea = (uint64_t)addr + offset;
+    if (ea + n > $P_memory.size)
+        trap($UP_TRAP_MEMORY);
+    return $P_memory.data + ea;
+}
+
+// little endian regardless of the host
+static inline uint64_t load8(const uint8_t *p) { return p[0]; }
+static inline uint64_t load16(const uint8_t *p) { return p[0] | (uint64_t)p[1] << 8; }
+static inline uint64_t load32(const uint8_t *p) { return load16(p) | load16(p + 2) << 16; }
+static inline uint64_t load64(const uint8_t *p) { return load32(p) | load32(p + 4) << 32; }
+static inline void store8(uint8_t *p, uint64_t v) { p[0] = (uint8_t)v; }
+static inline void store16(uint8_t *p, uint64_t v) { store8(p, v); store8(p + 1, v >> 8); }
+static inline void store32(uint8_t *p, uint64_t v) { store16(p, v); store16(p + 2, v >> 16); }
+static inline void store64(uint8_t *p, uint64_t v) { store32(p, v); store32(p + 4, v >> 32); }
+
+static uint32_t memory_grow(uint32_t n) {
+    uint32_t old = (uint32_t)($P_memory.size / PAGE_SIZE);
+    uint64_t size = $P_memory.size + (uint64_t)n * PAGE_SIZE;
+    uint8_t *data;
+    if ((uint64_t)old + n > $P_memory.max_pages)
+        return 0xffffffffu;
+    if (n == 0)
+        return old;
+    data = realloc($P_memory.data, (size_t)size);
+    if (!data)
+        return 0xffffffffu;
+    memset(data + $P_memory.size, 0, (size_t)(size - $P_memory.size));
+    $P_memory.data = data;
+    $P_memory.size = size;
+    return old;
+}
+
+static inline void memory_copy(uint32_t d, uint32_t s, uint32_t n) {
+    uint8_t *src = mem_at(s, 0, n);
+    memmove(mem_at(d, 0, n), src, n);
+}
+
+static inline void memory_fill(uint32_t d, uint32_t v, uint32_t n) {
+    memset(mem_at(d, 0, n), (int)(uint8_t)v, n);
+}
+
+static inline void memory_init(const uint8_t *data, uint32_t size, uint32_t d, uint32_t s, uint32_t n) {
+    if ((uint64_t)s + n > size)
+        trap($UP_TRAP_MEMORY);
+    memcpy(mem_at(d, 0, n), data + s, n);
+}
+
+static inline float f32_bits(uint32_t n) { Value v; v.i32 = n; return v.f32; }
+static inline double f64_bits(uint64_t n) { Value v; v.i64 = n; return v.f64; }
+static inline uint32_t f32_to_bits(float z) { Value v; v.f32 = z; return v.i32; }
+static inline uint64_t f64_to_bits(double z) { Value v; v.f64 = z; return v.i64; }
+
+static inline uint32_t i32_div_s(uint32_t a, uint32_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    if (a == 0x80000000u && b == 0xffffffffu)
+        trap($UP_TRAP_OVERFLOW);
+    return (uint32_t)((int32_t)a / (int32_t)b);
+}
+
+static inline uint32_t i32_div_u(uint32_t a, uint32_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    return a / b;
+}
+
+static inline uint32_t i32_rem_s(uint32_t a, uint32_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    if (b == 0xffffffffu)
+        return 0;
+    return (uint32_t)((int32_t)a % (int32_t)b);
+}
+
+static inline uint32_t i32_rem_u(uint32_t a, uint32_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    return a % b;
+}
+
+static inline uint64_t i64_div_s(uint64_t a, uint64_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    if (a == UINT64_C(0x8000000000000000) && b == UINT64_MAX)
+        trap($UP_TRAP_OVERFLOW);
+    return (uint64_t)((int64_t)a / (int64_t)b);
+}
+
+static inline uint64_t i64_div_u(uint64_t a, uint64_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    return a / b;
+}
+
+static inline uint64_t i64_rem_s(uint64_t a, uint64_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    if (b == UINT64_MAX)
+        return 0;
+    return (uint64_t)((int64_t)a % (int64_t)b);
+}
+
+static inline uint64_t i64_rem_u(uint64_t a, uint64_t b) {
+    if (b == 0)
+        trap($UP_TRAP_DIV_ZERO);
+    return a % b;
+}
+
+static inline uint32_t rotl32(uint32_t a, uint32_t b) { b &= 31; return b ? a << b | a >> (32 - b) : a; }
+static inline uint32_t rotr32(uint32_t a, uint32_t b) { b &= 31; return b ? a >> b | a << (32 - b) : a; }
+static inline uint64_t rotl64(uint64_t a, uint64_t b) { b &= 63; return b ? a << b | a >> (64 - b) : a; }
+static inline uint64_t rotr64(uint64_t a, uint64_t b) { b &= 63; return b ? a >> b | a << (64 - b) : a; }
+
+#if defined(__GNUC__)
+static inline uint32_t clz32(uint32_t a) { return a ? (uint32_t)__builtin_clz(a) : 32; }
+static inline uint32_t ctz32(uint32_t a) { return a ? (uint32_t)__builtin_ctz(a) : 32; }
+static inline uint32_t popcnt32(uint32_t a) { return (uint32_t)__builtin_popcount(a); }
+static inline uint64_t clz64(uint64_t a) { return a ? (uint64_t)__builtin_clzll(a) : 64; }
+static inline uint64_t ctz64(uint64_t a) { return a ? (uint64_t)__builtin_ctzll(a) : 64; }
+static inline uint64_t popcnt64(uint64_t a) { return (uint64_t)__builtin_popcountll(a); }
+#else
+static inline uint64_t clz64(uint64_t a) {
+    uint64_t n = 0;
+    if (!a)
+        return 64;
+    for (; !(a & UINT64_C(0x8000000000000000)); a <<= 1)
+        n++;
+    return n;
+}
+static inline uint64_t ctz64(uint64_t a) {
+    uint64_t n = 0;
+    if (!a)
+        return 64;
+    for (; !(a & 1); a >>= 1)
+        n++;
+    return n;
+}
+static inline uint64_t popcnt64(uint64_t a) {
+    uint64_t n = 0;
+    for (; a; a &= a - 1)
+        n++;
+    return n;
+}
+static inline uint32_t clz32(uint32_t a) { return (uint32_t)clz64(a) - 32; }
+static inline uint32_t ctz32(uint32_t a) { return a ? (uint32_t)ctz64(a) : 32; }
+static inline uint32_t popcnt32(uint32_t a) { return (uint32_t)popcnt64(a); }
+#endif
+
+static inline float f32_min(float a, float b) {
+    if (a != a || b != b)
+        return f32_bits(0x7fc00000u);
+    if (a == 0 && b == 0)
+        return signbit(a) ? a : b;
+    return a < b ? a : b;
+}
+
+static inline float f32_max(float a, float b) {
+    if (a != a || b != b)
+        return f32_bits(0x7fc00000u);
+    if (a == 0 && b == 0)
+        return signbit(a) ? b : a;
+    return a > b ? a : b;
+}
+
+static inline double f64_min(double a, double b) {
+    if (a != a || b != b)
+        return f64_bits(UINT64_C(0x7ff8000000000000));
+    if (a == 0 && b == 0)
+        return signbit(a) ? a : b;
+    return a < b ? a : b;
+}
+
+static inline double f64_max(double a, double b) {
+    if (a != a || b != b)
+        return f64_bits(UINT64_C(0x7ff8000000000000));
+    if (a == 0 && b == 0)
+        return signbit(a) ? b : a;
+    return a > b ? a : b;
+}
+
+// float to integer. f32 is converted to double exactly
+static inline double trunc_check(double z, double lower, double upper) {
+    if (z != z)
+        trap($UP_TRAP_CONVERSION);
+    if (z <= lower || z >= upper)
+        trap($UP_TRAP_OVERFLOW);
+    return z;
+}
+
+static inline uint32_t i32_trunc_s(double z) { return (uint32_t)(int32_t)trunc_check(z, -2147483649.0, 2147483648.0); }
+static inline uint32_t i32_trunc_u(double z) { return (uint32_t)trunc_check(z, -1.0, 4294967296.0); }
+static inline uint64_t i64_trunc_s(double z) {
+    return (uint64_t)(int64_t)trunc_check(z, -9223372036854777856.0, 9223372036854775808.0);
+}
+static inline uint64_t i64_trunc_u(double z) { return (uint64_t)trunc_check(z, -1.0, 18446744073709551616.0); }
+
+static inline uint32_t i32_trunc_sat_s(double z) {
+    if (z != z)
+        return 0;
+    if (z <= -2147483649.0)
+        return 0x80000000u;
+    if (z >= 2147483648.0)
+        return 0x7fffffffu;
+    return (uint32_t)(int32_t)z;
+}
+
+static inline uint32_t i32_trunc_sat_u(double z) {
+    if (!(z > -1.0))
+        return 0;
+    if (z >= 4294967296.0)
+        return 0xffffffffu;
+    return (uint32_t)z;
+}
+
+static inline uint64_t i64_trunc_sat_s(double z) {
+    if (z != z)
+        return 0;
+    if (z <= -9223372036854777856.0)
+        return UINT64_C(0x8000000000000000);
+    if (z >= 9223372036854775808.0)
+        return UINT64_C(0x7fffffffffffffff);
+    return (uint64_t)(int64_t)z;
+}
+
+static inline uint64_t i64_trunc_sat_u(double z) {
+    if (!(z > -1.0))
+        return 0;
+    if (z >= 18446744073709551616.0)
+        return UINT64_MAX;
+    return (uint64_t)z;
+}
+
+static inline void (*table_func(const Funcref *table, uint32_t size, uint32_t i, uint32_t type))(void) {
+    if (i >= size)
+        trap($UP_TRAP_UNDEFINED_ELEMENT);
+    if (!table[i].func)
+        trap($UP_TRAP_UNINITIALIZED_ELEMENT);
+    if (table[i].type != type)
+        trap($UP_TRAP_INDIRECT_TYPE);
+    return table[i].func;
+}
+"#;
+
+fn expand(template: &str, prefix: &str) -> String {
+    template.replace("$UP", &prefix.to_uppercase()).replace("$P", prefix)
+}
+
+// name usable as a part of a C identifier
+fn c_ident(name: &str) -> String {
+    let mut s: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if s.is_empty() || s.starts_with(|c: char| c.is_ascii_digit()) {
+        s.insert(0, '_');
+    }
+    s
+}
+
+fn c_type(t: &Valtype) -> Result<&'static str, String> {
+    match t.0 {
+        0x7f => Ok("uint32_t"),
+        0x7e => Ok("uint64_t"),
+        0x7d => Ok("float"),
+        0x7c => Ok("double"),
+        _ => Err(format!("{} is not supported", t)),
+    }
+}
+
+// member of Value
+fn member(t: &Valtype) -> &'static str {
+    match t.0 {
+        0x7e => "i64",
+        0x7d => "f32",
+        0x7c => "f64",
+        _ => "i32",
+    }
+}
+
+// C literal of a value
+fn c_literal(v: &Value) -> Result<String, String> {
+    match v {
+        Value::I32(n) => Ok(format!("{:#x}u", *n as u32)),
+        Value::I64(n) => Ok(format!("UINT64_C({:#x})", *n as u64)),
+        Value::F32(z) => Ok(format!("f32_bits({:#x}u)", z.to_bits())),
+        Value::F64(z) => Ok(format!("f64_bits(UINT64_C({:#x}))", z.to_bits())),
+        _ => Err(format!("{} is not supported", v)),
+    }
+}
+
+// initializer of a static Value
+fn c_initializer(v: &Value) -> Result<String, String> {
+    match v {
+        Value::I32(n) => Ok(format!("{{.i32 = {:#x}u}}", *n as u32)),
+        Value::I64(n) => Ok(format!("{{.i64 = UINT64_C({:#x})}}", *n as u64)),
+        Value::F32(z) => Ok(format!("{{.i32 = {:#x}u}}", z.to_bits())),
+        Value::F64(z) => Ok(format!("{{.i64 = UINT64_C({:#x})}}", z.to_bits())),
+        _ => Err(format!("{} is not supported", v)),
+    }
+}
+
+// evaluate a constant expression (same as exec) with the values of the globals so far
+fn const_value(expr: &Expr, globals: &[Value]) -> Result<Value, String> {
+    let mut stack = Vec::new();
+    for inst in expr.get_insts().map_err(|e| e.to_string())? {
+        let v = match (inst.op_code, &inst.operand) {
+            (0x41, Operand::I32(n)) => Value::I32(*n),
+            (0x42, Operand::I64(n)) => Value::I64(*n),
+            (0x43, Operand::F32(z)) => Value::F32(*z),
+            (0x44, Operand::F64(z)) => Value::F64(*z),
+            (0x23, Operand::Index(idx)) => globals.get(*idx as usize).cloned()
+                .ok_or(format!("unknown global {}", idx))?,
+            (0xd0, _) => Value::FuncRef(None),
+            (0xd2, Operand::Index(idx)) => Value::FuncRef(Some(*idx)),
+            (0x6a..=0x6c | 0x7c..=0x7e, _) => {
+                let (b, a) = (stack.pop(), stack.pop());
+                match (a, b) {
+                    (Some(Value::I32(a)), Some(Value::I32(b))) => Value::I32(match inst.op_code {
+                        0x6a => a.wrapping_add(b),
+                        0x6b => a.wrapping_sub(b),
+                        _ => a.wrapping_mul(b),
+                    }),
+                    (Some(Value::I64(a)), Some(Value::I64(b))) => Value::I64(match inst.op_code {
+                        0x7c => a.wrapping_add(b),
+                        0x7d => a.wrapping_sub(b),
+                        _ => a.wrapping_mul(b),
+                    }),
+                    _ => return Err("type mismatch in constant expression".to_string()),
+                }
+            },
+            (0x0b, _) => break,
+            _ => return Err(format!("op({:#02x}) is not supported in constant expression", inst.op_code)),
+        };
+        stack.push(v);
+    }
+    match (stack.pop(), stack.is_empty()) {
+        (Some(v), true) => Ok(v),
+        _ => Err("constant expression must result in one value".to_string()),
+    }
+}
+
+fn const_offset(expr: &Expr, globals: &[Value]) -> Result<u32, String> {
+    match const_value(expr, globals)? {
+        Value::I32(n) => Ok(n as u32),
+        _ => Err("offset is not i32".to_string()),
+    }
+}
+
+// numeric instruction: (number of operands, type of the operands, type of the result, C expression).
+// $1 and $2 in the expression are the operands. the expression is empty if it is nothing to do.
+fn numeric(inst: &Inst) -> Option<(usize, u8, u8, String)> {
+    const I32: u8 = 0x7f;
+    const I64: u8 = 0x7e;
+    const F32: u8 = 0x7d;
+    const F64: u8 = 0x7c;
+    const CMP: [&str; 10] = ["$1 == $2", "$1 != $2", "(S)$1 < (S)$2", "$1 < $2", "(S)$1 > (S)$2",
+                             "$1 > $2", "(S)$1 <= (S)$2", "$1 <= $2", "(S)$1 >= (S)$2", "$1 >= $2"];
+    const FCMP: [&str; 6] = ["$1 == $2", "$1 != $2", "$1 < $2", "$1 > $2", "$1 <= $2", "$1 >= $2"];
+    const INT: [&str; 18] = ["clzN($1)", "ctzN($1)", "popcntN($1)", "$1 + $2", "$1 - $2", "$1 * $2",
+                             "iN_div_s($1, $2)", "iN_div_u($1, $2)", "iN_rem_s($1, $2)", "iN_rem_u($1, $2)",
+                             "$1 & $2", "$1 | $2", "$1 ^ $2", "$1 << ($2 & M)", "(U)((S)$1 >> ($2 & M))",
+                             "$1 >> ($2 & M)", "rotlN($1, $2)", "rotrN($1, $2)"];
+    const FLOAT: [&str; 14] = ["fabsF($1)", "-$1", "ceilF($1)", "floorF($1)", "truncF($1)", "nearbyintF($1)",
+                               "sqrtF($1)", "$1 + $2", "$1 - $2", "$1 * $2", "$1 / $2", "fN_min($1, $2)",
+                               "fN_max($1, $2)", "copysignF($1, $2)"];
+    let arity = |e: &str| if e.contains("$2") { 2 } else { 1 };
+    let int = |e: &str, n: &str, s: &str, u: &str, m: &str| {
+        e.replace("iN", &format!("i{}", n)).replace('N', n).replace("(S)", &format!("({})", s))
+            .replace("(U)", &format!("({})", u)).replace('M', m)
+    };
+    let op = inst.op_code;
+    let (ty_in, ty_out, e) = match op {
+        0x45 => (I32, I32, "!$1".to_string()), // i32.eqz
+        0x46..=0x4f => (I32, I32, int(CMP[(op - 0x46) as usize], "32", "int32_t", "uint32_t", "31")),
+        0x50 => (I64, I32, "!$1".to_string()), // i64.eqz
+        0x51..=0x5a => (I64, I32, int(CMP[(op - 0x51) as usize], "64", "int64_t", "uint64_t", "63")),
+        0x5b..=0x60 => (F32, I32, FCMP[(op - 0x5b) as usize].to_string()),
+        0x61..=0x66 => (F64, I32, FCMP[(op - 0x61) as usize].to_string()),
+        0x67..=0x78 => (I32, I32, int(INT[(op - 0x67) as usize], "32", "int32_t", "uint32_t", "31")),
+        0x79..=0x8a => (I64, I64, int(INT[(op - 0x79) as usize], "64", "int64_t", "uint64_t", "63")),
+        0x8b..=0x98 => (F32, F32, FLOAT[(op - 0x8b) as usize].replace('F', "f").replace('N', "32")),
+        0x99..=0xa6 => (F64, F64, FLOAT[(op - 0x99) as usize].replace('F', "").replace('N', "64")),
+        0xa7 => (I64, I32, "(uint32_t)$1".to_string()), // i32.wrap_i64
+        0xa8 => (F32, I32, "i32_trunc_s($1)".to_string()),
+        0xa9 => (F32, I32, "i32_trunc_u($1)".to_string()),
+        0xaa => (F64, I32, "i32_trunc_s($1)".to_string()),
+        0xab => (F64, I32, "i32_trunc_u($1)".to_string()),
+        0xac => (I32, I64, "(uint64_t)(int64_t)(int32_t)$1".to_string()), // i64.extend_i32_s
+        0xad => (I32, I64, "(uint64_t)$1".to_string()),
+        0xae => (F32, I64, "i64_trunc_s($1)".to_string()),
+        0xaf => (F32, I64, "i64_trunc_u($1)".to_string()),
+        0xb0 => (F64, I64, "i64_trunc_s($1)".to_string()),
+        0xb1 => (F64, I64, "i64_trunc_u($1)".to_string()),
+        0xb2 => (I32, F32, "(float)(int32_t)$1".to_string()), // f32.convert_i32_s
+        0xb3 => (I32, F32, "(float)$1".to_string()),
+        0xb4 => (I64, F32, "(float)(int64_t)$1".to_string()),
+        0xb5 => (I64, F32, "(float)$1".to_string()),
+        0xb6 => (F64, F32, "(float)$1".to_string()), // f32.demote_f64
+        0xb7 => (I32, F64, "(double)(int32_t)$1".to_string()),
+        0xb8 => (I32, F64, "(double)$1".to_string()),
+        0xb9 => (I64, F64, "(double)(int64_t)$1".to_string()),
+        0xba => (I64, F64, "(double)$1".to_string()),
+        0xbb => (F32, F64, "(double)$1".to_string()), // f64.promote_f32
+        0xbc..=0xbf => (I32, I32, String::new()), // reinterpret
+        0xc0 => (I32, I32, "(uint32_t)(int32_t)(int8_t)$1".to_string()), // i32.extend8_s
+        0xc1 => (I32, I32, "(uint32_t)(int32_t)(int16_t)$1".to_string()),
+        0xc2 => (I64, I64, "(uint64_t)(int64_t)(int8_t)$1".to_string()), // i64.extend8_s
+        0xc3 => (I64, I64, "(uint64_t)(int64_t)(int16_t)$1".to_string()),
+        0xc4 => (I64, I64, "(uint64_t)(int64_t)(int32_t)$1".to_string()),
+        0xfc if inst.sub_op <= 7 => { // trunc_sat
+            let ty_in = if inst.sub_op & 2 == 0 { F32 } else { F64 };
+            let ty_out = if inst.sub_op < 4 { I32 } else { I64 };
+            let sign = if inst.sub_op & 1 == 0 { 's' } else { 'u' };
+            (ty_in, ty_out, format!("i{}_trunc_sat_{}($1)", if ty_out == I32 { 32 } else { 64 }, sign))
+        },
+        _ => return None,
+    };
+    Some((arity(&e), ty_in, ty_out, e))
+}
+
+// (width, type of the value, C expression of the value loaded from p)
+fn load_op(op_code: u8) -> (u32, u8, &'static str) {
+    match op_code {
+        0x28 | 0x2a => (4, 0x7f, "(uint32_t)load32(p)"), // i32.load, f32.load (as bits)
+        0x29 | 0x2b => (8, 0x7e, "load64(p)"),
+        0x2c => (1, 0x7f, "(uint32_t)(int32_t)(int8_t)load8(p)"),
+        0x2d => (1, 0x7f, "(uint32_t)load8(p)"),
+        0x2e => (2, 0x7f, "(uint32_t)(int32_t)(int16_t)load16(p)"),
+        0x2f => (2, 0x7f, "(uint32_t)load16(p)"),
+        0x30 => (1, 0x7e, "(uint64_t)(int64_t)(int8_t)load8(p)"),
+        0x31 => (1, 0x7e, "load8(p)"),
+        0x32 => (2, 0x7e, "(uint64_t)(int64_t)(int16_t)load16(p)"),
+        0x33 => (2, 0x7e, "load16(p)"),
+        0x34 => (4, 0x7e, "(uint64_t)(int64_t)(int32_t)load32(p)"),
+        _ => (4, 0x7e, "load32(p)"), // i64.load32_u
+    }
+}
+
+// (width, type of the value)
+fn store_op(op_code: u8) -> (u32, u8) {
+    match op_code {
+        0x36 | 0x38 => (4, 0x7f), // i32.store, f32.store (as bits)
+        0x37 | 0x39 => (8, 0x7e),
+        0x3a => (1, 0x7f),
+        0x3b => (2, 0x7f),
+        0x3c => (1, 0x7e),
+        0x3d => (2, 0x7e),
+        _ => (4, 0x7e), // i64.store32
+    }
+}
+
+struct Gen<'a> {
+    module: &'a Module,
+    prefix: String,
+    multi: Vec<Vec<Valtype>>, // result types of the functions returning multiple values
+    tables: Vec<u32>, // sizes of the tables
+}
+
+impl Gen<'_> {
+    // index of the first function type equal to ft
+    fn type_id(&self, ft: &Functype) -> usize {
+        self.module.types().iter().position(|t| t == ft).unwrap_or(0)
+    }
+
+    fn ret_type(&self, ft: &Functype) -> Result<String, String> {
+        match ft.output.0.len() {
+            0 => Ok("void".to_string()),
+            1 => Ok(c_type(&ft.output.0[0])?.to_string()),
+            _ => {
+                let n = self.multi.iter().position(|r| *r == ft.output.0).unwrap_or(0);
+                Ok(format!("{}_results{}", self.prefix, n))
+            },
+        }
+    }
+
+    fn param_types(&self, ft: &Functype) -> Result<Vec<&'static str>, String> {
+        ft.input.0.iter().map(c_type).collect()
+    }
+
+    // "ret name(params)" with the names of the params if named
+    fn signature(&self, ft: &Functype, name: &str, named: bool) -> Result<String, String> {
+        let params = self.param_types(ft)?;
+        let params: Vec<String> = params.iter().enumerate()
+            .map(|(i, t)| if named { format!("{} p{}", t, i) } else { t.to_string() })
+            .collect();
+        let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+        Ok(format!("{} {}({})", self.ret_type(ft)?, name, params))
+    }
+
+    // C function pointer type of ft
+    fn pointer_type(&self, ft: &Functype) -> Result<String, String> {
+        self.signature(ft, "(*)", false)
+    }
+}
+
+// result types of the functions returning multiple values. each has a struct
+fn multi_results(module: &Module) -> Vec<Vec<Valtype>> {
+    let mut multi: Vec<Vec<Valtype>> = Vec::new();
+    for ft in module.types() {
+        if ft.output.0.len() > 1 && !multi.contains(&ft.output.0) {
+            multi.push(ft.output.0.clone());
+        }
+    }
+    multi
+}
+
+// the parts of the module which can't be translated. the instructions are
+// checked while translating the functions.
+pub fn unsupported(module: &Module) -> Vec<String> {
+    let mut list = Vec::new();
+    for import in module.imports() {
+        let kind = match import.desc() {
+            Importdesc::Func(_) => continue,
+            Importdesc::Table(_) => "table",
+            Importdesc::Mem(_) => "memory",
+            Importdesc::Global(_) => "global",
+        };
+        list.push(format!("imported {} {}.{}", kind, import.module(), import.name()));
+    }
+    if module.mems().len() > 1 {
+        list.push("multiple memories".to_string());
+    }
+    let imported = module.imports().iter().filter(|i| matches!(i.desc(), Importdesc::Global(_))).count();
+    for (i, global) in module.globals().iter().enumerate() {
+        let t = Valtype(global.globaltype().valtype());
+        if c_type(&t).is_err() {
+            list.push(format!("global[{}] of {}", imported + i, t));
+        }
+    }
+    list
+}
+
+pub fn module_to_c(module: &Module, prefix: &str) -> Result<CSource, String> {
+    if !module.is_validated() {
+        return Err("module is not validated".to_string());
+    }
+    let prefix = c_ident(prefix);
+    if let Some(err) = unsupported(module).into_iter().next() {
+        return Err(format!("{} is not supported", err));
+    }
+    let multi = multi_results(module);
+    let tables = module.tables().iter().map(|tt| tt.limits().min()).collect();
+    let g = Gen {module, prefix, multi, tables};
+    let p = &g.prefix;
+    let mut header = expand(HEADER_START, p);
+    let mut source = expand(SOURCE_START, p);
+
+    // trap codes and results
+    header.push_str("\nenum {\n");
+    for (i, (name, _)) in TRAPS.iter().enumerate() {
+        header.push_str(&format!("    {}_TRAP_{} = {},\n", p.to_uppercase(), name, i + 1));
+    }
+    header.push_str("};\n");
+    for (n, results) in g.multi.iter().enumerate() {
+        header.push_str("\ntypedef struct {\n");
+        for (i, t) in results.iter().enumerate() {
+            header.push_str(&format!("    {} r{};\n", c_type(t)?, i));
+        }
+        header.push_str(&format!("}} {}_results{};\n", p, n));
+    }
+    source.push_str(&format!("\nconst char *{}_trap_message(int code) {{\n    switch (code) {{\n", p));
+    for (i, (_, message)) in TRAPS.iter().enumerate() {
+        source.push_str(&format!("    case {}: return \"{}\";\n", i + 1, message));
+    }
+    source.push_str("    default: return \"trap\";\n    }\n}\n");
+    if !module.mems().is_empty() {
+        header.push_str(&format!("\nextern {}_memory_t {}_memory;\n", p, p));
+    }
+
+    // globals
+    let mut globals = Vec::new();
+    if !module.globals().is_empty() {
+        source.push('\n');
+    }
+    for (i, global) in module.globals().iter().enumerate() {
+        let v = const_value(global.expr(), &globals).map_err(|e| format!("global[{}]: {}", i, e))?;
+        source.push_str(&format!("static Value g{} = {};\n", i, c_initializer(&v)?));
+        globals.push(v);
+    }
+
+    // data segments. the size becomes 0 by data.drop
+    for (i, data) in module.datas().iter().enumerate() {
+        let bytes: Vec<String> = data.data().iter().map(|b| b.to_string()).collect();
+        let bytes = if bytes.is_empty() { "0".to_string() } else { bytes.join(",") };
+        source.push_str(&format!("\nstatic const uint8_t d{}[] = {{{}}};\n", i, bytes));
+        source.push_str(&format!("static uint32_t d{}_size = {};\n", i, data.data().len()));
+    }
+
+    // tables
+    for (i, size) in g.tables.iter().enumerate() {
+        source.push_str(&format!("\nstatic Funcref t{}[{}];\n", i, (*size).max(1)));
+    }
+
+    // functions
+    let mut decls = String::new();
+    let mut defs = String::new();
+    let mut exports = String::new();
+    let mut import_ptrs = String::new();
+    for idx in 0..module.num_funcs() {
+        let ft = func_type(idx, module).ok_or("unknown function")?;
+        let name = format!("f{}", idx);
+        decls.push_str(&format!("static {};\n", g.signature(ft, &name, false)?));
+        match module.get_func(idx) {
+            Some(Function::Local(func)) => {
+                let code = gen_func(&g, idx, func).map_err(|e| format!("{}: {}", module.func_desc(idx), e))?;
+                defs.push_str(&code);
+            },
+            _ => {
+                // a wrapper of the pointer set by the host
+                let import = module.imports().iter().filter(|i| matches!(i.desc(), Importdesc::Func(_))).nth(idx)
+                    .ok_or("unknown import")?;
+                let ptr = format!("{}_import_{}_{}", p, c_ident(import.module()), c_ident(import.name()));
+                let decl = g.signature(ft, &format!("(*{})", ptr), false)?;
+                if import_ptrs.is_empty() {
+                    header.push_str("\n// imports. set them before init\n");
+                }
+                header.push_str(&format!("extern {};\n", decl));
+                import_ptrs.push_str(&format!("{};\n", decl));
+                let args: Vec<String> = (0..ft.input.0.len()).map(|i| format!("p{}", i)).collect();
+                let ret = if ft.output.0.is_empty() { "" } else { "return " };
+                defs.push_str(&format!("\nstatic {} {{\n    if (!{})\n        trap({}_TRAP_IMPORT);\n    {}{}({});\n}}\n",
+                                       g.signature(ft, &name, true)?, ptr, p.to_uppercase(), ret, ptr,
+                                       args.join(", ")));
+            },
+        }
+    }
+    if !import_ptrs.is_empty() {
+        source.push('\n');
+        source.push_str(&import_ptrs);
+    }
+    source.push('\n');
+    source.push_str(&decls);
+    source.push_str(&defs);
+
+    // exports. the names must not conflict with the others
+    let mut names: Vec<String> = ["init", "free", "memory", "memory_t", "trap_jmp", "trap_message"].iter()
+        .map(|n| format!("{}_{}", p, n))
+        .collect();
+    for export in module.exports() {
+        if let Exportdesc::Func(idx) = export.desc() {
+            let ft = func_type(*idx as usize, module).ok_or("unknown function")?;
+            let mut name = format!("{}_{}", p, c_ident(export.name()));
+            if names.contains(&name) || name.starts_with(&format!("{}_import_", p))
+                || name.starts_with(&format!("{}_results", p)) {
+                name = format!("{}_{}", name, names.len());
+            }
+            names.push(name.clone());
+            if exports.is_empty() {
+                header.push_str("\n// exports\n");
+            }
+            header.push_str(&format!("{};\n", g.signature(ft, &name, false)?));
+            let args: Vec<String> = (0..ft.input.0.len()).map(|i| format!("p{}", i)).collect();
+            let ret = if ft.output.0.is_empty() { "" } else { "return " };
+            exports.push_str(&format!("\n{} {{\n    {}f{}({});\n}}\n", g.signature(ft, &name, true)?, ret, idx,
+                                      args.join(", ")));
+        }
+    }
+    source.push_str(&exports);
+    source.push_str(&gen_init(&g, &globals)?);
+    header.push_str(&format!("\n#ifdef __cplusplus\n}}\n#endif\n\n#endif // {}_H\n", p.to_uppercase()));
+    Ok(CSource {header, source})
+}
+
+// <prefix>_init() and <prefix>_free()
+fn gen_init(g: &Gen, globals: &[Value]) -> Result<String, String> {
+    let module = g.module;
+    let p = &g.prefix;
+    let up = p.to_uppercase();
+    let mut body = Vec::new();
+    if let Some(limits) = module.mems().first() {
+        let max = limits.max().unwrap_or(65536);
+        body.push(format!("{}_memory.size = (uint64_t){} * PAGE_SIZE;", p, limits.min()));
+        body.push(format!("{}_memory.max_pages = {};", p, max));
+        body.push(format!("{}_memory.data = calloc((size_t){}_memory.size + 1, 1);", p, p));
+        body.push(format!("if (!{}_memory.data)", p));
+        body.push(format!("    trap({}_TRAP_MEMORY);", up));
+    }
+    for (i, global) in module.globals().iter().enumerate() {
+        body.push(format!("g{} = (Value){};", i, c_initializer(&const_value(global.expr(), globals)?)?));
+    }
+    for (i, elem) in module.elems().iter().enumerate() {
+        let (tableidx, offset) = match elem.mode() {
+            ElemMode::Active(tableidx, expr) => (tableidx, const_offset(expr, globals)?),
+            _ => continue,
+        };
+        let funcs: Vec<Option<u32>> = match elem.init() {
+            ElemInit::Funcs(funcs) => funcs.iter().map(|idx| Some(*idx)).collect(),
+            ElemInit::Exprs(exprs) => {
+                let mut funcs = Vec::new();
+                for expr in exprs {
+                    match const_value(expr, globals)? {
+                        Value::FuncRef(r) => funcs.push(r),
+                        _ => return Err(format!("elem[{}]: not funcref", i)),
+                    }
+                }
+                funcs
+            },
+        };
+        let size = g.tables.get(tableidx as usize).ok_or(format!("elem[{}]: unknown table", i))?;
+        if offset as u64 + funcs.len() as u64 > *size as u64 {
+            body.push(format!("trap({}_TRAP_TABLE); // elem[{}]", up, i));
+            break;
+        }
+        for (k, f) in funcs.iter().enumerate() {
+            let entry = match f {
+                Some(idx) => {
+                    let ft = func_type(*idx as usize, module).ok_or("unknown function")?;
+                    format!("{{{}, (void (*)(void))f{}}}", g.type_id(ft), idx)
+                },
+                None => "{0, NULL}".to_string(),
+            };
+            body.push(format!("t{}[{}] = (Funcref){};", tableidx, offset as u64 + k as u64, entry));
+        }
+    }
+    for (i, data) in module.datas().iter().enumerate() {
+        if let DataMode::Active(_, expr) = data.mode() {
+            let offset = const_offset(expr, globals)?;
+            body.push(format!("memory_init(d{}, d{}_size, {:#x}u, 0, d{}_size);", i, i, offset, i));
+            body.push(format!("d{}_size = 0;", i));
+        }
+    }
+    if let Some(idx) = module.start() {
+        body.push(format!("f{}();", idx));
+    }
+
+    let mut s = format!("\nint {}_init(void) {{\n", p);
+    s.push_str(&format!("    jmp_buf jb, *saved = {}_trap_jmp;\n    int code;\n", p));
+    s.push_str(&format!("    {}_free();\n", p));
+    for (i, data) in module.datas().iter().enumerate() {
+        s.push_str(&format!("    d{}_size = {};\n", i, data.data().len()));
+    }
+    for (i, size) in g.tables.iter().enumerate() {
+        s.push_str(&format!("    memset(t{}, 0, sizeof(Funcref) * {});\n", i, size));
+    }
+    s.push_str(&format!("    {}_trap_jmp = &jb;\n    if ((code = setjmp(jb)) == 0) {{\n", p));
+    for line in body {
+        s.push_str(&format!("        {}\n", line));
+    }
+    s.push_str(&format!("    }}\n    {}_trap_jmp = saved;\n    return code;\n}}\n", p));
+    s.push_str(&format!("\nvoid {}_free(void) {{\n", p));
+    s.push_str(&format!("    free({}_memory.data);\n    memset(&{}_memory, 0, sizeof({}_memory));\n", p, p, p));
+    s.push_str("    depth = 0;\n}\n");
+    Ok(s)
+}
+
+// block being translated
+struct Ctrl {
+    is_loop: bool,
+    height: usize, // operand stack height at the entry (without params)
+    params: usize,
+    results: usize,
+    label: usize, // end of the block or start of the loop
+    used: bool, // label is the target of a branch
+    label_line: usize, // line of the label of the loop
+    else_label: Option<usize>,
+    unreachable: bool,
+    dead: bool, // in unreachable code. not translated at all
+}
+
+struct FuncGen<'a> {
+    g: &'a Gen<'a>,
+    ft: &'a Functype,
+    lines: Vec<String>,
+    ctrls: Vec<Ctrl>,
+    nlocals: usize,
+    height: usize,
+    max_height: usize,
+    labels: usize,
+}
+
+fn gen_func(g: &Gen, idx: usize, func: &LocalFunc) -> Result<String, String> {
+    let ft = &func.ft;
+    let mut f = FuncGen {
+        g,
+        ft,
+        lines: Vec::new(),
+        ctrls: Vec::new(),
+        nlocals: ft.input.0.len() + func.locals.len(),
+        height: 0,
+        max_height: 0,
+        labels: 0,
+    };
+    let label = f.new_label();
+    let body = f.new_ctrl(false, 0, 0, ft.output.0.len(), label);
+    f.ctrls.push(body);
+    for (ip, inst) in func.insts.iter().enumerate() {
+        f.inst(inst).map_err(|err| format!("inst[{}] {}: {}", ip, inst.get_mnemonic(), err))?;
+    }
+
+    let mut s = format!("\nstatic {} {{\n", g.signature(ft, &format!("f{}", idx), true)?);
+    for (i, t) in ft.input.0.iter().enumerate() {
+        s.push_str(&format!("    Value l{};\n", i));
+        c_type(t)?;
+    }
+    for (i, t) in func.locals.iter().enumerate() {
+        c_type(t)?;
+        s.push_str(&format!("    Value l{} = {{0}};\n", ft.input.0.len() + i));
+    }
+    if f.max_height > 0 {
+        let slots: Vec<String> = (0..f.max_height).map(|h| format!("s{} = {{0}}", h)).collect();
+        s.push_str(&format!("    Value {};\n", slots.join(", ")));
+    }
+    s.push_str(&format!("    if (++depth > MAX_CALL_DEPTH)\n        trap({}_TRAP_CALL_STACK);\n",
+                        g.prefix.to_uppercase()));
+    for (i, t) in ft.input.0.iter().enumerate() {
+        s.push_str(&format!("    l{}.{} = p{};\n", i, member(t), i));
+    }
+    for line in f.lines.iter().filter(|line| !line.is_empty()) {
+        s.push_str(line);
+        s.push('\n');
+    }
+    s.push_str("}\n");
+    Ok(s)
+}
+
+impl FuncGen<'_> {
+    fn new_label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels
+    }
+
+    fn new_ctrl(&self, is_loop: bool, height: usize, params: usize, results: usize, label: usize) -> Ctrl {
+        Ctrl {
+            is_loop,
+            height,
+            params,
+            results,
+            label,
+            used: false,
+            label_line: 0,
+            else_label: None,
+            unreachable: false,
+            dead: false,
+        }
+    }
+
+    fn line(&mut self, s: String) {
+        let indent = "    ".repeat(self.ctrls.len());
+        self.lines.push(format!("{}{}", indent, s));
+    }
+
+    // labels are indented as the enclosing block
+    fn label(&mut self, label: usize) {
+        let indent = "    ".repeat(self.ctrls.len().saturating_sub(1));
+        self.lines.push(format!("{}L{}:;", indent, label));
+    }
+
+    // variable of the value n from the top (0 is the top)
+    fn top(&self, n: usize) -> String {
+        format!("s{}", self.height - 1 - n)
+    }
+
+    fn push(&mut self, n: usize) {
+        self.height += n;
+        self.max_height = self.max_height.max(self.height);
+    }
+
+    fn pop(&mut self, n: usize) -> Result<(), String> {
+        self.height = self.height.checked_sub(n).ok_or("operand stack underflow")?;
+        Ok(())
+    }
+
+    fn ctrl(&mut self) -> Result<&mut Ctrl, String> {
+        self.ctrls.last_mut().ok_or("after the end of the function".to_string())
+    }
+
+    fn set_unreachable(&mut self) -> Result<(), String> {
+        self.ctrl()?.unreachable = true;
+        Ok(())
+    }
+
+    fn block_type(&self, bt: &BlockType) -> (usize, usize) {
+        match bt {
+            BlockType::Empty => (0, 0),
+            BlockType::Valtype(_) => (0, 1),
+            BlockType::TypeIndex(idx) => self.g.module.types().get(*idx as usize)
+                .map_or((0, 0), |ft| (ft.input.0.len(), ft.output.0.len())),
+        }
+    }
+
+    // statements moving the values carried to label depth and jumping there
+    fn br(&mut self, depth: u32) -> Result<Vec<String>, String> {
+        let depth = depth as usize;
+        if depth >= self.ctrls.len() {
+            return Err(format!("unknown label {}", depth));
+        }
+        let i = self.ctrls.len() - 1 - depth;
+        self.ctrls[i].used = true;
+        let label = &self.ctrls[i];
+        let keep = if label.is_loop { label.params } else { label.results };
+        let src = self.height.checked_sub(keep).ok_or("operand stack underflow")?;
+        if src < label.height {
+            return Err("operand stack underflow".to_string());
+        }
+        let mut stmts = Vec::new();
+        if src != label.height {
+            for k in 0..keep {
+                stmts.push(format!("s{} = s{};", label.height + k, src + k));
+            }
+        }
+        stmts.push(format!("goto L{};", label.label));
+        Ok(stmts)
+    }
+
+    fn ret(&mut self) -> Result<String, String> {
+        let results = &self.ft.output.0;
+        let base = self.height.checked_sub(results.len()).ok_or("operand stack underflow")?;
+        let value = match results.len() {
+            0 => String::new(),
+            1 => format!(" s{}.{}", base, member(&results[0])),
+            _ => {
+                let values: Vec<String> = results.iter().enumerate()
+                    .map(|(k, t)| format!("s{}.{}", base + k, member(t)))
+                    .collect();
+                format!(" ({}){{{}}}", self.g.ret_type(self.ft)?, values.join(", "))
+            },
+        };
+        Ok(format!("depth--; return{};", value))
+    }
+
+    fn inst(&mut self, inst: &Inst) -> Result<(), String> {
+        let (unreachable, dead) = {
+            let ctrl = self.ctrl()?;
+            (ctrl.unreachable, ctrl.dead)
+        };
+        if unreachable {
+            // skip to the else or end of the block
+            match inst.op_code {
+                0x02..=0x04 => {
+                    let mut ctrl = self.new_ctrl(false, 0, 0, 0, 0);
+                    ctrl.unreachable = true;
+                    ctrl.dead = true;
+                    self.ctrls.push(ctrl);
+                    return Ok(());
+                },
+                0x05 | 0x0b if !dead => (),
+                0x0b => {
+                    self.ctrls.pop();
+                    return Ok(());
+                },
+                _ => return Ok(()),
+            }
+        }
+        let up = self.g.prefix.to_uppercase();
+        match (inst.op_code, &inst.operand) {
+            (0x00, _) => { // unreachable
+                self.line(format!("trap({}_TRAP_UNREACHABLE);", up));
+                self.set_unreachable()?;
+            },
+            (0x01, _) => (), // nop
+            (0x02..=0x04, Operand::BlockType(bt)) => { // block, loop, if
+                let (params, results) = self.block_type(bt);
+                let mut cond = String::new();
+                if inst.op_code == 0x04 {
+                    cond = self.top(0);
+                    self.pop(1)?;
+                }
+                let height = self.height.checked_sub(params).ok_or("operand stack underflow")?;
+                let label = self.new_label();
+                let mut ctrl = self.new_ctrl(inst.op_code == 0x03, height, params, results, label);
+                if inst.op_code == 0x04 {
+                    let else_label = self.new_label();
+                    self.line(format!("if (!{}.i32) goto L{};", cond, else_label));
+                    ctrl.else_label = Some(else_label);
+                }
+                ctrl.label_line = self.lines.len();
+                self.ctrls.push(ctrl);
+                if inst.op_code == 0x03 {
+                    self.label(label);
+                }
+            },
+            (0x05, _) => { // else
+                if !self.ctrl()?.unreachable {
+                    for stmt in self.br(0)? {
+                        self.line(stmt);
+                    }
+                }
+                let ctrl = self.ctrl()?;
+                let else_label = ctrl.else_label.take();
+                ctrl.unreachable = false;
+                self.height = ctrl.height + ctrl.params;
+                if let Some(label) = else_label {
+                    self.label(label);
+                }
+            },
+            (0x0b, _) => { // end
+                let ctrl = self.ctrls.last().ok_or("after the end of the function")?;
+                let (label, used, is_loop, label_line) = (ctrl.label, ctrl.used, ctrl.is_loop, ctrl.label_line);
+                let else_label = ctrl.else_label;
+                self.height = ctrl.height + ctrl.results;
+                self.max_height = self.max_height.max(self.height);
+                if let Some(label) = else_label {
+                    self.label(label);
+                }
+                if is_loop {
+                    if !used {
+                        self.lines[label_line].clear();
+                    }
+                } else if used {
+                    self.label(label);
+                }
+                if self.ctrls.len() == 1 {
+                    let ret = self.ret()?;
+                    self.line(ret);
+                }
+                self.ctrls.pop();
+            },
+            (0x0c, Operand::Index(n)) => { // br
+                for stmt in self.br(*n)? {
+                    self.line(stmt);
+                }
+                self.set_unreachable()?;
+            },
+            (0x0d, Operand::Index(n)) => { // br_if
+                let cond = self.top(0);
+                self.pop(1)?;
+                let stmts = self.br(*n)?;
+                if stmts.len() == 1 {
+                    self.line(format!("if ({}.i32) {}", cond, stmts[0]));
+                } else {
+                    self.line(format!("if ({}.i32) {{", cond));
+                    for stmt in stmts {
+                        self.line(format!("    {}", stmt));
+                    }
+                    self.line("}".to_string());
+                }
+            },
+            (0x0e, Operand::BrTable(table)) => { // br_table
+                let i = self.top(0);
+                self.pop(1)?;
+                self.line(format!("switch ({}.i32) {{", i));
+                for (k, n) in table.labels.iter().enumerate() {
+                    let stmts = self.br(*n)?;
+                    self.line(format!("case {}: {}", k, stmts.join(" ")));
+                }
+                let stmts = self.br(table.default)?;
+                self.line(format!("default: {}", stmts.join(" ")));
+                self.line("}".to_string());
+                self.set_unreachable()?;
+            },
+            (0x0f, _) => { // return
+                let ret = self.ret()?;
+                self.line(ret);
+                self.set_unreachable()?;
+            },
+            (0x10, Operand::Index(idx)) => { // call
+                let ft = func_type(*idx as usize, self.g.module).ok_or("unknown function")?;
+                let call = self.call_args(ft, format!("f{}", idx))?;
+                self.call(ft, call)?;
+            },
+            (0x11, Operand::Index2(tableidx, typeidx)) => { // call_indirect
+                let ft = self.g.module.types().get(*typeidx as usize).ok_or("unknown type")?;
+                let size = self.g.tables.get(*tableidx as usize).ok_or("unknown table")?;
+                let i = self.top(0);
+                self.pop(1)?;
+                let func = format!("(({})table_func(t{}, {}, {}.i32, {}))", self.g.pointer_type(ft)?, tableidx,
+                                   size, i, self.g.type_id(ft));
+                let call = self.call_args(ft, func)?;
+                self.call(ft, call)?;
+            },
+            (0x1a, _) => self.pop(1)?, // drop
+            (0x1b | 0x1c, _) => { // select
+                let (a, b, c) = (self.top(2), self.top(1), self.top(0));
+                self.line(format!("{} = {}.i32 ? {} : {};", a, c, a, b));
+                self.pop(2)?;
+            },
+            (0x20, Operand::Index(i)) => { // local.get
+                self.local(*i)?;
+                self.push(1);
+                self.line(format!("{} = l{};", self.top(0), i));
+            },
+            (0x21 | 0x22, Operand::Index(i)) => { // local.set, local.tee
+                self.local(*i)?;
+                self.line(format!("l{} = {};", i, self.top(0)));
+                if inst.op_code == 0x21 {
+                    self.pop(1)?;
+                }
+            },
+            (0x23, Operand::Index(i)) => { // global.get
+                self.push(1);
+                self.line(format!("{} = g{};", self.top(0), i));
+            },
+            (0x24, Operand::Index(i)) => { // global.set
+                self.line(format!("g{} = {};", i, self.top(0)));
+                self.pop(1)?;
+            },
+            (0x28..=0x35, Operand::Memarg(m)) => { // load
+                let (width, t, value) = load_op(inst.op_code);
+                let addr = self.top(0);
+                let p = format!("mem_at({}.i32, {:#x}u, {})", addr, m.offset, width);
+                self.line(format!("{}.{} = {};", addr, member(&Valtype(t)), value.replace("(p)", &format!("({})", p))));
+            },
+            (0x36..=0x3e, Operand::Memarg(m)) => { // store
+                let (width, t) = store_op(inst.op_code);
+                let (addr, value) = (self.top(1), self.top(0));
+                self.line(format!("store{}(mem_at({}.i32, {:#x}u, {}), {}.{});", width * 8, addr, m.offset, width,
+                                  value, member(&Valtype(t))));
+                self.pop(2)?;
+            },
+            (0x3f, _) => { // memory.size
+                self.push(1);
+                self.line(format!("{}.i32 = (uint32_t)({}_memory.size / PAGE_SIZE);", self.top(0), self.g.prefix));
+            },
+            (0x40, _) => { // memory.grow
+                let n = self.top(0);
+                self.line(format!("{}.i32 = memory_grow({}.i32);", n, n));
+            },
+            (0x41..=0x44, _) => { // const
+                let (v, comment) = match inst.operand {
+                    Operand::I32(n) => (format!("i32 = {:#x}u", n as u32), String::new()),
+                    Operand::I64(n) => (format!("i64 = UINT64_C({:#x})", n as u64), String::new()),
+                    Operand::F32(z) => (format!("i32 = {:#x}u", z.to_bits()), format!(" // {}", z)),
+                    Operand::F64(z) => (format!("i64 = UINT64_C({:#x})", z.to_bits()), format!(" // {}", z)),
+                    _ => return Err("bad operand".to_string()),
+                };
+                self.push(1);
+                self.line(format!("{}.{};{}", self.top(0), v, comment));
+            },
+            (0xfc, Operand::Index2(dataidx, _)) if inst.sub_op == 8 => { // memory.init
+                let (d, s, n) = (self.top(2), self.top(1), self.top(0));
+                self.line(format!("memory_init(d{}, d{}_size, {}.i32, {}.i32, {}.i32);", dataidx, dataidx, d, s, n));
+                self.pop(3)?;
+            },
+            (0xfc, Operand::Index(dataidx)) if inst.sub_op == 9 => { // data.drop
+                self.line(format!("d{}_size = 0;", dataidx));
+            },
+            (0xfc, _) if inst.sub_op == 10 || inst.sub_op == 11 => { // memory.copy, memory.fill
+                let (d, s, n) = (self.top(2), self.top(1), self.top(0));
+                let f = if inst.sub_op == 10 { "memory_copy" } else { "memory_fill" };
+                self.line(format!("{}({}.i32, {}.i32, {}.i32);", f, d, s, n));
+                self.pop(3)?;
+            },
+            _ => {
+                let (arity, ty_in, ty_out, e) = numeric(inst).ok_or("not supported")?;
+                if e.is_empty() {
+                    return Ok(());
+                }
+                let operand = |n: usize| format!("{}.{}", self.top(n), member(&Valtype(ty_in)));
+                let e = match arity {
+                    1 => e.replace("$1", &operand(0)),
+                    _ => e.replace("$1", &operand(1)).replace("$2", &operand(0)),
+                };
+                self.pop(arity - 1)?;
+                self.line(format!("{}.{} = {};", self.top(0), member(&Valtype(ty_out)), e));
+            },
+        }
+        Ok(())
+    }
+
+    fn local(&self, i: u32) -> Result<(), String> {
+        if i as usize >= self.nlocals {
+            return Err(format!("unknown local {}", i));
+        }
+        Ok(())
+    }
+
+    // pop the args and make the call expression
+    fn call_args(&mut self, ft: &Functype, func: String) -> Result<String, String> {
+        let n = ft.input.0.len();
+        self.pop(n)?;
+        let args: Vec<String> = ft.input.0.iter().enumerate()
+            .map(|(k, t)| format!("s{}.{}", self.height + k, member(t)))
+            .collect();
+        Ok(format!("{}({})", func, args.join(", ")))
+    }
+
+    // store the results of the call
+    fn call(&mut self, ft: &Functype, call: String) -> Result<(), String> {
+        let base = self.height;
+        match ft.output.0.len() {
+            0 => self.line(format!("{};", call)),
+            1 => self.line(format!("s{}.{} = {};", base, member(&ft.output.0[0]), call)),
+            _ => {
+                self.line("{".to_string());
+                self.line(format!("    {} r = {};", self.g.ret_type(ft)?, call));
+                for (k, t) in ft.output.0.iter().enumerate() {
+                    self.line(format!("    s{}.{} = r.r{};", base + k, member(t), k));
+                }
+                self.line("}".to_string());
+            },
+        }
+        self.push(ft.output.0.len());
+        Ok(())
+    }
+}
+
+// test harness: a C program which calls the functions (args as exec::invoke_str())
+// one by one, each on a new instance, and prints a line per call: the results
+// and the hash of the memory after it. harness_expected() gives the lines of the
+// interpreter in the same format, so the C translation can be compared with it.
+// only for examples/c_harness.rs and the tests. not a part of the API.
+
+// FNV-1a of the memory
+const HASH_OFFSET: u64 = 0xcbf29ce484222325;
+const HASH_PRIME: u64 = 0x100000001b3;
+
+#[doc(hidden)]
+pub fn harness_main(module: &Module, prefix: &str, calls: &[Vec<&str>]) -> Result<String, String> {
+    let p = c_ident(prefix);
+    let mut s = format!("// generated by wasmex\n#include <inttypes.h>\n\n#include \"{}.c\"\n", p);
+    s.push_str(&format!(r#"
+static uint64_t memory_hash(void) {{
+    uint64_t h = UINT64_C({HASH_OFFSET:#x}), i;
+    for (i = 0; i < {p}_memory.size; i++)
+        h = (h ^ {p}_memory.data[i]) * UINT64_C({HASH_PRIME:#x});
+    return h;
+}}
+
+int main(void) {{
+    jmp_buf jb;
+    int code;
+"#));
+    let g = Gen {module, prefix: p.clone(), multi: multi_results(module), tables: Vec::new()};
+    for args in calls {
+        let (idx, values) = parse_call(args, module)?;
+        let ft = func_type(idx, module).ok_or("unknown function")?;
+        let literals: Vec<String> = values.iter().map(c_literal).collect::<Result<_, _>>()?;
+        let call = format!("f{}({})", idx, literals.join(", "));
+        let print = |v: &str, t: &Valtype| match t.0 {
+            0x7f => format!("printf(\"i32:%\" PRId32 \" \", (int32_t){});", v),
+            0x7e => format!("printf(\"i64:%\" PRId64 \" \", (int64_t){});", v),
+            0x7d => format!("printf(\"f32:0x%08\" PRIx32 \" \", f32_to_bits({}));", v),
+            _ => format!("printf(\"f64:0x%016\" PRIx64 \" \", f64_to_bits({}));", v),
+        };
+        let mut stmts = Vec::new();
+        match ft.output.0.len() {
+            0 => stmts.push(format!("{};", call)),
+            1 => {
+                stmts.push(format!("{} r = {};", c_type(&ft.output.0[0])?, call));
+                stmts.push(print("r", &ft.output.0[0]));
+            },
+            _ => {
+                stmts.push(format!("{} r = {};", g.ret_type(ft)?, call));
+                for (k, t) in ft.output.0.iter().enumerate() {
+                    stmts.push(print(&format!("r.r{}", k), t));
+                }
+            },
+        }
+        s.push_str(&format!("\n    // {}\n", args.join(" ")));
+        s.push_str(&format!("    if ((code = {p}_init()) != 0) {{\n        printf(\"%s\\n\", {p}_trap_message(code));\n"));
+        s.push_str(&format!("    }} else {{\n        {p}_trap_jmp = &jb;\n        if ((code = setjmp(jb)) == 0) {{\n"));
+        for stmt in stmts {
+            s.push_str(&format!("            {}\n", stmt));
+        }
+        s.push_str(&format!("        }} else {{\n            printf(\"%s \", {p}_trap_message(code));\n        }}\n"));
+        s.push_str(&format!("        {p}_trap_jmp = NULL;\n        printf(\"mem:%016\" PRIx64 \"\\n\", memory_hash());\n"));
+        s.push_str(&format!("    }}\n    {p}_free();\n"));
+    }
+    s.push_str("    return 0;\n}\n");
+    Ok(s)
+}
+
+// line of harness_main() for the result of a call and the memory after it
+fn harness_line(result: &Result<Vec<Value>, String>, memory: &[u8]) -> String {
+    let mut items: Vec<String> = match result {
+        Ok(values) => values.iter().map(|v| match v {
+            Value::I32(n) => format!("i32:{}", n),
+            Value::I64(n) => format!("i64:{}", n),
+            Value::F32(z) => format!("f32:{:#010x}", z.to_bits()),
+            Value::F64(z) => format!("f64:{:#018x}", z.to_bits()),
+            v => v.to_string(),
+        }).collect(),
+        Err(err) => vec![err.clone()],
+    };
+    let hash = memory.iter().fold(HASH_OFFSET, |h, b| (h ^ *b as u64).wrapping_mul(HASH_PRIME));
+    items.push(format!("mem:{:016x}", hash));
+    items.join(" ")
+}
+
+// the lines of harness_main() by the interpreter
+#[doc(hidden)]
+pub fn harness_expected(module: &Module, calls: &[Vec<&str>]) -> Vec<String> {
+    calls.iter().map(|args| match make_store(module) {
+        Ok(mut store) => {
+            let result = invoke_str(args, module, &mut store);
+            harness_line(&result, &store.memory())
+        },
+        Err(err) => err,
+    }).collect()
+}
+
+// compile the harness in dir by cc and return the output lines
+#[doc(hidden)]
+pub fn harness_run(module: &Module, calls: &[Vec<&str>], cc: &str, dir: &Path) -> Result<Vec<String>, String> {
+    let c = module_to_c(module, "m")?;
+    let write = |name: &str, text: &str| fs::write(dir.join(name), text).map_err(|e| e.to_string());
+    write("m.h", &c.header)?;
+    write("m.c", &c.source)?;
+    write("main.c", &harness_main(module, "m", calls)?)?;
+    let exe = dir.join("harness");
+    let status = Command::new(cc)
+        .args(["-std=c99", "-O2", "-o"])
+        .arg(&exe)
+        .arg(dir.join("main.c"))
+        .arg("-lm")
+        .status()
+        .map_err(|e| format!("{}: {}", cc, e))?;
+    if !status.success() {
+        return Err(format!("{} failed. the sources are in {}", cc, dir.display()));
+    }
+    let output = Command::new(&exe).output().map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|l| l.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn same_as_interpreter() {
+        if Command::new("cc").arg("--version").output().is_err() {
+            eprintln!("cc not found. skipped");
+            return;
+        }
+        let module = testutil::load(r#"
+          (memory 1)
+          (data (i32.const 16) "wasm")
+          (func (export "div") (param i32 i32) (result i32) (i32.div_s (local.get 0) (local.get 1)))
+          (func (export "poke") (param i32 i64) (result i64)
+            (i64.store (local.get 0) (local.get 1))
+            (i64.load8_s (local.get 0)))
+          (func (export "pair") (param f32 f64) (result f64 f32)
+            (f64.mul (local.get 1) (f64.promote_f32 (local.get 0)))
+            (f32.sqrt (local.get 0)))"#);
+        let calls: Vec<Vec<&str>> = ["fib 20", "div 7 -2", "div 1 0", "div -2147483648 -1", "poke 100 -129",
+                                     "poke 65535 1", "pair 2.25 -1e300", "pair -1 0"]
+            .iter().map(|c| c.split(' ').collect()).collect();
+        let dir = std::env::temp_dir().join(format!("wasmex-cgen-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let lines = harness_run(&module, &calls, "cc", &dir).unwrap();
+        let expected = harness_expected(&module, &calls);
+        assert!(expected[0].starts_with("i32:6765 mem:"));
+        assert!(expected[2].starts_with("trap: integer divide by zero mem:"));
+        assert_eq!(lines, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// debugger of the interactive mode. a function started by exec stops at
+// breakpoints (funcidx and instruction index) and can be resumed by
+// step/next/finish/continue. the state (locals, stack, frames, memory and
+// globals) can be inspected while paused.
+
+use crate::bytecode::*;
+use crate::exec::*;
+use crate::module::*;
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<(usize, usize)>, // funcidx, ip
+    inv: Option<Invocation>, // paused execution
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inv.is_some()
+    }
+
+    // execute a debugger command. returns false if cmds is not a debugger command.
+    pub fn command(&mut self, cmds: &[&str], module: &Module, store: &mut Store) -> Result<bool, String> {
+        match cmds[0] {
+            "exec" => self.exec(&cmds[1..], module, store)?,
+            "break" | "b" => self.add_break(&cmds[1..], module)?,
+            "delete" => self.delete_break(&cmds[1..], module)?,
+            "step" | "s" => {
+                let n = match cmds.get(1) {
+                    Some(n) => n.parse().map_err(|_| format!("bad count '{}'", n))?,
+                    None => 1,
+                };
+                for _ in 0..n {
+                    if !self.resume(module, store, |_| true)? {
+                        break;
+                    }
+                }
+            },
+            "next" | "n" => {
+                let depth = self.paused()?.frames().len();
+                self.resume(module, store, |inv| inv.frames().len() <= depth)?;
+            },
+            "finish" => {
+                let depth = self.paused()?.frames().len();
+                self.resume(module, store, |inv| inv.frames().len() < depth)?;
+            },
+            "continue" | "c" => {
+                self.resume(module, store, |_| false)?;
+            },
+            "abort" => {
+                self.paused()?;
+                self.inv = None;
+            },
+            "list" | "l" => self.list(module, 5)?,
+            "locals" => self.locals(module)?,
+            "stack" => {
+                for (i, v) in self.paused()?.stack().iter().enumerate() {
+                    println!("{}: {}", i, show_value(v));
+                }
+            },
+            "backtrace" | "bt" => {
+                let inv = self.paused()?;
+                for (i, frame) in inv.frames().iter().rev().enumerate() {
+                    let func = module.get_local_func(frame.func_idx());
+                    println!("#{} {} ip {}: {}", i, module.func_desc(frame.func_idx()), frame.ip(),
+                             func.insts[frame.ip()].get_mnemonic());
+                }
+            },
+            "memory" | "x" => dump_memory(&cmds[1..], store)?,
+            "global" => show_globals(cmds.get(1), module, store)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn paused(&self) -> Result<&Invocation, String> {
+        self.inv.as_ref().ok_or("not running".to_string())
+    }
+
+    fn exec(&mut self, args: &[&str], module: &Module, store: &mut Store) -> Result<(), String> {
+        if self.inv.take().is_some() {
+            println!("previous execution is aborted");
+        }
+        let (idx, values) = parse_call(args, module)?;
+        let inv = Invocation::new(idx, &values, module, store)?;
+        let at_break = self.at_breakpoint(&inv);
+        self.inv = Some(inv);
+        if at_break {
+            self.stopped(module);
+            Ok(())
+        } else {
+            self.resume(module, store, |_| false).map(|_| ())
+        }
+    }
+
+    // run until stop() returns true after an instruction, a breakpoint is hit
+    // or the function returns. returns false if the execution is over.
+    fn resume<F>(&mut self, module: &Module, store: &mut Store, stop: F) -> Result<bool, String>
+    where
+        F: Fn(&Invocation) -> bool,
+    {
+        let mut inv = match self.inv.take() {
+            Some(inv) => inv,
+            None => return Err("not running".to_string()),
+        };
+        loop {
+            if !inv.is_finished() {
+                if let Err(err) = inv.step(module, store) {
+                    if err == OUT_OF_FUEL { // can be resumed after refueling
+                        self.inv = Some(inv);
+                    }
+                    return Err(err);
+                }
+            }
+            if inv.is_finished() {
+                let results = inv.results();
+                if !results.is_empty() {
+                    let v: Vec<String> = results.iter().map(|r| r.to_string()).collect();
+                    println!("result: {}", v.join(" "));
+                }
+                return Ok(false);
+            }
+            if stop(&inv) || self.at_breakpoint(&inv) {
+                self.inv = Some(inv);
+                self.stopped(module);
+                return Ok(true);
+            }
+        }
+    }
+
+    fn at_breakpoint(&self, inv: &Invocation) -> bool {
+        match inv.frames().last() {
+            Some(frame) => self.breakpoints.contains(&(frame.func_idx(), frame.ip())),
+            None => false,
+        }
+    }
+
+    fn stopped(&self, module: &Module) {
+        if let Some(frame) = self.inv.as_ref().and_then(|inv| inv.frames().last()) {
+            if self.breakpoints.contains(&(frame.func_idx(), frame.ip())) {
+                print!("breakpoint: ");
+            }
+            println!("{} ip {}", module.func_desc(frame.func_idx()), frame.ip());
+        }
+        let _ = self.list(module, 2);
+    }
+
+    // instructions around the current one
+    fn list(&self, module: &Module, n: usize) -> Result<(), String> {
+        let frame = match self.paused()?.frames().last() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let func = module.get_local_func(frame.func_idx());
+        let start = frame.ip().saturating_sub(n);
+        let end = (frame.ip() + n + 1).min(func.insts.len());
+        for (i, inst) in func.insts[start..end].iter().enumerate() {
+            let mark = if start + i == frame.ip() { "=>" } else { "  " };
+            print!("{} {:4}: ", mark, start + i);
+            inst.print();
+        }
+        Ok(())
+    }
+
+    fn locals(&self, module: &Module) -> Result<(), String> {
+        let frame = match self.paused()?.frames().last() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        for (i, v) in frame.locals().iter().enumerate() {
+            match module.names().local(frame.func_idx() as u32, i as u32) {
+                Some(name) => println!("local[{}] <{}>: {}", i, name, show_value(v)),
+                None => println!("local[{}]: {}", i, show_value(v)),
+            }
+        }
+        Ok(())
+    }
+
+    // break [func [ip]]. without args, list the breakpoints
+    fn add_break(&mut self, args: &[&str], module: &Module) -> Result<(), String> {
+        if args.is_empty() {
+            for (f, ip) in &self.breakpoints {
+                println!("{} ip {}", module.func_desc(*f), ip);
+            }
+            return Ok(());
+        }
+        let bp = breakpoint(args, module)?;
+        if !self.breakpoints.contains(&bp) {
+            self.breakpoints.push(bp);
+        }
+        Ok(())
+    }
+
+    // delete [func [ip]]. without args, delete all the breakpoints
+    fn delete_break(&mut self, args: &[&str], module: &Module) -> Result<(), String> {
+        if args.is_empty() {
+            self.breakpoints.clear();
+            return Ok(());
+        }
+        let bp = breakpoint(args, module)?;
+        self.breakpoints.retain(|b| *b != bp);
+        Ok(())
+    }
+}
+
+fn breakpoint(args: &[&str], module: &Module) -> Result<(usize, usize), String> {
+    let f = match resolve_func(args[0], module) {
+        Some(f) if f < module.num_funcs() => f,
+        _ => return Err(format!("unknown function '{}'", args[0])),
+    };
+    if module.is_import_func(f) {
+        return Err(format!("{} is an import function", module.func_desc(f)));
+    }
+    let ip = match args.get(1) {
+        Some(ip) => ip.parse().map_err(|_| format!("bad instruction index '{}'", ip))?,
+        None => 0,
+    };
+    if ip >= module.get_local_func(f).insts.len() {
+        return Err(format!("instruction index {} out of range", ip));
+    }
+    Ok((f, ip))
+}
+
+fn show_value(v: &Value) -> String {
+    match v.valtype() {
+        Some(t) => format!("{} {}", Valtype(t), v),
+        None => v.to_string(),
+    }
+}
+
+fn parse_addr(s: &str) -> Result<usize, String> {
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    n.map_err(|_| format!("bad number '{}'", s))
+}
+
+// memory addr [len]
+fn dump_memory(args: &[&str], store: &Store) -> Result<(), String> {
+    let addr = parse_addr(args.first().ok_or("need address")?)?;
+    let len = match args.get(1) {
+        Some(len) => parse_addr(len)?,
+        None => 64,
+    };
+    let mem = store.memory();
+    if addr >= mem.len() {
+        return Err(format!("address {:#x} out of memory (size {:#x})", addr, mem.len()));
+    }
+    let end = addr.saturating_add(len).min(mem.len());
+    for line in (addr..end).step_by(16) {
+        let bytes = &mem[line..(line + 16).min(end)];
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = bytes.iter()
+            .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+            .collect();
+        println!("{:08x}: {:<47}  |{}|", line, hex.join(" "), text);
+    }
+    Ok(())
+}
+
+// global [idx]. without idx, all the globals
+fn show_globals(idx: Option<&&str>, module: &Module, store: &Store) -> Result<(), String> {
+    let show = |i: usize, v: &Value| match module.names().global(i as u32) {
+        Some(name) => println!("global[{}] <{}>: {}", i, name, show_value(v)),
+        None => println!("global[{}]: {}", i, show_value(v)),
+    };
+    match idx {
+        Some(idx) => {
+            let i = idx.parse().map_err(|_| format!("bad global index '{}'", idx))?;
+            show(i, &store.global(i).ok_or(format!("global[{}] out of range", i))?);
+        },
+        None => {
+            let mut i = 0;
+            while let Some(v) = store.global(i) {
+                show(i, &v);
+                i += 1;
+            }
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    const SRC: &str = r#"
+      (memory 1)
+      (global $g (mut i32) (i32.const 0))
+      (func (export "twice") (param i32) (result i32)
+        (global.set $g (i32.const 1))
+        (i32.mul (call $fib (local.get 0)) (i32.const 2)))"#;
+
+    fn run(dbg: &mut Debugger, cmd: &str, module: &Module, store: &mut Store) -> Result<bool, String> {
+        let cmds: Vec<&str> = cmd.split_whitespace().collect();
+        dbg.command(&cmds, module, store)
+    }
+
+    // funcidx, ip and call depth of the paused execution
+    fn position(dbg: &Debugger) -> (usize, usize, usize) {
+        let inv = dbg.paused().unwrap();
+        let frame = inv.frames().last().unwrap();
+        (frame.func_idx(), frame.ip(), inv.frames().len())
+    }
+
+    #[test]
+    fn breakpoints_and_steps() {
+        let module = testutil::load(SRC);
+        let mut store = make_store(&module).unwrap();
+        let mut dbg = Debugger::new();
+        let mut cmd = |dbg: &mut Debugger, cmd: &str| run(dbg, cmd, &module, &mut store);
+        assert_eq!(cmd(&mut dbg, "break $fib"), Ok(true));
+        assert_eq!(cmd(&mut dbg, "exec twice 3"), Ok(true));
+        assert_eq!(position(&dbg), (0, 0, 2));
+        // the caller's frame is kept below
+        assert_eq!(dbg.paused().unwrap().frames()[0].locals(), &[Value::I32(3)]);
+        cmd(&mut dbg, "step 2").unwrap();
+        assert_eq!(position(&dbg), (0, 2, 2));
+        // the breakpoint stops the recursive call
+        cmd(&mut dbg, "continue").unwrap();
+        assert_eq!(position(&dbg), (0, 0, 3));
+        cmd(&mut dbg, "delete").unwrap();
+        cmd(&mut dbg, "finish").unwrap();
+        assert_eq!(position(&dbg).2, 2);
+        assert_eq!(dbg.paused().unwrap().stack().last(), Some(&Value::I32(1)));
+        cmd(&mut dbg, "continue").unwrap();
+        assert!(!dbg.is_paused());
+        assert_eq!(cmd(&mut dbg, "step"), Err("not running".to_string()));
+        assert_eq!(cmd(&mut dbg, "help"), Ok(false));
+    }
+
+    #[test]
+    fn resumed_results() {
+        // stepping one instruction at a time gives the same results as invoke
+        let module = testutil::load(SRC);
+        let mut store = make_store(&module).unwrap();
+        let idx = resolve_func("twice", &module).unwrap();
+        let mut inv = Invocation::new(idx, &[Value::I32(10)], &module, &mut store).unwrap();
+        let mut steps = 0;
+        while !inv.is_finished() {
+            inv.step(&module, &mut store).unwrap();
+            steps += 1;
+        }
+        assert!(steps > 100);
+        assert_eq!(inv.results(), vec![Value::I32(110)]);
+        assert_eq!(store.global(0), Some(Value::I32(1)));
+    }
+
+    #[test]
+    fn bad_commands() {
+        let module = testutil::load(SRC);
+        let mut store = make_store(&module).unwrap();
+        let mut dbg = Debugger::new();
+        let mut cmd = |cmd: &str| run(&mut dbg, cmd, &module, &mut store);
+        assert_eq!(cmd("break nothing"), Err("unknown function 'nothing'".to_string()));
+        assert_eq!(cmd("break fib 1000"), Err("instruction index 1000 out of range".to_string()));
+        assert_eq!(cmd("break fib x"), Err("bad instruction index 'x'".to_string()));
+        assert_eq!(cmd("memory 0x10000"), Err("address 0x10000 out of memory (size 0x10000)".to_string()));
+        assert_eq!(cmd("global 5"), Err("global[5] out of range".to_string()));
+        assert_eq!(cmd("locals"), Err("not running".to_string()));
+    }
+}
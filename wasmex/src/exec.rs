@@ -4,22 +4,494 @@
 
 #![allow(dead_code)]
 
+use std::cell::{Ref, RefCell, RefMut};
 use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use crate::bytecode::*;
 use crate::module::*;
+use crate::names::*;
 use crate::inst::*;
+use crate::simd;
+
+const PAGE_SIZE: usize = 65536;
+const MAX_CALL_DEPTH: usize = 10000;
+const MAX_STACK: usize = 1 << 20;
+pub const OUT_OF_FUEL: &str = "trap: out of fuel";
+
+// a host function. it receives the store (to access the memory) and the args,
+// and returns the results or a trap message.
+pub type HostFunc = Rc<dyn Fn(&mut Store, &[Value]) -> Result<Vec<Value>, String>>;
+
+// memories, globals and tables are shared handles. an imported one is the
+// instance of the exporter, so changes by either side are seen by the other.
+pub type MemoryRef = Rc<RefCell<MemInst>>;
+pub type GlobalRef = Rc<RefCell<GlobalInst>>;
+pub type TableRef = Rc<RefCell<TableInst>>;
 
 pub struct Store {
-    memory: Vec<u8>,
-    // table,
-    // global,
+    id: u32, // unique in the process. tags the funcrefs stored in tables
+    memory: MemoryRef, // empty (max 0) if the module has no memory
+    datas: Vec<Vec<u8>>, // data instances. empty if dropped
+    globals: Vec<GlobalRef>,
+    tables: Vec<TableRef>,
+    elems: Vec<Vec<Value>>, // element instances. empty if dropped
+    host_funcs: Vec<Option<HostFunc>>, // import functions. None if not provided
+    verbose: bool, // print the results of each function call
+    trace: Option<Trace>,
+    limits: StoreLimits,
+    fuel: Option<u64>, // instructions which can be executed. None if unlimited
+}
+
+// limits of the execution. exceeding them traps (or memory.grow and table.grow fail).
+#[derive(Clone)]
+pub struct StoreLimits {
+    pub max_call_depth: usize,
+    pub max_stack: usize, // values on the operand stack
+    pub max_memory_pages: u32,
+    pub max_table_elems: u32,
+}
+
+impl Default for StoreLimits {
+    fn default() -> StoreLimits {
+        StoreLimits {
+            max_call_depth: MAX_CALL_DEPTH,
+            max_stack: MAX_STACK,
+            max_memory_pages: 65536,
+            max_table_elems: u32::MAX,
+        }
+    }
+}
+
+// per-instruction trace. each executed instruction is logged to stderr with
+// the stack and the locals of the current function after it.
+#[derive(Clone, Default)]
+pub struct Trace {
+    pub json: bool, // JSON lines
+    pub funcs: Vec<usize>, // functions to trace. all if empty
+}
+
+static NEXT_STORE_ID: AtomicU32 = AtomicU32::new(1);
+
+pub struct MemInst {
+    data: Vec<u8>,
+    max: u32, // pages
+}
+
+impl MemInst {
+    pub(crate) fn new(limits: &Limits, max_pages: u32) -> Result<MemoryRef, String> {
+        let min = limits.min();
+        if min > max_pages {
+            return Err(format!("memory of {} pages exceeds the limit", min));
+        }
+        let mut data = Vec::new();
+        if data.try_reserve_exact(min as usize * PAGE_SIZE).is_err() {
+            return Err(format!("can't allocate memory of {} pages", min));
+        }
+        data.resize(min as usize * PAGE_SIZE, 0);
+        Ok(Rc::new(RefCell::new(MemInst {data, max: limits.max().unwrap_or(65536)})))
+    }
+
+    fn pages(&self) -> u32 {
+        (self.data.len() / PAGE_SIZE) as u32
+    }
+
+    // current size and max. 65536 pages is the implicit max.
+    pub fn limits(&self) -> Limits {
+        match self.max {
+            65536 => Limits::Min(self.pages()),
+            max => Limits::Minmax(self.pages(), max),
+        }
+    }
+}
+
+pub struct GlobalInst {
+    value: Value,
+    mutable: bool,
+}
+
+impl GlobalInst {
+    pub(crate) fn new(value: Value, mutable: bool) -> GlobalRef {
+        Rc::new(RefCell::new(GlobalInst {value, mutable}))
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn is_mutable(&self) -> bool {
+        self.mutable
+    }
+}
+
+pub struct TableInst {
+    reftype: u8,
+    // values and the ids of the stores which set them. a funcref is a
+    // function index of the module of that store.
+    elem: Vec<(Value, u32)>,
+    max: u32,
+}
+
+impl TableInst {
+    pub(crate) fn new(reftype: u8, limits: &Limits, max_elems: u32) -> Result<TableRef, String> {
+        let value = match Value::default_of(reftype) {
+            Some(value) => value,
+            None => return Err(format!("table type {:#02x} not supported", reftype)),
+        };
+        if limits.min() > max_elems {
+            return Err(format!("table of {} elements exceeds the limit", limits.min()));
+        }
+        let min = limits.min() as usize;
+        let mut elem = Vec::new();
+        if elem.try_reserve_exact(min).is_err() {
+            return Err(format!("can't allocate table of {} elements", min));
+        }
+        elem.resize(min, (value, 0));
+        Ok(Rc::new(RefCell::new(TableInst {reftype, elem, max: limits.max().unwrap_or(u32::MAX)})))
+    }
+
+    // reftype and current limits
+    pub fn tabletype(&self) -> (u8, Limits) {
+        let size = self.elem.len() as u32;
+        let limits = match self.max {
+            u32::MAX => Limits::Min(size),
+            max => Limits::Minmax(size, max),
+        };
+        (self.reftype, limits)
+    }
+
+    // range [i, i + n) in the table
+    fn range(&self, i: i32, n: usize) -> Result<std::ops::Range<usize>, String> {
+        let i = i as u32 as usize;
+        if i + n > self.elem.len() {
+            return Err("trap: out of bounds table access".to_string());
+        }
+        Ok(i..i + n)
+    }
+
+    // returns the old size or -1 if failed
+    fn grow(&mut self, n: u32, init: (Value, u32), limit: u32) -> i32 {
+        let old = self.elem.len() as u32;
+        if old as u64 + n as u64 > self.max.min(limit) as u64 {
+            return -1;
+        }
+        if self.elem.try_reserve_exact(n as usize).is_err() {
+            return -1;
+        }
+        self.elem.resize(old as usize + n as usize, init);
+        old as i32
+    }
+}
+
+impl Store {
+    // effective address of a memory access of size bytes
+    pub(crate) fn mem_addr(&self, base: i32, offset: u32, size: usize) -> Result<usize, String> {
+        let ea = base as u32 as u64 + offset as u64;
+        if ea + size as u64 > self.memory.borrow().data.len() as u64 {
+            return Err("trap: out of bounds memory access".to_string());
+        }
+        Ok(ea as usize)
+    }
+
+    fn load<const N: usize>(&self, base: i32, offset: u32) -> Result<[u8; N], String> {
+        let ea = self.mem_addr(base, offset, N)?;
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.memory.borrow().data[ea..ea + N]);
+        Ok(bytes)
+    }
+
+    fn store(&mut self, base: i32, offset: u32, bytes: &[u8]) -> Result<(), String> {
+        let ea = self.mem_addr(base, offset, bytes.len())?;
+        self.memory.borrow_mut().data[ea..ea + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub(crate) fn mem_pages(&self) -> u32 {
+        self.memory.borrow().pages()
+    }
+
+    // returns the old size in pages or -1 if failed
+    pub(crate) fn mem_grow(&mut self, n: u32) -> i32 {
+        let mut mem = self.memory.borrow_mut();
+        let old = mem.pages();
+        if old as u64 + n as u64 > mem.max.min(self.limits.max_memory_pages) as u64 {
+            return -1;
+        }
+        let size = n as usize * PAGE_SIZE;
+        if mem.data.try_reserve_exact(size).is_err() {
+            return -1;
+        }
+        let len = mem.data.len();
+        mem.data.resize(len + size, 0);
+        old as i32
+    }
+
+    pub fn memory(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.memory.borrow(), |mem| &mem.data[..])
+    }
+
+    pub fn memory_mut(&mut self) -> RefMut<'_, [u8]> {
+        RefMut::map(self.memory.borrow_mut(), |mem| &mut mem.data[..])
+    }
+
+    // the handles to export the memory, a global or a table to another module
+    pub fn memory_ref(&self) -> MemoryRef {
+        self.memory.clone()
+    }
+
+    pub fn global_ref(&self, idx: usize) -> Option<GlobalRef> {
+        self.globals.get(idx).cloned()
+    }
+
+    pub fn table_ref(&self, idx: usize) -> Option<TableRef> {
+        self.tables.get(idx).cloned()
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    // the limits of memory and tables are also checked at instantiation
+    // if they are given by Linker::limits() or make_store_with_limits().
+    pub fn set_limits(&mut self, limits: StoreLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &StoreLimits {
+        &self.limits
+    }
+
+    // each instruction consumes 1 fuel. None is unlimited
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, n: u64) {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_add(n);
+        }
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn global(&self, idx: usize) -> Option<Value> {
+        self.globals.get(idx).map(|g| g.borrow().value.clone())
+    }
+
+    pub fn global_mutable(&self, idx: usize) -> Option<bool> {
+        self.globals.get(idx).map(|g| g.borrow().mutable)
+    }
+
+    // global.set of validated code (the global is mutable)
+    pub(crate) fn set_global(&mut self, idx: usize, value: Value) {
+        self.globals[idx].borrow_mut().value = value;
+    }
+
+    // current size and max of the memory. 65536 pages is the implicit max.
+    pub fn memory_limits(&self) -> Limits {
+        self.memory.borrow().limits()
+    }
+
+    // reftype and current limits of the table
+    pub fn table_type(&self, idx: usize) -> Option<(u8, Limits)> {
+        self.tables.get(idx).map(|t| t.borrow().tabletype())
+    }
+}
+
+// instances of the imports (in order of each kind)
+pub(crate) struct Imports {
+    pub funcs: Vec<Option<HostFunc>>,
+    pub globals: Vec<GlobalRef>,
+    pub mems: Vec<MemoryRef>,
+    pub tables: Vec<TableRef>,
+    pub limits: StoreLimits,
+}
+
+impl Imports {
+    // imports as declared by the module. functions are not provided, globals
+    // have the default value and memories and tables are allocated by the limits.
+    fn from_module(module: &Module, limits: StoreLimits) -> Result<Imports, String> {
+        let mut imports = Imports {
+            funcs: Vec::new(),
+            globals: Vec::new(),
+            mems: Vec::new(),
+            tables: Vec::new(),
+            limits,
+        };
+        for import in module.imports() {
+            match import.desc() {
+                Importdesc::Func(_) => imports.funcs.push(None),
+                Importdesc::Global(gt) => match Value::default_of(gt.valtype()) {
+                    Some(value) => imports.globals.push(GlobalInst::new(value, gt.is_mutable())),
+                    None => return Err(format!("global type {:#02x} not supported", gt.valtype())),
+                },
+                Importdesc::Mem(l) => imports.mems.push(MemInst::new(l, imports.limits.max_memory_pages)?),
+                Importdesc::Table(tt) =>
+                    imports.tables.push(TableInst::new(tt.reftype(), tt.limits(), imports.limits.max_table_elems)?),
+            }
+        }
+        Ok(imports)
+    }
+}
+
+// store without host functions. calling an import function traps.
+pub fn make_store(module: &Module) -> Result<Store, String> {
+    new_store(module, Imports::from_module(module, StoreLimits::default())?)
+}
+
+pub fn make_store_with_limits(module: &Module, limits: StoreLimits) -> Result<Store, String> {
+    new_store(module, Imports::from_module(module, limits)?)
+}
+
+// instantiate the module: allocate memory, globals and tables, initialize
+// them by the segments, and then call the start function.
+pub(crate) fn new_store(module: &Module, imports: Imports) -> Result<Store, String> {
+    if !module.is_validated() {
+        return Err("module is not validated".to_string());
+    }
+
+    // memory. an imported memory is the instance of the exporter
+    let memory = match (imports.mems.first(), module.mems().first()) {
+        (Some(mem), _) => mem.clone(),
+        (None, Some(limits)) => MemInst::new(limits, imports.limits.max_memory_pages)?,
+        (None, None) => Rc::new(RefCell::new(MemInst {data: Vec::new(), max: 0})),
+    };
+
+    let datas = module.datas().iter().map(|d| d.data().to_vec()).collect();
+    let mut store = Store {
+        id: NEXT_STORE_ID.fetch_add(1, Ordering::Relaxed),
+        memory,
+        datas,
+        globals: imports.globals,
+        tables: imports.tables,
+        elems: Vec::new(),
+        host_funcs: imports.funcs,
+        verbose: false,
+        trace: None,
+        limits: imports.limits,
+        fuel: None,
+    };
+
+    // globals and tables. the imported ones are already in the store
+    for (i, global) in module.globals().iter().enumerate() {
+        let value = eval_const_expr(global.expr(), &store)?;
+        if value.valtype() != Some(global.globaltype().valtype()) {
+            return Err(format!("global[{}]: type mismatch", i));
+        }
+        store.globals.push(GlobalInst::new(value, global.globaltype().is_mutable()));
+    }
+    for tt in module.tables() {
+        store.tables.push(TableInst::new(tt.reftype(), tt.limits(), store.limits.max_table_elems)?);
+    }
+
+    // element instances
+    for elem in module.elems() {
+        let values = match elem.init() {
+            ElemInit::Funcs(funcs) => funcs.iter().map(|idx| Value::FuncRef(Some(*idx))).collect(),
+            ElemInit::Exprs(exprs) => {
+                let mut values = Vec::new();
+                for expr in exprs {
+                    values.push(eval_const_expr(expr, &store)?);
+                }
+                values
+            },
+        };
+        store.elems.push(values);
+    }
+
+    // initialize tables by active element segments
+    for (i, elem) in module.elems().iter().enumerate() {
+        match elem.mode() {
+            ElemMode::Active(tableidx, expr) => {
+                let offset = match eval_const_expr(expr, &store)? {
+                    Value::I32(n) => n,
+                    _ => return Err(format!("elem[{}]: offset is not i32", i)),
+                };
+                let values = std::mem::take(&mut store.elems[i]); // elem.drop
+                let mut table = store.tables[tableidx as usize].borrow_mut();
+                let range = table.range(offset, values.len())?;
+                for (e, v) in table.elem[range].iter_mut().zip(values) {
+                    *e = (v, store.id);
+                }
+            },
+            ElemMode::Declarative => store.elems[i].clear(),
+            ElemMode::Passive => (),
+        }
+    }
+
+    // initialize memory by active data segments
+    for (i, data) in module.datas().iter().enumerate() {
+        if let DataMode::Active(_, expr) = data.mode() {
+            let offset = match eval_const_expr(expr, &store)? {
+                Value::I32(n) => n,
+                _ => return Err(format!("data[{}]: offset is not i32", i)),
+            };
+            store.store(offset, 0, data.data())?;
+            store.datas[i].clear(); // data.drop
+        }
+    }
+
+    // start function
+    if let Some(idx) = module.start() {
+        invoke(idx as usize, &[], module, &mut store)?;
+    }
+
+    Ok(store)
 }
 
-pub fn make_store(module: &Module) -> Store {
-    let mut memory = Vec::new();
-    //TODO: allocate memory and initialize from data segment
-    Store {memory,}
+// evaluate a constant expression.
+// extended-const (i32/i64 add, sub and mul) is supported.
+fn eval_const_expr(expr: &Expr, store: &Store) -> Result<Value, String> {
+    let insts = expr.get_insts().map_err(|e| e.to_string())?;
+    let mut stack = Stack::new();
+    for inst in &insts {
+        match (inst.op_code, &inst.operand) {
+            (0x41, Operand::I32(n)) => stack.push_i32(*n),
+            (0x42, Operand::I64(n)) => stack.push_i64(*n),
+            (0x43, Operand::F32(z)) => stack.push_f32(*z),
+            (0x44, Operand::F64(z)) => stack.push_f64(*z),
+            (0x23, Operand::Index(idx)) => match store.global(*idx as usize) {
+                Some(value) => stack.stack.push(value),
+                None => return Err(format!("unknown global {}", idx)),
+            },
+            (0xd0, Operand::Valtype(0x70)) => stack.stack.push(Value::FuncRef(None)),
+            (0xd0, _) => stack.stack.push(Value::ExternRef(None)),
+            (0xd2, Operand::Index(idx)) => stack.stack.push(Value::FuncRef(Some(*idx))),
+            (0xfd, Operand::V128(v)) => stack.push_v128(*v),
+            (0x6a..=0x6c, _) => {
+                let n2 = stack.pop_i32()?;
+                let n1 = stack.pop_i32()?;
+                stack.push_i32(match inst.op_code {
+                    0x6a => n1.wrapping_add(n2),
+                    0x6b => n1.wrapping_sub(n2),
+                    _ => n1.wrapping_mul(n2),
+                });
+            },
+            (0x7c..=0x7e, _) => {
+                let n2 = stack.pop_i64()?;
+                let n1 = stack.pop_i64()?;
+                stack.push_i64(match inst.op_code {
+                    0x7c => n1.wrapping_add(n2),
+                    0x7d => n1.wrapping_sub(n2),
+                    _ => n1.wrapping_mul(n2),
+                });
+            },
+            (0x0b, _) => break,
+            _ => return Err(format!("op({:#02x}) is not supported in constant expression",
+                                    inst.op_code)),
+        }
+    }
+    if stack.stack.len() != 1 {
+        return Err("constant expression must result in one value".to_string());
+    }
+    Ok(stack.pop())
 }
 
 #[derive(Debug, Clone)]
@@ -28,11 +500,53 @@ pub enum Value {
     I64(i64),
     F32(f32),
     F64(f64),
-    //V128(v128), not supported yet
-    //Index(u32), funcref
-    //???, externref
-    // stack only
-    Label(Label),
+    V128(u128),
+    FuncRef(Option<u32>), // None is null
+    ExternRef(Option<u32>),
+}
+
+// floats are equal when their bits are, so NaNs compare by payload
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::I32(a), Value::I32(b)) => a == b,
+            (Value::I64(a), Value::I64(b)) => a == b,
+            (Value::F32(a), Value::F32(b)) => a.to_bits() == b.to_bits(),
+            (Value::F64(a), Value::F64(b)) => a.to_bits() == b.to_bits(),
+            (Value::V128(a), Value::V128(b)) => a == b,
+            (Value::FuncRef(a), Value::FuncRef(b)) => a == b,
+            (Value::ExternRef(a), Value::ExternRef(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Value {
+    pub fn valtype(&self) -> Option<u8> {
+        match self {
+            Value::I32(_) => Some(0x7f),
+            Value::I64(_) => Some(0x7e),
+            Value::F32(_) => Some(0x7d),
+            Value::F64(_) => Some(0x7c),
+            Value::V128(_) => Some(0x7b),
+            Value::FuncRef(_) => Some(0x70),
+            Value::ExternRef(_) => Some(0x6f),
+        }
+    }
+
+    // initial value of locals and globals
+    pub fn default_of(valtype: u8) -> Option<Value> {
+        match valtype {
+            0x7f => Some(Value::I32(0)),
+            0x7e => Some(Value::I64(0)),
+            0x7d => Some(Value::F32(0.0)),
+            0x7c => Some(Value::F64(0.0)),
+            0x7b => Some(Value::V128(0)),
+            0x70 => Some(Value::FuncRef(None)),
+            0x6f => Some(Value::ExternRef(None)),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -42,7 +556,11 @@ impl fmt::Display for Value {
             Value::I64(n) => write!(f, "{}", n),
             Value::F32(n) => write!(f, "{}", n),
             Value::F64(n) => write!(f, "{}", n),
-            _ => write!(f, ""),
+            Value::V128(v) => write!(f, "{}", v128_text(*v)),
+            Value::FuncRef(Some(idx)) => write!(f, "ref.func {}", idx),
+            Value::FuncRef(None) => write!(f, "ref.null func"),
+            Value::ExternRef(Some(n)) => write!(f, "ref.extern {}", n),
+            Value::ExternRef(None) => write!(f, "ref.null extern"),
         }
     }
 }
@@ -119,32 +637,28 @@ impl Stack {
         self.stack.push(Value::F64(n));
     }
 
-    fn pop_label(&mut self) -> Result<Label, String> {
+    fn pop_v128(&mut self) -> Result<u128, String> {
         let item = self.stack.pop().unwrap();
-        if let Value::Label(label) = item {
-            return Ok(label);
+        if let Value::V128(v) = item {
+            return Ok(v);
         }
-        Err("stack value expect label".to_string())
+        Err("stack value expect v128".to_string())
     }
 
-    fn pop_nth_label(&mut self, n: u32) -> Label {
-        let mut num = n + 1;
-        loop {
-            let item = self.stack.pop().unwrap();
-            match item {
-                Value::Label(label) => {
-                    num -= 1;
-                    if num == 0 {
-                        return label;
-                    }
-                },
-                _ => (),
-            }
-        }
+    fn push_v128(&mut self, v: u128) {
+        self.stack.push(Value::V128(v));
     }
 
-    fn push_label(&mut self, label: Label) {
-        self.stack.push(Value::Label(label));
+    fn pop_values(&mut self, n: usize) -> Vec<Value> {
+        self.stack.split_off(self.stack.len() - n)
+    }
+
+    // keep the top n values and discard the values below them down to height
+    fn unwind(&mut self, height: usize, n: usize) {
+        let len = self.stack.len();
+        if len - n > height {
+            self.stack.drain(height..len - n);
+        }
     }
 }
 
@@ -153,9 +667,24 @@ pub struct Frame {
     func_idx: usize,
     locals: Vec<Value>,
     ip: usize,
+    base: usize, // stack height at function entry
+    end_ip: usize, // function end
+    labels: Vec<usize>, // control stack: stack heights at the entries of blocks
 }
 
 impl Frame {
+    pub fn func_idx(&self) -> usize {
+        self.func_idx
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn locals(&self) -> &[Value] {
+        &self.locals
+    }
+
     fn next(&mut self) {
         self.ip += 1;
     }
@@ -165,12 +694,6 @@ impl Frame {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Label {
-    arity: usize,
-    next_ip: usize,
-}
-
 pub enum Function {
     Import(ImportFunc),
     Local(LocalFunc),
@@ -185,306 +708,920 @@ pub struct LocalFunc {
     pub ft: Functype,
     pub locals: Vec<Valtype>,
     pub insts: Vec<Inst>,
+    pub jumps: Vec<Jump>, // side table of insts (see compile_jumps)
+}
+
+// a branch resolved by compile_jumps
+#[derive(Clone, Copy)]
+pub struct Branch {
+    pub ip: usize, // next instruction
+    pub arity: usize, // values carried to the target
+    pub depth: usize, // n of br n. the function body (return) if there are not so many labels
+    pub is_loop: bool, // the label of a loop remains
+}
+
+// control-flow side table entry of an instruction
+pub enum Jump {
+    None,
+    Block(usize), // block, loop: params
+    If(usize, usize), // params, next ip if the condition is false (after else, or the end)
+    Else(usize), // the end of the if
+    Br(Branch), // br, br_if
+    BrTable(Vec<Branch>), // labels and the default (last)
+}
+
+// a block being compiled
+struct OpenBlock {
+    op_code: u8,
+    start: usize,
+    params: usize,
+    results: usize,
+    fixups: Vec<(usize, usize)>, // ip and index in br_table of the branches to the end
+}
+
+// resolve the targets of block/loop/if/else/br/br_if/br_table in the function
+// body, so that the interpreter jumps without scanning the instructions.
+pub(crate) fn compile_jumps(insts: &[Inst], ft: &Functype, types: &[Functype]) -> Vec<Jump> {
+    let arity = |bt: &BlockType| match bt {
+        BlockType::Empty => (0, 0),
+        BlockType::Valtype(_) => (0, 1),
+        BlockType::TypeIndex(idx) => types.get(*idx as usize)
+            .map_or((0, 0), |ft| (ft.input.0.len(), ft.output.0.len())),
+    };
+    let end_ip = insts.len() - 1;
+    let mut jumps: Vec<Jump> = insts.iter().map(|_| Jump::None).collect();
+    let mut blocks = vec![OpenBlock {op_code: 0, start: 0, params: 0, results: ft.output.0.len(), fixups: Vec::new()}];
+    for (ip, inst) in insts.iter().enumerate() {
+        match (inst.op_code, &inst.operand) {
+            (0x02..=0x04, Operand::BlockType(bt)) => {
+                let (params, results) = arity(bt);
+                blocks.push(OpenBlock {op_code: inst.op_code, start: ip, params, results, fixups: Vec::new()});
+                jumps[ip] = match inst.op_code {
+                    0x04 => Jump::If(params, 0), // resolved by else or end
+                    _ => Jump::Block(params),
+                };
+            },
+            (0x05, _) => { // else
+                if let Some(block) = blocks.last_mut() {
+                    if let Jump::If(_, next) = &mut jumps[block.start] {
+                        *next = ip + 1;
+                    }
+                    block.fixups.push((ip, 0));
+                }
+                jumps[ip] = Jump::Else(0);
+            },
+            (0x0b, _) if blocks.len() > 1 => { // end (not the function end)
+                let block = blocks.pop().unwrap();
+                if let Jump::If(_, next) = &mut jumps[block.start] {
+                    if *next == 0 { // no else
+                        *next = ip;
+                    }
+                }
+                for (i, k) in block.fixups {
+                    match &mut jumps[i] {
+                        Jump::Br(br) => br.ip = ip + 1,
+                        Jump::BrTable(brs) => brs[k].ip = ip + 1,
+                        Jump::Else(end) => *end = ip,
+                        _ => (),
+                    }
+                }
+            },
+            (0x0c | 0x0d, Operand::Index(n)) => {
+                jumps[ip] = Jump::Br(branch_to(&mut blocks, *n, ip, 0, end_ip));
+            },
+            (0x0e, Operand::BrTable(table)) => {
+                let mut brs = Vec::new();
+                for (k, n) in table.labels.iter().chain([table.default].iter()).enumerate() {
+                    brs.push(branch_to(&mut blocks, *n, ip, k, end_ip));
+                }
+                jumps[ip] = Jump::BrTable(brs);
+            },
+            _ => (),
+        }
+    }
+    jumps
+}
+
+fn branch_to(blocks: &mut [OpenBlock], n: u32, ip: usize, k: usize, end_ip: usize) -> Branch {
+    let depth = n as usize;
+    if depth + 1 >= blocks.len() { // the function body
+        return Branch {ip: end_ip, arity: blocks[0].results, depth, is_loop: false};
+    }
+    let i = blocks.len() - 1 - depth;
+    let block = &mut blocks[i];
+    if block.op_code == 0x03 {
+        // br to loop continues after the loop instruction
+        Branch {ip: block.start + 1, arity: block.params, depth, is_loop: true}
+    } else {
+        block.fixups.push((ip, k));
+        Branch {ip: 0, arity: block.results, depth, is_loop: false}
+    }
 }
 
 impl LocalFunc {
-    pub fn show_insts(&self) {
+    pub fn show_insts(&self, names: &Names, func_idx: u32) {
+        let mut num_labels = 0; // labels are numbered in order of block/loop/if
         for inst in &self.insts {
-            inst.print();
+            let note = match (inst.op_code, &inst.operand) {
+                (0x02..=0x04, _) => {
+                    num_labels += 1;
+                    names.label(func_idx, num_labels - 1)
+                },
+                (0x10 | 0xd2, Operand::Index(idx)) => names.func(*idx),
+                (0x20..=0x22, Operand::Index(idx)) => names.local(func_idx, *idx),
+                (0x23 | 0x24, Operand::Index(idx)) => names.global(*idx),
+                _ => None,
+            };
+            inst.print_note(note);
         }
     }
 }
 
-pub fn exec_func(args: &[&str], module: &Module, store: &mut Store) -> Result<(), String> {
-    if args.len() == 0 {
-        return Err("need funcidx".to_string());
+// function index by funcidx, export name or the name in the name section ($name)
+pub fn resolve_func(name: &str, module: &Module) -> Option<usize> {
+    if let Ok(idx) = name.parse() {
+        return Some(idx);
+    }
+    if let Some(idx) = module.export_func(name) {
+        return Some(idx);
     }
-    let idx: usize = match args[0].parse() {
-        Ok(n) => n,
-        Err(err) => return Err(format!("{}", err)),
+    module.func_index(name.trim_start_matches('$'))
+}
+
+// call the function args[0] with args[1..] given as strings and return its results
+pub fn invoke_str(args: &[&str], module: &Module, store: &mut Store) -> Result<Vec<Value>, String> {
+    let (idx, values) = parse_call(args, module)?;
+    invoke(idx, &values, module, store)
+}
+
+// funcidx and arg values of "func args.."
+pub(crate) fn parse_call(args: &[&str], module: &Module) -> Result<(usize, Vec<Value>), String> {
+    if args.is_empty() {
+        return Err("need funcidx or name".to_string());
+    }
+    let idx = match resolve_func(args[0], module) {
+        Some(idx) => idx,
+        None => return Err(format!("unknown function '{}'", args[0])),
     };
     if idx >= module.num_funcs() {
         return Err(format!("funcidx {} out of range. must be less than {}", idx,
                            module.num_funcs()));
     }
-    if module.is_import_func(idx) {
-        return Err("import function is not supported".to_string());
-    }
-
-    let func = module.get_local_func(idx);
-    if func.ft.input.0.len() != args.len() - 1 {
-        return Err(format!("need {} args", func.ft.input.0.len()));
+    let desc = module.func_desc(idx);
+    let ft = func_type(idx, module).unwrap();
+    if ft.input.0.len() != args.len() - 1 {
+        return Err(format!("{}: need {} args", desc, ft.input.0.len()));
     }
 
-    let mut stack = Stack::new();
+    let mut values = Vec::new();
 
-    for (i, v) in func.ft.input.0.iter().enumerate() {
+    for (i, v) in ft.input.0.iter().enumerate() {
         match &v.0 {
-            0x7f => {
-                let a: i32 = match args[i + 1].parse() {
-                    Ok(n) => n,
-                    Err(err) => return Err(format!("{}", err)),
-                };
-                stack.push_i32(a);
+            0x7f => match parse_int(args[i + 1], 32) {
+                Ok(n) => values.push(Value::I32(n as i32)),
+                Err(err) => return Err(format!("{}: arg {}: {}", desc, i, err)),
             },
-            0x7e => {
-                let a: i64 = match args[i + 1].parse() {
-                    Ok(n) => n,
-                    Err(err) => return Err(format!("{}", err)),
-                };
-                stack.push_i64(a);
+            0x7e => match parse_int(args[i + 1], 64) {
+                Ok(n) => values.push(Value::I64(n as i64)),
+                Err(err) => return Err(format!("{}: arg {}: {}", desc, i, err)),
             },
             0x7d => {
                 let a: f32 = match args[i + 1].parse() {
                     Ok(n) => n,
-                    Err(err) => return Err(format!("{}", err)),
+                    Err(err) => return Err(format!("{}: arg {}: {}", desc, i, err)),
                 };
-                stack.push_f32(a);
+                values.push(Value::F32(a));
             }
             0x7c => {
                 let a: f64 = match args[i + 1].parse() {
                     Ok(n) => n,
-                    Err(err) => return Err(format!("{}", err)),
+                    Err(err) => return Err(format!("{}: arg {}: {}", desc, i, err)),
+                };
+                values.push(Value::F64(a));
+            },
+            0x7b => { // hex (0x...) or decimal u128
+                let s = args[i + 1];
+                let a = match s.strip_prefix("0x") {
+                    Some(hex) => u128::from_str_radix(hex, 16),
+                    None => s.parse(),
                 };
-                stack.push_f64(a);
+                match a {
+                    Ok(v) => values.push(Value::V128(v)),
+                    Err(err) => return Err(format!("{}: arg {}: {}", desc, i, err)),
+                }
             },
             _ => {
                 return Err("not supported".to_string());
             },
         }
     }
+    Ok((idx, values))
+}
+
+// integer arg of bits width: signed or unsigned decimal, or hex (0x...) with
+// an optional '-'. the value is returned as the unsigned bit pattern.
+fn parse_int(s: &str, bits: u32) -> Result<u64, String> {
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let n = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    let n = n.map_err(|err| format!("'{}': {}", s, err))?;
+    let max = u64::MAX >> (64 - bits);
+    match neg {
+        true if n <= max / 2 + 1 => Ok(n.wrapping_neg() & max),
+        false if n <= max => Ok(n),
+        _ => Err(format!("'{}' out of range of i{}", s, bits)),
+    }
+}
+
+pub fn exec_func(args: &[&str], module: &Module, store: &mut Store) -> Result<(), String> {
+    let results = invoke_str(args, module, store)?;
+    if !results.is_empty() {
+        let v: Vec<String> = results.iter().map(|r| r.to_string()).collect();
+        println!("result: {}", v.join(" "));
+    }
+    Ok(())
+}
+
+// call function idx with args and return its results.
+// this is the entry point for library users.
+pub fn invoke(idx: usize, args: &[Value], module: &Module, store: &mut Store) -> Result<Vec<Value>, String> {
+    let mut inv = Invocation::new(idx, args, module, store)?;
+    inv.run(module, store)?;
+    Ok(inv.results())
+}
+
+// execution of a function call. frames are kept here (not in the Rust call
+// stack), so the execution can be suspended after any instruction and resumed.
+// this is used by the debugger.
+pub struct Invocation {
+    stack: Stack,
+    frames: Vec<Frame>,
+    num_results: usize,
+}
+
+impl Invocation {
+    // prepare to call function idx with args. nothing is executed yet
+    // unless it is an import function.
+    pub fn new(idx: usize, args: &[Value], module: &Module, store: &mut Store) -> Result<Invocation, String> {
+        let ft = match func_type(idx, module) {
+            Some(ft) => ft,
+            None => return Err(format!("funcidx {} out of range. must be less than {}", idx,
+                                       module.num_funcs())),
+        };
+        if ft.input.0.len() != args.len() {
+            return Err(format!("need {} args", ft.input.0.len()));
+        }
+        for (v, a) in ft.input.0.iter().zip(args) {
+            if a.valtype() != Some(v.0) {
+                return Err(format!("arg {} is not {}", a, v));
+            }
+        }
+
+        let mut inv = Invocation {
+            stack: Stack::new(),
+            frames: Vec::new(),
+            num_results: ft.output.0.len(),
+        };
+        inv.stack.stack.extend_from_slice(args);
+        inv.call(idx, module, store)?;
+        Ok(inv)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // frames of the calls. the last one is the current function
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    // values on the stack of the current function
+    pub fn stack(&self) -> &[Value] {
+        let base = self.frames.last().map_or(0, |f| f.base);
+        &self.stack.stack[base..]
+    }
+
+    pub fn results(mut self) -> Vec<Value> {
+        let n = self.stack.stack.len() - self.num_results;
+        self.stack.stack.split_off(n)
+    }
+
+    // run to the end
+    pub fn run(&mut self, module: &Module, store: &mut Store) -> Result<(), String> {
+        while !self.frames.is_empty() {
+            self.step(module, store)?;
+        }
+        Ok(())
+    }
+
+    // execute one instruction. a call enters the callee (except import functions).
+    // if it traps with OUT_OF_FUEL, nothing is executed and it can be resumed
+    // after Store::add_fuel().
+    pub fn step(&mut self, module: &Module, store: &mut Store) -> Result<(), String> {
+        if let Some(fuel) = &mut store.fuel {
+            if *fuel == 0 {
+                return Err(OUT_OF_FUEL.to_string());
+            }
+            *fuel -= 1;
+        }
+        if self.stack.stack.len() > store.limits.max_stack {
+            return Err("trap: operand stack exhausted".to_string());
+        }
+        let trace = match (&store.trace, self.frames.last()) {
+            (Some(trace), Some(frame)) if trace.funcs.is_empty() || trace.funcs.contains(&frame.func_idx) =>
+                Some((trace.json, frame.func_idx, frame.ip)),
+            _ => None,
+        };
+        match trace {
+            Some((json, func_idx, ip)) => {
+                let result = self.exec_inst(module, store);
+                self.trace(json, func_idx, ip, module, result.as_ref().err());
+                result
+            },
+            None => self.exec_inst(module, store),
+        }
+    }
+
+    fn trace(&self, json: bool, func_idx: usize, ip: usize, module: &Module, trap: Option<&String>) {
+        eprintln!("{}", self.trace_line(json, func_idx, ip, module, trap));
+    }
+
+    fn trace_line(&self, json: bool, func_idx: usize, ip: usize, module: &Module, trap: Option<&String>) -> String {
+        let inst = &module.get_local_func(func_idx).insts[ip];
+        let locals = self.frames.last().map_or(&[][..], |f| &f.locals[..]);
+        if json {
+            let values = |vs: &[Value]| {
+                let v: Vec<String> = vs.iter().map(|v| json_str(&trace_value(v))).collect();
+                format!("[{}]", v.join(","))
+            };
+            let mut line = format!("{{\"func\":{},\"ip\":{},\"op\":{},\"operands\":{},\"stack\":{},\"locals\":{}",
+                                   func_idx, ip, json_str(&inst.get_mnemonic()),
+                                   json_str(inst.operand_string().trim_start()),
+                                   values(self.stack()), values(locals));
+            if let Some(trap) = trap {
+                line += &format!(",\"trap\":{}", json_str(trap));
+            }
+            line + "}"
+        } else {
+            let values = |vs: &[Value]| {
+                let v: Vec<String> = vs.iter().map(trace_value).collect();
+                v.join(", ")
+            };
+            let line = format!("trace: {} {}: {}{} stack=[{}] locals=[{}]", module.func_desc(func_idx), ip,
+                               inst.get_mnemonic(), inst.operand_string(), values(self.stack()), values(locals));
+            match trap {
+                Some(trap) => format!("{} {}", line, trap),
+                None => line,
+            }
+        }
+    }
+
+    fn exec_inst(&mut self, module: &Module, store: &mut Store) -> Result<(), String> {
+        let frame = match self.frames.last_mut() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let stack = &mut self.stack;
+        let func = module.get_local_func(frame.func_idx);
+        let inst = &func.insts[frame.ip];
+        let jump = &func.jumps[frame.ip];
+        match inst.op_code {
+            0x00 => { // unreachable
+                return Err("trap: unreachable".to_string());
+            },
+            0x02 | 0x03 => { // block, loop
+                if let Jump::Block(params) = jump {
+                    frame.labels.push(stack.stack.len() - params);
+                }
+                frame.next();
+            },
+            0x04 => { // if
+                let c = stack.pop_i32()?;
+                if let Jump::If(params, next) = jump {
+                    frame.labels.push(stack.stack.len() - params);
+                    if c != 0 {
+                        frame.next();
+                    } else {
+                        frame.set_ip(*next); // else + 1 or end (which pops the label)
+                    }
+                }
+            },
+            0x05 => { // else: end of then. jump to end
+                if let Jump::Else(end) = jump {
+                    frame.set_ip(*end);
+                }
+            },
+            0x0b => { // end
+                if inst.level < 0 {
+                    self.ret(module, store);
+                } else {
+                    frame.labels.pop();
+                    frame.next();
+                }
+            },
+            0x0c => { // br
+                if let Jump::Br(br) = jump {
+                    branch(br, frame, stack);
+                }
+            },
+            0x0d => { // br_if
+                let c = stack.pop_i32()?;
+                match jump {
+                    Jump::Br(br) if c != 0 => branch(br, frame, stack),
+                    _ => frame.next(),
+                }
+            },
+            0x0e => { // br_table
+                let i = stack.pop_i32()? as u32 as usize;
+                if let Jump::BrTable(brs) = jump {
+                    branch(&brs[i.min(brs.len() - 1)], frame, stack);
+                }
+            },
+            0x10 => { // call
+                if let Operand::Index(idx) = &inst.operand {
+                    self.call(*idx as usize, module, store)?;
+                }
+            },
+            0x11 => { // call_indirect
+                if let Operand::Index2(tableidx, typeidx) = &inst.operand {
+                    let idx = indirect_func(*tableidx, *typeidx, stack.pop_i32()?, module, store)?;
+                    self.call(idx, module, store)?;
+                }
+            },
+            _ => {
+                let e_fn = EXEC_TABLE[inst.op_code as usize];
+                e_fn(inst, frame, stack, store)?;
+            },
+        }
+        Ok(())
+    }
+
+    // enter function idx. the args are on the stack.
+    // an import function is called here and the caller goes to the next instruction.
+    fn call(&mut self, idx: usize, module: &Module, store: &mut Store) -> Result<(), String> {
+        if let Some(Function::Import(func)) = module.get_func(idx) {
+            call_host_func(idx, func, store, &mut self.stack)?;
+            if let Some(caller) = self.frames.last_mut() {
+                caller.next();
+            }
+            return Ok(());
+        }
+        if self.frames.len() >= store.limits.max_call_depth {
+            return Err("trap: call stack exhausted".to_string());
+        }
+        let func = module.get_local_func(idx);
 
-    call_func(idx, module, store, &mut stack)?;
+        // Frame locals: args followed by the default values of locals
+        let mut locals = self.stack.pop_values(func.ft.input.0.len());
+        for v in &func.locals {
+            match Value::default_of(v.0) {
+                Some(value) => locals.push(value),
+                None => return Err("not supported".to_string()),
+            }
+        }
+
+        self.frames.push(Frame {
+            func_idx: idx,
+            locals,
+            ip: 0,
+            base: self.stack.stack.len(),
+            end_ip: func.insts.len() - 1,
+            labels: Vec::new(),
+        });
+        Ok(())
+    }
+
+    // return from the current function: leave the results only
+    fn ret(&mut self, module: &Module, store: &Store) {
+        let frame = self.frames.pop().unwrap();
+        let func = module.get_local_func(frame.func_idx);
+        let results = self.stack.pop_values(func.ft.output.0.len());
+        self.stack.stack.truncate(frame.base);
+        self.stack.stack.extend(results);
+        if store.verbose {
+            println!("{} done: {:?}", module.func_desc(frame.func_idx), &self.stack.stack[frame.base..]);
+        }
+        if let Some(caller) = self.frames.last_mut() {
+            caller.next();
+        }
+    }
+}
+
+// "i32:1"
+fn trace_value(v: &Value) -> String {
+    match v.valtype() {
+        Some(t) => format!("{}:{}", Valtype(t), v),
+        None => v.to_string(),
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+fn call_host_func(idx: usize, func: &ImportFunc, store: &mut Store, stack: &mut Stack) -> Result<(), String> {
+    let host_func = match &store.host_funcs[idx] {
+        Some(f) => f.clone(),
+        None => return Err(format!("trap: import function '{}' is not provided", func.name)),
+    };
+    let args = stack.pop_values(func.ft.input.0.len());
+    let results = host_func(store, &args)?;
+    let types: Vec<Option<u8>> = results.iter().map(|v| v.valtype()).collect();
+    let expect: Vec<Option<u8>> = func.ft.output.0.iter().map(|v| Some(v.0)).collect();
+    if types != expect {
+        return Err(format!("trap: import function '{}' returned wrong results", func.name));
+    }
+    stack.stack.extend(results);
+    Ok(())
+}
+
+pub(crate) fn func_type(idx: usize, module: &Module) -> Option<&Functype> {
+    match module.get_func(idx) {
+        Some(Function::Import(func)) => Some(&func.ft),
+        Some(Function::Local(func)) => Some(&func.ft),
+        None => None,
+    }
+}
+
+// function to be called by call_indirect
+pub(crate) fn indirect_func(tableidx: u32, typeidx: u32, i: i32, module: &Module, store: &Store) -> Result<usize, String> {
+    let idx = match store.tables[tableidx as usize].borrow().elem.get(i as u32 as usize) {
+        Some((Value::FuncRef(Some(idx)), id)) if *id == store.id => *idx as usize,
+        Some((Value::FuncRef(Some(_)), _)) =>
+            return Err("trap: indirect call to a function of another instance is not supported".to_string()),
+        Some((Value::FuncRef(None), _)) => return Err("trap: uninitialized element".to_string()),
+        _ => return Err("trap: undefined element".to_string()),
+    };
+    if func_type(idx, module) != module.types().get(typeidx as usize) {
+        return Err("trap: indirect call type mismatch".to_string());
+    }
+    Ok(idx)
+}
+
+fn branch(br: &Branch, frame: &mut Frame, stack: &mut Stack) {
+    if br.depth >= frame.labels.len() {
+        frame.set_ip(frame.end_ip); // br to the function body is return
+        return;
+    }
+    let i = frame.labels.len() - 1 - br.depth;
+    stack.unwind(frame.labels[i], br.arity);
+    frame.labels.truncate(if br.is_loop { i + 1 } else { i });
+    frame.set_ip(br.ip);
+}
+
+// return
+fn exec_0f(_inst: &Inst, frame: &mut Frame, _stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    frame.set_ip(frame.end_ip);
+    Ok(())
+}
+
+// nop
+fn exec_01(_inst: &Inst, frame: &mut Frame, _stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    frame.next();
+    Ok(())
+}
+
+// drop
+fn exec_1a(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    stack.pop();
+    frame.next();
+    Ok(())
+}
+
+// select
+fn exec_1b(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let c = stack.pop_i32()?;
+    let v2 = stack.pop();
+    let v1 = stack.pop();
+    stack.stack.push(if c != 0 { v1 } else { v2 });
+    frame.next();
+    Ok(())
+}
+
+// local.get
+fn exec_20(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(idx) = inst.operand {
+        stack.stack.push(frame.locals[idx as usize].clone());
+    }
+    frame.next();
+    Ok(())
+}
+
+// local.set
+fn exec_21(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(idx) = inst.operand {
+        frame.locals[idx as usize] = stack.pop();
+    }
+    frame.next();
+    Ok(())
+}
+
+// global.get
+fn exec_23(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(idx) = inst.operand {
+        stack.stack.push(store.globals[idx as usize].borrow().value.clone());
+    }
+    frame.next();
+    Ok(())
+}
+
+// global.set
+fn exec_24(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(idx) = inst.operand {
+        let mut global = store.globals[idx as usize].borrow_mut();
+        if !global.mutable {
+            return Err(format!("trap: global {} is immutable", idx));
+        }
+        global.value = stack.pop();
+    }
+    frame.next();
+    Ok(())
+}
+
+// table.get
+fn exec_25(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(tableidx) = inst.operand {
+        let table = store.tables[tableidx as usize].borrow();
+        let range = table.range(stack.pop_i32()?, 1)?;
+        stack.stack.push(table.elem[range.start].0.clone());
+    }
+    frame.next();
+    Ok(())
+}
+
+// table.set
+fn exec_26(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(tableidx) = inst.operand {
+        let value = stack.pop();
+        let mut table = store.tables[tableidx as usize].borrow_mut();
+        let range = table.range(stack.pop_i32()?, 1)?;
+        table.elem[range.start] = (value, store.id);
+    }
+    frame.next();
+    Ok(())
+}
+
+// table.init
+fn table_init(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index2(elemidx, tableidx) = inst.operand {
+        let n = stack.pop_i32()? as u32 as usize;
+        let s = stack.pop_i32()? as u32 as usize;
+        let d = stack.pop_i32()?;
+        let elem = &store.elems[elemidx as usize];
+        if s + n > elem.len() {
+            return Err("trap: out of bounds table access".to_string());
+        }
+        let mut table = store.tables[tableidx as usize].borrow_mut();
+        let range = table.range(d, n)?;
+        for (e, v) in table.elem[range].iter_mut().zip(&elem[s..s + n]) {
+            *e = (v.clone(), store.id);
+        }
+    }
+    frame.next();
+    Ok(())
+}
+
+// elem.drop
+fn elem_drop(inst: &Inst, frame: &mut Frame, _stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(elemidx) = inst.operand {
+        store.elems[elemidx as usize] = Vec::new();
+    }
+    frame.next();
+    Ok(())
+}
+
+// table.copy
+fn table_copy(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index2(dst, src) = inst.operand {
+        let n = stack.pop_i32()? as u32 as usize;
+        let s = store.tables[src as usize].borrow().range(stack.pop_i32()?, n)?;
+        let d = store.tables[dst as usize].borrow().range(stack.pop_i32()?, n)?;
+        let values = store.tables[src as usize].borrow().elem[s].to_vec();
+        store.tables[dst as usize].borrow_mut().elem[d].clone_from_slice(&values);
+    }
+    frame.next();
+    Ok(())
+}
+
+// table.grow
+fn table_grow(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(tableidx) = inst.operand {
+        let n = stack.pop_i32()?;
+        let init = stack.pop();
+        let limit = store.limits.max_table_elems;
+        stack.push_i32(store.tables[tableidx as usize].borrow_mut().grow(n as u32, (init, store.id), limit));
+    }
+    frame.next();
+    Ok(())
+}
+
+// table.size
+fn table_size(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(tableidx) = inst.operand {
+        stack.push_i32(store.tables[tableidx as usize].borrow().elem.len() as i32);
+    }
+    frame.next();
+    Ok(())
+}
 
-    if func.ft.output.0.len() == 1 {
-        println!("result: {}", stack.pop());
+// table.fill
+fn table_fill(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(tableidx) = inst.operand {
+        let n = stack.pop_i32()? as u32 as usize;
+        let value = stack.pop();
+        let mut table = store.tables[tableidx as usize].borrow_mut();
+        let range = table.range(stack.pop_i32()?, n)?;
+        table.elem[range].fill((value, store.id));
     }
+    frame.next();
     Ok(())
 }
 
-fn call_func(idx: usize, module: &Module, store: &Store, stack: &mut Stack) -> Result<(), String> {
-    if module.is_import_func(idx) {
-        return Err("import function is not supported".to_string());
+// ref.null
+fn exec_d0(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if let Operand::Valtype(t) = inst.operand {
+        stack.stack.push(Value::default_of(t).unwrap_or(Value::FuncRef(None)));
     }
-    let func = module.get_local_func(idx);
+    frame.next();
+    Ok(())
+}
 
-    let mut locals = Vec::new(); // Frame locals
-    // set default
-    for v in func.ft.input.0.iter() {
-       match &v.0 {
-           0x7f => locals.push(Value::I32(0)),
-           0x7e => locals.push(Value::I64(0)),
-           0x7d => locals.push(Value::F32(0.0)),
-           0x7c => locals.push(Value::F64(0.0)),
-           _ => {
-               return Err("not supported".to_string());
-           },
-        }
-    }
-    for v in &func.locals {
-       match &v.0 {
-           0x7f => locals.push(Value::I32(0)),
-           0x7e => locals.push(Value::I64(0)),
-           0x7d => locals.push(Value::F32(0.0)),
-           0x7c => locals.push(Value::F64(0.0)),
-           _ => {
-               return Err("not supported".to_string());
-           },
-       }
+// ref.is_null
+fn exec_d1(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let r = matches!(stack.pop(), Value::FuncRef(None) | Value::ExternRef(None));
+    stack.push_i32(r as i32);
+    frame.next();
+    Ok(())
+}
+
+// ref.func
+fn exec_d2(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(idx) = inst.operand {
+        stack.stack.push(Value::FuncRef(Some(idx)));
     }
+    frame.next();
+    Ok(())
+}
 
-    let num_input = func.ft.input.0.len();
-    for i in 0..num_input {
-        let j = num_input - i - 1;
-        let v = &func.ft.input.0[j];
-        match &v.0 {
-            0x7f => {
-                let a = stack.pop_i32()?;
-                locals[j] = Value::I32(a);
-            },
-            0x7e => {
-                let a = stack.pop_i64()?;
-                locals[j] = Value::I64(a);
-            },
-            0x7d => {
-                let a = stack.pop_f32()?;
-                locals[j] = Value::F32(a);
-            }
-            0x7c => {
-                let a = stack.pop_f64()?;
-                locals[j] = Value::F64(a);
-            },
-            _ => {
-                return Err("not supported".to_string());
-            },
+// local.tee
+fn exec_22(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    stack.dup_top();
+    exec_21(inst, frame, stack, store)
+}
+
+// load
+fn mem_load(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Memarg(m) = &inst.operand {
+        let base = stack.pop_i32()?;
+        let offset = m.offset;
+        match inst.op_code {
+            0x28 => stack.push_i32(i32::from_le_bytes(store.load(base, offset)?)),
+            0x29 => stack.push_i64(i64::from_le_bytes(store.load(base, offset)?)),
+            0x2a => stack.push_f32(f32::from_le_bytes(store.load(base, offset)?)),
+            0x2b => stack.push_f64(f64::from_le_bytes(store.load(base, offset)?)),
+            0x2c => stack.push_i32(i8::from_le_bytes(store.load(base, offset)?) as i32),
+            0x2d => stack.push_i32(u8::from_le_bytes(store.load(base, offset)?) as i32),
+            0x2e => stack.push_i32(i16::from_le_bytes(store.load(base, offset)?) as i32),
+            0x2f => stack.push_i32(u16::from_le_bytes(store.load(base, offset)?) as i32),
+            0x30 => stack.push_i64(i8::from_le_bytes(store.load(base, offset)?) as i64),
+            0x31 => stack.push_i64(u8::from_le_bytes(store.load(base, offset)?) as i64),
+            0x32 => stack.push_i64(i16::from_le_bytes(store.load(base, offset)?) as i64),
+            0x33 => stack.push_i64(u16::from_le_bytes(store.load(base, offset)?) as i64),
+            0x34 => stack.push_i64(i32::from_le_bytes(store.load(base, offset)?) as i64),
+            0x35 => stack.push_i64(u32::from_le_bytes(store.load(base, offset)?) as i64),
+            _ => return not_supported(inst, frame, stack, store),
         }
     }
+    frame.next();
+    Ok(())
+}
 
-    let mut frame = Frame {
-        func_idx: idx,
-        locals: locals,
-        ip: 0,
-    };
-
-    // execute function
-    loop {
-        let inst = &func.insts[frame.ip];
-        //println!("{}: {:?} {:?}", frame.ip, &frame.locals, &self.stack);
-        //inst.print();
+// store
+fn mem_store(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Memarg(m) = &inst.operand {
+        let offset = m.offset;
         match inst.op_code {
-            0x00 => { // unreachable
-                return Err("trap: unreachable".to_string());
+            0x36 => { // i32.store
+                let n = stack.pop_i32()?;
+                store.store(stack.pop_i32()?, offset, &n.to_le_bytes())?;
             },
-            0x02 => { // block
-                exec_02(func, &inst, &mut frame, stack)?;
+            0x37 => { // i64.store
+                let n = stack.pop_i64()?;
+                store.store(stack.pop_i32()?, offset, &n.to_le_bytes())?;
             },
-            0x0b => { // end
-                if inst.level < 0 {
-                    //TODO: suport only one return value
-                    let item = stack.peek_top(); // must be return value
-                    println!("function[{}] done: {:?}", frame.func_idx, item);
-                    break;
-                }
-                exec_0b(&inst, &mut frame, stack)?;
+            0x38 => { // f32.store
+                let z = stack.pop_f32()?;
+                store.store(stack.pop_i32()?, offset, &z.to_le_bytes())?;
             },
-            0x0d => { // br_if
-                let t = stack.pop_i32()?;
-                if t == 0 {
-                    frame.next();
-                } else {
-                    exec_0c(&inst, &mut frame, stack)?;
-                }
+            0x39 => { // f64.store
+                let z = stack.pop_f64()?;
+                store.store(stack.pop_i32()?, offset, &z.to_le_bytes())?;
             },
-            0x0f => { // return
-                let item = stack.peek_top(); // must be return value
-                println!("function[{}] done: {:?}", frame.func_idx, item);
-                break;
+            0x3a => { // i32.store8
+                let n = stack.pop_i32()?;
+                store.store(stack.pop_i32()?, offset, &(n as u8).to_le_bytes())?;
             },
-            0x10 => { // call
-                if let Operand::Index(idx) = &inst.operand {
-                    call_func(*idx as usize, module, store, stack)?;
-                    frame.next();
-                }
+            0x3b => { // i32.store16
+                let n = stack.pop_i32()?;
+                store.store(stack.pop_i32()?, offset, &(n as u16).to_le_bytes())?;
             },
-            _ => {
-                let e_fn = EXEC_TABLE[inst.op_code as usize];
-                e_fn(&inst, &mut frame, stack)?;
+            0x3c => { // i64.store8
+                let n = stack.pop_i64()?;
+                store.store(stack.pop_i32()?, offset, &(n as u8).to_le_bytes())?;
             },
+            0x3d => { // i64.store16
+                let n = stack.pop_i64()?;
+                store.store(stack.pop_i32()?, offset, &(n as u16).to_le_bytes())?;
+            },
+            0x3e => { // i64.store32
+                let n = stack.pop_i64()?;
+                store.store(stack.pop_i32()?, offset, &(n as u32).to_le_bytes())?;
+            },
+            _ => return not_supported(inst, frame, stack, store),
         }
     }
-    Ok(())
-}
-
-// block
-fn exec_02(func: &LocalFunc, inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
-    //TODO: suport EMPTY only now
-    let mut next_ip = frame.ip + 1;
-    loop {
-        let it = &func.insts[next_ip];
-        if it.op_code == 0x0b && it.level == inst.level {
-             break;
-        }
-        next_ip += 1;
-    }
-    next_ip += 1;
-    stack.push_label(Label {
-         arity: 0,
-         next_ip,
-    });
     frame.next();
     Ok(())
 }
 
-// loop
-fn exec_03(_inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
-    //TODO: suport EMPTY only now
-    stack.push_label(Label {
-        arity: 0,
-        next_ip: frame.ip,
-    });
+// memory.size
+fn exec_3f(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    stack.push_i32(store.mem_pages() as i32);
     frame.next();
     Ok(())
 }
 
-// end
-fn exec_0b(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
-    //NOTE: this is called when loop end
-    assert!(inst.level >= 0);
-    //TODO: return value
-    stack.pop_label()?;
+// memory.grow
+fn exec_40(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    let n = stack.pop_i32()?;
+    stack.push_i32(store.mem_grow(n as u32));
     frame.next();
     Ok(())
 }
 
-// br
-fn exec_0c(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
-    if let Operand::Index(n) = inst.operand {
-        let label = stack.pop_nth_label(n);
-        frame.set_ip(label.next_ip);
+// memory.init
+fn memory_init(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index2(dataidx, _) = inst.operand {
+        let n = stack.pop_i32()? as u32 as usize;
+        let s = stack.pop_i32()? as u32 as usize;
+        let d = stack.pop_i32()?;
+        let data = &store.datas[dataidx as usize];
+        if s + n > data.len() {
+            return Err("trap: out of bounds memory access".to_string());
+        }
+        let bytes = data[s..s + n].to_vec();
+        store.store(d, 0, &bytes)?;
     }
+    frame.next();
     Ok(())
 }
 
-// local.get
-fn exec_20(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
-    if let Operand::Index(idx) = inst.operand {
-        let local_value = &frame.locals[idx as usize];
-        match local_value {
-            Value::I32(n) => {
-                stack.push_i32(*n);
-            },
-            Value::I64(n) => {
-                stack.push_i64(*n);
-            },
-            Value::F32(n) => {
-                stack.push_f32(*n);
-            },
-            Value::F64(n) => {
-                stack.push_f64(*n);
-            },
-            _ => return Err("trap: not supported yet".to_string()),
-        }
+// data.drop
+fn data_drop(inst: &Inst, frame: &mut Frame, _stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    if let Operand::Index(dataidx) = inst.operand {
+        store.datas[dataidx as usize] = Vec::new();
     }
     frame.next();
     Ok(())
 }
 
-// local.set
-fn exec_21(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
-    if let Operand::Index(idx) = inst.operand {
-        let local_value = &frame.locals[idx as usize];
-        match local_value {
-            Value::I32(_) => {
-                let a = stack.pop_i32()?;
-                frame.locals[idx as usize] = Value::I32(a);
-            },
-            Value::I64(_) => {
-                let a = stack.pop_i64()?;
-                frame.locals[idx as usize] = Value::I64(a);
-            },
-            Value::F32(_) => {
-                let a = stack.pop_f32()?;
-                frame.locals[idx as usize] = Value::F32(a);
-            },
-            Value::F64(_) => {
-                let a = stack.pop_f64()?;
-                frame.locals[idx as usize] = Value::F64(a);
-            },
-            _ => return Err("trap: not supported yet".to_string()),
-        }
-    }
+// memory.copy
+fn memory_copy(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    let n = stack.pop_i32()? as u32 as usize;
+    let s = store.mem_addr(stack.pop_i32()?, 0, n)?;
+    let d = store.mem_addr(stack.pop_i32()?, 0, n)?;
+    store.memory_mut().copy_within(s..s + n, d);
     frame.next();
     Ok(())
 }
 
-// local.tee
-fn exec_22(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
-    stack.dup_top();
-    exec_21(inst, frame, stack)
+// memory.fill
+fn memory_fill(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    let n = stack.pop_i32()? as u32 as usize;
+    let val = stack.pop_i32()? as u8;
+    let d = store.mem_addr(stack.pop_i32()?, 0, n)?;
+    store.memory_mut()[d..d + n].fill(val);
+    frame.next();
+    Ok(())
 }
 
 // i32.const
-fn exec_41(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
+fn exec_41(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
     if let Operand::I32(n) = inst.operand {
         stack.push_i32(n);
     }
@@ -493,7 +1630,7 @@ fn exec_41(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), Stri
 }
 
 // i32.eqz
-fn exec_45(_inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
+fn exec_45(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
     let n = stack.pop_i32()?;
     stack.push_i32((n == 0) as i32);
     frame.next();
@@ -501,7 +1638,7 @@ fn exec_45(_inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), Str
 }
 
 // i32 binop bool
-fn i32_binop_bool(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
+fn i32_binop_bool(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
     let n2 = stack.pop_i32()?;
     let n1 = stack.pop_i32()?;
     let r: bool = match inst.op_code {
@@ -512,7 +1649,7 @@ fn i32_binop_bool(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(
         0x4a => n1 > n2, // gt_s
         0x4b => (n1 as u32) > (n2 as u32), // gt_u
         0x4c => n1 <= n2, // le_s
-        0x4d => (n1 as u32) <= (n1 as u32), // le_u
+        0x4d => (n1 as u32) <= (n2 as u32), // le_u
         0x4e => n1 >= n2, // ge_s
         0x4f => (n1 as u32) >= (n2 as u32), // ge_u
         _ => false,
@@ -522,21 +1659,48 @@ fn i32_binop_bool(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(
     Ok(())
 }
 
+// i32 unop
+fn i32_unop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let n = stack.pop_i32()?;
+    let r: i32 = match inst.op_code {
+        0x67 => n.leading_zeros() as i32, // clz
+        0x68 => n.trailing_zeros() as i32, // ctz
+        0x69 => n.count_ones() as i32, // popcnt
+        0xc0 => n as i8 as i32, // extend8_s
+        0xc1 => n as i16 as i32, // extend16_s
+        _ => 0,
+    };
+    stack.push_i32(r);
+    frame.next();
+    Ok(())
+}
+
 // i32 binop
-fn i32_binop(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), String> {
+fn i32_binop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
     let n2 = stack.pop_i32()?;
     let n1 = stack.pop_i32()?;
+    if (0x6d..=0x70).contains(&inst.op_code) && n2 == 0 {
+        return Err("trap: integer divide by zero".to_string());
+    }
     let r: i32 = match inst.op_code {
-        0x6a => n1 + n2, // add
-        0x6b => n1 - n2, // sub
-        0x6c => n1 * n2, // mul
-        0x6d => n1 / n2, // div_s
+        0x6a => n1.wrapping_add(n2), // add
+        0x6b => n1.wrapping_sub(n2), // sub
+        0x6c => n1.wrapping_mul(n2), // mul
+        0x6d => match n1.checked_div(n2) { // div_s
+            Some(r) => r,
+            None => return Err("trap: integer overflow".to_string()),
+        },
         0x6e => ((n1 as u32) / (n2 as u32)) as i32, // div_u
-        0x6f => n1 % n2, // rem_s
+        0x6f => n1.wrapping_rem(n2), // rem_s
         0x70 => ((n1 as u32) % (n2 as u32)) as i32, // rem_u
         0x71 => n1 & n2, // and
         0x72 => n1 | n2, // or
         0x73 => n1 ^ n2, // xor
+        0x74 => n1.wrapping_shl(n2 as u32), // shl
+        0x75 => n1.wrapping_shr(n2 as u32), // shr_s
+        0x76 => (n1 as u32).wrapping_shr(n2 as u32) as i32, // shr_u
+        0x77 => n1.rotate_left(n2 as u32 % 32), // rotl
+        0x78 => n1.rotate_right(n2 as u32 % 32), // rotr
         _ => 0,
     };
     stack.push_i32(r);
@@ -544,16 +1708,487 @@ fn i32_binop(inst: &Inst, frame: &mut Frame, stack: &mut Stack) -> Result<(), St
     Ok(())
 }
 
-fn not_supported(inst: &Inst, _frame: &mut Frame, _stack: &mut Stack) -> Result<(), String> {
+// i64.const
+fn exec_42(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if let Operand::I64(n) = inst.operand {
+        stack.push_i64(n);
+    }
+    frame.next();
+    Ok(())
+}
+
+// i64.eqz
+fn exec_50(_inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let n = stack.pop_i64()?;
+    stack.push_i32((n == 0) as i32);
+    frame.next();
+    Ok(())
+}
+
+// i64 binop bool
+fn i64_binop_bool(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let n2 = stack.pop_i64()?;
+    let n1 = stack.pop_i64()?;
+    let r: bool = match inst.op_code {
+        0x51 => n1 == n2, // eq
+        0x52 => n1 != n2, // ne
+        0x53 => n1 < n2, // lt_s
+        0x54 => (n1 as u64) < (n2 as u64), // lt_u
+        0x55 => n1 > n2, // gt_s
+        0x56 => (n1 as u64) > (n2 as u64), // gt_u
+        0x57 => n1 <= n2, // le_s
+        0x58 => (n1 as u64) <= (n2 as u64), // le_u
+        0x59 => n1 >= n2, // ge_s
+        0x5a => (n1 as u64) >= (n2 as u64), // ge_u
+        _ => false,
+    };
+    stack.push_i32(r as i32);
+    frame.next();
+    Ok(())
+}
+
+// i64 unop
+fn i64_unop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let n = stack.pop_i64()?;
+    let r: i64 = match inst.op_code {
+        0x79 => n.leading_zeros() as i64, // clz
+        0x7a => n.trailing_zeros() as i64, // ctz
+        0x7b => n.count_ones() as i64, // popcnt
+        0xc2 => n as i8 as i64, // extend8_s
+        0xc3 => n as i16 as i64, // extend16_s
+        0xc4 => n as i32 as i64, // extend32_s
+        _ => 0,
+    };
+    stack.push_i64(r);
+    frame.next();
+    Ok(())
+}
+
+// i64 binop
+fn i64_binop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let n2 = stack.pop_i64()?;
+    let n1 = stack.pop_i64()?;
+    if (0x7f..=0x82).contains(&inst.op_code) && n2 == 0 {
+        return Err("trap: integer divide by zero".to_string());
+    }
+    let r: i64 = match inst.op_code {
+        0x7c => n1.wrapping_add(n2), // add
+        0x7d => n1.wrapping_sub(n2), // sub
+        0x7e => n1.wrapping_mul(n2), // mul
+        0x7f => match n1.checked_div(n2) { // div_s
+            Some(r) => r,
+            None => return Err("trap: integer overflow".to_string()),
+        },
+        0x80 => ((n1 as u64) / (n2 as u64)) as i64, // div_u
+        0x81 => n1.wrapping_rem(n2), // rem_s
+        0x82 => ((n1 as u64) % (n2 as u64)) as i64, // rem_u
+        0x83 => n1 & n2, // and
+        0x84 => n1 | n2, // or
+        0x85 => n1 ^ n2, // xor
+        0x86 => n1.wrapping_shl(n2 as u32), // shl
+        0x87 => n1.wrapping_shr(n2 as u32), // shr_s
+        0x88 => (n1 as u64).wrapping_shr(n2 as u32) as i64, // shr_u
+        0x89 => n1.rotate_left((n2 as u64 % 64) as u32), // rotl
+        0x8a => n1.rotate_right((n2 as u64 % 64) as u32), // rotr
+        _ => 0,
+    };
+    stack.push_i64(r);
+    frame.next();
+    Ok(())
+}
+
+// float min/max: NaN if either is NaN, and -0 < +0
+pub(crate) fn f32_min(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == 0.0 && b == 0.0 {
+        if a.is_sign_negative() { a } else { b }
+    } else {
+        a.min(b)
+    }
+}
+
+pub(crate) fn f32_max(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == 0.0 && b == 0.0 {
+        if a.is_sign_positive() { a } else { b }
+    } else {
+        a.max(b)
+    }
+}
+
+pub(crate) fn f64_min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == 0.0 && b == 0.0 {
+        if a.is_sign_negative() { a } else { b }
+    } else {
+        a.min(b)
+    }
+}
+
+pub(crate) fn f64_max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == 0.0 && b == 0.0 {
+        if a.is_sign_positive() { a } else { b }
+    } else {
+        a.max(b)
+    }
+}
+
+// f32.const
+fn exec_43(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if let Operand::F32(n) = inst.operand {
+        stack.push_f32(n);
+    }
+    frame.next();
+    Ok(())
+}
+
+// f32 binop bool
+fn f32_binop_bool(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let z2 = stack.pop_f32()?;
+    let z1 = stack.pop_f32()?;
+    let r: bool = match inst.op_code {
+        0x5b => z1 == z2, // eq
+        0x5c => z1 != z2, // ne
+        0x5d => z1 < z2, // lt
+        0x5e => z1 > z2, // gt
+        0x5f => z1 <= z2, // le
+        0x60 => z1 >= z2, // ge
+        _ => false,
+    };
+    stack.push_i32(r as i32);
+    frame.next();
+    Ok(())
+}
+
+// f32 unop
+fn f32_unop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let z = stack.pop_f32()?;
+    let r: f32 = match inst.op_code {
+        0x8b => z.abs(), // abs
+        0x8c => -z, // neg
+        0x8d => z.ceil(), // ceil
+        0x8e => z.floor(), // floor
+        0x8f => z.trunc(), // trunc
+        0x90 => z.round_ties_even(), // nearest
+        0x91 => z.sqrt(), // sqrt
+        _ => 0.0,
+    };
+    stack.push_f32(r);
+    frame.next();
+    Ok(())
+}
+
+// f32 binop
+fn f32_binop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let z2 = stack.pop_f32()?;
+    let z1 = stack.pop_f32()?;
+    let r: f32 = match inst.op_code {
+        0x92 => z1 + z2, // add
+        0x93 => z1 - z2, // sub
+        0x94 => z1 * z2, // mul
+        0x95 => z1 / z2, // div
+        0x96 => f32_min(z1, z2), // min
+        0x97 => f32_max(z1, z2), // max
+        0x98 => z1.copysign(z2), // copysign
+        _ => 0.0,
+    };
+    stack.push_f32(r);
+    frame.next();
+    Ok(())
+}
+
+// f64.const
+fn exec_44(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if let Operand::F64(n) = inst.operand {
+        stack.push_f64(n);
+    }
+    frame.next();
+    Ok(())
+}
+
+// f64 binop bool
+fn f64_binop_bool(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let z2 = stack.pop_f64()?;
+    let z1 = stack.pop_f64()?;
+    let r: bool = match inst.op_code {
+        0x61 => z1 == z2, // eq
+        0x62 => z1 != z2, // ne
+        0x63 => z1 < z2, // lt
+        0x64 => z1 > z2, // gt
+        0x65 => z1 <= z2, // le
+        0x66 => z1 >= z2, // ge
+        _ => false,
+    };
+    stack.push_i32(r as i32);
+    frame.next();
+    Ok(())
+}
+
+// f64 unop
+fn f64_unop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let z = stack.pop_f64()?;
+    let r: f64 = match inst.op_code {
+        0x99 => z.abs(), // abs
+        0x9a => -z, // neg
+        0x9b => z.ceil(), // ceil
+        0x9c => z.floor(), // floor
+        0x9d => z.trunc(), // trunc
+        0x9e => z.round_ties_even(), // nearest
+        0x9f => z.sqrt(), // sqrt
+        _ => 0.0,
+    };
+    stack.push_f64(r);
+    frame.next();
+    Ok(())
+}
+
+// f64 binop
+fn f64_binop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    let z2 = stack.pop_f64()?;
+    let z1 = stack.pop_f64()?;
+    let r: f64 = match inst.op_code {
+        0xa0 => z1 + z2, // add
+        0xa1 => z1 - z2, // sub
+        0xa2 => z1 * z2, // mul
+        0xa3 => z1 / z2, // div
+        0xa4 => f64_min(z1, z2), // min
+        0xa5 => f64_max(z1, z2), // max
+        0xa6 => z1.copysign(z2), // copysign
+        _ => 0.0,
+    };
+    stack.push_f64(r);
+    frame.next();
+    Ok(())
+}
+
+// truncate z for the conversion to an integer in range (lower, upper).
+// the bounds are exclusive and z (f32 or f64) is exact as f64.
+pub(crate) fn trunc_float(z: f64, lower: f64, upper: f64) -> Result<f64, String> {
+    if z.is_nan() {
+        return Err("trap: invalid conversion to integer".to_string());
+    }
+    if z <= lower || z >= upper {
+        return Err("trap: integer overflow".to_string());
+    }
+    Ok(z.trunc())
+}
+
+// (lower, upper) of trunc_float for i32_s, i32_u, i64_s, i64_u
+pub(crate) const I32_S_RANGE: (f64, f64) = (-2147483649.0, 2147483648.0);
+pub(crate) const I32_U_RANGE: (f64, f64) = (-1.0, 4294967296.0);
+pub(crate) const I64_S_RANGE: (f64, f64) = (-9223372036854777856.0, 9223372036854775808.0);
+pub(crate) const I64_U_RANGE: (f64, f64) = (-1.0, 18446744073709551616.0);
+
+// conversions
+fn cvtop(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    match inst.op_code {
+        0xa7 => { // i32.wrap_i64
+            let n = stack.pop_i64()?;
+            stack.push_i32(n as i32);
+        },
+        0xa8..=0xab => { // i32.trunc_f32_s, i32.trunc_f32_u, i32.trunc_f64_s, i32.trunc_f64_u
+            let z = if inst.op_code < 0xaa { stack.pop_f32()? as f64 } else { stack.pop_f64()? };
+            if inst.op_code & 1 == 0 { // _s
+                let t = trunc_float(z, I32_S_RANGE.0, I32_S_RANGE.1)?;
+                stack.push_i32(t as i32);
+            } else {
+                let t = trunc_float(z, I32_U_RANGE.0, I32_U_RANGE.1)?;
+                stack.push_i32(t as u32 as i32);
+            }
+        },
+        0xac => { // i64.extend_i32_s
+            let n = stack.pop_i32()?;
+            stack.push_i64(n as i64);
+        },
+        0xad => { // i64.extend_i32_u
+            let n = stack.pop_i32()?;
+            stack.push_i64(n as u32 as i64);
+        },
+        0xae..=0xb1 => { // i64.trunc_f32_s, i64.trunc_f32_u, i64.trunc_f64_s, i64.trunc_f64_u
+            let z = if inst.op_code < 0xb0 { stack.pop_f32()? as f64 } else { stack.pop_f64()? };
+            if inst.op_code & 1 == 0 { // _s
+                let t = trunc_float(z, I64_S_RANGE.0, I64_S_RANGE.1)?;
+                stack.push_i64(t as i64);
+            } else {
+                let t = trunc_float(z, I64_U_RANGE.0, I64_U_RANGE.1)?;
+                stack.push_i64(t as u64 as i64);
+            }
+        },
+        0xb2 => { // f32.convert_i32_s
+            let n = stack.pop_i32()?;
+            stack.push_f32(n as f32);
+        },
+        0xb3 => { // f32.convert_i32_u
+            let n = stack.pop_i32()?;
+            stack.push_f32(n as u32 as f32);
+        },
+        0xb4 => { // f32.convert_i64_s
+            let n = stack.pop_i64()?;
+            stack.push_f32(n as f32);
+        },
+        0xb5 => { // f32.convert_i64_u
+            let n = stack.pop_i64()?;
+            stack.push_f32(n as u64 as f32);
+        },
+        0xb6 => { // f32.demote_f64
+            let z = stack.pop_f64()?;
+            stack.push_f32(z as f32);
+        },
+        0xb7 => { // f64.convert_i32_s
+            let n = stack.pop_i32()?;
+            stack.push_f64(n as f64);
+        },
+        0xb8 => { // f64.convert_i32_u
+            let n = stack.pop_i32()?;
+            stack.push_f64(n as u32 as f64);
+        },
+        0xb9 => { // f64.convert_i64_s
+            let n = stack.pop_i64()?;
+            stack.push_f64(n as f64);
+        },
+        0xba => { // f64.convert_i64_u
+            let n = stack.pop_i64()?;
+            stack.push_f64(n as u64 as f64);
+        },
+        0xbb => { // f64.promote_f32
+            let z = stack.pop_f32()?;
+            stack.push_f64(z as f64);
+        },
+        0xbc => { // i32.reinterpret_f32
+            let z = stack.pop_f32()?;
+            stack.push_i32(z.to_bits() as i32);
+        },
+        0xbd => { // i64.reinterpret_f64
+            let z = stack.pop_f64()?;
+            stack.push_i64(z.to_bits() as i64);
+        },
+        0xbe => { // f32.reinterpret_i32
+            let n = stack.pop_i32()?;
+            stack.push_f32(f32::from_bits(n as u32));
+        },
+        0xbf => { // f64.reinterpret_i64
+            let n = stack.pop_i64()?;
+            stack.push_f64(f64::from_bits(n as u64));
+        },
+        _ => return not_supported(inst, frame, stack, store),
+    }
+    frame.next();
+    Ok(())
+}
+
+// saturating truncation (0xfc 0-7). "as" saturates and maps NaN to 0.
+fn trunc_sat(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    let z = if inst.sub_op & 2 == 0 { stack.pop_f32()? as f64 } else { stack.pop_f64()? };
+    match inst.sub_op {
+        0 | 2 => stack.push_i32(z as i32),
+        1 | 3 => stack.push_i32(z as u32 as i32),
+        4 | 6 => stack.push_i64(z as i64),
+        5 | 7 => stack.push_i64(z as u64 as i64),
+        _ => return not_supported(inst, frame, stack, store),
+    }
+    frame.next();
+    Ok(())
+}
+
+// 0xfc prefix
+fn exec_fc(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    match EXEC_TABLE_FC.get(inst.sub_op as usize) {
+        Some(e_fn) => e_fn(inst, frame, stack, store),
+        None => not_supported(inst, frame, stack, store),
+    }
+}
+
+// 0xfd prefix (SIMD). the lane-wise operations are in simd.rs
+fn exec_fd(inst: &Inst, frame: &mut Frame, stack: &mut Stack, store: &mut Store) -> Result<(), String> {
+    let op = inst.sub_op;
+    match (op, &inst.operand) {
+        (0x0b, Operand::Memarg(m)) => { // v128.store
+            let v = stack.pop_v128()?;
+            store.store(stack.pop_i32()?, m.offset, &v.to_le_bytes())?;
+        },
+        (_, Operand::Memarg(m)) => { // v128.load*
+            let n = 1 << natural_align_fd(op);
+            let ea = store.mem_addr(stack.pop_i32()?, m.offset, n)?;
+            stack.push_v128(simd::load(op, &store.memory()[ea..ea + n]));
+        },
+        (0x54..=0x57, Operand::MemargLane(m, lane)) => { // v128.load*_lane
+            let mut bytes = stack.pop_v128()?.to_le_bytes();
+            let n = 1 << natural_align_fd(op);
+            let ea = store.mem_addr(stack.pop_i32()?, m.offset, n)?;
+            let i = *lane as usize * n;
+            bytes[i..i + n].copy_from_slice(&store.memory()[ea..ea + n]);
+            stack.push_v128(u128::from_le_bytes(bytes));
+        },
+        (_, Operand::MemargLane(m, lane)) => { // v128.store*_lane
+            let bytes = stack.pop_v128()?.to_le_bytes();
+            let n = 1 << natural_align_fd(op);
+            let i = *lane as usize * n;
+            store.store(stack.pop_i32()?, m.offset, &bytes[i..i + n])?;
+        },
+        (_, Operand::V128(v)) => stack.push_v128(*v),
+        (_, Operand::Shuffle(lanes)) => {
+            let b = stack.pop_v128()?;
+            let a = stack.pop_v128()?;
+            stack.push_v128(simd::shuffle(a, b, lanes));
+        },
+        (0x0f..=0x14, _) => { // splat
+            let x = stack.pop();
+            stack.push_v128(simd::splat(op, &x));
+        },
+        (0x17 | 0x1a | 0x1c | 0x1e | 0x20 | 0x22, Operand::Lane(lane)) => { // replace_lane
+            let x = stack.pop();
+            let a = stack.pop_v128()?;
+            stack.push_v128(simd::replace_lane(op, a, *lane, &x));
+        },
+        (_, Operand::Lane(lane)) => { // extract_lane
+            let a = stack.pop_v128()?;
+            stack.stack.push(simd::extract_lane(op, a, *lane));
+        },
+        (0x53 | 0x63 | 0x64 | 0x83 | 0x84 | 0xa3 | 0xa4 | 0xc3 | 0xc4, _) => {
+            let a = stack.pop_v128()?;
+            stack.push_i32(simd::test(op, a));
+        },
+        (0x6b..=0x6d | 0x8b..=0x8d | 0xab..=0xad | 0xcb..=0xcd, _) => { // shifts
+            let n = stack.pop_i32()?;
+            let a = stack.pop_v128()?;
+            stack.push_v128(simd::shift(op, a, n));
+        },
+        _ => {
+            let num_params = simd::signature(op).ok_or("unknown SIMD instruction")?.0.len();
+            let c = stack.pop_v128()?;
+            let v = match num_params {
+                1 => simd::unop(op, c),
+                2 => simd::binop(op, stack.pop_v128()?, c),
+                _ => {
+                    let b = stack.pop_v128()?;
+                    simd::ternop(op, stack.pop_v128()?, b, c)
+                },
+            };
+            stack.push_v128(v);
+        },
+    }
+    frame.next();
+    Ok(())
+}
+
+fn not_supported(inst: &Inst, _frame: &mut Frame, _stack: &mut Stack, _store: &mut Store) -> Result<(), String> {
+    if inst.op_code == 0xfc || inst.op_code == 0xfd {
+        return Err(format!("trap: op({:#02x} {}) not supported yet", inst.op_code, inst.sub_op));
+    }
     Err(format!("trap: op({:#02x}) not supported yet", inst.op_code))
 }
 
-type ExecInst = fn(&Inst, &mut Frame, &mut Stack) -> Result<(), String>;
+type ExecInst = fn(&Inst, &mut Frame, &mut Stack, &mut Store) -> Result<(), String>;
 const EXEC_TABLE: [ExecInst; 256] = [
 /*0x00*/ not_supported, // "unreachale"
-/*0x01*/ not_supported, // "nop"
+/*0x01*/ exec_01, // "nop"
 /*0x02*/ not_supported, // "block"
-/*0x03*/ exec_03, // "loop
+/*0x03*/ not_supported, // "loop
 /*0x04*/ not_supported, // "if"
 /*0x05*/ not_supported, // "else"
 /*0x06*/ not_supported, // REVERVED
@@ -561,11 +2196,11 @@ const EXEC_TABLE: [ExecInst; 256] = [
 /*0x08*/ not_supported, // REVERVED
 /*0x09*/ not_supported, // REVERVED
 /*0x0a*/ not_supported, // REVERVED
-/*0x0b*/ exec_0b, // "end"
-/*0x0c*/ exec_0c, // "br"
+/*0x0b*/ not_supported, // "end"
+/*0x0c*/ not_supported, // "br"
 /*0x0d*/ not_supported, // "br_if"
 /*0x0e*/ not_supported, // "br_table"
-/*0x0f*/ not_supported, // "return"
+/*0x0f*/ exec_0f, // "return"
 /*0x10*/ not_supported, // "call"
 /*0x11*/ not_supported, // "call_indirect"
 /*0x12*/ not_supported, // REVERVED
@@ -576,49 +2211,49 @@ const EXEC_TABLE: [ExecInst; 256] = [
 /*0x17*/ not_supported, // REVERVED
 /*0x18*/ not_supported, // REVERVED
 /*0x19*/ not_supported, // REVERVED
-/*0x1a*/ not_supported, // "drop"
-/*0x1b*/ not_supported, // "select"
-/*0x1c*/ not_supported, // "select"
+/*0x1a*/ exec_1a, // "drop"
+/*0x1b*/ exec_1b, // "select"
+/*0x1c*/ exec_1b, // "select"
 /*0x1d*/ not_supported, // REVERVED
 /*0x1e*/ not_supported, // REVERVED
 /*0x1f*/ not_supported, // REVERVED
 /*0x20*/ exec_20, // "local.get"
 /*0x21*/ exec_21, // "local.set"
 /*0x22*/ exec_22, // "local.tee"
-/*0x23*/ not_supported, // "global.get"
-/*0x24*/ not_supported, // "global.set"
-/*0x25*/ not_supported, // "table.get"
-/*0x26*/ not_supported, // "table.set"
+/*0x23*/ exec_23, // "global.get"
+/*0x24*/ exec_24, // "global.set"
+/*0x25*/ exec_25, // "table.get"
+/*0x26*/ exec_26, // "table.set"
 /*0x27*/ not_supported, // REVERVED
-/*0x28*/ not_supported, // "i32.load"
-/*0x29*/ not_supported, // "i64.load"
-/*0x2a*/ not_supported, // "f32.load"
-/*0x2b*/ not_supported, // "f64.load"
-/*0x2c*/ not_supported, // "i32.load8_s"
-/*0x2d*/ not_supported, // "i32.load8_u"
-/*0x2e*/ not_supported, // "i32.load16_s"
-/*0x2f*/ not_supported, // "i32.load16_u"
-/*0x30*/ not_supported, // "i64.load8_s"
-/*0x31*/ not_supported, // "i64.load8_u"
-/*0x32*/ not_supported, // "i64.load16_s"
-/*0x33*/ not_supported, // "i64.load16_u"
-/*0x34*/ not_supported, // "i64.load32_s"
-/*0x35*/ not_supported, // "i64.load32_u"
-/*0x36*/ not_supported, // "i32.store"
-/*0x37*/ not_supported, // "i64.store"
-/*0x38*/ not_supported, // "f32.store"
-/*0x39*/ not_supported, // "f64.store"
-/*0x3a*/ not_supported, // "i32.store8"
-/*0x3b*/ not_supported, // "i32.store16"
-/*0x3c*/ not_supported, // "i64.store8"
-/*0x3d*/ not_supported, // "i64.store16"
-/*0x3e*/ not_supported, // "i64.store32"
-/*0x3f*/ not_supported, // "memory.size"
-/*0x40*/ not_supported, // "memory.grow"
+/*0x28*/ mem_load, // "i32.load"
+/*0x29*/ mem_load, // "i64.load"
+/*0x2a*/ mem_load, // "f32.load"
+/*0x2b*/ mem_load, // "f64.load"
+/*0x2c*/ mem_load, // "i32.load8_s"
+/*0x2d*/ mem_load, // "i32.load8_u"
+/*0x2e*/ mem_load, // "i32.load16_s"
+/*0x2f*/ mem_load, // "i32.load16_u"
+/*0x30*/ mem_load, // "i64.load8_s"
+/*0x31*/ mem_load, // "i64.load8_u"
+/*0x32*/ mem_load, // "i64.load16_s"
+/*0x33*/ mem_load, // "i64.load16_u"
+/*0x34*/ mem_load, // "i64.load32_s"
+/*0x35*/ mem_load, // "i64.load32_u"
+/*0x36*/ mem_store, // "i32.store"
+/*0x37*/ mem_store, // "i64.store"
+/*0x38*/ mem_store, // "f32.store"
+/*0x39*/ mem_store, // "f64.store"
+/*0x3a*/ mem_store, // "i32.store8"
+/*0x3b*/ mem_store, // "i32.store16"
+/*0x3c*/ mem_store, // "i64.store8"
+/*0x3d*/ mem_store, // "i64.store16"
+/*0x3e*/ mem_store, // "i64.store32"
+/*0x3f*/ exec_3f, // "memory.size"
+/*0x40*/ exec_40, // "memory.grow"
 /*0x41*/ exec_41, // "i32.const"
-/*0x42*/ not_supported, // "i64.const"
-/*0x43*/ not_supported, // "f32.const"
-/*0x44*/ not_supported, // "f64.const"
+/*0x42*/ exec_42, // "i64.const"
+/*0x43*/ exec_43, // "f32.const"
+/*0x44*/ exec_44, // "f64.const"
 /*0x45*/ exec_45, // "i32.eqz"
 /*0x46*/ i32_binop_bool, // "i32.eq"
 /*0x47*/ i32_binop_bool, // "i32.ne"
@@ -630,32 +2265,32 @@ const EXEC_TABLE: [ExecInst; 256] = [
 /*0x4d*/ i32_binop_bool, // "i32.le_u"
 /*0x4e*/ i32_binop_bool, // "i32.ge_s"
 /*0x4f*/ i32_binop_bool, // "i32.ge_u"
-/*0x50*/ not_supported, // "i64.eqz"
-/*0x51*/ not_supported, // "i64.eq"
-/*0x52*/ not_supported, // "i64.ne"
-/*0x53*/ not_supported, // "i64.lt_s"
-/*0x54*/ not_supported, // "i64.lt_u"
-/*0x55*/ not_supported, // "i64.gt_s"
-/*0x56*/ not_supported, // "i64.gt_u"
-/*0x57*/ not_supported, // "i64.le_s"
-/*0x58*/ not_supported, // "i64.le_u"
-/*0x59*/ not_supported, // "i64.ge_s"
-/*0x5a*/ not_supported, // "i64.ge_u"
-/*0x5b*/ not_supported, // "f32.eq"
-/*0x5c*/ not_supported, // "f32.ne"
-/*0x5d*/ not_supported, // "f32.lt"
-/*0x5e*/ not_supported, // "f32.gt"
-/*0x5f*/ not_supported, // "f32.le"
-/*0x60*/ not_supported, // "f32.ge"
-/*0x61*/ not_supported, // "f64.eq"
-/*0x62*/ not_supported, // "f64.ne"
-/*0x63*/ not_supported, // "f64.lt"
-/*0x64*/ not_supported, // "f64.gt"
-/*0x65*/ not_supported, // "f64.le"
-/*0x66*/ not_supported, // "f64.ge"
-/*0x67*/ not_supported, // "i32.clz"
-/*0x68*/ not_supported, // "i32.ctz"
-/*0x69*/ not_supported, // "i32.popcnt"
+/*0x50*/ exec_50, // "i64.eqz"
+/*0x51*/ i64_binop_bool, // "i64.eq"
+/*0x52*/ i64_binop_bool, // "i64.ne"
+/*0x53*/ i64_binop_bool, // "i64.lt_s"
+/*0x54*/ i64_binop_bool, // "i64.lt_u"
+/*0x55*/ i64_binop_bool, // "i64.gt_s"
+/*0x56*/ i64_binop_bool, // "i64.gt_u"
+/*0x57*/ i64_binop_bool, // "i64.le_s"
+/*0x58*/ i64_binop_bool, // "i64.le_u"
+/*0x59*/ i64_binop_bool, // "i64.ge_s"
+/*0x5a*/ i64_binop_bool, // "i64.ge_u"
+/*0x5b*/ f32_binop_bool, // "f32.eq"
+/*0x5c*/ f32_binop_bool, // "f32.ne"
+/*0x5d*/ f32_binop_bool, // "f32.lt"
+/*0x5e*/ f32_binop_bool, // "f32.gt"
+/*0x5f*/ f32_binop_bool, // "f32.le"
+/*0x60*/ f32_binop_bool, // "f32.ge"
+/*0x61*/ f64_binop_bool, // "f64.eq"
+/*0x62*/ f64_binop_bool, // "f64.ne"
+/*0x63*/ f64_binop_bool, // "f64.lt"
+/*0x64*/ f64_binop_bool, // "f64.gt"
+/*0x65*/ f64_binop_bool, // "f64.le"
+/*0x66*/ f64_binop_bool, // "f64.ge"
+/*0x67*/ i32_unop, // "i32.clz"
+/*0x68*/ i32_unop, // "i32.ctz"
+/*0x69*/ i32_unop, // "i32.popcnt"
 /*0x6a*/ i32_binop, // "i32.add"
 /*0x6b*/ i32_binop, // "i32.sub"
 /*0x6c*/ i32_binop, // "i32.mul"
@@ -666,87 +2301,87 @@ const EXEC_TABLE: [ExecInst; 256] = [
 /*0x71*/ i32_binop, // "i32.and"
 /*0x72*/ i32_binop, // "i32.or"
 /*0x73*/ i32_binop, // "i32.xor"
-/*0x74*/ not_supported, // "i32.shl"
-/*0x75*/ not_supported, // "i32.shr_s"
-/*0x76*/ not_supported, // "i32.shr_u"
-/*0x77*/ not_supported, // "i32.rotl"
-/*0x78*/ not_supported, // "i32.rotr"
-/*0x79*/ not_supported, // "i64.clz"
-/*0x7a*/ not_supported, // "i64.ctz"
-/*0x7b*/ not_supported, // "i64.popcnt"
-/*0x7c*/ not_supported, // "i64.add"
-/*0x7d*/ not_supported, // "i64.sub"
-/*0x7e*/ not_supported, // "i64.mul"
-/*0x7f*/ not_supported, // "i64.div_s"
-/*0x80*/ not_supported, // "i64.div_u"
-/*0x81*/ not_supported, // "i64.rem_s"
-/*0x82*/ not_supported, // "i64.rem_u"
-/*0x83*/ not_supported, // "i64.and"
-/*0x84*/ not_supported, // "i64.or"
-/*0x85*/ not_supported, // "i64.xor"
-/*0x86*/ not_supported, // "i64.shl"
-/*0x87*/ not_supported, // "i64.shr_s"
-/*0x88*/ not_supported, // "i64.shr_u"
-/*0x89*/ not_supported, // "i64.rotl"
-/*0x8a*/ not_supported, // "i64.rotr"
-/*0x8b*/ not_supported, // "f32.abs"
-/*0x8c*/ not_supported, // "f32.neg"
-/*0x8d*/ not_supported, // "f32.ceil"
-/*0x8e*/ not_supported, // "f32.floor"
-/*0x8f*/ not_supported, // "f32.trunc"
-/*0x90*/ not_supported, // "f32.nearest"
-/*0x91*/ not_supported, // "f32.sqrt"
-/*0x92*/ not_supported, // "f32.add"
-/*0x93*/ not_supported, // "f32.sub"
-/*0x94*/ not_supported, // "f32.mul"
-/*0x95*/ not_supported, // "f32.div"
-/*0x96*/ not_supported, // "f32.min"
-/*0x97*/ not_supported, // "f32.max"
-/*0x98*/ not_supported, // "f32.copysign"
-/*0x99*/ not_supported, // "f64.abs"
-/*0x9a*/ not_supported, // "f64.neg"
-/*0x9b*/ not_supported, // "f64.ceil"
-/*0x9c*/ not_supported, // "f64.floor"
-/*0x9d*/ not_supported, // "f64.trunc"
-/*0x9e*/ not_supported, // "f64.nearest"
-/*0x9f*/ not_supported, // "f64.sqrt"
-/*0xa0*/ not_supported, // "f64.add"
-/*0xa1*/ not_supported, // "f64.sub"
-/*0xa2*/ not_supported, // "f64.mul"
-/*0xa3*/ not_supported, // "f64.div"
-/*0xa4*/ not_supported, // "f64.min"
-/*0xa5*/ not_supported, // "f64.max"
-/*0xa6*/ not_supported, // "f64.copysign"
-/*0xa7*/ not_supported, // "i32.wrap_i64"
-/*0xa8*/ not_supported, // "i32.trunc_f32_s"
-/*0xa9*/ not_supported, // "i32.trunc_f32_u"
-/*0xaa*/ not_supported, // "i32.trunc_f64_s"
-/*0xab*/ not_supported, // "i32.trunc_f64_u"
-/*0xac*/ not_supported, // "i64.extend_i32_s"
-/*0xad*/ not_supported, // "i64.extend_i32_u"
-/*0xae*/ not_supported, // "i64.trunc_f32_s"
-/*0xaf*/ not_supported, // "i64.trunc_f32_u"
-/*0xb0*/ not_supported, // "i64.trunc_f64_s"
-/*0xb1*/ not_supported, // "i64.trunc_f64_u"
-/*0xb2*/ not_supported, // "f32.convert_i32_s"
-/*0xb3*/ not_supported, // "f32.convert_i32_u"
-/*0xb4*/ not_supported, // "f32.convert_i64_s"
-/*0xb5*/ not_supported, // "f32.convert_i64_u"
-/*0xb6*/ not_supported, // "f32.demote_f64"
-/*0xb7*/ not_supported, // "f64.convert_i32_s"
-/*0xb8*/ not_supported, // "f64.convert_i32_u"
-/*0xb9*/ not_supported, // "f64.convert_i64_s"
-/*0xba*/ not_supported, // "f64.convert_i64_u"
-/*0xbb*/ not_supported, // "f64.promote_f32"
-/*0xbc*/ not_supported, // "i32.reinterpret_f32"
-/*0xbd*/ not_supported, // "i64.reinterpret_f64"
-/*0xbe*/ not_supported, // "f32.reinterpret_i32"
-/*0xbf*/ not_supported, // "f64.reinterpret_i64"
-/*0xc0*/ not_supported, // "i32.extend8_s"
-/*0xc1*/ not_supported, // "i32.extend16_s"
-/*0xc2*/ not_supported, // "i64.extend8_s"
-/*0xc3*/ not_supported, // "i64.extend16_s"
-/*0xc4*/ not_supported, // "i64.extend32_s"
+/*0x74*/ i32_binop, // "i32.shl"
+/*0x75*/ i32_binop, // "i32.shr_s"
+/*0x76*/ i32_binop, // "i32.shr_u"
+/*0x77*/ i32_binop, // "i32.rotl"
+/*0x78*/ i32_binop, // "i32.rotr"
+/*0x79*/ i64_unop, // "i64.clz"
+/*0x7a*/ i64_unop, // "i64.ctz"
+/*0x7b*/ i64_unop, // "i64.popcnt"
+/*0x7c*/ i64_binop, // "i64.add"
+/*0x7d*/ i64_binop, // "i64.sub"
+/*0x7e*/ i64_binop, // "i64.mul"
+/*0x7f*/ i64_binop, // "i64.div_s"
+/*0x80*/ i64_binop, // "i64.div_u"
+/*0x81*/ i64_binop, // "i64.rem_s"
+/*0x82*/ i64_binop, // "i64.rem_u"
+/*0x83*/ i64_binop, // "i64.and"
+/*0x84*/ i64_binop, // "i64.or"
+/*0x85*/ i64_binop, // "i64.xor"
+/*0x86*/ i64_binop, // "i64.shl"
+/*0x87*/ i64_binop, // "i64.shr_s"
+/*0x88*/ i64_binop, // "i64.shr_u"
+/*0x89*/ i64_binop, // "i64.rotl"
+/*0x8a*/ i64_binop, // "i64.rotr"
+/*0x8b*/ f32_unop, // "f32.abs"
+/*0x8c*/ f32_unop, // "f32.neg"
+/*0x8d*/ f32_unop, // "f32.ceil"
+/*0x8e*/ f32_unop, // "f32.floor"
+/*0x8f*/ f32_unop, // "f32.trunc"
+/*0x90*/ f32_unop, // "f32.nearest"
+/*0x91*/ f32_unop, // "f32.sqrt"
+/*0x92*/ f32_binop, // "f32.add"
+/*0x93*/ f32_binop, // "f32.sub"
+/*0x94*/ f32_binop, // "f32.mul"
+/*0x95*/ f32_binop, // "f32.div"
+/*0x96*/ f32_binop, // "f32.min"
+/*0x97*/ f32_binop, // "f32.max"
+/*0x98*/ f32_binop, // "f32.copysign"
+/*0x99*/ f64_unop, // "f64.abs"
+/*0x9a*/ f64_unop, // "f64.neg"
+/*0x9b*/ f64_unop, // "f64.ceil"
+/*0x9c*/ f64_unop, // "f64.floor"
+/*0x9d*/ f64_unop, // "f64.trunc"
+/*0x9e*/ f64_unop, // "f64.nearest"
+/*0x9f*/ f64_unop, // "f64.sqrt"
+/*0xa0*/ f64_binop, // "f64.add"
+/*0xa1*/ f64_binop, // "f64.sub"
+/*0xa2*/ f64_binop, // "f64.mul"
+/*0xa3*/ f64_binop, // "f64.div"
+/*0xa4*/ f64_binop, // "f64.min"
+/*0xa5*/ f64_binop, // "f64.max"
+/*0xa6*/ f64_binop, // "f64.copysign"
+/*0xa7*/ cvtop, // "i32.wrap_i64"
+/*0xa8*/ cvtop, // "i32.trunc_f32_s"
+/*0xa9*/ cvtop, // "i32.trunc_f32_u"
+/*0xaa*/ cvtop, // "i32.trunc_f64_s"
+/*0xab*/ cvtop, // "i32.trunc_f64_u"
+/*0xac*/ cvtop, // "i64.extend_i32_s"
+/*0xad*/ cvtop, // "i64.extend_i32_u"
+/*0xae*/ cvtop, // "i64.trunc_f32_s"
+/*0xaf*/ cvtop, // "i64.trunc_f32_u"
+/*0xb0*/ cvtop, // "i64.trunc_f64_s"
+/*0xb1*/ cvtop, // "i64.trunc_f64_u"
+/*0xb2*/ cvtop, // "f32.convert_i32_s"
+/*0xb3*/ cvtop, // "f32.convert_i32_u"
+/*0xb4*/ cvtop, // "f32.convert_i64_s"
+/*0xb5*/ cvtop, // "f32.convert_i64_u"
+/*0xb6*/ cvtop, // "f32.demote_f64"
+/*0xb7*/ cvtop, // "f64.convert_i32_s"
+/*0xb8*/ cvtop, // "f64.convert_i32_u"
+/*0xb9*/ cvtop, // "f64.convert_i64_s"
+/*0xba*/ cvtop, // "f64.convert_i64_u"
+/*0xbb*/ cvtop, // "f64.promote_f32"
+/*0xbc*/ cvtop, // "i32.reinterpret_f32"
+/*0xbd*/ cvtop, // "i64.reinterpret_f64"
+/*0xbe*/ cvtop, // "f32.reinterpret_i32"
+/*0xbf*/ cvtop, // "f64.reinterpret_i64"
+/*0xc0*/ i32_unop, // "i32.extend8_s"
+/*0xc1*/ i32_unop, // "i32.extend16_s"
+/*0xc2*/ i64_unop, // "i64.extend8_s"
+/*0xc3*/ i64_unop, // "i64.extend16_s"
+/*0xc4*/ i64_unop, // "i64.extend32_s"
 /*0xc5*/ not_supported, // REVERVED
 /*0xc6*/ not_supported, // REVERVED
 /*0xc7*/ not_supported, // REVERVED
@@ -758,9 +2393,9 @@ const EXEC_TABLE: [ExecInst; 256] = [
 /*0xcd*/ not_supported, // REVERVED
 /*0xce*/ not_supported, // REVERVED
 /*0xcf*/ not_supported, // REVERVED
-/*0xd0*/ not_supported, // "ref.null"
-/*0xd1*/ not_supported, // "ref.is_null"
-/*0xd2*/ not_supported, // "ref.func"
+/*0xd0*/ exec_d0, // "ref.null"
+/*0xd1*/ exec_d1, // "ref.is_null"
+/*0xd2*/ exec_d2, // "ref.func"
 /*0xd3*/ not_supported, // REVERVED
 /*0xd4*/ not_supported, // REVERVED
 /*0xd5*/ not_supported, // REVERVED
@@ -802,8 +2437,448 @@ const EXEC_TABLE: [ExecInst; 256] = [
 /*0xf9*/ not_supported, // REVERVED
 /*0xfa*/ not_supported, // REVERVED
 /*0xfb*/ not_supported, // REVERVED
-/*0xfc*/ not_supported, // see another table
-/*0xfd*/ not_supported, // see another table
+/*0xfc*/ exec_fc, // see EXEC_TABLE_FC
+/*0xfd*/ exec_fd, // SIMD
 /*0xfe*/ not_supported, // not defined
 /*0xff*/ not_supported, // not defined
 ];
+
+const EXEC_TABLE_FC: [ExecInst; 18] = [
+/*0*/ trunc_sat, // "i32.trunc_sat_f32_s"
+/*1*/ trunc_sat, // "i32.trunc_sat_f32_u"
+/*2*/ trunc_sat, // "i32.trunc_sat_f64_s"
+/*3*/ trunc_sat, // "i32.trunc_sat_f64_u"
+/*4*/ trunc_sat, // "i64.trunc_sat_f32_s"
+/*5*/ trunc_sat, // "i64.trunc_sat_f32_u"
+/*6*/ trunc_sat, // "i64.trunc_sat_f64_s"
+/*7*/ trunc_sat, // "i64.trunc_sat_f64_u"
+/*8*/ memory_init, // "memory.init"
+/*9*/ data_drop, // "data.drop"
+/*10*/ memory_copy, // "memory.copy"
+/*11*/ memory_fill, // "memory.fill"
+/*12*/ table_init, // "table.init"
+/*13*/ elem_drop, // "elem.drop"
+/*14*/ table_copy, // "table.copy"
+/*15*/ table_grow, // "table.grow"
+/*16*/ table_size, // "table.size"
+/*17*/ table_fill, // "table.fill"
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    const SRC: &str = r#"
+      (func (export "i32.div_s") (param i32 i32) (result i32) (i32.div_s (local.get 0) (local.get 1)))
+      (func (export "i32.div_u") (param i32 i32) (result i32) (i32.div_u (local.get 0) (local.get 1)))
+      (func (export "i32.rem_s") (param i32 i32) (result i32) (i32.rem_s (local.get 0) (local.get 1)))
+      (func (export "i64.div_s") (param i64 i64) (result i64) (i64.div_s (local.get 0) (local.get 1)))
+      (func (export "i64.rem_s") (param i64 i64) (result i64) (i64.rem_s (local.get 0) (local.get 1)))
+      (func (export "i32.le_u") (param i32 i32) (result i32) (i32.le_u (local.get 0) (local.get 1)))
+      (func (export "i32.add") (param i32 i32) (result i32) (i32.add (local.get 0) (local.get 1)))
+      (func (export "i32.trunc_f32_s") (param f32) (result i32) (i32.trunc_f32_s (local.get 0)))
+      (func (export "i32.trunc_f64_u") (param f64) (result i32) (i32.trunc_f64_u (local.get 0)))
+      (func (export "i64.trunc_f64_s") (param f64) (result i64) (i64.trunc_f64_s (local.get 0)))
+      (func (export "i32.trunc_sat_f32_s") (param f32) (result i32) (i32.trunc_sat_f32_s (local.get 0)))
+      (func (export "f32.min") (param f32 f32) (result f32) (f32.min (local.get 0) (local.get 1)))
+      (func (export "f32.max") (param f32 f32) (result f32) (f32.max (local.get 0) (local.get 1)))
+      (func (export "f64.min") (param f64 f64) (result f64) (f64.min (local.get 0) (local.get 1)))
+      (func (export "f64.max") (param f64 f64) (result f64) (f64.max (local.get 0) (local.get 1)))
+      (func (export "f32.nearest") (param f32) (result f32) (f32.nearest (local.get 0)))"#;
+
+    fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+        testutil::call(&testutil::load(SRC), name, args, None, &invoke).0.map(|r| r[0].clone())
+    }
+
+    fn check(name: &str, args: &[Value], expect: Value) {
+        assert_eq!(call(name, args), Ok(expect), "{}", name);
+    }
+
+    // call name of a module made of fields
+    fn run(fields: &str, name: &str, args: &[Value]) -> Result<Vec<Value>, String> {
+        testutil::call(&testutil::load(fields), name, args, None, &invoke).0
+    }
+
+    #[test]
+    fn integer_division() {
+        let e = call("i32.div_s", &[Value::I32(1), Value::I32(0)]).unwrap_err();
+        assert_eq!(e, "trap: integer divide by zero");
+        let e = call("i32.div_u", &[Value::I32(1), Value::I32(0)]).unwrap_err();
+        assert_eq!(e, "trap: integer divide by zero");
+        let e = call("i32.div_s", &[Value::I32(i32::MIN), Value::I32(-1)]).unwrap_err();
+        assert_eq!(e, "trap: integer overflow");
+        let e = call("i64.div_s", &[Value::I64(i64::MIN), Value::I64(-1)]).unwrap_err();
+        assert_eq!(e, "trap: integer overflow");
+        // rem_s of MIN by -1 is 0, not a trap
+        check("i32.rem_s", &[Value::I32(i32::MIN), Value::I32(-1)], Value::I32(0));
+        check("i64.rem_s", &[Value::I64(i64::MIN), Value::I64(-1)], Value::I64(0));
+        check("i32.rem_s", &[Value::I32(-7), Value::I32(2)], Value::I32(-1));
+        check("i32.div_u", &[Value::I32(-1), Value::I32(2)], Value::I32(0x7fffffff));
+    }
+
+    #[test]
+    fn integer_wrap_and_compare() {
+        check("i32.add", &[Value::I32(i32::MAX), Value::I32(1)], Value::I32(i32::MIN));
+        check("i32.le_u", &[Value::I32(2), Value::I32(1)], Value::I32(0));
+        check("i32.le_u", &[Value::I32(1), Value::I32(-1)], Value::I32(1));
+    }
+
+    #[test]
+    fn float_truncation() {
+        let e = call("i32.trunc_f32_s", &[Value::F32(f32::NAN)]).unwrap_err();
+        assert_eq!(e, "trap: invalid conversion to integer");
+        let e = call("i32.trunc_f32_s", &[Value::F32(2147483648.0)]).unwrap_err();
+        assert_eq!(e, "trap: integer overflow");
+        check("i32.trunc_f32_s", &[Value::F32(-2147483648.0)], Value::I32(i32::MIN));
+        check("i32.trunc_f64_u", &[Value::F64(-0.9)], Value::I32(0));
+        let e = call("i32.trunc_f64_u", &[Value::F64(-1.0)]).unwrap_err();
+        assert_eq!(e, "trap: integer overflow");
+        check("i32.trunc_f64_u", &[Value::F64(4294967295.9)], Value::I32(-1));
+        let e = call("i64.trunc_f64_s", &[Value::F64(9223372036854775808.0)]).unwrap_err();
+        assert_eq!(e, "trap: integer overflow");
+        check("i32.trunc_sat_f32_s", &[Value::F32(f32::NAN)], Value::I32(0));
+        check("i32.trunc_sat_f32_s", &[Value::F32(f32::INFINITY)], Value::I32(i32::MAX));
+    }
+
+    #[test]
+    fn float_min_max() {
+        // -0 is less than +0
+        check("f32.min", &[Value::F32(0.0), Value::F32(-0.0)], Value::F32(-0.0));
+        check("f32.min", &[Value::F32(-0.0), Value::F32(0.0)], Value::F32(-0.0));
+        check("f32.max", &[Value::F32(-0.0), Value::F32(0.0)], Value::F32(0.0));
+        check("f64.min", &[Value::F64(0.0), Value::F64(-0.0)], Value::F64(-0.0));
+        check("f64.max", &[Value::F64(0.0), Value::F64(-0.0)], Value::F64(0.0));
+        // a NaN operand gives the canonical NaN
+        let nan = Value::F32(f32::from_bits(0x7fa00001));
+        let canonical = Value::F32(f32::from_bits(0x7fc00000));
+        check("f32.min", &[nan.clone(), Value::F32(1.0)], canonical.clone());
+        check("f32.max", &[Value::F32(1.0), nan], canonical);
+        let nan = Value::F64(f64::from_bits(0xfff4000000000001));
+        match call("f64.max", &[Value::F64(f64::INFINITY), nan]) {
+            Ok(Value::F64(f)) => assert_eq!(f.to_bits() & !(1 << 63), 0x7ff8000000000000),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn float_nearest() {
+        check("f32.nearest", &[Value::F32(2.5)], Value::F32(2.0));
+        check("f32.nearest", &[Value::F32(3.5)], Value::F32(4.0));
+        check("f32.nearest", &[Value::F32(-0.5)], Value::F32(-0.0));
+    }
+
+    #[test]
+    fn control_flow() {
+        let src = r#"
+          (type $pair (func (param i32) (result i32 i32)))
+          (func (export "if") (param i32) (result i32)
+            (if (result i32) (local.get 0) (then (i32.const 1)) (else (i32.const 2))))
+          (func (export "block_params") (param i32) (result i32 i32)
+            (local.get 0)
+            (block (type $pair) (i32.const 10)))
+          (func (export "br_unwind") (result i32)
+            (i32.const 1)
+            (block (result i32) (i32.const 2) (i32.const 3) (br 0) (i32.const 4))
+            (i32.add))
+          (func (export "br_table") (param i32) (result i32)
+            (block (result i32)
+              (block (result i32)
+                (block (result i32)
+                  (br_table 0 1 2 (i32.const 100) (local.get 0)))
+                (return (i32.const 0)))
+              (return (i32.const 1))))
+          (func (export "loop") (param i32) (result i32) (local i32)
+            (loop $l
+              (local.set 1 (i32.add (local.get 1) (local.get 0)))
+              (br_if $l (local.tee 0 (i32.sub (local.get 0) (i32.const 1)))))
+            (local.get 1))
+          (func (export "multi") (result i32 i64 f32)
+            (i32.const 1) (i64.const 2) (return (f32.const 3)))"#;
+        let i32s = |v: &[i32]| Ok(v.iter().map(|v| Value::I32(*v)).collect::<Vec<_>>());
+        assert_eq!(run(src, "if", &[Value::I32(5)]), i32s(&[1]));
+        assert_eq!(run(src, "if", &[Value::I32(0)]), i32s(&[2]));
+        assert_eq!(run(src, "block_params", &[Value::I32(7)]), i32s(&[7, 10]));
+        // values above the label are dropped by br
+        assert_eq!(run(src, "br_unwind", &[]), i32s(&[4]));
+        assert_eq!(run(src, "br_table", &[Value::I32(0)]), i32s(&[0]));
+        assert_eq!(run(src, "br_table", &[Value::I32(1)]), i32s(&[1]));
+        // out of range index takes the default target
+        assert_eq!(run(src, "br_table", &[Value::I32(2)]), i32s(&[100]));
+        assert_eq!(run(src, "br_table", &[Value::I32(-1)]), i32s(&[100]));
+        assert_eq!(run(src, "loop", &[Value::I32(4)]), i32s(&[10]));
+        assert_eq!(run(src, "multi", &[]), Ok(vec![Value::I32(1), Value::I64(2), Value::F32(3.0)]));
+    }
+
+    #[test]
+    fn memory() {
+        let src = r#"
+          (memory 1 2)
+          (data (i32.const 4) "\01\80\ff\7f")
+          (func (export "load8_s") (param i32) (result i32) (i32.load8_s (local.get 0)))
+          (func (export "load16_u") (param i32) (result i32) (i32.load16_u offset=1 (local.get 0)))
+          (func (export "store_load") (param i32 i64) (result i64)
+            (i64.store offset=8 (local.get 0) (local.get 1))
+            (i64.load offset=8 (local.get 0)))
+          (func (export "grow") (param i32) (result i32 i32)
+            (memory.grow (local.get 0)) (memory.size))"#;
+        assert_eq!(run(src, "load8_s", &[Value::I32(4)]), Ok(vec![Value::I32(1)]));
+        assert_eq!(run(src, "load8_s", &[Value::I32(5)]), Ok(vec![Value::I32(-128)]));
+        assert_eq!(run(src, "load16_u", &[Value::I32(5)]), Ok(vec![Value::I32(0x7fff)]));
+        assert_eq!(run(src, "store_load", &[Value::I32(65528), Value::I64(-2)]).unwrap_err(),
+                   "trap: out of bounds memory access");
+        assert_eq!(run(src, "store_load", &[Value::I32(65520), Value::I64(-2)]), Ok(vec![Value::I64(-2)]));
+        // the offset is added without wrapping
+        assert_eq!(run(src, "load16_u", &[Value::I32(-1)]).unwrap_err(), "trap: out of bounds memory access");
+        assert_eq!(run(src, "grow", &[Value::I32(1)]), Ok(vec![Value::I32(1), Value::I32(2)]));
+        // growing beyond the max fails
+        assert_eq!(run(src, "grow", &[Value::I32(2)]), Ok(vec![Value::I32(-1), Value::I32(1)]));
+        // an active data segment out of the memory fails the instantiation
+        let module = testutil::load(r#"(memory 1) (data (i32.const 65535) "ab")"#);
+        assert_eq!(make_store(&module).err().unwrap(), "trap: out of bounds memory access");
+    }
+
+    #[test]
+    fn globals() {
+        let module = testutil::load(r#"
+          (import "env" "base" (global i32))
+          (global $g (mut i32) (i32.add (global.get 0) (i32.mul (i32.const 2) (i32.const 3))))
+          (global $f f64 (f64.const -1.5))
+          (global $r funcref (ref.func $fib))
+          (global $n externref (ref.null extern))
+          (func (export "inc") (result i32)
+            (global.set $g (i32.add (global.get $g) (i32.const 1)))
+            (global.get $g))"#);
+        let mut linker = crate::linker::Linker::new();
+        linker.global("env", "base", Value::I32(40), false);
+        let mut store = linker.instantiate(&module).unwrap();
+        assert_eq!(store.global(1), Some(Value::I32(46)));
+        assert_eq!(store.global(2), Some(Value::F64(-1.5)));
+        assert_eq!(store.global(3), Some(Value::FuncRef(Some(0))));
+        assert_eq!(store.global(4), Some(Value::ExternRef(None)));
+        assert_eq!(store.global_mutable(0), Some(false));
+        assert_eq!(store.global_mutable(1), Some(true));
+        let inc = resolve_func("inc", &module).unwrap();
+        assert_eq!(invoke(inc, &[], &module, &mut store), Ok(vec![Value::I32(47)]));
+        assert_eq!(invoke(inc, &[], &module, &mut store), Ok(vec![Value::I32(48)]));
+        assert_eq!(store.global(1), Some(Value::I32(48)));
+        // the import must have the declared type
+        let mut linker = crate::linker::Linker::new();
+        linker.global("env", "base", Value::I32(40), true);
+        assert_eq!(linker.instantiate(&module).err().unwrap(), "incompatible import type: env.base");
+    }
+
+    #[test]
+    fn tables() {
+        let src = r#"
+          (type $i2i (func (param i32) (result i32)))
+          (type $v2i (func (result i32)))
+          (table $t 4 6 funcref)
+          (table $x 1 externref)
+          (elem (table $t) (i32.const 0) func $fib $seven)
+          (elem $p funcref (ref.func $seven))
+          (func $seven (result i32) (i32.const 7))
+          (func (export "call") (param i32 i32) (result i32)
+            (call_indirect $t (type $i2i) (local.get 1) (local.get 0)))
+          (func (export "call_v") (param i32) (result i32)
+            (call_indirect $t (type $v2i) (local.get 0)))
+          (func (export "size_grow") (param i32) (result i32 i32)
+            (table.grow $t (ref.null func) (local.get 0)) (table.size $t))
+          (func (export "fill_init") (result i32 i32)
+            (table.fill $t (i32.const 2) (ref.func $fib) (i32.const 2))
+            (table.init $t $p (i32.const 1) (i32.const 0) (i32.const 1))
+            (call_indirect $t (type $i2i) (i32.const 10) (i32.const 3))
+            (call_indirect $t (type $v2i) (i32.const 1)))
+          (func (export "get_set") (param externref) (result externref)
+            (table.set $x (i32.const 0) (local.get 0))
+            (table.get $x (i32.const 0)))"#;
+        assert_eq!(run(src, "call", &[Value::I32(0), Value::I32(10)]), Ok(vec![Value::I32(55)]));
+        assert_eq!(run(src, "call_v", &[Value::I32(1)]), Ok(vec![Value::I32(7)]));
+        assert_eq!(run(src, "call", &[Value::I32(1), Value::I32(0)]).unwrap_err(),
+                   "trap: indirect call type mismatch");
+        assert_eq!(run(src, "call_v", &[Value::I32(2)]).unwrap_err(), "trap: uninitialized element");
+        assert_eq!(run(src, "call_v", &[Value::I32(4)]).unwrap_err(), "trap: undefined element");
+        assert_eq!(run(src, "size_grow", &[Value::I32(2)]), Ok(vec![Value::I32(4), Value::I32(6)]));
+        // growing beyond the max fails
+        assert_eq!(run(src, "size_grow", &[Value::I32(3)]), Ok(vec![Value::I32(-1), Value::I32(4)]));
+        assert_eq!(run(src, "fill_init", &[]), Ok(vec![Value::I32(55), Value::I32(7)]));
+        assert_eq!(run(src, "get_set", &[Value::ExternRef(Some(5))]), Ok(vec![Value::ExternRef(Some(5))]));
+    }
+
+    #[test]
+    fn invoke_by_name() {
+        let module = testutil::load(r#"
+          (func $id32 (export "id32") (param i32) (result i32) (local.get 0))
+          (func $id64 (param i64) (result i64) (local.get 0))
+          (func (export "f") (param f32 f64) (result f64) (f64.add (f64.promote_f32 (local.get 0)) (local.get 1)))"#);
+        assert_eq!(resolve_func("0", &module), Some(0));
+        assert_eq!(resolve_func("fib", &module), Some(0));
+        assert_eq!(resolve_func("id32", &module), Some(1));
+        assert_eq!(resolve_func("$id64", &module), Some(2));
+        assert_eq!(resolve_func("id64", &module), Some(2));
+        assert_eq!(resolve_func("nothing", &module), None);
+        let mut store = make_store(&module).unwrap();
+        let mut call = |args: &[&str]| invoke_str(args, &module, &mut store);
+        assert_eq!(call(&["fib", "10"]), Ok(vec![Value::I32(55)]));
+        assert_eq!(call(&["f", "0.5", "-2"]), Ok(vec![Value::F64(-1.5)]));
+        // signed, unsigned and hex integers of each width
+        for (arg, v) in [("-1", -1), ("4294967295", -1), ("0xffffffff", -1), ("-0x80000000", i32::MIN),
+                         ("2147483647", i32::MAX)] {
+            assert_eq!(call(&["id32", arg]), Ok(vec![Value::I32(v)]), "{}", arg);
+        }
+        for (arg, v) in [("-1", -1), ("18446744073709551615", -1), ("0xffffffffffffffff", -1),
+                         ("-9223372036854775808", i64::MIN), ("0x7fffffffffffffff", i64::MAX)] {
+            assert_eq!(call(&["$id64", arg]), Ok(vec![Value::I64(v)]), "{}", arg);
+        }
+        for arg in ["4294967296", "-2147483649", "0x100000000", "1.0", "", "0x"] {
+            assert!(call(&["id32", arg]).is_err(), "{}", arg);
+        }
+        assert!(call(&["$id64", "18446744073709551616"]).is_err());
+        assert_eq!(call(&["nothing"]).unwrap_err(), "unknown function 'nothing'");
+        assert_eq!(call(&["9"]).unwrap_err(), "funcidx 9 out of range. must be less than 4");
+        assert!(call(&["fib"]).unwrap_err().ends_with("need 1 args"));
+    }
+
+    // trace lines of each executed instruction of name
+    fn trace_lines(name: &str, args: &[Value], json: bool) -> Vec<String> {
+        let module = testutil::load(r#"
+          (func (export "div") (param i32) (result i32) (i32.div_s (i32.const 7) (local.get 0)))"#);
+        let mut store = make_store(&module).unwrap();
+        let mut inv = Invocation::new(resolve_func(name, &module).unwrap(), args, &module, &mut store).unwrap();
+        let mut lines = Vec::new();
+        while !inv.is_finished() {
+            let (func_idx, ip) = inv.frames().last().map(|f| (f.func_idx, f.ip)).unwrap();
+            let result = inv.step(&module, &mut store);
+            lines.push(inv.trace_line(json, func_idx, ip, &module, result.as_ref().err()));
+            if result.is_err() {
+                break;
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn trace() {
+        assert_eq!(trace_lines("div", &[Value::I32(2)], false), [
+            "trace: function[1] 0: i32.const 7 stack=[i32:7] locals=[i32:2]",
+            "trace: function[1] 1: local.get 0 stack=[i32:7, i32:2] locals=[i32:2]",
+            "trace: function[1] 2: i32.div_s stack=[i32:3] locals=[i32:2]",
+            "trace: function[1] 3: end stack=[i32:3] locals=[]",
+        ]);
+        let lines = trace_lines("div", &[Value::I32(0)], true);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], concat!(r#"{"func":1,"ip":1,"op":"local.get","operands":"0","#,
+                                     r#""stack":["i32:7","i32:0"],"locals":["i32:0"]}"#));
+        // the trap is logged with the instruction
+        assert_eq!(lines[2], concat!(r#"{"func":1,"ip":2,"op":"i32.div_s","operands":"","stack":[],"locals":["i32:0"],"#,
+                                     r#""trap":"trap: integer divide by zero"}"#));
+    }
+
+    #[test]
+    fn limits() {
+        let module = testutil::load(r#"
+          (memory 1)
+          (table 1 funcref)
+          (func $spin (export "spin") (param i32) (result i32)
+            (loop (br_if 0 (local.tee 0 (i32.sub (local.get 0) (i32.const 1)))))
+            (local.get 0))
+          (func $deep (export "deep") (param i32) (result i32)
+            (if (result i32) (local.get 0)
+              (then (call $deep (i32.sub (local.get 0) (i32.const 1))))
+              (else (i32.const 0))))
+          (func (export "push") (param i32) (result i32)
+            (i32.add (local.get 0) (i32.add (local.get 0) (i32.add (local.get 0) (i32.add (local.get 0)
+              (i32.add (local.get 0) (i32.add (local.get 0) (local.get 0))))))))
+          (func (export "grow") (param i32) (result i32 i32)
+            (memory.grow (local.get 0)) (table.grow (ref.null func) (local.get 0)))"#);
+        let limits = StoreLimits {max_call_depth: 100, max_stack: 6, max_memory_pages: 2, max_table_elems: 3};
+        let mut store = make_store_with_limits(&module, limits.clone()).unwrap();
+        let mut call = |name: &str, arg: i32| invoke(resolve_func(name, &module).unwrap(), &[Value::I32(arg)],
+                                                     &module, &mut store);
+        assert_eq!(call("deep", 90), Ok(vec![Value::I32(0)]));
+        assert_eq!(call("deep", 100).unwrap_err(), "trap: call stack exhausted");
+        // push has 7 values on the stack at most
+        assert_eq!(call("push", 1).unwrap_err(), "trap: operand stack exhausted");
+        assert_eq!(call("grow", 1), Ok(vec![Value::I32(1), Value::I32(1)]));
+        // memory.grow and table.grow fail beyond the limits
+        assert_eq!(call("grow", 1), Ok(vec![Value::I32(-1), Value::I32(2)]));
+        assert_eq!(call("grow", 2), Ok(vec![Value::I32(-1), Value::I32(-1)]));
+        let mut store = make_store_with_limits(&module, StoreLimits {max_stack: 7, ..limits.clone()}).unwrap();
+        let push = resolve_func("push", &module).unwrap();
+        assert_eq!(invoke(push, &[Value::I32(1)], &module, &mut store), Ok(vec![Value::I32(7)]));
+        // the initial memory must be in the limit
+        let module2 = testutil::load("(memory 3)");
+        assert!(make_store_with_limits(&module2, limits).is_err());
+
+        // out of fuel can be resumed after refueling
+        let mut store = make_store(&module).unwrap();
+        store.set_fuel(Some(100));
+        let spin = resolve_func("spin", &module).unwrap();
+        let mut inv = Invocation::new(spin, &[Value::I32(1000)], &module, &mut store).unwrap();
+        assert_eq!(inv.run(&module, &mut store).unwrap_err(), OUT_OF_FUEL);
+        assert_eq!(store.fuel(), Some(0));
+        let mut refuels = 1;
+        while let Err(err) = inv.run(&module, &mut store) {
+            assert_eq!(err, OUT_OF_FUEL);
+            store.add_fuel(100);
+            refuels += 1;
+        }
+        assert!(refuels > 10);
+        assert_eq!(inv.results(), vec![Value::I32(0)]);
+    }
+
+    #[test]
+    fn jumps() {
+        let module = testutil::load(r#"
+          (func (export "jumps") (param i32 i32) (result i32)
+            block (result i32)
+              loop
+                local.get 1
+                i32.const 1
+                i32.sub
+                local.tee 1
+                br_if 0
+              end
+              i32.const 1
+              local.get 0
+              if (result i32)
+                i32.const 2
+              else
+                i32.const 3
+                br 2
+              end
+              local.get 0
+              i32.const 1
+              i32.sub
+              br_table 0 1
+            end
+            i32.const 10
+            i32.add)"#);
+        let idx = resolve_func("jumps", &module).unwrap();
+        let br = |b: &Branch| (b.ip, b.arity, b.depth, b.is_loop);
+        let jumps: Vec<(usize, String)> = module.get_local_func(idx).jumps.iter().enumerate()
+            .filter_map(|(ip, jump)| match jump {
+                Jump::None => None,
+                Jump::Block(params) => Some((ip, format!("block {}", params))),
+                Jump::If(params, next) => Some((ip, format!("if {} {}", params, next))),
+                Jump::Else(end) => Some((ip, format!("else {}", end))),
+                Jump::Br(b) => Some((ip, format!("br {:?}", br(b)))),
+                Jump::BrTable(brs) => Some((ip, format!("br_table {:?}", brs.iter().map(br).collect::<Vec<_>>()))),
+            })
+            .collect();
+        let expect = [
+            (0, "block 0"),
+            (1, "block 0"),
+            (6, "br (2, 0, 0, true)"), // the instruction after loop
+            (10, "if 0 13"), // after else
+            (12, "else 15"), // the end of if
+            (14, "br (23, 1, 2, false)"), // the function end
+            (19, "br_table [(21, 1, 0, false), (23, 1, 1, false)]"), // after the end of block, the function end
+        ];
+        assert_eq!(jumps, expect.map(|(ip, s)| (ip, s.to_string())));
+        let call = |a, b| invoke(idx, &[Value::I32(a), Value::I32(b)], &module, &mut make_store(&module).unwrap());
+        assert_eq!(call(0, 3), Ok(vec![Value::I32(3)]));
+        assert_eq!(call(1, 1), Ok(vec![Value::I32(12)]));
+        assert_eq!(call(2, 1), Ok(vec![Value::I32(2)]));
+    }
+}
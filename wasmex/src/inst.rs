@@ -7,7 +7,7 @@ use crate::bytecode::*;
 const REVERVED:&str = "reserved";
 
 const B2M: [&str; 256] = [
-/*0x00*/ "unreachale", // none
+/*0x00*/ "unreachable", // none
 /*0x01*/ "nop", // none
 /*0x02*/ "block", // blocktype
 /*0x03*/ "loop", // blocktype
@@ -286,6 +286,330 @@ const B2M_FC: [&str; 18] = [
 /*17*/ "table.fill", // index
 ];
 
+// 0xfd (SIMD). 0x100.. are relaxed SIMD
+const B2M_FD: [&str; 0x114] = [
+/*0x00*/ "v128.load", // memarg
+/*0x01*/ "v128.load8x8_s", // memarg
+/*0x02*/ "v128.load8x8_u", // memarg
+/*0x03*/ "v128.load16x4_s", // memarg
+/*0x04*/ "v128.load16x4_u", // memarg
+/*0x05*/ "v128.load32x2_s", // memarg
+/*0x06*/ "v128.load32x2_u", // memarg
+/*0x07*/ "v128.load8_splat", // memarg
+/*0x08*/ "v128.load16_splat", // memarg
+/*0x09*/ "v128.load32_splat", // memarg
+/*0x0a*/ "v128.load64_splat", // memarg
+/*0x0b*/ "v128.store", // memarg
+/*0x0c*/ "v128.const", // i128
+/*0x0d*/ "i8x16.shuffle", // 16 laneidx
+/*0x0e*/ "i8x16.swizzle", // none
+/*0x0f*/ "i8x16.splat", // none
+/*0x10*/ "i16x8.splat", // none
+/*0x11*/ "i32x4.splat", // none
+/*0x12*/ "i64x2.splat", // none
+/*0x13*/ "f32x4.splat", // none
+/*0x14*/ "f64x2.splat", // none
+/*0x15*/ "i8x16.extract_lane_s", // laneidx
+/*0x16*/ "i8x16.extract_lane_u", // laneidx
+/*0x17*/ "i8x16.replace_lane", // laneidx
+/*0x18*/ "i16x8.extract_lane_s", // laneidx
+/*0x19*/ "i16x8.extract_lane_u", // laneidx
+/*0x1a*/ "i16x8.replace_lane", // laneidx
+/*0x1b*/ "i32x4.extract_lane", // laneidx
+/*0x1c*/ "i32x4.replace_lane", // laneidx
+/*0x1d*/ "i64x2.extract_lane", // laneidx
+/*0x1e*/ "i64x2.replace_lane", // laneidx
+/*0x1f*/ "f32x4.extract_lane", // laneidx
+/*0x20*/ "f32x4.replace_lane", // laneidx
+/*0x21*/ "f64x2.extract_lane", // laneidx
+/*0x22*/ "f64x2.replace_lane", // laneidx
+/*0x23*/ "i8x16.eq", // none
+/*0x24*/ "i8x16.ne", // none
+/*0x25*/ "i8x16.lt_s", // none
+/*0x26*/ "i8x16.lt_u", // none
+/*0x27*/ "i8x16.gt_s", // none
+/*0x28*/ "i8x16.gt_u", // none
+/*0x29*/ "i8x16.le_s", // none
+/*0x2a*/ "i8x16.le_u", // none
+/*0x2b*/ "i8x16.ge_s", // none
+/*0x2c*/ "i8x16.ge_u", // none
+/*0x2d*/ "i16x8.eq", // none
+/*0x2e*/ "i16x8.ne", // none
+/*0x2f*/ "i16x8.lt_s", // none
+/*0x30*/ "i16x8.lt_u", // none
+/*0x31*/ "i16x8.gt_s", // none
+/*0x32*/ "i16x8.gt_u", // none
+/*0x33*/ "i16x8.le_s", // none
+/*0x34*/ "i16x8.le_u", // none
+/*0x35*/ "i16x8.ge_s", // none
+/*0x36*/ "i16x8.ge_u", // none
+/*0x37*/ "i32x4.eq", // none
+/*0x38*/ "i32x4.ne", // none
+/*0x39*/ "i32x4.lt_s", // none
+/*0x3a*/ "i32x4.lt_u", // none
+/*0x3b*/ "i32x4.gt_s", // none
+/*0x3c*/ "i32x4.gt_u", // none
+/*0x3d*/ "i32x4.le_s", // none
+/*0x3e*/ "i32x4.le_u", // none
+/*0x3f*/ "i32x4.ge_s", // none
+/*0x40*/ "i32x4.ge_u", // none
+/*0x41*/ "f32x4.eq", // none
+/*0x42*/ "f32x4.ne", // none
+/*0x43*/ "f32x4.lt", // none
+/*0x44*/ "f32x4.gt", // none
+/*0x45*/ "f32x4.le", // none
+/*0x46*/ "f32x4.ge", // none
+/*0x47*/ "f64x2.eq", // none
+/*0x48*/ "f64x2.ne", // none
+/*0x49*/ "f64x2.lt", // none
+/*0x4a*/ "f64x2.gt", // none
+/*0x4b*/ "f64x2.le", // none
+/*0x4c*/ "f64x2.ge", // none
+/*0x4d*/ "v128.not", // none
+/*0x4e*/ "v128.and", // none
+/*0x4f*/ "v128.andnot", // none
+/*0x50*/ "v128.or", // none
+/*0x51*/ "v128.xor", // none
+/*0x52*/ "v128.bitselect", // none
+/*0x53*/ "v128.any_true", // none
+/*0x54*/ "v128.load8_lane", // memarg, laneidx
+/*0x55*/ "v128.load16_lane", // memarg, laneidx
+/*0x56*/ "v128.load32_lane", // memarg, laneidx
+/*0x57*/ "v128.load64_lane", // memarg, laneidx
+/*0x58*/ "v128.store8_lane", // memarg, laneidx
+/*0x59*/ "v128.store16_lane", // memarg, laneidx
+/*0x5a*/ "v128.store32_lane", // memarg, laneidx
+/*0x5b*/ "v128.store64_lane", // memarg, laneidx
+/*0x5c*/ "v128.load32_zero", // memarg
+/*0x5d*/ "v128.load64_zero", // memarg
+/*0x5e*/ "f32x4.demote_f64x2_zero", // none
+/*0x5f*/ "f64x2.promote_low_f32x4", // none
+/*0x60*/ "i8x16.abs", // none
+/*0x61*/ "i8x16.neg", // none
+/*0x62*/ "i8x16.popcnt", // none
+/*0x63*/ "i8x16.all_true", // none
+/*0x64*/ "i8x16.bitmask", // none
+/*0x65*/ "i8x16.narrow_i16x8_s", // none
+/*0x66*/ "i8x16.narrow_i16x8_u", // none
+/*0x67*/ "f32x4.ceil", // none
+/*0x68*/ "f32x4.floor", // none
+/*0x69*/ "f32x4.trunc", // none
+/*0x6a*/ "f32x4.nearest", // none
+/*0x6b*/ "i8x16.shl", // none
+/*0x6c*/ "i8x16.shr_s", // none
+/*0x6d*/ "i8x16.shr_u", // none
+/*0x6e*/ "i8x16.add", // none
+/*0x6f*/ "i8x16.add_sat_s", // none
+/*0x70*/ "i8x16.add_sat_u", // none
+/*0x71*/ "i8x16.sub", // none
+/*0x72*/ "i8x16.sub_sat_s", // none
+/*0x73*/ "i8x16.sub_sat_u", // none
+/*0x74*/ "f64x2.ceil", // none
+/*0x75*/ "f64x2.floor", // none
+/*0x76*/ "i8x16.min_s", // none
+/*0x77*/ "i8x16.min_u", // none
+/*0x78*/ "i8x16.max_s", // none
+/*0x79*/ "i8x16.max_u", // none
+/*0x7a*/ "f64x2.trunc", // none
+/*0x7b*/ "i8x16.avgr_u", // none
+/*0x7c*/ "i16x8.extadd_pairwise_i8x16_s", // none
+/*0x7d*/ "i16x8.extadd_pairwise_i8x16_u", // none
+/*0x7e*/ "i32x4.extadd_pairwise_i16x8_s", // none
+/*0x7f*/ "i32x4.extadd_pairwise_i16x8_u", // none
+/*0x80*/ "i16x8.abs", // none
+/*0x81*/ "i16x8.neg", // none
+/*0x82*/ "i16x8.q15mulr_sat_s", // none
+/*0x83*/ "i16x8.all_true", // none
+/*0x84*/ "i16x8.bitmask", // none
+/*0x85*/ "i16x8.narrow_i32x4_s", // none
+/*0x86*/ "i16x8.narrow_i32x4_u", // none
+/*0x87*/ "i16x8.extend_low_i8x16_s", // none
+/*0x88*/ "i16x8.extend_high_i8x16_s", // none
+/*0x89*/ "i16x8.extend_low_i8x16_u", // none
+/*0x8a*/ "i16x8.extend_high_i8x16_u", // none
+/*0x8b*/ "i16x8.shl", // none
+/*0x8c*/ "i16x8.shr_s", // none
+/*0x8d*/ "i16x8.shr_u", // none
+/*0x8e*/ "i16x8.add", // none
+/*0x8f*/ "i16x8.add_sat_s", // none
+/*0x90*/ "i16x8.add_sat_u", // none
+/*0x91*/ "i16x8.sub", // none
+/*0x92*/ "i16x8.sub_sat_s", // none
+/*0x93*/ "i16x8.sub_sat_u", // none
+/*0x94*/ "f64x2.nearest", // none
+/*0x95*/ "i16x8.mul", // none
+/*0x96*/ "i16x8.min_s", // none
+/*0x97*/ "i16x8.min_u", // none
+/*0x98*/ "i16x8.max_s", // none
+/*0x99*/ "i16x8.max_u", // none
+/*0x9a*/ REVERVED,
+/*0x9b*/ "i16x8.avgr_u", // none
+/*0x9c*/ "i16x8.extmul_low_i8x16_s", // none
+/*0x9d*/ "i16x8.extmul_high_i8x16_s", // none
+/*0x9e*/ "i16x8.extmul_low_i8x16_u", // none
+/*0x9f*/ "i16x8.extmul_high_i8x16_u", // none
+/*0xa0*/ "i32x4.abs", // none
+/*0xa1*/ "i32x4.neg", // none
+/*0xa2*/ REVERVED,
+/*0xa3*/ "i32x4.all_true", // none
+/*0xa4*/ "i32x4.bitmask", // none
+/*0xa5*/ REVERVED,
+/*0xa6*/ REVERVED,
+/*0xa7*/ "i32x4.extend_low_i16x8_s", // none
+/*0xa8*/ "i32x4.extend_high_i16x8_s", // none
+/*0xa9*/ "i32x4.extend_low_i16x8_u", // none
+/*0xaa*/ "i32x4.extend_high_i16x8_u", // none
+/*0xab*/ "i32x4.shl", // none
+/*0xac*/ "i32x4.shr_s", // none
+/*0xad*/ "i32x4.shr_u", // none
+/*0xae*/ "i32x4.add", // none
+/*0xaf*/ REVERVED,
+/*0xb0*/ REVERVED,
+/*0xb1*/ "i32x4.sub", // none
+/*0xb2*/ REVERVED,
+/*0xb3*/ REVERVED,
+/*0xb4*/ REVERVED,
+/*0xb5*/ "i32x4.mul", // none
+/*0xb6*/ "i32x4.min_s", // none
+/*0xb7*/ "i32x4.min_u", // none
+/*0xb8*/ "i32x4.max_s", // none
+/*0xb9*/ "i32x4.max_u", // none
+/*0xba*/ "i32x4.dot_i16x8_s", // none
+/*0xbb*/ REVERVED,
+/*0xbc*/ "i32x4.extmul_low_i16x8_s", // none
+/*0xbd*/ "i32x4.extmul_high_i16x8_s", // none
+/*0xbe*/ "i32x4.extmul_low_i16x8_u", // none
+/*0xbf*/ "i32x4.extmul_high_i16x8_u", // none
+/*0xc0*/ "i64x2.abs", // none
+/*0xc1*/ "i64x2.neg", // none
+/*0xc2*/ REVERVED,
+/*0xc3*/ "i64x2.all_true", // none
+/*0xc4*/ "i64x2.bitmask", // none
+/*0xc5*/ REVERVED,
+/*0xc6*/ REVERVED,
+/*0xc7*/ "i64x2.extend_low_i32x4_s", // none
+/*0xc8*/ "i64x2.extend_high_i32x4_s", // none
+/*0xc9*/ "i64x2.extend_low_i32x4_u", // none
+/*0xca*/ "i64x2.extend_high_i32x4_u", // none
+/*0xcb*/ "i64x2.shl", // none
+/*0xcc*/ "i64x2.shr_s", // none
+/*0xcd*/ "i64x2.shr_u", // none
+/*0xce*/ "i64x2.add", // none
+/*0xcf*/ REVERVED,
+/*0xd0*/ REVERVED,
+/*0xd1*/ "i64x2.sub", // none
+/*0xd2*/ REVERVED,
+/*0xd3*/ REVERVED,
+/*0xd4*/ REVERVED,
+/*0xd5*/ "i64x2.mul", // none
+/*0xd6*/ "i64x2.eq", // none
+/*0xd7*/ "i64x2.ne", // none
+/*0xd8*/ "i64x2.lt_s", // none
+/*0xd9*/ "i64x2.gt_s", // none
+/*0xda*/ "i64x2.le_s", // none
+/*0xdb*/ "i64x2.ge_s", // none
+/*0xdc*/ "i64x2.extmul_low_i32x4_s", // none
+/*0xdd*/ "i64x2.extmul_high_i32x4_s", // none
+/*0xde*/ "i64x2.extmul_low_i32x4_u", // none
+/*0xdf*/ "i64x2.extmul_high_i32x4_u", // none
+/*0xe0*/ "f32x4.abs", // none
+/*0xe1*/ "f32x4.neg", // none
+/*0xe2*/ REVERVED,
+/*0xe3*/ "f32x4.sqrt", // none
+/*0xe4*/ "f32x4.add", // none
+/*0xe5*/ "f32x4.sub", // none
+/*0xe6*/ "f32x4.mul", // none
+/*0xe7*/ "f32x4.div", // none
+/*0xe8*/ "f32x4.min", // none
+/*0xe9*/ "f32x4.max", // none
+/*0xea*/ "f32x4.pmin", // none
+/*0xeb*/ "f32x4.pmax", // none
+/*0xec*/ "f64x2.abs", // none
+/*0xed*/ "f64x2.neg", // none
+/*0xee*/ REVERVED,
+/*0xef*/ "f64x2.sqrt", // none
+/*0xf0*/ "f64x2.add", // none
+/*0xf1*/ "f64x2.sub", // none
+/*0xf2*/ "f64x2.mul", // none
+/*0xf3*/ "f64x2.div", // none
+/*0xf4*/ "f64x2.min", // none
+/*0xf5*/ "f64x2.max", // none
+/*0xf6*/ "f64x2.pmin", // none
+/*0xf7*/ "f64x2.pmax", // none
+/*0xf8*/ "i32x4.trunc_sat_f32x4_s", // none
+/*0xf9*/ "i32x4.trunc_sat_f32x4_u", // none
+/*0xfa*/ "f32x4.convert_i32x4_s", // none
+/*0xfb*/ "f32x4.convert_i32x4_u", // none
+/*0xfc*/ "i32x4.trunc_sat_f64x2_s_zero", // none
+/*0xfd*/ "i32x4.trunc_sat_f64x2_u_zero", // none
+/*0xfe*/ "f64x2.convert_low_i32x4_s", // none
+/*0xff*/ "f64x2.convert_low_i32x4_u", // none
+/*0x100*/ "i8x16.relaxed_swizzle", // none
+/*0x101*/ "i32x4.relaxed_trunc_f32x4_s", // none
+/*0x102*/ "i32x4.relaxed_trunc_f32x4_u", // none
+/*0x103*/ "i32x4.relaxed_trunc_f64x2_s_zero", // none
+/*0x104*/ "i32x4.relaxed_trunc_f64x2_u_zero", // none
+/*0x105*/ "f32x4.relaxed_madd", // none
+/*0x106*/ "f32x4.relaxed_nmadd", // none
+/*0x107*/ "f64x2.relaxed_madd", // none
+/*0x108*/ "f64x2.relaxed_nmadd", // none
+/*0x109*/ "i8x16.relaxed_laneselect", // none
+/*0x10a*/ "i16x8.relaxed_laneselect", // none
+/*0x10b*/ "i32x4.relaxed_laneselect", // none
+/*0x10c*/ "i64x2.relaxed_laneselect", // none
+/*0x10d*/ "f32x4.relaxed_min", // none
+/*0x10e*/ "f32x4.relaxed_max", // none
+/*0x10f*/ "f64x2.relaxed_min", // none
+/*0x110*/ "f64x2.relaxed_max", // none
+/*0x111*/ "i16x8.relaxed_q15mulr_s", // none
+/*0x112*/ "i16x8.relaxed_dot_i8x16_i7x16_s", // none
+/*0x113*/ "i32x4.relaxed_dot_i8x16_i7x16_add_s", // none
+];
+
+// opcode (and sub opcode for 0xfc and 0xfd) of a mnemonic
+pub fn get_opcode(mnemonic: &str) -> Option<(u8, u32)> {
+    if let Some(i) = B2M_FC.iter().position(|m| *m == mnemonic) {
+        return Some((0xfc, i as u32));
+    }
+    if let Some(i) = B2M_FD.iter().position(|m| *m == mnemonic && *m != REVERVED) {
+        return Some((0xfd, i as u32));
+    }
+    B2M.iter()
+        .position(|m| *m == mnemonic && *m != REVERVED)
+        .map(|i| (i as u8, 0))
+}
+
+// log2 of the natural alignment of load/store instructions
+pub fn natural_align(op_code: u8) -> u32 {
+    match op_code {
+        0x2c | 0x2d | 0x30 | 0x31 | 0x3a | 0x3c => 0,
+        0x2e | 0x2f | 0x32 | 0x33 | 0x3b | 0x3d => 1,
+        0x29 | 0x2b | 0x37 | 0x39 => 3,
+        _ => 2,
+    }
+}
+
+// log2 of the natural alignment of 0xfd load/store instructions
+pub fn natural_align_fd(sub_op: u32) -> u32 {
+    match sub_op {
+        0x07 | 0x54 | 0x58 => 0,
+        0x08 | 0x55 | 0x59 => 1,
+        0x09 | 0x56 | 0x5a | 0x5c => 2,
+        0x01..=0x06 | 0x0a | 0x57 | 0x5b | 0x5d => 3,
+        _ => 4,
+    }
+}
+
+// number of lanes of the laneidx of 0xfd instructions
+pub fn lanes_fd(sub_op: u32) -> u8 {
+    match sub_op {
+        0x15..=0x17 | 0x54 | 0x58 => 16,
+        0x18..=0x1a | 0x55 | 0x59 => 8,
+        0x1b | 0x1c | 0x1f | 0x20 | 0x56 | 0x5a => 4,
+        _ => 2,
+    }
+}
+
 pub enum BlockType {
     Empty,
     Valtype(u8),
@@ -293,13 +617,13 @@ pub enum BlockType {
 }
 
 pub struct BrTable {
-    labels: Vec<u32>,
-    default: u32,
+    pub labels: Vec<u32>,
+    pub default: u32,
 }
 
 pub struct Memarg {
-    align: u32,
-    offset: u32,
+    pub align: u32,
+    pub offset: u32,
 }
 
 pub enum Operand {
@@ -315,6 +639,10 @@ pub enum Operand {
     F32(f32),
     F64(f64),
     Valtype(u8),
+    V128(u128), // v128.const
+    Lane(u8), // extract_lane, replace_lane
+    Shuffle([u8; 16]), // i8x16.shuffle
+    MemargLane(Memarg, u8), // load_lane, store_lane
 }
 
 pub struct Inst {
@@ -324,6 +652,15 @@ pub struct Inst {
     pub level: i32,
 }
 
+// "i32x4 0x00000001 0x00000002 0x00000003 0x00000004"
+pub fn v128_text(v: u128) -> String {
+    let mut s = "i32x4".to_string();
+    for i in 0..4 {
+        s += &format!(" {:#010x}", (v >> (32 * i)) as u32);
+    }
+    s
+}
+
 fn fmt_valuetype(t: &u8) -> String {
     match t {
         0x7f => "i32".to_string(),
@@ -338,78 +675,160 @@ fn fmt_valuetype(t: &u8) -> String {
 }
 
 impl Inst {
-    fn get_mnemonic(&self) -> String {
+    pub fn get_mnemonic(&self) -> String {
         if self.op_code == 0xfc {
             B2M_FC[self.sub_op as usize].to_string()
+        } else if self.op_code == 0xfd {
+            B2M_FD[self.sub_op as usize].to_string()
         } else {
             B2M[self.op_code as usize].to_string()
         }
     }
 
-    pub fn print(&self) {
-        let l = if self.level < 0 {0} else {self.level};
-        for _ in 0..l {
-            print!("  ");
-        }
-        print!("{}", self.get_mnemonic());
+    // operands as printed after the mnemonic (with a leading space)
+    pub fn operand_string(&self) -> String {
         match &self.operand {
             Operand::BlockType(br_type) => {
                 match br_type {
-                    BlockType::Empty => (),
-                    BlockType::Valtype(v) => print!(" {}", fmt_valuetype(v)),
-                    BlockType::TypeIndex(idx) => print!(" {}", idx),
+                    BlockType::Empty => String::new(),
+                    BlockType::Valtype(v) => format!(" {}", fmt_valuetype(v)),
+                    BlockType::TypeIndex(idx) => format!(" {}", idx),
                 }
             },
-            Operand::Index(idx) => print!(" {}", idx),
-            Operand::Index2(idx1, idx2) => print!(" {} {}", idx1, idx2),
+            Operand::Index(idx) => format!(" {}", idx),
+            Operand::Index2(idx1, idx2) => format!(" {} {}", idx1, idx2),
             Operand::BrTable(br_table) => {
-                print!(" [");
+                let mut s = " [".to_string();
                 for l in &br_table.labels {
-                    print!("{} ", l);
+                    s += &format!("{} ", l);
                 }
-                print!("] {}", br_table.default);
+                s + &format!("] {}", br_table.default)
             },
             Operand::VecValtype(values) => {
-                print!(" [");
+                let mut s = " [".to_string();
                 for v in values {
-                    print!("{} ", fmt_valuetype(v));
+                    s += &format!("{} ", fmt_valuetype(v));
+                }
+                s + "]"
+            },
+            Operand::Memarg(memarg) => format!(" {} {}", memarg.align, memarg.offset),
+            Operand::I32(num) => format!(" {}", num),
+            Operand::I64(num) => format!(" {}", num),
+            Operand::F32(num) => format!(" {}", num),
+            Operand::F64(num) => format!(" {}", num),
+            Operand::Valtype(v) => format!(" {}", fmt_valuetype(v)),
+            Operand::V128(v) => format!(" {}", v128_text(*v)),
+            Operand::Lane(lane) => format!(" {}", lane),
+            Operand::Shuffle(lanes) => {
+                let lanes: Vec<String> = lanes.iter().map(|l| l.to_string()).collect();
+                format!(" {}", lanes.join(" "))
+            },
+            Operand::MemargLane(memarg, lane) => format!(" {} {} {}", memarg.align, memarg.offset, lane),
+            _ => String::new(),
+        }
+    }
+
+    pub fn print(&self) {
+        self.print_note(None);
+    }
+
+    // print with a note (e.g. the name of the callee) like "call 3 <gcd>"
+    pub fn print_note(&self, note: Option<&str>) {
+        let l = if self.level < 0 {0} else {self.level};
+        for _ in 0..l {
+            print!("  ");
+        }
+        print!("{}{}", self.get_mnemonic(), self.operand_string());
+        if let Some(note) = note {
+            print!(" <{}>", note);
+        }
+        println!();
+    }
+}
+
+impl PutType for Inst {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_byte(self.op_code);
+        if self.op_code == 0xfc || self.op_code == 0xfd {
+            buf.put_u32(self.sub_op);
+        }
+        match &self.operand {
+            Operand::None => (),
+            Operand::BlockType(bt) => {
+                match bt {
+                    BlockType::Empty => buf.put_byte(0x40),
+                    BlockType::Valtype(v) => buf.put_byte(*v),
+                    BlockType::TypeIndex(idx) => buf.put_i64(*idx as i64), // s33
                 }
-                print!("]");
             },
-            Operand::Memarg(memarg) => print!(" {} {}", memarg.align, memarg.offset),
-            Operand::I32(num) => print!(" {}", num),
-            Operand::I64(num) => print!(" {}", num),
-            Operand::F32(num) => print!(" {}", num),
-            Operand::F64(num) => print!(" {}", num),
-            Operand::Valtype(v) => print!(" {}", fmt_valuetype(v)),
-            _ => ()
+            Operand::Index(idx) => buf.put_u32(*idx),
+            Operand::Index2(idx1, idx2) => {
+                if self.op_code == 0x11 {
+                    // call_indirect: typeidx, tableidx
+                    buf.put_u32(*idx2);
+                    buf.put_u32(*idx1);
+                } else {
+                    buf.put_u32(*idx1);
+                    buf.put_u32(*idx2);
+                }
+            },
+            Operand::BrTable(br_table) => {
+                put_vector(&br_table.labels, buf);
+                buf.put_u32(br_table.default);
+            },
+            Operand::VecValtype(values) => {
+                buf.put_u32(values.len() as u32);
+                buf.put_bytes(values);
+            },
+            Operand::Memarg(memarg) => {
+                buf.put_u32(memarg.align);
+                buf.put_u32(memarg.offset);
+            },
+            Operand::I32(num) => buf.put_i32(*num),
+            Operand::I64(num) => buf.put_i64(*num),
+            Operand::F32(num) => buf.put_f32(*num),
+            Operand::F64(num) => buf.put_f64(*num),
+            Operand::Valtype(v) => buf.put_byte(*v),
+            Operand::V128(v) => buf.put_bytes(&v.to_le_bytes()),
+            Operand::Lane(lane) => buf.put_byte(*lane),
+            Operand::Shuffle(lanes) => buf.put_bytes(lanes),
+            Operand::MemargLane(memarg, lane) => {
+                buf.put_u32(memarg.align);
+                buf.put_u32(memarg.offset);
+                buf.put_byte(*lane);
+            },
         }
-        println!("");
     }
 }
 
-pub fn get_insts(buf: &mut ByteCodeBuff) -> Vec<Inst> {
-    _get_insts(buf, 0)
+pub fn put_insts(insts: &[Inst], buf: &mut ByteCodeBuff) {
+    for inst in insts {
+        inst.put(buf);
+    }
 }
 
-fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
+// nested blocks are tracked with the level counter instead of recursion
+// so that deeply nested code can't overflow the native stack.
+pub fn get_insts(buf: &mut ByteCodeBuff) -> Result<Vec<Inst>, DecodeError> {
     let mut insts: Vec<Inst> = Vec::new();
+    let mut level: i32 = 0;
 
     loop {
-        let code = buf.get_byte();
+        let op_cur = buf.get_cur();
+        let code = buf.get_byte()?;
 
         match code {
             // blocktype
-            0x02 | 0x03 | 0x04 => {
+            0x02..=0x04 => {
                 let cur = buf.get_cur();
-                let block_type = buf.get_byte();
+                let block_type = buf.get_byte()?;
                 match block_type {
                     0x40 => {
                         let inst = Inst {
                             op_code: code,
                             sub_op: 0,
                             operand: Operand::BlockType(BlockType::Empty),
-                            level: level,
+                            level,
                         };
                         insts.push(inst);
                     },
@@ -418,25 +837,27 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                             op_code: code,
                             sub_op: 0,
                             operand: Operand::BlockType(BlockType::Valtype(block_type)),
-                            level: level,
+                            level,
                         };
                         insts.push(inst);
                     },
                     _ => {
                         // s33 but same as i32
                         buf.set_cur(cur); // put back 1 byte
-                        let idx = buf.get_i32();
+                        let idx = buf.get_i32()?;
+                        if idx < 0 {
+                            return Err(buf.err_unexpected(cur, "blocktype", block_type as u32));
+                        }
                         let inst = Inst {
                             op_code: code,
                             sub_op: 0,
                             operand: Operand::BlockType(BlockType::TypeIndex(idx as u32)),
-                            level: level,
+                            level,
                         };
                         insts.push(inst);
                     }
                 }
-                let block_insts = _get_insts(buf, level + 1);
-                insts.extend(block_insts);
+                level += 1;
             },
             // else
             0x05 => {
@@ -457,25 +878,28 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                     level: level - 1,
                 };
                 insts.push(inst);
-                break;
+                level -= 1;
+                if level < 0 {
+                    break;
+                }
             },
             // br_table
             0x0e => {
-                let n = buf.get_u32();
+                let n = buf.get_u32()?;
                 let mut labels: Vec<u32> = Vec::new();
                 for _ in 0..n {
-                    labels.push(buf.get_u32());
+                    labels.push(buf.get_u32()?);
                 }
-                let default = buf.get_u32();
+                let default = buf.get_u32()?;
                 let br_table = BrTable {
-                    labels: labels,
-                    default: default
+                    labels,
+                    default,
                 };
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
                     operand: Operand::BrTable(br_table),
-                    level: level,
+                    level,
                 };
                 insts.push(inst);
             },
@@ -484,21 +908,21 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
-                    operand: Operand::Index(buf.get_u32()),
-                    level: level,
+                    operand: Operand::Index(buf.get_u32()?),
+                    level,
                 };
                 insts.push(inst);
                 // 0x3f | 0x40 0x00
             },
             // operand: index, index
             0x11 => {
-                let idx2 = buf.get_u32(); // typeidx
-                let idx1 = buf.get_u32(); // tableidx
+                let idx2 = buf.get_u32()?; // typeidx
+                let idx1 = buf.get_u32()?; // tableidx
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
                     operand: Operand::Index2(idx1, idx2),
-                    level: level,
+                    level,
                 };
                 insts.push(inst);
             },
@@ -507,8 +931,8 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
-                    operand: Operand::I32(buf.get_i32()),
-                    level: level,
+                    operand: Operand::I32(buf.get_i32()?),
+                    level,
                 };
                 insts.push(inst);
             },
@@ -517,8 +941,8 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
-                    operand: Operand::I64(buf.get_i64()),
-                    level: level,
+                    operand: Operand::I64(buf.get_i64()?),
+                    level,
                 };
                 insts.push(inst);
             },
@@ -527,8 +951,8 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
-                    operand: Operand::F32(buf.get_f32()),
-                    level: level,
+                    operand: Operand::F32(buf.get_f32()?),
+                    level,
                 };
                 insts.push(inst);
             },
@@ -537,30 +961,30 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
-                    operand: Operand::F64(buf.get_f64()),
-                    level: level,
+                    operand: Operand::F64(buf.get_f64()?),
+                    level,
                 };
                 insts.push(inst);
             },
             // operand: vec(valtype)
             0x1c => {
-                let n = buf.get_u32();
+                let n = buf.get_u32()?;
                 let mut values = Vec::new();
                 for _ in 0..n {
-                    values.push(buf.get_byte());
+                    values.push(Valtype::get(buf)?.0);
                 }
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
                     operand: Operand::VecValtype(values),
-                    level: level,
+                    level,
                 };
                 insts.push(inst);
             },
             // operand: memarg
             0x28..=0x3e => {
-                let align = buf.get_u32();
-                let offset = buf.get_u32();
+                let align = buf.get_u32()?;
+                let offset = buf.get_u32()?;
                 let memarg = Memarg {
                     align,
                     offset,
@@ -569,18 +993,22 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                     op_code: code,
                     sub_op: 0,
                     operand: Operand::Memarg(memarg),
-                    level: level,
+                    level,
                 };
                 insts.push(inst);
             },
             // operand: valtype
             0xd0 => {
-                let valuetype = buf.get_byte();
+                let cur = buf.get_cur();
+                let valuetype = buf.get_byte()?;
+                if valuetype != 0x70 && valuetype != 0x6f {
+                    return Err(buf.err_unexpected(cur, "reftype", valuetype as u32));
+                }
                 let inst = Inst {
                     op_code: code,
                     sub_op: 0,
                     operand: Operand::Valtype(valuetype),
-                    level: level,
+                    level,
                 };
                 insts.push(inst);
             },
@@ -590,61 +1018,90 @@ fn _get_insts(buf: &mut ByteCodeBuff, level: i32) -> Vec<Inst> {
                     op_code: code,
                     sub_op: 0,
                     operand: Operand::None,
-                    level: level,
+                    level,
                 };
                 insts.push(inst);
             },
             // FC
             0xfc => {
-                let sub_op = buf.get_u32();
+                let sub_op = buf.get_u32()?;
                 match sub_op {
                     0..=7 => {
                         let inst = Inst {
                             op_code: code,
-                            sub_op: sub_op,
+                            sub_op,
                             operand: Operand::None,
-                            level: level,
+                            level,
                         };
                         insts.push(inst);
                     },
                     8 | 10 | 12 | 14 => {
-                        let idx1 = buf.get_u32();
-                        let idx2 = buf.get_u32();
+                        let idx1 = buf.get_u32()?;
+                        let idx2 = buf.get_u32()?;
                         let inst = Inst {
                             op_code: code,
-                            sub_op: sub_op,
+                            sub_op,
                             operand: Operand::Index2(idx1, idx2),
-                            level: level,
+                            level,
                         };
                         insts.push(inst);
                         // 8: index,0x00
                         // 10: 0x00, 0x00
                     },
                     9 | 11 | 13 | 15 | 16 | 17 => {
-                        let idx = buf.get_u32();
+                        let idx = buf.get_u32()?;
                         let inst = Inst {
                             op_code: code,
-                            sub_op: sub_op,
+                            sub_op,
                             operand: Operand::Index(idx),
-                            level: level,
+                            level,
                         };
                         insts.push(inst);
                         // 11: 0x00
                     },
                     _ => {
-                        panic!("illegal sub_op: {}", sub_op);
+                        return Err(buf.err_unexpected(op_cur, "0xfc sub opcode", sub_op));
                     }
                 }
             },
             // FD
             0xfd => {
-                panic!("0xFD not suported at the moment");
+                let sub_op = buf.get_u32()?;
+                if B2M_FD.get(sub_op as usize).is_none_or(|m| *m == REVERVED) {
+                    return Err(buf.err_unexpected(op_cur, "0xfd sub opcode", sub_op));
+                }
+                let operand = match sub_op {
+                    0x00..=0x0b | 0x5c | 0x5d => {
+                        let align = buf.get_u32()?;
+                        let offset = buf.get_u32()?;
+                        Operand::Memarg(Memarg {align, offset})
+                    },
+                    0x0c => {
+                        let bytes: [u8; 16] = buf.get_bytes(16)?.try_into().unwrap();
+                        Operand::V128(u128::from_le_bytes(bytes))
+                    },
+                    0x0d => Operand::Shuffle(buf.get_bytes(16)?.try_into().unwrap()),
+                    0x15..=0x22 => Operand::Lane(buf.get_byte()?),
+                    0x54..=0x5b => {
+                        let align = buf.get_u32()?;
+                        let offset = buf.get_u32()?;
+                        Operand::MemargLane(Memarg {align, offset}, buf.get_byte()?)
+                    },
+                    _ => Operand::None,
+                };
+                let inst = Inst {
+                    op_code: code,
+                    sub_op,
+                    operand,
+                    level,
+                };
+                insts.push(inst);
             },
             _ => {
-                panic!("illegal op: {}", code);
+                return Err(buf.err_unexpected(op_cur, "opcode", code as u32));
             }
         }
     }
 
-    insts
+    Ok(insts)
 }
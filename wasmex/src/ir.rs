@@ -0,0 +1,1119 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// compact IR for faster execution. the instructions of a function are lowered
+// to pre-decoded ops on untyped 64-bit slots. the operand stack height is known
+// at each instruction, so blocks disappear and a branch is a jump with the
+// number of values to keep and to drop. common sequences (local.get or const
+// followed by a binop, i32.eqz followed by br_if or if) are fused into one op.
+// the locals live on the same stack as the operands.
+// fuel is consumed as in the interpreter: each op costs the number of the
+// instructions it was made of, including block, loop, nop and end just before it.
+// functions using instructions not supported here (references, tables, bulk
+// memory other than memory.copy/fill and v128) and import functions are
+// executed by the interpreter (exec.rs) when they are called.
+
+use crate::bytecode::*;
+use crate::exec::*;
+use crate::inst::*;
+use crate::module::*;
+
+pub(crate) type UnFn = fn(u64) -> u64;
+pub(crate) type UnTrapFn = fn(u64) -> Result<u64, String>;
+pub(crate) type BinFn = fn(u64, u64) -> u64;
+type BinTrapFn = fn(u64, u64) -> Result<u64, String>;
+
+// called for each call with the callee and the stack (args on the top). if it
+// executes the callee, it replaces the args by the results and returns Some.
+pub(crate) type CallHook<'a> = dyn FnMut(usize, &mut Vec<u64>, &mut Store) -> Option<Result<(), String>> + 'a;
+
+// a slot holds i32 zero-extended, i64, floats as bits and references as
+// funcidx or NULL_REF.
+const NULL_REF: u64 = u64::MAX;
+
+fn i32v(s: u64) -> i32 {
+    s as u32 as i32
+}
+
+fn i32s(n: i32) -> u64 {
+    n as u32 as u64
+}
+
+fn f32v(s: u64) -> f32 {
+    f32::from_bits(s as u32)
+}
+
+fn f32s(z: f32) -> u64 {
+    z.to_bits() as u64
+}
+
+fn f64v(s: u64) -> f64 {
+    f64::from_bits(s)
+}
+
+fn f64s(z: f64) -> u64 {
+    z.to_bits()
+}
+
+pub(crate) fn to_slot(v: &Value) -> u64 {
+    match v {
+        Value::I32(n) => i32s(*n),
+        Value::I64(n) => *n as u64,
+        Value::F32(z) => f32s(*z),
+        Value::F64(z) => f64s(*z),
+        Value::V128(v) => *v as u64, // not lowered (see check_v128)
+        Value::FuncRef(r) | Value::ExternRef(r) => r.map_or(NULL_REF, |i| i as u64),
+    }
+}
+
+pub(crate) fn from_slot(s: u64, valtype: u8) -> Value {
+    let r = if s == NULL_REF { None } else { Some(s as u32) };
+    match valtype {
+        0x7f => Value::I32(i32v(s)),
+        0x7e => Value::I64(s as i64),
+        0x7d => Value::F32(f32v(s)),
+        0x7c => Value::F64(f64v(s)),
+        0x70 => Value::FuncRef(r),
+        _ => Value::ExternRef(r),
+    }
+}
+
+// jump to pc keeping the top keep values and dropping drop values below them.
+// skip is the fuel given back: block, loop and end charged to the op at pc
+// but not executed when branching there.
+#[derive(Clone, Copy)]
+struct Target {
+    pc: u32,
+    keep: u32,
+    drop: u32,
+    skip: u32,
+}
+
+#[derive(Clone)]
+enum Op {
+    Unreachable,
+    Br(Target),
+    BrIf(Target),
+    BrUnless(Target), // if, i32.eqz + br_if
+    BrTable(Box<[Target]>), // labels and the default (last)
+    Return,
+    Call(u32),
+    CallIndirect(u32, u32), // tableidx, typeidx
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Load(UnFn, u32, u32), // extension of the loaded bytes, width, offset
+    Store(u32, u32), // width, offset. the low bytes of the slot are stored
+    MemorySize,
+    MemoryGrow,
+    MemoryCopy,
+    MemoryFill,
+    Const(u64),
+    I32Eqz,
+    Un(UnFn),
+    UnTrap(UnTrapFn),
+    Bin(BinFn),
+    BinTrap(BinTrapFn),
+    // fused
+    BinL(BinFn, u32), // local.get; binop
+    BinC(BinFn, u64), // const; binop
+    BinLL(BinFn, u32, u32), // local.get; local.get; binop
+    BinLC(BinFn, u32, u64), // local.get; const; binop
+}
+
+struct IrFunc {
+    code: Vec<Op>,
+    costs: Vec<u32>, // fuel of each op
+    params: Vec<u8>,
+    results: Vec<u8>,
+    locals: Vec<u64>, // initial values of the locals after the params
+    max_height: usize, // of the operand stack
+}
+
+// a function called from the IR
+struct CallFrame {
+    func_idx: usize,
+    pc: usize,
+    fp: usize, // slot of local 0
+}
+
+pub struct IrModule {
+    funcs: Vec<Result<IrFunc, String>>, // Err is the reason why it is not lowered
+}
+
+impl IrModule {
+    // lower all the local functions of the (validated) module
+    pub fn new(module: &Module) -> IrModule {
+        let funcs = module.funcs().iter().map(|func| match func {
+            Function::Import(_) => Err("import function".to_string()),
+            Function::Local(func) => lower(func, module),
+        }).collect();
+        IrModule {funcs}
+    }
+
+    pub fn is_lowered(&self, idx: usize) -> bool {
+        matches!(self.funcs.get(idx), Some(Ok(_)))
+    }
+
+    // local functions executed by the interpreter and the reasons
+    pub fn unsupported(&self, module: &Module) -> Vec<(usize, &str)> {
+        self.funcs.iter().enumerate()
+            .filter(|(idx, _)| !module.is_import_func(*idx))
+            .filter_map(|(idx, func)| func.as_ref().err().map(|reason| (idx, reason.as_str())))
+            .collect()
+    }
+
+    // same as exec::invoke_str()
+    pub fn invoke_str(&self, args: &[&str], module: &Module, store: &mut Store) -> Result<Vec<Value>, String> {
+        let (idx, values) = parse_call(args, module)?;
+        self.invoke(idx, &values, module, store)
+    }
+
+    // same as exec::invoke(). trace is not supported.
+    pub fn invoke(&self, idx: usize, args: &[Value], module: &Module, store: &mut Store) -> Result<Vec<Value>, String> {
+        let func = match self.funcs.get(idx) {
+            Some(Ok(func)) => func,
+            _ => return invoke(idx, args, module, store),
+        };
+        if func.params.len() != args.len() {
+            return Err(format!("need {} args", func.params.len()));
+        }
+        for (t, a) in func.params.iter().zip(args) {
+            if a.valtype() != Some(*t) {
+                return Err(format!("arg {} is not {}", a, Valtype(*t)));
+            }
+        }
+        let mut stack: Vec<u64> = args.iter().map(to_slot).collect();
+        let metered = store.fuel().is_some();
+        let mut fuel = store.fuel().unwrap_or(u64::MAX);
+        let result = self.run(idx, &mut stack, &mut fuel, module, store, None);
+        if metered {
+            store.set_fuel(Some(fuel));
+        }
+        result?;
+        Ok(stack.iter().zip(&func.results).map(|(s, t)| from_slot(*s, *t)).collect())
+    }
+
+    fn lowered(&self, idx: usize) -> &IrFunc {
+        match &self.funcs[idx] {
+            Ok(func) => func,
+            Err(_) => panic!("function[{}] is not lowered", idx),
+        }
+    }
+
+    // execute lowered function idx. the args are on the stack and replaced by the results.
+    pub(crate) fn run(&self, idx: usize, stack: &mut Vec<u64>, fuel: &mut u64, module: &Module, store: &mut Store,
+                      mut hook: Option<&mut CallHook>) -> Result<(), String> {
+        let mut frames: Vec<CallFrame> = Vec::new();
+        let mut func_idx = idx;
+        let mut func = self.lowered(idx);
+        let mut fp = 0;
+        let mut pc = 0;
+        stack.extend_from_slice(&func.locals);
+        if stack.len() + func.max_height > store.limits().max_stack {
+            return Err("trap: operand stack exhausted".to_string());
+        }
+        loop {
+            let cost = func.costs[pc] as u64;
+            if *fuel < cost {
+                *fuel = 0;
+                return Err(OUT_OF_FUEL.to_string());
+            }
+            *fuel -= cost;
+            let op = &func.code[pc];
+            pc += 1;
+            match op {
+                Op::Unreachable => return Err("trap: unreachable".to_string()),
+                Op::Br(t) => pc = branch(stack, fuel, t),
+                Op::BrIf(t) => {
+                    if pop(stack) as u32 != 0 {
+                        pc = branch(stack, fuel, t);
+                    }
+                },
+                Op::BrUnless(t) => {
+                    if pop(stack) as u32 == 0 {
+                        pc = branch(stack, fuel, t);
+                    }
+                },
+                Op::BrTable(targets) => {
+                    let i = (pop(stack) as u32 as usize).min(targets.len() - 1);
+                    pc = branch(stack, fuel, &targets[i]);
+                },
+                Op::Return => {
+                    let n = func.results.len();
+                    let top = stack.len() - n;
+                    stack.copy_within(top.., fp);
+                    stack.truncate(fp + n);
+                    match frames.pop() {
+                        Some(caller) => {
+                            func_idx = caller.func_idx;
+                            func = self.lowered(func_idx);
+                            pc = caller.pc;
+                            fp = caller.fp;
+                        },
+                        None => return Ok(()),
+                    }
+                },
+                Op::Call(_) | Op::CallIndirect(..) => {
+                    let callee = match op {
+                        Op::CallIndirect(tableidx, typeidx) =>
+                            indirect_func(*tableidx, *typeidx, i32v(pop(stack)), module, store)?,
+                        Op::Call(idx) => *idx as usize,
+                        _ => unreachable!(),
+                    };
+                    if let Some(hook) = hook.as_mut() {
+                        if let Some(result) = hook(callee, stack, store) {
+                            result?;
+                            continue;
+                        }
+                    }
+                    match &self.funcs[callee] {
+                        Ok(f) => {
+                            if frames.len() + 1 >= store.limits().max_call_depth {
+                                return Err("trap: call stack exhausted".to_string());
+                            }
+                            frames.push(CallFrame {func_idx, pc, fp});
+                            fp = stack.len() - f.params.len();
+                            stack.extend_from_slice(&f.locals);
+                            if stack.len() + f.max_height > store.limits().max_stack {
+                                return Err("trap: operand stack exhausted".to_string());
+                            }
+                            func_idx = callee;
+                            func = f;
+                            pc = 0;
+                        },
+                        Err(_) => call_interp(callee, stack, fuel, module, store)?,
+                    }
+                },
+                Op::Drop => {
+                    stack.pop();
+                },
+                Op::Select => {
+                    let c = pop(stack) as u32;
+                    let v2 = pop(stack);
+                    if c == 0 {
+                        *top(stack) = v2;
+                    }
+                },
+                Op::LocalGet(i) => stack.push(stack[fp + *i as usize]),
+                Op::LocalSet(i) => {
+                    let v = pop(stack);
+                    stack[fp + *i as usize] = v;
+                },
+                Op::LocalTee(i) => {
+                    let v = *top(stack);
+                    stack[fp + *i as usize] = v;
+                },
+                Op::GlobalGet(i) => {
+                    let v = store.global(*i as usize).map_or(0, |v| to_slot(&v));
+                    stack.push(v);
+                },
+                Op::GlobalSet(i) => {
+                    let i = *i as usize;
+                    let t = store.global(i).and_then(|v| v.valtype()).unwrap_or(0x7f);
+                    store.set_global(i, from_slot(pop(stack), t));
+                },
+                Op::Load(ext, width, offset) => {
+                    let w = *width as usize;
+                    let ea = store.mem_addr(i32v(*top(stack)), *offset, w)?;
+                    let mut bytes = [0; 8];
+                    bytes[..w].copy_from_slice(&store.memory()[ea..ea + w]);
+                    *top(stack) = ext(u64::from_le_bytes(bytes));
+                },
+                Op::Store(width, offset) => {
+                    let w = *width as usize;
+                    let v = pop(stack);
+                    let ea = store.mem_addr(i32v(pop(stack)), *offset, w)?;
+                    store.memory_mut()[ea..ea + w].copy_from_slice(&v.to_le_bytes()[..w]);
+                },
+                Op::MemorySize => stack.push(store.mem_pages() as u64),
+                Op::MemoryGrow => {
+                    let r = store.mem_grow(*top(stack) as u32);
+                    *top(stack) = i32s(r);
+                },
+                Op::MemoryCopy => {
+                    let n = pop(stack) as u32 as usize;
+                    let s = store.mem_addr(i32v(pop(stack)), 0, n)?;
+                    let d = store.mem_addr(i32v(pop(stack)), 0, n)?;
+                    store.memory_mut().copy_within(s..s + n, d);
+                },
+                Op::MemoryFill => {
+                    let n = pop(stack) as u32 as usize;
+                    let val = pop(stack) as u8;
+                    let d = store.mem_addr(i32v(pop(stack)), 0, n)?;
+                    store.memory_mut()[d..d + n].fill(val);
+                },
+                Op::Const(c) => stack.push(*c),
+                Op::I32Eqz => {
+                    let v = top(stack);
+                    *v = (*v as u32 == 0) as u64;
+                },
+                Op::Un(f) => {
+                    let v = top(stack);
+                    *v = f(*v);
+                },
+                Op::UnTrap(f) => {
+                    let v = top(stack);
+                    *v = f(*v)?;
+                },
+                Op::Bin(f) => {
+                    let b = pop(stack);
+                    let v = top(stack);
+                    *v = f(*v, b);
+                },
+                Op::BinTrap(f) => {
+                    let b = pop(stack);
+                    let v = top(stack);
+                    *v = f(*v, b)?;
+                },
+                Op::BinL(f, i) => {
+                    let b = stack[fp + *i as usize];
+                    let v = top(stack);
+                    *v = f(*v, b);
+                },
+                Op::BinC(f, c) => {
+                    let v = top(stack);
+                    *v = f(*v, *c);
+                },
+                Op::BinLL(f, i, j) => stack.push(f(stack[fp + *i as usize], stack[fp + *j as usize])),
+                Op::BinLC(f, i, c) => stack.push(f(stack[fp + *i as usize], *c)),
+            }
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<u64>) -> u64 {
+    stack.pop().unwrap()
+}
+
+fn top(stack: &mut [u64]) -> &mut u64 {
+    stack.last_mut().unwrap()
+}
+
+fn branch(stack: &mut Vec<u64>, fuel: &mut u64, t: &Target) -> usize {
+    *fuel = fuel.saturating_add(t.skip as u64);
+    if t.drop > 0 {
+        let (keep, drop) = (t.keep as usize, t.drop as usize);
+        let len = stack.len();
+        stack.copy_within(len - keep.., len - keep - drop);
+        stack.truncate(len - drop);
+    }
+    t.pc as usize
+}
+
+// call an import function or a function not lowered by the interpreter
+fn call_interp(idx: usize, stack: &mut Vec<u64>, fuel: &mut u64, module: &Module, store: &mut Store) -> Result<(), String> {
+    let ft = match func_type(idx, module) {
+        Some(ft) => ft,
+        None => return Err(format!("funcidx {} out of range", idx)),
+    };
+    let n = stack.len() - ft.input.0.len();
+    let args: Vec<Value> = stack[n..].iter().zip(&ft.input.0).map(|(s, t)| from_slot(*s, t.0)).collect();
+    stack.truncate(n);
+    if store.fuel().is_some() {
+        store.set_fuel(Some(*fuel));
+    }
+    let results = invoke(idx, &args, module, store);
+    if let Some(f) = store.fuel() {
+        *fuel = f;
+    }
+    stack.extend(results?.iter().map(to_slot));
+    Ok(())
+}
+
+// block being lowered
+struct Ctrl {
+    is_loop: bool,
+    height: usize, // operand stack height at the entry (without params)
+    params: usize,
+    results: usize,
+    start: usize, // pc of the loop body
+    fixups: Vec<(usize, usize)>, // branches to the end: pc and index in br_table
+    else_fixup: Option<usize>, // pc of the branch of if to else
+    then_fixup: Option<usize>, // pc of the branch of else to end
+    skip: u32, // of the branch to the loop body
+    unreachable: bool, // after br, return etc.
+    dead: bool, // in unreachable code. not lowered at all
+}
+
+impl Ctrl {
+    fn new(is_loop: bool, height: usize, params: usize, results: usize) -> Ctrl {
+        Ctrl {
+            is_loop,
+            height,
+            params,
+            results,
+            start: 0,
+            fixups: Vec::new(),
+            else_fixup: None,
+            then_fixup: None,
+            skip: 0,
+            unreachable: false,
+            dead: false,
+        }
+    }
+}
+
+struct Lower<'a> {
+    module: &'a Module,
+    code: Vec<Op>,
+    costs: Vec<u32>,
+    pending: u32, // block, loop, nop and end after the last op. charged to the next op
+    ctrls: Vec<Ctrl>,
+    height: usize,
+    max_height: usize,
+    barrier: usize, // branch target. ops before it can't be fused
+}
+
+// a v128 doesn't fit in a slot. functions which have v128 params, results or
+// locals, or pass v128 values by calls, globals and blocks can't be lowered.
+pub(crate) fn check_v128(func: &LocalFunc, module: &Module) -> Result<(), String> {
+    let has_v128 = |ft: &Functype| ft.input.0.iter().chain(&ft.output.0).any(|v| v.0 == 0x7b);
+    if has_v128(&func.ft) || func.locals.iter().any(|v| v.0 == 0x7b) {
+        return Err("v128 is not supported".to_string());
+    }
+    for (ip, inst) in func.insts.iter().enumerate() {
+        let v128 = match (inst.op_code, &inst.operand) {
+            (0x02..=0x04, Operand::BlockType(BlockType::Valtype(t))) => *t == 0x7b,
+            (0x02..=0x04, Operand::BlockType(BlockType::TypeIndex(idx))) |
+            (0x11, Operand::Index2(_, idx)) => module.types().get(*idx as usize).is_some_and(has_v128),
+            (0x10, Operand::Index(idx)) => func_type(*idx as usize, module).is_some_and(has_v128),
+            (0x1c, Operand::VecValtype(types)) => types.contains(&0x7b),
+            (0x23 | 0x24, Operand::Index(idx)) =>
+                module.global_type(*idx as usize).is_some_and(|gt| gt.valtype() == 0x7b),
+            (0xfd, _) => true,
+            _ => false,
+        };
+        if v128 {
+            return Err(format!("inst[{}] {}: v128 is not supported", ip, inst.get_mnemonic()));
+        }
+    }
+    Ok(())
+}
+
+fn lower(func: &LocalFunc, module: &Module) -> Result<IrFunc, String> {
+    check_v128(func, module)?;
+    let mut locals = Vec::new();
+    for v in &func.locals {
+        match Value::default_of(v.0) {
+            Some(value) => locals.push(to_slot(&value)),
+            None => return Err(format!("local of {} is not supported", v)),
+        }
+    }
+    let results: Vec<u8> = func.ft.output.0.iter().map(|v| v.0).collect();
+    let mut lw = Lower {
+        module,
+        code: Vec::new(),
+        costs: Vec::new(),
+        pending: 0,
+        ctrls: vec![Ctrl::new(false, 0, 0, results.len())], // function body
+        height: 0,
+        max_height: 0,
+        barrier: 0,
+    };
+    for (ip, inst) in func.insts.iter().enumerate() {
+        lw.inst(inst).map_err(|err| format!("inst[{}] {}: {}", ip, inst.get_mnemonic(), err))?;
+    }
+    Ok(IrFunc {
+        code: lw.code,
+        costs: lw.costs,
+        params: func.ft.input.0.iter().map(|v| v.0).collect(),
+        results,
+        locals,
+        max_height: lw.max_height,
+    })
+}
+
+impl Lower<'_> {
+    fn push(&mut self, n: usize) {
+        self.height += n;
+        self.max_height = self.max_height.max(self.height);
+    }
+
+    fn pop(&mut self, n: usize) -> Result<(), String> {
+        self.height = self.height.checked_sub(n).ok_or("operand stack underflow")?;
+        Ok(())
+    }
+
+    // add op replacing the last n ops fused into it
+    fn emit_fused(&mut self, op: Op, n: usize) {
+        let len = self.code.len();
+        let cost: u32 = self.costs.drain(len - n..).sum();
+        self.code.truncate(len - n);
+        self.code.push(op);
+        self.costs.push(cost + self.pending + 1);
+        self.pending = 0;
+    }
+
+    fn emit(&mut self, op: Op) {
+        self.emit_fused(op, 0);
+    }
+
+    fn top(&mut self) -> Result<&mut Ctrl, String> {
+        self.ctrls.last_mut().ok_or("after the end of the function".to_string())
+    }
+
+    fn set_unreachable(&mut self) -> Result<(), String> {
+        self.top()?.unreachable = true;
+        Ok(())
+    }
+
+    // op n from the last which can be fused into the next op
+    fn fusable(&self, n: usize) -> Option<Op> {
+        let len = self.code.len();
+        if len < self.barrier + n {
+            return None;
+        }
+        match self.code[len - n] {
+            Op::LocalGet(i) => Some(Op::LocalGet(i)),
+            Op::Const(c) => Some(Op::Const(c)),
+            Op::I32Eqz => Some(Op::I32Eqz),
+            _ => None,
+        }
+    }
+
+    fn binop(&mut self, f: BinFn) -> Result<(), String> {
+        self.pop(1)?;
+        let (op, n) = match (self.fusable(2), self.fusable(1)) {
+            (Some(Op::LocalGet(i)), Some(Op::LocalGet(j))) => (Op::BinLL(f, i, j), 2),
+            (Some(Op::LocalGet(i)), Some(Op::Const(c))) => (Op::BinLC(f, i, c), 2),
+            (_, Some(Op::LocalGet(i))) => (Op::BinL(f, i), 1),
+            (_, Some(Op::Const(c))) => (Op::BinC(f, c), 1),
+            _ => (Op::Bin(f), 0),
+        };
+        self.emit_fused(op, n);
+        Ok(())
+    }
+
+    // conditional branch. i32.eqz before it inverts the condition
+    fn cond_branch(&mut self, t: Target, if_true: bool) {
+        let inverted = matches!(self.fusable(1), Some(Op::I32Eqz));
+        let op = if if_true != inverted { Op::BrIf(t) } else { Op::BrUnless(t) };
+        self.emit_fused(op, inverted as usize);
+    }
+
+    fn label(&self, depth: u32) -> Result<&Ctrl, String> {
+        let depth = depth as usize;
+        if depth >= self.ctrls.len() {
+            return Err(format!("unknown label {}", depth));
+        }
+        Ok(&self.ctrls[self.ctrls.len() - 1 - depth])
+    }
+
+    // branch to label depth. the pc of a forward branch is resolved at the end of the block.
+    fn target(&self, depth: u32) -> Result<Target, String> {
+        let label = self.label(depth)?;
+        let keep = if label.is_loop { label.params } else { label.results };
+        let drop = self.height.checked_sub(label.height + keep).ok_or("operand stack underflow")?;
+        let (pc, skip) = if label.is_loop { (label.start, label.skip) } else { (0, 0) };
+        Ok(Target {pc: pc as u32, keep: keep as u32, drop: drop as u32, skip})
+    }
+
+    // the last op branches to the end of label depth
+    fn add_fixup(&mut self, depth: u32, k: usize) {
+        let pc = self.code.len() - 1;
+        let i = self.ctrls.len() - 1 - depth as usize;
+        let label = &mut self.ctrls[i];
+        if !label.is_loop {
+            label.fixups.push((pc, k));
+        }
+    }
+
+    fn patch(&mut self, pc: usize, k: usize, target: usize, skip: u32) {
+        let t = match &mut self.code[pc] {
+            Op::Br(t) | Op::BrIf(t) | Op::BrUnless(t) => t,
+            Op::BrTable(targets) => &mut targets[k],
+            _ => return,
+        };
+        t.pc = target as u32;
+        t.skip = skip;
+    }
+
+    fn block_type(&self, bt: &BlockType) -> (usize, usize) {
+        match bt {
+            BlockType::Empty => (0, 0),
+            BlockType::Valtype(_) => (0, 1),
+            BlockType::TypeIndex(idx) => self.module.types().get(*idx as usize)
+                .map_or((0, 0), |ft| (ft.input.0.len(), ft.output.0.len())),
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) -> Result<(), String> {
+        let (unreachable, dead) = {
+            let top = self.top()?;
+            (top.unreachable, top.dead)
+        };
+        if unreachable {
+            // skip to the else or end of the block
+            match inst.op_code {
+                0x02..=0x04 => {
+                    let mut ctrl = Ctrl::new(false, 0, 0, 0);
+                    ctrl.unreachable = true;
+                    ctrl.dead = true;
+                    self.ctrls.push(ctrl);
+                    return Ok(());
+                },
+                0x05 | 0x0b if !dead => (),
+                0x0b => {
+                    self.ctrls.pop();
+                    return Ok(());
+                },
+                _ => return Ok(()),
+            }
+        }
+        match (inst.op_code, &inst.operand) {
+            (0x00, _) => { // unreachable
+                self.emit(Op::Unreachable);
+                self.set_unreachable()?;
+            },
+            (0x01, _) => self.pending += 1, // nop
+            (0x02..=0x04, Operand::BlockType(bt)) => { // block, loop, if
+                let (params, results) = self.block_type(bt);
+                if inst.op_code == 0x04 {
+                    self.pop(1)?;
+                }
+                let height = self.height.checked_sub(params).ok_or("operand stack underflow")?;
+                let mut ctrl = Ctrl::new(inst.op_code == 0x03, height, params, results);
+                if inst.op_code != 0x04 {
+                    self.pending += 1;
+                }
+                if inst.op_code == 0x03 {
+                    self.barrier = self.code.len();
+                    ctrl.start = self.code.len();
+                    ctrl.skip = self.pending;
+                }
+                if inst.op_code == 0x04 {
+                    self.cond_branch(Target {pc: 0, keep: 0, drop: 0, skip: 0}, false);
+                    ctrl.else_fixup = Some(self.code.len() - 1);
+                }
+                self.ctrls.push(ctrl);
+            },
+            (0x05, _) => { // else
+                if !self.top()?.unreachable {
+                    let t = self.target(0)?;
+                    self.emit(Op::Br(t));
+                    let pc = self.code.len() - 1;
+                    self.top()?.then_fixup = Some(pc);
+                }
+                let pc = self.code.len();
+                let ctrl = self.top()?;
+                let else_fixup = ctrl.else_fixup.take();
+                ctrl.unreachable = false;
+                self.height = ctrl.height + ctrl.params;
+                if let Some(i) = else_fixup {
+                    self.patch(i, 0, pc, self.pending);
+                }
+                self.barrier = pc;
+            },
+            (0x0b, _) => { // end
+                let ctrl = self.ctrls.pop().ok_or("after the end of the function")?;
+                let pc = self.code.len();
+                // the interpreter jumps to the end itself from if and else and
+                // from branches to the function body, and after it otherwise
+                let before = self.pending;
+                let after = if self.ctrls.is_empty() {
+                    self.emit(Op::Return);
+                    before
+                } else {
+                    self.pending += 1;
+                    self.pending
+                };
+                for (i, k) in ctrl.fixups {
+                    self.patch(i, k, pc, after);
+                }
+                for i in ctrl.else_fixup.into_iter().chain(ctrl.then_fixup) {
+                    self.patch(i, 0, pc, before);
+                }
+                self.height = ctrl.height + ctrl.results;
+                self.max_height = self.max_height.max(self.height);
+                self.barrier = pc;
+            },
+            (0x0c, Operand::Index(n)) => { // br
+                let t = self.target(*n)?;
+                self.emit(Op::Br(t));
+                self.add_fixup(*n, 0);
+                self.set_unreachable()?;
+            },
+            (0x0d, Operand::Index(n)) => { // br_if
+                self.pop(1)?;
+                let t = self.target(*n)?;
+                self.cond_branch(t, true);
+                self.add_fixup(*n, 0);
+            },
+            (0x0e, Operand::BrTable(table)) => { // br_table
+                self.pop(1)?;
+                let labels: Vec<u32> = table.labels.iter().chain([table.default].iter()).copied().collect();
+                let mut targets = Vec::new();
+                for n in &labels {
+                    targets.push(self.target(*n)?);
+                }
+                self.emit(Op::BrTable(targets.into_boxed_slice()));
+                for (k, n) in labels.iter().enumerate() {
+                    self.add_fixup(*n, k);
+                }
+                self.set_unreachable()?;
+            },
+            (0x0f, _) => { // return
+                self.pending += 1; // the interpreter executes the end of the function too
+                self.emit(Op::Return);
+                self.set_unreachable()?;
+            },
+            (0x10, Operand::Index(idx)) => { // call
+                let ft = func_type(*idx as usize, self.module).ok_or("unknown function")?;
+                self.pop(ft.input.0.len())?;
+                self.push(ft.output.0.len());
+                self.emit(Op::Call(*idx));
+            },
+            (0x11, Operand::Index2(tableidx, typeidx)) => { // call_indirect
+                let ft = self.module.types().get(*typeidx as usize).ok_or("unknown type")?;
+                self.pop(1 + ft.input.0.len())?;
+                self.push(ft.output.0.len());
+                self.emit(Op::CallIndirect(*tableidx, *typeidx));
+            },
+            (0x1a, _) => { // drop
+                self.pop(1)?;
+                self.emit(Op::Drop);
+            },
+            (0x1b | 0x1c, _) => { // select
+                self.pop(2)?;
+                self.emit(Op::Select);
+            },
+            (0x20, Operand::Index(i)) => {
+                self.push(1);
+                self.emit(Op::LocalGet(*i));
+            },
+            (0x21, Operand::Index(i)) => {
+                self.pop(1)?;
+                self.emit(Op::LocalSet(*i));
+            },
+            (0x22, Operand::Index(i)) => self.emit(Op::LocalTee(*i)),
+            (0x23, Operand::Index(i)) => {
+                self.push(1);
+                self.emit(Op::GlobalGet(*i));
+            },
+            (0x24, Operand::Index(i)) => {
+                self.pop(1)?;
+                self.emit(Op::GlobalSet(*i));
+            },
+            (0x28..=0x35, Operand::Memarg(m)) => {
+                let (width, ext) = load_op(inst.op_code);
+                self.emit(Op::Load(ext, width, m.offset));
+            },
+            (0x36..=0x3e, Operand::Memarg(m)) => {
+                self.pop(2)?;
+                let width = match inst.op_code {
+                    0x3a | 0x3c => 1,
+                    0x3b | 0x3d => 2,
+                    0x37 | 0x39 => 8,
+                    _ => 4,
+                };
+                self.emit(Op::Store(width, m.offset));
+            },
+            (0x3f, _) => {
+                self.push(1);
+                self.emit(Op::MemorySize);
+            },
+            (0x40, _) => self.emit(Op::MemoryGrow),
+            (0x41..=0x44, _) => {
+                let c = match inst.operand {
+                    Operand::I32(n) => i32s(n),
+                    Operand::I64(n) => n as u64,
+                    Operand::F32(z) => f32s(z),
+                    Operand::F64(z) => f64s(z),
+                    _ => 0,
+                };
+                self.push(1);
+                self.emit(Op::Const(c));
+            },
+            (0x45, _) => self.emit(Op::I32Eqz),
+            (0xfc, _) if inst.sub_op == 10 || inst.sub_op == 11 => {
+                self.pop(3)?;
+                self.emit(if inst.sub_op == 10 { Op::MemoryCopy } else { Op::MemoryFill });
+            },
+            _ => {
+                if let Some(f) = unop(inst) {
+                    self.emit(Op::Un(f));
+                } else if let Some(f) = unop_trap(inst.op_code) {
+                    self.emit(Op::UnTrap(f));
+                } else if let Some(f) = binop(inst.op_code) {
+                    self.binop(f)?;
+                } else if let Some(f) = binop_trap(inst.op_code) {
+                    self.pop(1)?;
+                    self.emit(Op::BinTrap(f));
+                } else {
+                    return Err("not supported".to_string());
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+// width and extension of loads
+fn load_op(op_code: u8) -> (u32, UnFn) {
+    match op_code {
+        0x2c => (1, |a| i32s(a as i8 as i32)), // i32.load8_s
+        0x2d => (1, |a| a), // i32.load8_u
+        0x2e => (2, |a| i32s(a as i16 as i32)), // i32.load16_s
+        0x2f => (2, |a| a), // i32.load16_u
+        0x30 => (1, |a| a as i8 as u64), // i64.load8_s
+        0x31 => (1, |a| a), // i64.load8_u
+        0x32 => (2, |a| a as i16 as u64), // i64.load16_s
+        0x33 => (2, |a| a), // i64.load16_u
+        0x34 => (4, |a| a as i32 as u64), // i64.load32_s
+        0x35 => (4, |a| a), // i64.load32_u
+        0x29 | 0x2b => (8, |a| a), // i64.load, f64.load
+        _ => (4, |a| a), // i32.load, f32.load
+    }
+}
+
+pub(crate) fn unop(inst: &Inst) -> Option<UnFn> {
+    let f: UnFn = match inst.op_code {
+        0x50 => |a| (a == 0) as u64, // i64.eqz
+        0x67 => |a| (a as u32).leading_zeros() as u64, // i32.clz
+        0x68 => |a| (a as u32).trailing_zeros() as u64, // i32.ctz
+        0x69 => |a| (a as u32).count_ones() as u64, // i32.popcnt
+        0x79 => |a| a.leading_zeros() as u64, // i64.clz
+        0x7a => |a| a.trailing_zeros() as u64, // i64.ctz
+        0x7b => |a| a.count_ones() as u64, // i64.popcnt
+        0x8b => |a| f32s(f32v(a).abs()),
+        0x8c => |a| f32s(-f32v(a)),
+        0x8d => |a| f32s(f32v(a).ceil()),
+        0x8e => |a| f32s(f32v(a).floor()),
+        0x8f => |a| f32s(f32v(a).trunc()),
+        0x90 => |a| f32s(f32v(a).round_ties_even()),
+        0x91 => |a| f32s(f32v(a).sqrt()),
+        0x99 => |a| f64s(f64v(a).abs()),
+        0x9a => |a| f64s(-f64v(a)),
+        0x9b => |a| f64s(f64v(a).ceil()),
+        0x9c => |a| f64s(f64v(a).floor()),
+        0x9d => |a| f64s(f64v(a).trunc()),
+        0x9e => |a| f64s(f64v(a).round_ties_even()),
+        0x9f => |a| f64s(f64v(a).sqrt()),
+        0xa7 => |a| a as u32 as u64, // i32.wrap_i64
+        0xac => |a| i32v(a) as i64 as u64, // i64.extend_i32_s
+        0xad => |a| a as u32 as u64, // i64.extend_i32_u
+        0xb2 => |a| f32s(i32v(a) as f32),
+        0xb3 => |a| f32s(a as u32 as f32),
+        0xb4 => |a| f32s(a as i64 as f32),
+        0xb5 => |a| f32s(a as f32),
+        0xb6 => |a| f32s(f64v(a) as f32), // f32.demote_f64
+        0xb7 => |a| f64s(i32v(a) as f64),
+        0xb8 => |a| f64s(a as u32 as f64),
+        0xb9 => |a| f64s(a as i64 as f64),
+        0xba => |a| f64s(a as f64),
+        0xbb => |a| f64s(f32v(a) as f64), // f64.promote_f32
+        0xbc..=0xbf => |a| a, // reinterpret
+        0xc0 => |a| i32s(a as i8 as i32), // i32.extend8_s
+        0xc1 => |a| i32s(a as i16 as i32), // i32.extend16_s
+        0xc2 => |a| a as i8 as u64, // i64.extend8_s
+        0xc3 => |a| a as i16 as u64, // i64.extend16_s
+        0xc4 => |a| a as i32 as u64, // i64.extend32_s
+        0xfc => match inst.sub_op { // trunc_sat. "as" saturates and maps NaN to 0
+            0 => |a| i32s(f32v(a) as i32),
+            1 => |a| f32v(a) as u32 as u64,
+            2 => |a| i32s(f64v(a) as i32),
+            3 => |a| f64v(a) as u32 as u64,
+            4 => |a| f32v(a) as i64 as u64,
+            5 => |a| f32v(a) as u64,
+            6 => |a| f64v(a) as i64 as u64,
+            7 => |a| f64v(a) as u64,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(f)
+}
+
+pub(crate) fn unop_trap(op_code: u8) -> Option<UnTrapFn> {
+    let f: UnTrapFn = match op_code {
+        0xa8 => |a| Ok(i32s(trunc_float(f32v(a) as f64, I32_S_RANGE.0, I32_S_RANGE.1)? as i32)),
+        0xa9 => |a| Ok(trunc_float(f32v(a) as f64, I32_U_RANGE.0, I32_U_RANGE.1)? as u32 as u64),
+        0xaa => |a| Ok(i32s(trunc_float(f64v(a), I32_S_RANGE.0, I32_S_RANGE.1)? as i32)),
+        0xab => |a| Ok(trunc_float(f64v(a), I32_U_RANGE.0, I32_U_RANGE.1)? as u32 as u64),
+        0xae => |a| Ok(trunc_float(f32v(a) as f64, I64_S_RANGE.0, I64_S_RANGE.1)? as i64 as u64),
+        0xaf => |a| Ok(trunc_float(f32v(a) as f64, I64_U_RANGE.0, I64_U_RANGE.1)? as u64),
+        0xb0 => |a| Ok(trunc_float(f64v(a), I64_S_RANGE.0, I64_S_RANGE.1)? as i64 as u64),
+        0xb1 => |a| Ok(trunc_float(f64v(a), I64_U_RANGE.0, I64_U_RANGE.1)? as u64),
+        _ => return None,
+    };
+    Some(f)
+}
+
+pub(crate) fn binop(op_code: u8) -> Option<BinFn> {
+    let f: BinFn = match op_code {
+        0x46 => |a, b| (a as u32 == b as u32) as u64, // i32.eq
+        0x47 => |a, b| (a as u32 != b as u32) as u64,
+        0x48 => |a, b| (i32v(a) < i32v(b)) as u64,
+        0x49 => |a, b| ((a as u32) < (b as u32)) as u64,
+        0x4a => |a, b| (i32v(a) > i32v(b)) as u64,
+        0x4b => |a, b| (a as u32 > b as u32) as u64,
+        0x4c => |a, b| (i32v(a) <= i32v(b)) as u64,
+        0x4d => |a, b| (a as u32 <= b as u32) as u64,
+        0x4e => |a, b| (i32v(a) >= i32v(b)) as u64,
+        0x4f => |a, b| (a as u32 >= b as u32) as u64,
+        0x51 => |a, b| (a == b) as u64, // i64.eq
+        0x52 => |a, b| (a != b) as u64,
+        0x53 => |a, b| ((a as i64) < (b as i64)) as u64,
+        0x54 => |a, b| (a < b) as u64,
+        0x55 => |a, b| (a as i64 > b as i64) as u64,
+        0x56 => |a, b| (a > b) as u64,
+        0x57 => |a, b| (a as i64 <= b as i64) as u64,
+        0x58 => |a, b| (a <= b) as u64,
+        0x59 => |a, b| (a as i64 >= b as i64) as u64,
+        0x5a => |a, b| (a >= b) as u64,
+        0x5b => |a, b| (f32v(a) == f32v(b)) as u64, // f32.eq
+        0x5c => |a, b| (f32v(a) != f32v(b)) as u64,
+        0x5d => |a, b| (f32v(a) < f32v(b)) as u64,
+        0x5e => |a, b| (f32v(a) > f32v(b)) as u64,
+        0x5f => |a, b| (f32v(a) <= f32v(b)) as u64,
+        0x60 => |a, b| (f32v(a) >= f32v(b)) as u64,
+        0x61 => |a, b| (f64v(a) == f64v(b)) as u64, // f64.eq
+        0x62 => |a, b| (f64v(a) != f64v(b)) as u64,
+        0x63 => |a, b| (f64v(a) < f64v(b)) as u64,
+        0x64 => |a, b| (f64v(a) > f64v(b)) as u64,
+        0x65 => |a, b| (f64v(a) <= f64v(b)) as u64,
+        0x66 => |a, b| (f64v(a) >= f64v(b)) as u64,
+        0x6a => |a, b| i32s(i32v(a).wrapping_add(i32v(b))), // i32.add
+        0x6b => |a, b| i32s(i32v(a).wrapping_sub(i32v(b))),
+        0x6c => |a, b| i32s(i32v(a).wrapping_mul(i32v(b))),
+        0x71 => |a, b| a & b,
+        0x72 => |a, b| a | b,
+        0x73 => |a, b| a ^ b,
+        0x74 => |a, b| i32s(i32v(a).wrapping_shl(b as u32)),
+        0x75 => |a, b| i32s(i32v(a).wrapping_shr(b as u32)),
+        0x76 => |a, b| (a as u32).wrapping_shr(b as u32) as u64,
+        0x77 => |a, b| (a as u32).rotate_left(b as u32 % 32) as u64,
+        0x78 => |a, b| (a as u32).rotate_right(b as u32 % 32) as u64,
+        0x7c => |a, b| a.wrapping_add(b), // i64.add
+        0x7d => |a, b| a.wrapping_sub(b),
+        0x7e => |a, b| a.wrapping_mul(b),
+        0x83 => |a, b| a & b,
+        0x84 => |a, b| a | b,
+        0x85 => |a, b| a ^ b,
+        0x86 => |a, b| a.wrapping_shl(b as u32),
+        0x87 => |a, b| (a as i64).wrapping_shr(b as u32) as u64,
+        0x88 => |a, b| a.wrapping_shr(b as u32),
+        0x89 => |a, b| a.rotate_left((b % 64) as u32),
+        0x8a => |a, b| a.rotate_right((b % 64) as u32),
+        0x92 => |a, b| f32s(f32v(a) + f32v(b)), // f32.add
+        0x93 => |a, b| f32s(f32v(a) - f32v(b)),
+        0x94 => |a, b| f32s(f32v(a) * f32v(b)),
+        0x95 => |a, b| f32s(f32v(a) / f32v(b)),
+        0x96 => |a, b| f32s(f32_min(f32v(a), f32v(b))),
+        0x97 => |a, b| f32s(f32_max(f32v(a), f32v(b))),
+        0x98 => |a, b| f32s(f32v(a).copysign(f32v(b))),
+        0xa0 => |a, b| f64s(f64v(a) + f64v(b)), // f64.add
+        0xa1 => |a, b| f64s(f64v(a) - f64v(b)),
+        0xa2 => |a, b| f64s(f64v(a) * f64v(b)),
+        0xa3 => |a, b| f64s(f64v(a) / f64v(b)),
+        0xa4 => |a, b| f64s(f64_min(f64v(a), f64v(b))),
+        0xa5 => |a, b| f64s(f64_max(f64v(a), f64v(b))),
+        0xa6 => |a, b| f64s(f64v(a).copysign(f64v(b))),
+        _ => return None,
+    };
+    Some(f)
+}
+
+fn divide_by_zero() -> String {
+    "trap: integer divide by zero".to_string()
+}
+
+fn binop_trap(op_code: u8) -> Option<BinTrapFn> {
+    let f: BinTrapFn = match op_code {
+        0x6d => |a, b| match i32v(b) { // i32.div_s
+            0 => Err(divide_by_zero()),
+            b => i32v(a).checked_div(b).map(i32s).ok_or("trap: integer overflow".to_string()),
+        },
+        0x6e => |a, b| (a as u32).checked_div(b as u32).map(|r| r as u64).ok_or_else(divide_by_zero),
+        0x6f => |a, b| match i32v(b) { // i32.rem_s
+            0 => Err(divide_by_zero()),
+            b => Ok(i32s(i32v(a).wrapping_rem(b))),
+        },
+        0x70 => |a, b| (a as u32).checked_rem(b as u32).map(|r| r as u64).ok_or_else(divide_by_zero),
+        0x7f => |a, b| match b as i64 { // i64.div_s
+            0 => Err(divide_by_zero()),
+            b => (a as i64).checked_div(b).map(|r| r as u64).ok_or("trap: integer overflow".to_string()),
+        },
+        0x80 => |a, b| a.checked_div(b).ok_or_else(divide_by_zero),
+        0x81 => |a, b| match b as i64 { // i64.rem_s
+            0 => Err(divide_by_zero()),
+            b => Ok((a as i64).wrapping_rem(b) as u64),
+        },
+        0x82 => |a, b| a.checked_rem(b).ok_or_else(divide_by_zero),
+        _ => return None,
+    };
+    Some(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    const SRC: &str = r#"
+      (func (export "sum") (param i32) (result i32) (local i32)
+        (block
+          (loop
+            (br_if 1 (i32.eqz (local.get 0)))
+            (nop)
+            (local.set 1 (i32.add (local.get 1) (local.get 0)))
+            (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+            (br 0)))
+        (local.get 1))
+      (func (export "nested") (param i32) (result i32)
+        (block (block (block
+          (nop)
+          (br_table 0 1 2 (local.get 0)))
+          (return (i32.const 10)))
+          (block (nop))
+          (return (i32.const 20)))
+        (if (i32.gt_s (local.get 0) (i32.const 5)) (then (return (i32.const 40))))
+        (i32.const 30))
+      (func (export "ifs") (param i32) (result i32) (local i32)
+        (if (local.get 0) (then (local.set 1 (i32.const 1))))
+        (if (i32.eqz (local.get 0)) (then (nop)) (else (block (nop))))
+        (block (if (local.get 0) (then (br 1))) (local.set 1 (i32.const 2)))
+        (loop (nop) (block (nop)))
+        (if (local.get 0) (then (br 0)))
+        (local.get 1))"#;
+
+    fn run(name: &str, arg: i32, fuel: Option<u64>, lower: bool) -> (Result<Vec<Value>, String>, Option<u64>) {
+        let module = testutil::load(SRC);
+        let args = [Value::I32(arg)];
+        if !lower {
+            return testutil::call(&module, name, &args, fuel, &invoke);
+        }
+        let ir = IrModule::new(&module);
+        assert!(ir.is_lowered(resolve_func(name, &module).unwrap()));
+        testutil::call(&module, name, &args, fuel, &|idx, args, module, store| ir.invoke(idx, args, module, store))
+    }
+
+    #[test]
+    fn same_results() {
+        for (name, args) in [("fib", 0..12), ("sum", 0..10), ("nested", 0..8), ("ifs", 0..3)] {
+            for arg in args {
+                assert_eq!(run(name, arg, None, true), run(name, arg, None, false), "{} {}", name, arg);
+            }
+        }
+    }
+
+    #[test]
+    fn same_fuel() {
+        for (name, args) in [("fib", 0..6), ("sum", 0..4), ("nested", 0..8), ("ifs", 0..3)] {
+            for arg in args {
+                // the fuel used by the interpreter
+                let used = u64::MAX - run(name, arg, Some(u64::MAX), false).1.unwrap();
+                for fuel in 0..=used + 1 {
+                    assert_eq!(run(name, arg, Some(fuel), true), run(name, arg, Some(fuel), false),
+                               "{} {} with fuel {}", name, arg, fuel);
+                }
+            }
+        }
+    }
+}
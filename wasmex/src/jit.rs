@@ -0,0 +1,1227 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// baseline JIT compiler for x86-64 linux (feature "jit").
+// a function is executed by the IR (ir.rs) until it is called threshold times,
+// and then compiled to native code in a single pass over its instructions.
+// the native code keeps the locals and the operands in 64-bit slots as the IR
+// does (the stack height at each instruction is static, so each slot has a fixed
+// address relative to the frame) and uses registers only within an instruction.
+//   rbx: frame (slot of local 0)
+//   r12: memory base, r13: memory size
+//   r14: context
+// memory accesses are bounds checked and a trap returns its code to the caller.
+// calls and the instructions which are not compiled inline (floats, conversions,
+// globals, memory.grow etc.) go through the helper functions in Rust.
+// functions using instructions not supported here (references, tables and bulk
+// memory other than memory.copy/fill) are left to the IR or the interpreter.
+
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::rc::Rc;
+
+use crate::exec::*;
+use crate::inst::*;
+use crate::ir::*;
+use crate::module::*;
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R8: u8 = 8;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// condition codes
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_BE: u8 = 0x6;
+const CC_A: u8 = 0x7;
+const CC_L: u8 = 0xc;
+const CC_GE: u8 = 0xd;
+const CC_LE: u8 = 0xe;
+const CC_G: u8 = 0xf;
+
+// return codes of the native code. 0 is success
+const TRAP_UNREACHABLE: u32 = 1;
+const TRAP_MEMORY: u32 = 2;
+const TRAP_DIV_ZERO: u32 = 3;
+const TRAP_OVERFLOW: u32 = 4;
+const TRAP_ERROR: u32 = 5; // the message is in Ctx::error
+
+fn trap_message(code: u32) -> &'static str {
+    match code {
+        TRAP_UNREACHABLE => "trap: unreachable",
+        TRAP_MEMORY => "trap: out of bounds memory access",
+        TRAP_DIV_ZERO => "trap: integer divide by zero",
+        TRAP_OVERFLOW => "trap: integer overflow",
+        _ => "trap",
+    }
+}
+
+pub const JIT_THRESHOLD: u32 = 100;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+// executable memory holding the code of a function
+struct ExecMem {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl ExecMem {
+    fn new(code: &[u8]) -> Result<ExecMem, String> {
+        let len = code.len().div_ceil(4096) * 4096;
+        let ptr = unsafe { mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+        if ptr as isize == -1 {
+            return Err("mmap failed".to_string());
+        }
+        let mem = ExecMem {ptr, len};
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return Err("mprotect failed".to_string());
+            }
+        }
+        Ok(mem)
+    }
+}
+
+impl Drop for ExecMem {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+// native code: (context, frame) -> return code
+type NativeFn = unsafe extern "sysv64" fn(*mut Ctx, *mut u64) -> u32;
+
+struct JitFunc {
+    _mem: ExecMem,
+    entry: NativeFn,
+    params: usize,
+    locals: Vec<u64>, // initial values of the locals after the params
+    frame_size: usize, // locals and the operand stack in slots
+}
+
+enum Tier {
+    Counting(u32), // calls so far
+    Native(Rc<JitFunc>),
+    Unsupported,
+}
+
+pub struct Jit {
+    ir: IrModule,
+    threshold: u32,
+    tiers: RefCell<Vec<Tier>>,
+    stack: RefCell<Vec<u64>>, // slots of the native frames. reused by the invocations
+}
+
+impl Jit {
+    // functions are compiled when they are called threshold times (at the first call if 0)
+    pub fn new(module: &Module, threshold: u32) -> Jit {
+        let tiers = (0..module.num_funcs()).map(|idx| match module.is_import_func(idx) {
+            true => Tier::Unsupported,
+            false => Tier::Counting(0),
+        }).collect();
+        Jit {ir: IrModule::new(module), threshold, tiers: RefCell::new(tiers), stack: RefCell::default()}
+    }
+
+    // functions compiled to native code so far
+    pub fn compiled(&self) -> Vec<usize> {
+        self.tiers.borrow().iter().enumerate()
+            .filter(|(_, tier)| matches!(tier, Tier::Native(_)))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    // same as exec::invoke_str()
+    pub fn invoke_str(&self, args: &[&str], module: &Module, store: &mut Store) -> Result<Vec<Value>, String> {
+        let (idx, values) = parse_call(args, module)?;
+        self.invoke(idx, &values, module, store)
+    }
+
+    // same as exec::invoke(). the native code doesn't consume fuel, so the IR
+    // is used if fuel is set.
+    pub fn invoke(&self, idx: usize, args: &[Value], module: &Module, store: &mut Store) -> Result<Vec<Value>, String> {
+        if store.fuel().is_some() {
+            return self.ir.invoke(idx, args, module, store);
+        }
+        let ft = match func_type(idx, module) {
+            Some(ft) => ft,
+            None => return Err(format!("funcidx {} out of range. must be less than {}", idx,
+                                       module.num_funcs())),
+        };
+        // v128 args and results don't fit in slots
+        let v128 = ft.input.0.iter().chain(&ft.output.0).any(|v| v.0 == 0x7b);
+        if module.is_import_func(idx) || v128 {
+            return invoke(idx, args, module, store);
+        }
+        if ft.input.0.len() != args.len() {
+            return Err(format!("need {} args", ft.input.0.len()));
+        }
+        for (v, a) in ft.input.0.iter().zip(args) {
+            if a.valtype() != Some(v.0) {
+                return Err(format!("arg {} is not {}", a, v));
+            }
+        }
+        // the slots are allocated at the first invocation. an invocation from a
+        // host function while another one is running gets its own slots.
+        let len = store.limits().max_stack.max(args.len() + ft.output.0.len());
+        let mut stack = self.stack.take();
+        if stack.len() < len {
+            stack.resize(len, 0);
+        }
+        for (slot, a) in stack.iter_mut().zip(args) {
+            *slot = to_slot(a);
+        }
+        let mut ctx = Ctx {
+            mem_base: std::ptr::null_mut(),
+            mem_len: 0,
+            jit: self,
+            module,
+            store,
+            stack: stack.as_mut_ptr(),
+            stack_len: len,
+            sp: 0,
+            depth: 0,
+            error: None,
+        };
+        ctx.refresh_memory();
+        let result = ctx.call(idx, 0);
+        let values = stack.iter().zip(&ft.output.0).map(|(s, t)| from_slot(*s, t.0)).collect();
+        self.stack.replace(stack);
+        result.map(|_| values)
+    }
+
+    // count the call of function idx and compile it at the threshold.
+    // returns the native code if it is compiled.
+    fn native(&self, idx: usize, module: &Module) -> Option<Rc<JitFunc>> {
+        let mut tiers = self.tiers.borrow_mut();
+        match &mut tiers[idx] {
+            Tier::Native(func) => return Some(func.clone()),
+            Tier::Unsupported => return None,
+            Tier::Counting(n) => {
+                *n += 1;
+                if *n < self.threshold {
+                    return None;
+                }
+            },
+        }
+        let func = match module.get_func(idx) {
+            Some(Function::Local(func)) => func,
+            _ => return None,
+        };
+        match compile(func, module) {
+            Ok(func) => {
+                let func = Rc::new(func);
+                tiers[idx] = Tier::Native(func.clone());
+                Some(func)
+            },
+            Err(_) => {
+                tiers[idx] = Tier::Unsupported;
+                None
+            },
+        }
+    }
+}
+
+// execution context shared with the native code. the native code reads
+// mem_base and mem_len (reloaded after the calls of helpers).
+#[repr(C)]
+struct Ctx<'a> {
+    mem_base: *mut u8,
+    mem_len: u64,
+    jit: &'a Jit,
+    module: &'a Module,
+    store: *mut Store,
+    stack: *mut u64, // slots of the native frames
+    stack_len: usize,
+    sp: usize, // first free slot
+    depth: usize,
+    error: Option<String>,
+}
+
+impl Ctx<'_> {
+    fn store(&mut self) -> &mut Store {
+        unsafe { &mut *self.store }
+    }
+
+    fn refresh_memory(&mut self) {
+        let (base, len) = {
+            let mut mem = self.store().memory_mut();
+            (mem.as_mut_ptr(), mem.len() as u64)
+        };
+        self.mem_base = base;
+        self.mem_len = len;
+    }
+
+    fn slots(&mut self, fp: usize, n: usize) -> &mut [u64] {
+        unsafe { std::slice::from_raw_parts_mut(self.stack.add(fp), n) }
+    }
+
+    // call function idx with the args at slot fp. the results replace them.
+    fn call(&mut self, idx: usize, fp: usize) -> Result<(), String> {
+        if self.depth >= self.store().limits().max_call_depth {
+            return Err("trap: call stack exhausted".to_string());
+        }
+        self.depth += 1;
+        let saved_sp = self.sp;
+        let result = match self.jit.native(idx, self.module) {
+            Some(func) => self.call_native(&func, fp),
+            None => self.call_ir(idx, fp),
+        };
+        self.sp = saved_sp;
+        self.depth -= 1;
+        self.refresh_memory();
+        result
+    }
+
+    fn call_native(&mut self, func: &JitFunc, fp: usize) -> Result<(), String> {
+        if fp + func.frame_size > self.stack_len {
+            return Err("trap: operand stack exhausted".to_string());
+        }
+        self.sp = fp + func.frame_size;
+        self.slots(fp + func.params, func.locals.len()).copy_from_slice(&func.locals);
+        let frame = unsafe { self.stack.add(fp) };
+        match unsafe { (func.entry)(self, frame) } {
+            0 => Ok(()),
+            TRAP_ERROR => Err(self.error.take().unwrap_or_else(|| "trap".to_string())),
+            code => Err(trap_message(code).to_string()),
+        }
+    }
+
+    // execute by the IR (or the interpreter if it is not lowered). the calls
+    // from the IR come back here to be counted and compiled.
+    fn call_ir(&mut self, idx: usize, fp: usize) -> Result<(), String> {
+        let ft = func_type(idx, self.module).ok_or("unknown function")?;
+        let (params, results) = (ft.input.0.len(), ft.output.0.len());
+        self.sp = fp + params.max(results);
+        let (jit, module) = (self.jit, self.module);
+        let store = unsafe { &mut *self.store };
+        let mut stack = self.slots(fp, params).to_vec();
+        if jit.ir.is_lowered(idx) {
+            let mut hook = |callee: usize, stack: &mut Vec<u64>, store: &mut Store| {
+                let func = jit.native(callee, module)?;
+                self.store = store;
+                let (sp, n) = (self.sp, stack.len() - func.params);
+                self.slots(sp, func.params).copy_from_slice(&stack[n..]);
+                stack.truncate(n);
+                let result = self.call(callee, sp);
+                let results = func_type(callee, module).map_or(0, |ft| ft.output.0.len());
+                stack.extend_from_slice(self.slots(sp, results));
+                Some(result)
+            };
+            let mut fuel = u64::MAX;
+            jit.ir.run(idx, &mut stack, &mut fuel, module, store, Some(&mut hook))?;
+        } else {
+            let args: Vec<Value> = stack.iter().zip(&ft.input.0).map(|(s, t)| from_slot(*s, t.0)).collect();
+            let values = invoke(idx, &args, module, store)?;
+            stack = values.iter().map(to_slot).collect();
+        }
+        self.slots(fp, results).copy_from_slice(&stack);
+        Ok(())
+    }
+}
+
+// helpers called by the native code
+
+fn helper_result(ctx: *mut Ctx, result: Result<(), String>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            unsafe { (*ctx).error = Some(err) };
+            TRAP_ERROR
+        },
+    }
+}
+
+extern "sysv64" fn jit_call(ctx: *mut Ctx, idx: u32, frame: *mut u64) -> u32 {
+    let c = unsafe { &mut *ctx };
+    let fp = unsafe { frame.offset_from(c.stack) } as usize;
+    helper_result(ctx, c.call(idx as usize, fp))
+}
+
+extern "sysv64" fn jit_call_indirect(ctx: *mut Ctx, tableidx: u32, typeidx: u32, i: u32, frame: *mut u64) -> u32 {
+    let c = unsafe { &mut *ctx };
+    let fp = unsafe { frame.offset_from(c.stack) } as usize;
+    let result = indirect_func(tableidx, typeidx, i as i32, c.module, c.store())
+        .and_then(|idx| c.call(idx, fp));
+    helper_result(ctx, result)
+}
+
+// f is the address of the UnFn, BinFn or UnTrapFn of the IR
+extern "sysv64" fn jit_unop(f: usize, a: u64) -> u64 {
+    let f = unsafe { std::mem::transmute::<usize, UnFn>(f) };
+    f(a)
+}
+
+extern "sysv64" fn jit_binop(f: usize, a: u64, b: u64) -> u64 {
+    let f = unsafe { std::mem::transmute::<usize, BinFn>(f) };
+    f(a, b)
+}
+
+extern "sysv64" fn jit_unop_trap(ctx: *mut Ctx, f: usize, slot: *mut u64) -> u32 {
+    let f = unsafe { std::mem::transmute::<usize, UnTrapFn>(f) };
+    let result = f(unsafe { *slot }).map(|v| unsafe { *slot = v });
+    helper_result(ctx, result)
+}
+
+extern "sysv64" fn jit_global_get(ctx: *mut Ctx, idx: u32) -> u64 {
+    let c = unsafe { &mut *ctx };
+    c.store().global(idx as usize).map_or(0, |v| to_slot(&v))
+}
+
+extern "sysv64" fn jit_global_set(ctx: *mut Ctx, idx: u32, v: u64) {
+    let c = unsafe { &mut *ctx };
+    let store = c.store();
+    let t = store.global(idx as usize).and_then(|g| g.valtype()).unwrap_or(0x7f);
+    store.set_global(idx as usize, from_slot(v, t));
+}
+
+extern "sysv64" fn jit_memory_grow(ctx: *mut Ctx, n: u32) -> u64 {
+    let c = unsafe { &mut *ctx };
+    let r = c.store().mem_grow(n);
+    c.refresh_memory();
+    r as u32 as u64
+}
+
+extern "sysv64" fn jit_memory_copy(ctx: *mut Ctx, d: u32, s: u32, n: u32) -> u32 {
+    let c = unsafe { &mut *ctx };
+    let store = c.store();
+    let n = n as usize;
+    let result = store.mem_addr(s as i32, 0, n).and_then(|s| {
+        let d = store.mem_addr(d as i32, 0, n)?;
+        store.memory_mut().copy_within(s..s + n, d);
+        Ok(())
+    });
+    helper_result(ctx, result)
+}
+
+extern "sysv64" fn jit_memory_fill(ctx: *mut Ctx, d: u32, val: u32, n: u32) -> u32 {
+    let c = unsafe { &mut *ctx };
+    let store = c.store();
+    let n = n as usize;
+    let result = store.mem_addr(d as i32, 0, n).map(|d| store.memory_mut()[d..d + n].fill(val as u8));
+    helper_result(ctx, result)
+}
+
+// x86-64 code buffer
+#[derive(Default)]
+struct Asm {
+    code: Vec<u8>,
+}
+
+impl Asm {
+    fn byte(&mut self, b: u8) {
+        self.code.push(b);
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b);
+    }
+
+    fn imm32(&mut self, n: u32) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | (base >> 3);
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    // op reg, rm (register)
+    fn rr(&mut self, w: bool, op: &[u8], reg: u8, rm: u8) {
+        self.rex(w, reg, 0, rm);
+        self.bytes(op);
+        self.byte(0xc0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    // op reg, [base + disp]
+    fn rm(&mut self, w: bool, op: &[u8], reg: u8, base: u8, disp: i32) {
+        self.rex(w, reg, 0, base);
+        self.bytes(op);
+        self.byte(0x80 | (reg & 7) << 3 | (base & 7));
+        if base & 7 == 4 {
+            self.byte(0x24);
+        }
+        self.imm32(disp as u32);
+    }
+
+    // op reg, [base + index]. base is not rbp/r13
+    fn rmi(&mut self, w: bool, op: &[u8], reg: u8, base: u8, index: u8) {
+        self.rex(w, reg, index, base);
+        self.bytes(op);
+        self.byte((reg & 7) << 3 | 4);
+        self.byte((index & 7) << 3 | (base & 7));
+    }
+
+    fn load(&mut self, reg: u8, slot: usize) {
+        self.rm(true, &[0x8b], reg, RBX, slot as i32 * 8);
+    }
+
+    fn load32(&mut self, reg: u8, slot: usize) {
+        self.rm(false, &[0x8b], reg, RBX, slot as i32 * 8);
+    }
+
+    fn store(&mut self, slot: usize, reg: u8) {
+        self.rm(true, &[0x89], reg, RBX, slot as i32 * 8);
+    }
+
+    fn lea_slot(&mut self, reg: u8, slot: usize) {
+        self.rm(true, &[0x8d], reg, RBX, slot as i32 * 8);
+    }
+
+    fn mov_imm(&mut self, reg: u8, n: u64) {
+        if n <= u32::MAX as u64 {
+            self.rex(false, 0, 0, reg);
+            self.byte(0xb8 | (reg & 7));
+            self.imm32(n as u32);
+        } else {
+            self.rex(true, 0, 0, reg);
+            self.byte(0xb8 | (reg & 7));
+            self.bytes(&n.to_le_bytes());
+        }
+    }
+
+    fn mov_rr(&mut self, dst: u8, src: u8) {
+        self.rr(true, &[0x89], src, dst);
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.byte(0x50 | (reg & 7));
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.byte(0x58 | (reg & 7));
+    }
+
+    fn call(&mut self, addr: usize) {
+        self.mov_imm(RAX, addr as u64);
+        self.rr(false, &[0xff], 2, RAX);
+    }
+
+    // jmp/jcc with rel32 to be patched. returns the position of rel32
+    fn jmp(&mut self) -> usize {
+        self.byte(0xe9);
+        self.imm32(0);
+        self.code.len() - 4
+    }
+
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.bytes(&[0x0f, 0x80 | cc]);
+        self.imm32(0);
+        self.code.len() - 4
+    }
+
+    fn patch(&mut self, pos: usize, target: usize) {
+        let rel = target as i64 - (pos as i64 + 4);
+        self.code[pos..pos + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    // to the current position
+    fn bind(&mut self, pos: usize) {
+        self.patch(pos, self.code.len());
+    }
+
+    fn jmp_to(&mut self, target: usize) {
+        let pos = self.jmp();
+        self.patch(pos, target);
+    }
+
+    fn test32(&mut self, reg: u8) {
+        self.rr(false, &[0x85], reg, reg);
+    }
+
+    // cmp reg, imm32 (sign-extended if w)
+    fn cmp_imm(&mut self, w: bool, reg: u8, n: u32) {
+        self.rr(w, &[0x81], 7, reg);
+        self.imm32(n);
+    }
+
+    // rax = (cc) ? 1 : 0
+    fn setcc(&mut self, cc: u8) {
+        self.rr(false, &[0x0f, 0x90 | cc], 0, RAX);
+        self.rr(false, &[0x0f, 0xb6], RAX, RAX);
+    }
+}
+
+// block being compiled
+struct Ctrl {
+    is_loop: bool,
+    height: usize, // operand stack height at the entry (without params)
+    params: usize,
+    results: usize,
+    start: usize, // code offset of the loop body
+    fixups: Vec<usize>, // jumps to the end
+    else_fixup: Option<usize>, // jump of if to else
+    unreachable: bool,
+    dead: bool, // in unreachable code. not compiled at all
+}
+
+impl Ctrl {
+    fn new(is_loop: bool, height: usize, params: usize, results: usize) -> Ctrl {
+        Ctrl {
+            is_loop,
+            height,
+            params,
+            results,
+            start: 0,
+            fixups: Vec::new(),
+            else_fixup: None,
+            unreachable: false,
+            dead: false,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    module: &'a Module,
+    asm: Asm,
+    ctrls: Vec<Ctrl>,
+    nlocals: usize, // params and locals
+    height: usize,
+    max_height: usize,
+    traps: Vec<(usize, u32)>, // jumps to trap with the code
+    returns: Vec<usize>, // jumps to the epilogue (eax is set)
+}
+
+fn compile(func: &LocalFunc, module: &Module) -> Result<JitFunc, String> {
+    check_v128(func, module)?;
+    let mut locals = Vec::new();
+    for v in &func.locals {
+        match Value::default_of(v.0) {
+            Some(value) => locals.push(to_slot(&value)),
+            None => return Err(format!("local of {} is not supported", v)),
+        }
+    }
+    let params = func.ft.input.0.len();
+    let mut c = Compiler {
+        module,
+        asm: Asm::default(),
+        ctrls: vec![Ctrl::new(false, 0, 0, func.ft.output.0.len())], // function body
+        nlocals: params + locals.len(),
+        height: 0,
+        max_height: 0,
+        traps: Vec::new(),
+        returns: Vec::new(),
+    };
+    c.prologue();
+    for (ip, inst) in func.insts.iter().enumerate() {
+        c.inst(inst).map_err(|err| format!("inst[{}] {}: {}", ip, inst.get_mnemonic(), err))?;
+    }
+    c.epilogue();
+    let mem = ExecMem::new(&c.asm.code)?;
+    let entry: NativeFn = unsafe { std::mem::transmute::<*mut c_void, NativeFn>(mem.ptr) };
+    Ok(JitFunc {
+        _mem: mem,
+        entry,
+        params,
+        frame_size: c.nlocals + c.max_height,
+        locals,
+    })
+}
+
+impl Compiler<'_> {
+    fn prologue(&mut self) {
+        for r in [RBX, R12, R13, R14, R15] {
+            self.asm.push(r);
+        }
+        self.asm.mov_rr(R14, RDI);
+        self.asm.mov_rr(RBX, RSI);
+        self.reload_memory();
+    }
+
+    // the return code is in eax
+    fn epilogue(&mut self) {
+        let mut stubs: Vec<(u32, usize)> = Vec::new();
+        for (pos, code) in std::mem::take(&mut self.traps) {
+            let stub = match stubs.iter().find(|(c, _)| *c == code) {
+                Some((_, stub)) => *stub,
+                None => {
+                    let stub = self.asm.code.len();
+                    self.asm.mov_imm(RAX, code as u64);
+                    let j = self.asm.jmp();
+                    self.returns.push(j);
+                    stubs.push((code, stub));
+                    stub
+                },
+            };
+            self.asm.patch(pos, stub);
+        }
+        for pos in std::mem::take(&mut self.returns) {
+            self.asm.bind(pos);
+        }
+        for r in [R15, R14, R13, R12, RBX] {
+            self.asm.pop(r);
+        }
+        self.asm.byte(0xc3); // ret
+    }
+
+    fn reload_memory(&mut self) {
+        self.asm.rm(true, &[0x8b], R12, R14, 0);
+        self.asm.rm(true, &[0x8b], R13, R14, 8);
+    }
+
+    fn trap_if(&mut self, cc: u8, code: u32) {
+        let pos = self.asm.jcc(cc);
+        self.traps.push((pos, code));
+    }
+
+    // return if eax (the return code of a helper) is not 0
+    fn check_result(&mut self) {
+        self.asm.test32(RAX);
+        let pos = self.asm.jcc(CC_NE);
+        self.returns.push(pos);
+    }
+
+    // slot of the value n from the top (0 is the top)
+    fn top(&self, n: usize) -> usize {
+        self.nlocals + self.height - 1 - n
+    }
+
+    fn push(&mut self, n: usize) {
+        self.height += n;
+        self.max_height = self.max_height.max(self.height);
+    }
+
+    fn pop(&mut self, n: usize) -> Result<(), String> {
+        self.height = self.height.checked_sub(n).ok_or("operand stack underflow")?;
+        Ok(())
+    }
+
+    fn ctrl(&mut self) -> Result<&mut Ctrl, String> {
+        self.ctrls.last_mut().ok_or("after the end of the function".to_string())
+    }
+
+    fn set_unreachable(&mut self) -> Result<(), String> {
+        self.ctrl()?.unreachable = true;
+        Ok(())
+    }
+
+    fn block_type(&self, bt: &BlockType) -> (usize, usize) {
+        match bt {
+            BlockType::Empty => (0, 0),
+            BlockType::Valtype(_) => (0, 1),
+            BlockType::TypeIndex(idx) => self.module.types().get(*idx as usize)
+                .map_or((0, 0), |ft| (ft.input.0.len(), ft.output.0.len())),
+        }
+    }
+
+    // move the values carried to label depth and jump there
+    fn br(&mut self, depth: u32) -> Result<(), String> {
+        let depth = depth as usize;
+        if depth >= self.ctrls.len() {
+            return Err(format!("unknown label {}", depth));
+        }
+        let i = self.ctrls.len() - 1 - depth;
+        let label = &self.ctrls[i];
+        let keep = if label.is_loop { label.params } else { label.results };
+        let dst = self.nlocals + label.height;
+        let src = (self.nlocals + self.height).checked_sub(keep).ok_or("operand stack underflow")?;
+        if src < dst {
+            return Err("operand stack underflow".to_string());
+        }
+        let (is_loop, start) = (label.is_loop, label.start);
+        if src != dst {
+            for k in 0..keep {
+                self.asm.load(RAX, src + k);
+                self.asm.store(dst + k, RAX);
+            }
+        }
+        if is_loop {
+            self.asm.jmp_to(start);
+        } else {
+            let pos = self.asm.jmp();
+            self.ctrls[i].fixups.push(pos);
+        }
+        Ok(())
+    }
+
+    // copy the results to the slots of the args and return
+    fn ret(&mut self) {
+        let n = self.ctrls[0].results;
+        for k in 0..n {
+            self.asm.load(RAX, self.nlocals + self.height - n + k);
+            self.asm.store(k, RAX);
+        }
+        self.asm.rr(false, &[0x31], RAX, RAX); // xor eax, eax
+        let pos = self.asm.jmp();
+        self.returns.push(pos);
+    }
+
+    // eax = address of the access of width bytes at the top + offset. traps if out of bounds
+    fn mem_addr(&mut self, slot: usize, offset: u32, width: u32) {
+        self.asm.load32(RAX, slot);
+        if offset != 0 {
+            self.asm.mov_imm(RCX, offset as u64);
+            self.asm.rr(true, &[0x01], RCX, RAX); // add rax, rcx
+        }
+        self.asm.rm(true, &[0x8d], RDX, RAX, width as i32); // lea rdx, [rax + width]
+        self.asm.rr(true, &[0x39], R13, RDX); // cmp rdx, r13
+        self.trap_if(CC_A, TRAP_MEMORY);
+    }
+
+    fn call_helper(&mut self, f: *const ()) {
+        self.asm.call(f as usize);
+    }
+
+    fn inst(&mut self, inst: &Inst) -> Result<(), String> {
+        let (unreachable, dead) = {
+            let ctrl = self.ctrl()?;
+            (ctrl.unreachable, ctrl.dead)
+        };
+        if unreachable {
+            // skip to the else or end of the block
+            match inst.op_code {
+                0x02..=0x04 => {
+                    let mut ctrl = Ctrl::new(false, 0, 0, 0);
+                    ctrl.unreachable = true;
+                    ctrl.dead = true;
+                    self.ctrls.push(ctrl);
+                    return Ok(());
+                },
+                0x05 | 0x0b if !dead => (),
+                0x0b => {
+                    self.ctrls.pop();
+                    return Ok(());
+                },
+                _ => return Ok(()),
+            }
+        }
+        match (inst.op_code, &inst.operand) {
+            (0x00, _) => { // unreachable
+                let pos = self.asm.jmp();
+                self.traps.push((pos, TRAP_UNREACHABLE));
+                self.set_unreachable()?;
+            },
+            (0x01, _) => (), // nop
+            (0x02..=0x04, Operand::BlockType(bt)) => { // block, loop, if
+                let (params, results) = self.block_type(bt);
+                if inst.op_code == 0x04 {
+                    self.asm.load32(RAX, self.top(0));
+                    self.pop(1)?;
+                }
+                let height = self.height.checked_sub(params).ok_or("operand stack underflow")?;
+                let mut ctrl = Ctrl::new(inst.op_code == 0x03, height, params, results);
+                ctrl.start = self.asm.code.len();
+                if inst.op_code == 0x04 {
+                    self.asm.test32(RAX);
+                    ctrl.else_fixup = Some(self.asm.jcc(CC_E));
+                }
+                self.ctrls.push(ctrl);
+            },
+            (0x05, _) => { // else
+                if !self.ctrl()?.unreachable {
+                    self.br(0)?;
+                }
+                let pos = self.asm.code.len();
+                let ctrl = self.ctrl()?;
+                let else_fixup = ctrl.else_fixup.take();
+                ctrl.unreachable = false;
+                self.height = ctrl.height + ctrl.params;
+                if let Some(j) = else_fixup {
+                    self.asm.patch(j, pos);
+                }
+            },
+            (0x0b, _) => { // end
+                let ctrl = self.ctrls.pop().ok_or("after the end of the function")?;
+                for j in ctrl.fixups.iter().chain(ctrl.else_fixup.iter()) {
+                    self.asm.bind(*j);
+                }
+                self.height = ctrl.height + ctrl.results;
+                self.max_height = self.max_height.max(self.height);
+                if self.ctrls.is_empty() {
+                    self.ctrls.push(ctrl); // ret() needs the results of the body
+                    self.ret();
+                    self.ctrls.pop();
+                }
+            },
+            (0x0c, Operand::Index(n)) => { // br
+                self.br(*n)?;
+                self.set_unreachable()?;
+            },
+            (0x0d, Operand::Index(n)) => { // br_if
+                self.asm.load32(RAX, self.top(0));
+                self.pop(1)?;
+                self.asm.test32(RAX);
+                let skip = self.asm.jcc(CC_E);
+                self.br(*n)?;
+                self.asm.bind(skip);
+            },
+            (0x0e, Operand::BrTable(table)) => { // br_table
+                self.asm.load32(RDX, self.top(0));
+                self.pop(1)?;
+                for (k, n) in table.labels.iter().enumerate() {
+                    self.asm.cmp_imm(false, RDX, k as u32);
+                    let next = self.asm.jcc(CC_NE);
+                    self.br(*n)?;
+                    self.asm.bind(next);
+                }
+                self.br(table.default)?;
+                self.set_unreachable()?;
+            },
+            (0x0f, _) => { // return
+                self.ret();
+                self.set_unreachable()?;
+            },
+            (0x10, Operand::Index(idx)) => { // call
+                let ft = func_type(*idx as usize, self.module).ok_or("unknown function")?;
+                let (params, results) = (ft.input.0.len(), ft.output.0.len());
+                self.pop(params)?;
+                self.asm.mov_rr(RDI, R14);
+                self.asm.mov_imm(RSI, *idx as u64);
+                self.asm.lea_slot(RDX, self.nlocals + self.height);
+                self.call_helper(jit_call as *const ());
+                self.check_result();
+                self.reload_memory();
+                self.push(results);
+            },
+            (0x11, Operand::Index2(tableidx, typeidx)) => { // call_indirect
+                let ft = self.module.types().get(*typeidx as usize).ok_or("unknown type")?;
+                let (params, results) = (ft.input.0.len(), ft.output.0.len());
+                self.asm.load32(RCX, self.top(0));
+                self.pop(1 + params)?;
+                self.asm.mov_rr(RDI, R14);
+                self.asm.mov_imm(RSI, *tableidx as u64);
+                self.asm.mov_imm(RDX, *typeidx as u64);
+                self.asm.lea_slot(R8, self.nlocals + self.height);
+                self.call_helper(jit_call_indirect as *const ());
+                self.check_result();
+                self.reload_memory();
+                self.push(results);
+            },
+            (0x1a, _) => self.pop(1)?, // drop
+            (0x1b | 0x1c, _) => { // select
+                self.asm.load32(RAX, self.top(0));
+                self.asm.load(RCX, self.top(2));
+                self.asm.load(RDX, self.top(1));
+                self.asm.test32(RAX);
+                self.asm.rr(true, &[0x0f, 0x44], RCX, RDX); // cmovz rcx, rdx
+                self.pop(2)?;
+                self.asm.store(self.top(0), RCX);
+            },
+            (0x20, Operand::Index(i)) => { // local.get
+                self.push(1);
+                self.asm.load(RAX, *i as usize);
+                self.asm.store(self.top(0), RAX);
+            },
+            (0x21 | 0x22, Operand::Index(i)) => { // local.set, local.tee
+                self.asm.load(RAX, self.top(0));
+                self.asm.store(*i as usize, RAX);
+                if inst.op_code == 0x21 {
+                    self.pop(1)?;
+                }
+            },
+            (0x23, Operand::Index(i)) => { // global.get
+                self.push(1);
+                self.asm.mov_rr(RDI, R14);
+                self.asm.mov_imm(RSI, *i as u64);
+                self.call_helper(jit_global_get as *const ());
+                self.asm.store(self.top(0), RAX);
+            },
+            (0x24, Operand::Index(i)) => { // global.set
+                self.asm.mov_rr(RDI, R14);
+                self.asm.mov_imm(RSI, *i as u64);
+                self.asm.load(RDX, self.top(0));
+                self.call_helper(jit_global_set as *const ());
+                self.pop(1)?;
+            },
+            (0x28..=0x35, Operand::Memarg(m)) => self.load(inst.op_code, m.offset),
+            (0x36..=0x3e, Operand::Memarg(m)) => self.store(inst.op_code, m.offset)?,
+            (0x3f, _) => { // memory.size
+                self.push(1);
+                self.asm.mov_rr(RAX, R13);
+                self.asm.rr(true, &[0xc1], 5, RAX); // shr rax, 16
+                self.asm.byte(16);
+                self.asm.store(self.top(0), RAX);
+            },
+            (0x40, _) => { // memory.grow
+                self.asm.mov_rr(RDI, R14);
+                self.asm.load32(RSI, self.top(0));
+                self.call_helper(jit_memory_grow as *const ());
+                self.asm.store(self.top(0), RAX);
+                self.reload_memory();
+            },
+            (0x41..=0x44, _) => { // const
+                let n = match inst.operand {
+                    Operand::I32(n) => n as u32 as u64,
+                    Operand::I64(n) => n as u64,
+                    Operand::F32(z) => z.to_bits() as u64,
+                    Operand::F64(z) => z.to_bits(),
+                    _ => 0,
+                };
+                self.push(1);
+                self.asm.mov_imm(RAX, n);
+                self.asm.store(self.top(0), RAX);
+            },
+            (0x45 | 0x50, _) => { // eqz
+                self.asm.load(RAX, self.top(0));
+                self.asm.rr(inst.op_code == 0x50, &[0x85], RAX, RAX);
+                self.asm.setcc(CC_E);
+                self.asm.store(self.top(0), RAX);
+            },
+            (0x46..=0x4f | 0x51..=0x5a, _) => self.compare(inst.op_code)?,
+            (0x6a..=0x6c | 0x71..=0x78 | 0x7c..=0x7e | 0x83..=0x8a, _) => self.arith(inst.op_code)?,
+            (0x6d..=0x70 | 0x7f..=0x82, _) => self.divide(inst.op_code)?,
+            (0xa7 | 0xad, _) => { // i32.wrap_i64, i64.extend_i32_u
+                self.asm.load32(RAX, self.top(0));
+                self.asm.store(self.top(0), RAX);
+            },
+            (0xac, _) => { // i64.extend_i32_s
+                self.asm.rm(true, &[0x63], RAX, RBX, self.top(0) as i32 * 8); // movsxd
+                self.asm.store(self.top(0), RAX);
+            },
+            (0xfc, _) if inst.sub_op == 10 || inst.sub_op == 11 => { // memory.copy, memory.fill
+                self.asm.mov_rr(RDI, R14);
+                self.asm.load32(RSI, self.top(2));
+                self.asm.load32(RDX, self.top(1));
+                self.asm.load32(RCX, self.top(0));
+                let f = match inst.sub_op {
+                    10 => jit_memory_copy as *const (),
+                    _ => jit_memory_fill as *const (),
+                };
+                self.call_helper(f);
+                self.check_result();
+                self.pop(3)?;
+            },
+            _ => {
+                if let Some(f) = unop(inst) {
+                    self.asm.mov_imm(RDI, f as *const () as u64);
+                    self.asm.load(RSI, self.top(0));
+                    self.call_helper(jit_unop as *const ());
+                    self.asm.store(self.top(0), RAX);
+                } else if let Some(f) = unop_trap(inst.op_code) {
+                    self.asm.mov_rr(RDI, R14);
+                    self.asm.mov_imm(RSI, f as *const () as u64);
+                    self.asm.lea_slot(RDX, self.top(0));
+                    self.call_helper(jit_unop_trap as *const ());
+                    self.check_result();
+                } else if let Some(f) = binop(inst.op_code) {
+                    self.asm.mov_imm(RDI, f as *const () as u64);
+                    self.asm.load(RSI, self.top(1));
+                    self.asm.load(RDX, self.top(0));
+                    self.call_helper(jit_binop as *const ());
+                    self.pop(1)?;
+                    self.asm.store(self.top(0), RAX);
+                } else {
+                    return Err("not supported".to_string());
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, op_code: u8, offset: u32) {
+        let (width, op, w): (u32, &[u8], bool) = match op_code {
+            0x29 | 0x2b => (8, &[0x8b], true), // i64.load, f64.load
+            0x2c => (1, &[0x0f, 0xbe], false), // i32.load8_s
+            0x2d | 0x31 => (1, &[0x0f, 0xb6], false), // load8_u
+            0x2e => (2, &[0x0f, 0xbf], false), // i32.load16_s
+            0x2f | 0x33 => (2, &[0x0f, 0xb7], false), // load16_u
+            0x30 => (1, &[0x0f, 0xbe], true), // i64.load8_s
+            0x32 => (2, &[0x0f, 0xbf], true), // i64.load16_s
+            0x34 => (4, &[0x63], true), // i64.load32_s
+            _ => (4, &[0x8b], false), // i32.load, f32.load, i64.load32_u
+        };
+        self.mem_addr(self.top(0), offset, width);
+        self.asm.rmi(w, op, RAX, R12, RAX);
+        self.asm.store(self.top(0), RAX);
+    }
+
+    fn store(&mut self, op_code: u8, offset: u32) -> Result<(), String> {
+        let width = match op_code {
+            0x3a | 0x3c => 1,
+            0x3b | 0x3d => 2,
+            0x37 | 0x39 => 8,
+            _ => 4,
+        };
+        self.mem_addr(self.top(1), offset, width);
+        self.asm.load(RCX, self.top(0));
+        match width {
+            1 => self.asm.rmi(false, &[0x88], RCX, R12, RAX),
+            2 => {
+                self.asm.byte(0x66);
+                self.asm.rmi(false, &[0x89], RCX, R12, RAX);
+            },
+            4 => self.asm.rmi(false, &[0x89], RCX, R12, RAX),
+            _ => self.asm.rmi(true, &[0x89], RCX, R12, RAX),
+        }
+        self.pop(2)
+    }
+
+    // i32/i64 comparisons
+    fn compare(&mut self, op_code: u8) -> Result<(), String> {
+        let w = op_code >= 0x51;
+        let cc = match op_code - if w { 0x51 } else { 0x46 } {
+            0 => CC_E,
+            1 => CC_NE,
+            2 => CC_L,
+            3 => CC_B,
+            4 => CC_G,
+            5 => CC_A,
+            6 => CC_LE,
+            7 => CC_BE,
+            8 => CC_GE,
+            _ => CC_AE,
+        };
+        self.asm.load(RAX, self.top(1));
+        self.asm.load(RCX, self.top(0));
+        self.asm.rr(w, &[0x39], RCX, RAX); // cmp rax, rcx
+        self.asm.setcc(cc);
+        self.pop(1)?;
+        self.asm.store(self.top(0), RAX);
+        Ok(())
+    }
+
+    // i32/i64 add, sub, mul, and, or, xor, shifts and rotates
+    fn arith(&mut self, op_code: u8) -> Result<(), String> {
+        let w = op_code >= 0x7c;
+        self.asm.load(RAX, self.top(1));
+        self.asm.load(RCX, self.top(0));
+        match op_code - if w { 0x7c } else { 0x6a } {
+            0 => self.asm.rr(w, &[0x01], RCX, RAX), // add
+            1 => self.asm.rr(w, &[0x29], RCX, RAX), // sub
+            2 => self.asm.rr(w, &[0x0f, 0xaf], RAX, RCX), // imul
+            7 => self.asm.rr(w, &[0x21], RCX, RAX), // and
+            8 => self.asm.rr(w, &[0x09], RCX, RAX), // or
+            9 => self.asm.rr(w, &[0x31], RCX, RAX), // xor
+            10 => self.asm.rr(w, &[0xd3], 4, RAX), // shl
+            11 => self.asm.rr(w, &[0xd3], 7, RAX), // sar
+            12 => self.asm.rr(w, &[0xd3], 5, RAX), // shr
+            13 => self.asm.rr(w, &[0xd3], 0, RAX), // rol
+            _ => self.asm.rr(w, &[0xd3], 1, RAX), // ror
+        }
+        if !w {
+            self.asm.rr(false, &[0x89], RAX, RAX); // mov eax, eax (zero-extend)
+        }
+        self.pop(1)?;
+        self.asm.store(self.top(0), RAX);
+        Ok(())
+    }
+
+    // i32/i64 div_s, div_u, rem_s, rem_u
+    fn divide(&mut self, op_code: u8) -> Result<(), String> {
+        let w = op_code >= 0x7f;
+        let op = op_code - if w { 0x7f } else { 0x6d };
+        self.asm.load(RAX, self.top(1));
+        self.asm.load(RCX, self.top(0));
+        self.asm.rr(w, &[0x85], RCX, RCX); // test rcx, rcx
+        self.trap_if(CC_E, TRAP_DIV_ZERO);
+        let mut done = None;
+        if op == 0 || op == 2 { // signed
+            self.asm.cmp_imm(w, RCX, u32::MAX); // -1
+            let not_minus1 = self.asm.jcc(CC_NE);
+            if op == 0 { // div_s: MIN / -1 overflows
+                if w {
+                    self.asm.mov_imm(RDX, i64::MIN as u64);
+                    self.asm.rr(true, &[0x39], RDX, RAX); // cmp rax, rdx
+                } else {
+                    self.asm.cmp_imm(false, RAX, i32::MIN as u32);
+                }
+                self.trap_if(CC_E, TRAP_OVERFLOW);
+            } else { // rem_s: x % -1 is 0
+                self.asm.rr(false, &[0x31], RAX, RAX);
+                done = Some(self.asm.jmp());
+            }
+            self.asm.bind(not_minus1);
+            if w {
+                self.asm.bytes(&[0x48, 0x99]); // cqo
+            } else {
+                self.asm.byte(0x99); // cdq
+            }
+            self.asm.rr(w, &[0xf7], 7, RCX); // idiv
+        } else {
+            self.asm.rr(false, &[0x31], RDX, RDX); // xor edx, edx
+            self.asm.rr(w, &[0xf7], 6, RCX); // div
+        }
+        if op >= 2 { // rem
+            self.asm.mov_rr(RAX, RDX);
+        }
+        if let Some(j) = done {
+            self.asm.bind(j);
+        }
+        if !w {
+            self.asm.rr(false, &[0x89], RAX, RAX); // mov eax, eax
+        }
+        self.pop(1)?;
+        self.asm.store(self.top(0), RAX);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    const SRC: &str = r#"
+      (memory 1)
+      (func (export "sum") (param i32) (result i64) (local i64)
+        (block
+          (loop
+            (br_if 1 (i32.eqz (local.get 0)))
+            (local.set 1 (i64.add (local.get 1) (i64.mul (i64.extend_i32_s (local.get 0)) (i64.const 3))))
+            (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+            (br 0)))
+        (local.get 1))
+      (func (export "mem") (param i32) (result i32)
+        (i32.store (i32.const 8) (local.get 0))
+        (i32.store8 (i32.const 9) (i32.const 0xff))
+        (i32.add (i32.load (i32.const 8)) (i32.load16_s (i32.const 10))))
+      (func (export "switch") (param i32) (result i32)
+        (block (block (block
+          (br_table 0 1 2 (local.get 0)))
+          (return (i32.const 10)))
+          (return (select (i32.const 20) (i32.const 21) (local.get 0))))
+        (i32.const 30))
+      (func (export "div") (param i32) (result i32)
+        (i32.div_s (i32.const 100) (local.get 0)))
+      (func (export "float") (param i32) (result f64)
+        (f64.add (f64.sqrt (f64.convert_i32_s (local.get 0)))
+                 (f64.promote_f32 (f32.div (f32.const 1) (f32.convert_i32_u (local.get 0))))))"#;
+
+    #[test]
+    fn same_as_interpreter() {
+        let module = testutil::load(SRC);
+        for threshold in [0, 3] {
+            let jit = Jit::new(&module, threshold);
+            for name in ["fib", "sum", "mem", "switch", "div", "float"] {
+                for arg in [0, 1, 2, 3, 7, -1, i32::MIN] {
+                    let arg = if name == "fib" || name == "sum" { arg.clamp(0, 15) } else { arg };
+                    let args = [Value::I32(arg)];
+                    let result = testutil::call(&module, name, &args, None, &|idx, args, module, store| {
+                        jit.invoke(idx, args, module, store)
+                    });
+                    let expected = testutil::call(&module, name, &args, None, &invoke);
+                    assert_eq!(result, expected, "{}({}) threshold {}", name, arg, threshold);
+                }
+            }
+            assert_eq!(jit.compiled().len(), 6);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// wasmex library: decoder, validator and a small interpreter of
+// WebAssembly modules (binary or text format). the wasmex command is built on top of this.
+
+pub mod bytecode;
+pub mod cgen;
+pub mod debug;
+pub mod exec;
+pub mod inst;
+pub mod ir;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod linker;
+pub mod module;
+pub mod names;
+pub mod simd;
+#[cfg(test)]
+mod testutil;
+pub mod validate;
+pub mod wasi;
+pub mod wast;
+pub mod wat;
+pub mod watparse;
+
+pub use bytecode::{DecodeError, Expr, Functype, Globaltype, Limits, Resulttype, Tabletype, Valtype};
+pub use cgen::{module_to_c, CSource};
+pub use debug::Debugger;
+pub use exec::{exec_func, invoke, invoke_str, make_store, make_store_with_limits, resolve_func, Frame, Function, GlobalInst,
+              GlobalRef, HostFunc, ImportFunc, Invocation, LocalFunc, MemInst, MemoryRef, Store, StoreLimits, TableInst,
+              TableRef, Trace, Value, OUT_OF_FUEL};
+pub use inst::{BlockType, BrTable, Inst, Memarg, Operand};
+pub use ir::IrModule;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub use jit::Jit;
+pub use linker::Linker;
+pub use module::{init_module, Module};
+pub use names::Names;
+pub use validate::{validate_module, ValidateError};
+pub use wasi::Wasi;
+pub use wast::{run_wast, WastSummary};
+pub use wat::module_to_wat;
+pub use watparse::{parse_wat, wat2wasm, WatError};
@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// registry of host functions (and globals, memories and tables) which are
+// provided for the imports of a module.
+//
+//   let mut linker = Linker::new();
+//   linker.func("env", "log", &[0x7f], &[], |_store, args| {
+//       println!("log: {}", args[0]);
+//       Ok(vec![])
+//   });
+//   let mut store = linker.instantiate(&module)?;
+//
+// the memory, globals and tables of an instance can be given by their handles
+// (Store::memory_ref() etc.) to be shared with the importing module.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::bytecode::*;
+use crate::exec::*;
+use crate::module::*;
+
+enum Extern {
+    Func(Functype, HostFunc),
+    Global(GlobalRef),
+    Memory(Limits), // allocated at each instantiation
+    Table(u8, Limits), // reftype, limits. allocated at each instantiation
+    SharedMemory(MemoryRef),
+    SharedTable(TableRef),
+}
+
+#[derive(Default)]
+pub struct Linker {
+    externs: HashMap<(String, String), Extern>,
+    limits: StoreLimits,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+
+    fn define(&mut self, module: &str, name: &str, item: Extern) -> &mut Linker {
+        self.externs.insert((module.to_string(), name.to_string()), item);
+        self
+    }
+
+    // register a host function for import (module, name).
+    // params and results are valtypes (ex. 0x7f for i32).
+    pub fn func<F>(&mut self, module: &str, name: &str, params: &[u8], results: &[u8], f: F) -> &mut Linker
+    where
+        F: Fn(&mut Store, &[Value]) -> Result<Vec<Value>, String> + 'static,
+    {
+        let ft = Functype {
+            input: Resulttype(params.iter().map(|t| Valtype(*t)).collect()),
+            output: Resulttype(results.iter().map(|t| Valtype(*t)).collect()),
+        };
+        self.define(module, name, Extern::Func(ft, Rc::new(f)))
+    }
+
+    // a new global. it's shared by the modules instantiated by this linker.
+    pub fn global(&mut self, module: &str, name: &str, value: Value, mutable: bool) -> &mut Linker {
+        self.define(module, name, Extern::Global(GlobalInst::new(value, mutable)))
+    }
+
+    // an imported memory is allocated by the limits
+    pub fn memory(&mut self, module: &str, name: &str, limits: Limits) -> &mut Linker {
+        self.define(module, name, Extern::Memory(limits))
+    }
+
+    pub fn table(&mut self, module: &str, name: &str, reftype: u8, limits: Limits) -> &mut Linker {
+        self.define(module, name, Extern::Table(reftype, limits))
+    }
+
+    // instances of another store (ex. Store::memory_ref()). the importer uses them, not copies.
+    pub fn shared_global(&mut self, module: &str, name: &str, global: GlobalRef) -> &mut Linker {
+        self.define(module, name, Extern::Global(global))
+    }
+
+    pub fn shared_memory(&mut self, module: &str, name: &str, memory: MemoryRef) -> &mut Linker {
+        self.define(module, name, Extern::SharedMemory(memory))
+    }
+
+    pub fn shared_table(&mut self, module: &str, name: &str, table: TableRef) -> &mut Linker {
+        self.define(module, name, Extern::SharedTable(table))
+    }
+
+    // limits of the stores instantiated by this linker
+    pub fn limits(&mut self, limits: StoreLimits) -> &mut Linker {
+        self.limits = limits;
+        self
+    }
+
+    pub fn has_func(&self, module: &str, name: &str) -> bool {
+        matches!(self.externs.get(&(module.to_string(), name.to_string())), Some(Extern::Func(..)))
+    }
+
+    // instantiate the module. all imports must be provided with the matching type.
+    pub fn instantiate(&self, module: &Module) -> Result<Store, String> {
+        let mut imports = Imports {
+            funcs: Vec::new(),
+            globals: Vec::new(),
+            mems: Vec::new(),
+            tables: Vec::new(),
+            limits: self.limits.clone(),
+        };
+        for import in module.imports() {
+            let key = (import.module().to_string(), import.name().to_string());
+            let item = match self.externs.get(&key) {
+                Some(item) => item,
+                None => return Err(format!("unknown import: {}.{}", key.0, key.1)),
+            };
+            let matched = match (import.desc(), item) {
+                (Importdesc::Func(typeidx), Extern::Func(ft, f)) => {
+                    imports.funcs.push(Some(f.clone()));
+                    module.types().get(*typeidx as usize) == Some(ft)
+                },
+                (Importdesc::Global(gt), Extern::Global(global)) => {
+                    imports.globals.push(global.clone());
+                    let g = global.borrow();
+                    g.value().valtype() == Some(gt.valtype()) && g.is_mutable() == gt.is_mutable()
+                },
+                (Importdesc::Mem(l), Extern::Memory(limits)) => {
+                    imports.mems.push(MemInst::new(limits, self.limits.max_memory_pages)?);
+                    limits.matches(l)
+                },
+                (Importdesc::Mem(l), Extern::SharedMemory(memory)) => {
+                    imports.mems.push(memory.clone());
+                    memory.borrow().limits().matches(l)
+                },
+                (Importdesc::Table(tt), Extern::Table(reftype, limits)) => {
+                    imports.tables.push(TableInst::new(*reftype, limits, self.limits.max_table_elems)?);
+                    *reftype == tt.reftype() && limits.matches(tt.limits())
+                },
+                (Importdesc::Table(tt), Extern::SharedTable(table)) => {
+                    imports.tables.push(table.clone());
+                    let (reftype, limits) = table.borrow().tabletype();
+                    reftype == tt.reftype() && limits.matches(tt.limits())
+                },
+                _ => false,
+            };
+            if !matched {
+                return Err(format!("incompatible import type: {}.{}", key.0, key.1));
+            }
+        }
+        new_store(module, imports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const SRC: &str = r#"(module
+      (import "env" "log" (func $log (param i32 i32)))
+      (import "env" "add" (func $add (param i64 i64) (result i64)))
+      (memory (export "memory") 1)
+      (data (i32.const 16) "hello")
+      (func (export "hello") (call $log (i32.const 16) (i32.const 5)))
+      (func (export "add") (param i64) (result i64) (call $add (local.get 0) (i64.const 1))))"#;
+
+    fn load() -> Module {
+        let mut module = crate::parse_wat(SRC).unwrap();
+        module.validate().unwrap();
+        module
+    }
+
+    fn env(add: impl Fn(&[Value]) -> Result<Vec<Value>, String> + 'static) -> (Linker, Rc<RefCell<String>>) {
+        let logged = Rc::new(RefCell::new(String::new()));
+        let out = logged.clone();
+        let mut linker = Linker::new();
+        linker.func("env", "log", &[0x7f, 0x7f], &[], move |store, args| {
+            let (Value::I32(p), Value::I32(n)) = (&args[0], &args[1]) else { unreachable!() };
+            let mem = store.memory();
+            out.borrow_mut().push_str(std::str::from_utf8(&mem[*p as usize..(*p + *n) as usize]).unwrap());
+            Ok(vec![])
+        });
+        linker.func("env", "add", &[0x7e, 0x7e], &[0x7e], move |_, args| add(args));
+        (linker, logged)
+    }
+
+    fn call(linker: &Linker, name: &str, args: &[Value]) -> Result<Vec<Value>, String> {
+        let module = load();
+        let mut store = linker.instantiate(&module)?;
+        invoke(resolve_func(name, &module).unwrap(), args, &module, &mut store)
+    }
+
+    #[test]
+    fn host_funcs() {
+        let (linker, logged) = env(|args| match args {
+            [Value::I64(a), Value::I64(b)] => Ok(vec![Value::I64(a + b)]),
+            _ => Err("trap: bad args".to_string()),
+        });
+        assert_eq!(call(&linker, "hello", &[]), Ok(vec![]));
+        assert_eq!(*logged.borrow(), "hello");
+        assert_eq!(call(&linker, "add", &[Value::I64(41)]), Ok(vec![Value::I64(42)]));
+    }
+
+    #[test]
+    fn host_traps() {
+        let (linker, _) = env(|_| Err("trap: from host".to_string()));
+        assert_eq!(call(&linker, "add", &[Value::I64(1)]).unwrap_err(), "trap: from host");
+        // results must match the signature
+        let (linker, _) = env(|_| Ok(vec![Value::I32(1)]));
+        assert!(call(&linker, "add", &[Value::I64(1)]).unwrap_err().contains("returned wrong results"));
+        let (linker, _) = env(|_| Ok(vec![]));
+        assert!(call(&linker, "add", &[Value::I64(1)]).unwrap_err().contains("returned wrong results"));
+    }
+
+    #[test]
+    fn import_errors() {
+        let module = load();
+        let mut linker = Linker::new();
+        linker.func("env", "log", &[0x7f, 0x7f], &[], |_, _| Ok(vec![]));
+        assert_eq!(linker.instantiate(&module).err().unwrap(), "unknown import: env.add");
+        linker.func("env", "add", &[0x7e], &[0x7e], |_, _| Ok(vec![]));
+        assert_eq!(linker.instantiate(&module).err().unwrap(), "incompatible import type: env.add");
+        linker.global("env", "add", Value::I64(0), false);
+        assert_eq!(linker.instantiate(&module).err().unwrap(), "incompatible import type: env.add");
+        assert!(linker.has_func("env", "log"));
+        assert!(!linker.has_func("env", "add"));
+    }
+
+    #[test]
+    fn shared_instances() {
+        let load = |src: &str| {
+            let mut module = crate::parse_wat(src).unwrap();
+            module.validate().unwrap();
+            module
+        };
+        let exporter = load(r#"(module
+          (memory (export "mem") 1 3)
+          (global (export "g") (mut i32) (i32.const 0))
+          (table (export "t") 2 externref)
+          (table (export "f") 1 funcref)
+          (func $seven (result i32) (i32.const 7))
+          (elem (table 1) (i32.const 0) func $seven)
+          (func (export "load") (result i32) (i32.load (i32.const 8)))
+          (func (export "size") (result i32) (memory.size))
+          (func (export "get") (result i32) (global.get 0))
+          (func (export "elem") (result externref) (table.get 0 (i32.const 1)))
+          (func (export "call") (result i32) (call_indirect 1 (result i32) (i32.const 0))))"#);
+        let importer = load(r#"(module
+          (import "m" "mem" (memory 1))
+          (import "m" "g" (global (mut i32)))
+          (import "m" "t" (table 1 externref))
+          (import "m" "f" (table 1 funcref))
+          (func $nine (result i32) (i32.const 9))
+          (elem declare func $nine)
+          (func (export "write") (param externref)
+            (i32.store (i32.const 8) (i32.const 42))
+            (drop (memory.grow (i32.const 1)))
+            (global.set 0 (i32.const 9))
+            (table.set 0 (i32.const 1) (local.get 0)))
+          (func (export "call") (result i32) (call_indirect 1 (result i32) (i32.const 0)))
+          (func (export "replace") (table.set 1 (i32.const 0) (ref.func $nine))))"#);
+        let mut exp_store = make_store(&exporter).unwrap();
+        let mut linker = Linker::new();
+        linker.shared_memory("m", "mem", exp_store.memory_ref())
+            .shared_global("m", "g", exp_store.global_ref(0).unwrap())
+            .shared_table("m", "t", exp_store.table_ref(0).unwrap())
+            .shared_table("m", "f", exp_store.table_ref(1).unwrap());
+        let mut imp_store = linker.instantiate(&importer).unwrap();
+        let mut exp = |name: &str| invoke(resolve_func(name, &exporter).unwrap(), &[], &exporter, &mut exp_store);
+        let mut imp = |name: &str, args: &[Value]| invoke(resolve_func(name, &importer).unwrap(), args, &importer,
+                                                          &mut imp_store);
+
+        assert_eq!(imp("write", &[Value::ExternRef(Some(5))]), Ok(vec![]));
+        assert_eq!(exp("load"), Ok(vec![Value::I32(42)]));
+        assert_eq!(exp("size"), Ok(vec![Value::I32(2)]));
+        assert_eq!(exp("get"), Ok(vec![Value::I32(9)]));
+        assert_eq!(exp("elem"), Ok(vec![Value::ExternRef(Some(5))]));
+        // a funcref is the function of the module which stored it
+        assert_eq!(exp("call"), Ok(vec![Value::I32(7)]));
+        assert!(imp("call", &[]).unwrap_err().contains("another instance"));
+        assert_eq!(imp("replace", &[]), Ok(vec![]));
+        assert!(exp("call").unwrap_err().contains("another instance"));
+
+        // the import type is checked against the current size
+        let mut linker = Linker::new();
+        linker.shared_memory("m", "mem", exp_store.memory_ref())
+            .shared_global("m", "g", exp_store.global_ref(0).unwrap())
+            .shared_table("m", "t", exp_store.table_ref(0).unwrap())
+            .shared_table("m", "f", exp_store.table_ref(0).unwrap());
+        assert_eq!(linker.instantiate(&importer).err().unwrap(), "incompatible import type: m.f");
+    }
+}
@@ -2,21 +2,22 @@
 // Copyright(c) 2023 Itsuro Oda
 // https://opensource.org/license/mit/
 
-#![allow(dead_code)]
-
 use std::{fs, io, process};
+use std::path::Path;
 use std::io::Write;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-mod bytecode;
-mod exec;
-mod inst;
-mod module;
+use wasmex::{cgen, debug, exec, ir, module, wasi, wast, wat, watparse};
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// path of wasm binary module
-    path: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// path of wasm module (binary, or text format if it ends with .wat)
+    #[arg(required = true)]
+    path: Option<String>,
 
     /// show section detail
     #[arg(short)]
@@ -29,26 +30,374 @@ struct Args {
     /// interactive (typically to use to exec functions)
     #[arg(short)]
     intr: bool,
+
+    /// validate the module
+    #[arg(short)]
+    validate: bool,
+
+    /// print the module in the text format (wat)
+    #[arg(short)]
+    wat: bool,
+
+    /// print folded instructions with -w
+    #[arg(long)]
+    folded: bool,
+
+    /// write the module in the binary format to the file
+    #[arg(short)]
+    output: Option<String>,
+
+    /// accept the relaxed SIMD instructions
+    #[arg(long)]
+    relaxed_simd: bool,
+
+    /// translate the module to C source and write it to BASE.c and BASE.h
+    #[arg(long, value_name = "BASE")]
+    emit_c: Option<String>,
+
+    /// call the function (export name, $name or funcidx) with args, print the results and exit
+    #[arg(long, num_args = 1.., allow_negative_numbers = true, value_name = "NAME ARGS")]
+    invoke: Option<Vec<String>>,
+
+    /// execute --invoke with the compact IR (faster, without trace)
+    #[arg(long, requires = "invoke")]
+    ir: bool,
+
+    /// execute --invoke with the JIT compiler (hot functions are compiled to native code)
+    #[cfg(feature = "jit")]
+    #[arg(long, requires = "invoke", conflicts_with = "ir")]
+    jit: bool,
+
+    /// number of calls before a function is compiled with --jit
+    #[cfg(feature = "jit")]
+    #[arg(long, requires = "jit", value_name = "N", default_value_t = wasmex::jit::JIT_THRESHOLD)]
+    jit_threshold: u32,
+
+    #[command(flatten)]
+    trace: TraceArgs,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+}
+
+#[derive(clap::Args)]
+struct LimitArgs {
+    /// trap with "out of fuel" after executing N instructions
+    #[arg(long, value_name = "N")]
+    fuel: Option<u64>,
+
+    /// maximum depth of function calls
+    #[arg(long, value_name = "N")]
+    max_call_depth: Option<usize>,
+
+    /// maximum number of values on the operand stack
+    #[arg(long, value_name = "N")]
+    max_stack: Option<usize>,
+
+    /// maximum memory size in pages (64KiB)
+    #[arg(long, value_name = "PAGES")]
+    max_memory: Option<u32>,
+
+    /// maximum number of table elements
+    #[arg(long, value_name = "N")]
+    max_table: Option<u32>,
+}
+
+impl LimitArgs {
+    fn to_limits(&self) -> exec::StoreLimits {
+        let mut limits = exec::StoreLimits::default();
+        if let Some(n) = self.max_call_depth {
+            limits.max_call_depth = n;
+        }
+        if let Some(n) = self.max_stack {
+            limits.max_stack = n;
+        }
+        if let Some(n) = self.max_memory {
+            limits.max_memory_pages = n;
+        }
+        if let Some(n) = self.max_table {
+            limits.max_table_elems = n;
+        }
+        limits
+    }
+}
+
+#[derive(clap::Args)]
+struct TraceArgs {
+    /// log each executed instruction with the stack and locals to stderr
+    #[arg(long)]
+    trace: bool,
+
+    /// log the trace in JSON lines
+    #[arg(long)]
+    trace_json: bool,
+
+    /// trace only the function (export name, $name or funcidx). can be repeated
+    #[arg(long = "trace-func", value_name = "FUNC")]
+    trace_funcs: Vec<String>,
+}
+
+impl TraceArgs {
+    fn to_trace(&self, module: &module::Module) -> Option<exec::Trace> {
+        if !self.trace && !self.trace_json && self.trace_funcs.is_empty() {
+            return None;
+        }
+        let names: Vec<&str> = self.trace_funcs.iter().map(|s| s.as_str()).collect();
+        let funcs = resolve_funcs(&names, module).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        Some(exec::Trace {json: self.trace_json, funcs})
+    }
+}
+
+fn resolve_funcs(names: &[&str], module: &module::Module) -> Result<Vec<usize>, String> {
+    let mut funcs = Vec::new();
+    for name in names {
+        match exec::resolve_func(name, module) {
+            Some(idx) if idx < module.num_funcs() => funcs.push(idx),
+            _ => return Err(format!("unknown function '{}'", name)),
+        }
+    }
+    Ok(funcs)
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// run a WASI command module (call _start)
+    Run {
+        /// path of wasm module
+        path: String,
+
+        /// preopen the directory for the module
+        #[arg(long = "dir", value_name = "DIR")]
+        dirs: Vec<String>,
+
+        /// environment variable passed to the module
+        #[arg(long = "env", value_name = "KEY=VALUE")]
+        envs: Vec<String>,
+
+        /// args passed to the module (after --)
+        #[arg(last = true)]
+        args: Vec<String>,
+
+        /// accept the relaxed SIMD instructions
+        #[arg(long)]
+        relaxed_simd: bool,
+
+        #[command(flatten)]
+        trace: TraceArgs,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+
+    /// run .wast scripts (spec testsuite) and show the summary of each file
+    Wast {
+        /// paths of .wast scripts
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+}
+
+fn load_module(path: &str) -> module::Module {
+    if path.ends_with(".wat") {
+        let src = fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("Read from '{}' failed: {}", path, err);
+            process::exit(1);
+        });
+        watparse::parse_wat(&src).unwrap_or_else(|err| {
+            eprintln!("parse failed: {}:{}", path, err);
+            process::exit(1);
+        })
+    } else {
+        let buf = fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Read from '{}' failed: {}", path, err);
+            process::exit(1);
+        });
+        module::init_module(buf).unwrap_or_else(|err| {
+            eprintln!("init module failed: {}", err);
+            process::exit(1);
+        })
+    }
+}
+
+// write BASE.c and BASE.h. the file name of BASE is the prefix of the C names
+fn emit_c(module: &module::Module, base: &str) -> ! {
+    let prefix = Path::new(base).file_name().and_then(|name| name.to_str()).unwrap_or("");
+    if prefix.is_empty() || prefix.starts_with(|c: char| c.is_ascii_digit())
+        || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        eprintln!("the file name of '{}' must be a C identifier.", base);
+        process::exit(1);
+    }
+    let unsupported = cgen::unsupported(module);
+    if !unsupported.is_empty() {
+        for err in &unsupported {
+            eprintln!("unsupported by --emit-c: {}", err);
+        }
+        process::exit(1);
+    }
+    let c = cgen::module_to_c(module, prefix).unwrap_or_else(|err| {
+        eprintln!("translation to C failed: {}", err);
+        process::exit(1);
+    });
+    for (ext, text) in [("h", &c.header), ("c", &c.source)] {
+        let path = format!("{}.{}", base, ext);
+        if let Err(err) = fs::write(&path, text) {
+            eprintln!("Write to '{}' failed: {}", path, err);
+            process::exit(1);
+        }
+    }
+    process::exit(0);
+}
+
+// print the results of --invoke and exit
+fn print_results(result: Result<Vec<exec::Value>, String>) -> ! {
+    match result {
+        Ok(results) => {
+            let v: Vec<String> = results.iter().map(|r| r.to_string()).collect();
+            if !v.is_empty() {
+                println!("{}", v.join(" "));
+            }
+            process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+fn run(path: &str, dirs: &[String], envs: &[String], args: &[String], relaxed_simd: bool,
+       trace: &TraceArgs, limits: &LimitArgs) -> ! {
+    let mut module = load_module(path);
+    module.set_relaxed_simd(relaxed_simd);
+    if let Err(errs) = module.validate() {
+        for e in &errs {
+            eprintln!("validation error: {}", e);
+        }
+        process::exit(1);
+    }
+    let mut argv = vec![path.to_string()];
+    argv.extend_from_slice(args);
+    match wasi::run(&module, argv, envs.to_vec(), dirs, limits.to_limits(), limits.fuel, trace.to_trace(&module)) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        },
+    }
+}
+
+fn run_wast(paths: &[String]) -> ! {
+    let mut ok = true;
+    for path in paths {
+        let src = fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("Read from '{}' failed: {}", path, err);
+            process::exit(1);
+        });
+        match wast::run_wast(&src) {
+            Ok(summary) => {
+                print!("{}: {}/{} passed", path, summary.passed, summary.total());
+                if summary.skipped > 0 {
+                    print!(" ({} skipped)", summary.skipped);
+                }
+                println!();
+                for (line, msg) in &summary.failed {
+                    println!("  {}:{}: {}", path, line, msg);
+                }
+                ok &= summary.failed.is_empty();
+            },
+            Err(err) => {
+                println!("{}: parse failed: {}", path, err);
+                ok = false;
+            },
+        }
+    }
+    process::exit(if ok { 0 } else { 1 });
 }
 
 fn main() {
     let args = Args::parse();
 
-    let opts = args.sec as i32 + args.dis as i32 + args.intr as i32;
+    match &args.command {
+        Some(Command::Run {path, dirs, envs, args, relaxed_simd, trace, limits}) =>
+            run(path, dirs, envs, args, *relaxed_simd, trace, limits),
+        Some(Command::Wast {paths}) => run_wast(paths),
+        None => (),
+    }
+    let path = args.path.as_deref().unwrap();
+
+    let opts = args.sec as i32 + args.dis as i32 + args.intr as i32 + args.validate as i32
+        + args.wat as i32 + args.output.is_some() as i32 + args.invoke.is_some() as i32 + args.emit_c.is_some() as i32;
     if opts > 1 {
-        eprintln!("-s, -d, -i cannot be specified at the same time.");
+        eprintln!("-s, -d, -i, -v, -w, -o, --invoke, --emit-c cannot be specified at the same time.");
         process::exit(1);
     }
 
-    let buf = fs::read(&args.path).unwrap_or_else(|err| {
-        eprintln!("Read from '{}' failed: {}", &args.path, err);
-        process::exit(1);
-    });
+    let mut module = load_module(path);
+    module.set_relaxed_simd(args.relaxed_simd);
 
-    let module = module::init_module(buf).unwrap_or_else(|err| {
-        eprintln!("init module failed: {}", err);
-        process::exit(1);
-    });
+    if let Some(output) = &args.output {
+        if let Err(err) = fs::write(output, module.encode()) {
+            eprintln!("Write to '{}' failed: {}", output, err);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    if args.validate || args.intr || args.invoke.is_some() || args.emit_c.is_some() {
+        if let Err(errs) = module.validate() {
+            for e in &errs {
+                eprintln!("validation error: {}", e);
+            }
+            process::exit(1);
+        }
+        if args.validate {
+            println!("validation OK");
+            process::exit(0);
+        }
+    }
+
+    if let Some(base) = &args.emit_c {
+        emit_c(&module, base);
+    }
+
+    if let Some(invoke) = &args.invoke {
+        let mut store = exec::make_store_with_limits(&module, args.limits.to_limits()).unwrap_or_else(|err| {
+            eprintln!("instantiation failed: {}", err);
+            process::exit(1);
+        });
+        store.set_fuel(args.limits.fuel);
+        let trace = args.trace.to_trace(&module);
+        if args.ir && trace.is_some() {
+            eprintln!("--ir cannot be used with --trace.");
+            process::exit(1);
+        }
+        #[cfg(feature = "jit")]
+        if args.jit && trace.is_some() {
+            eprintln!("--jit cannot be used with --trace.");
+            process::exit(1);
+        }
+        store.set_trace(trace);
+        let invoke: Vec<&str> = invoke.iter().map(|s| s.as_str()).collect();
+        #[cfg(feature = "jit")]
+        if args.jit {
+            let jit = wasmex::jit::Jit::new(&module, args.jit_threshold);
+            print_results(jit.invoke_str(&invoke, &module, &mut store));
+        }
+        let result = if args.ir {
+            ir::IrModule::new(&module).invoke_str(&invoke, &module, &mut store)
+        } else {
+            exec::invoke_str(&invoke, &module, &mut store)
+        };
+        print_results(result);
+    }
+
+    if args.wat {
+        print!("{}", wat::module_to_wat(&module, args.folded));
+        process::exit(0);
+    }
 
     if opts == 0 || args.intr {
         module.show_summary();
@@ -62,26 +411,61 @@ fn main() {
         process::exit(0);
     }
 
-    let mut store = exec::make_store(&module);
+    let mut store = exec::make_store_with_limits(&module, args.limits.to_limits()).unwrap_or_else(|err| {
+        eprintln!("instantiation failed: {}", err);
+        process::exit(1);
+    });
+    store.set_verbose(true);
+    store.set_fuel(args.limits.fuel);
+    store.set_trace(args.trace.to_trace(&module));
+    let mut debugger = debug::Debugger::new();
 
     loop {
+        if debugger.is_paused() {
+            print!("(paused) ");
+        }
         print!("> ");
         io::stdout().flush().unwrap();
 
         let mut line = String::new();
-        io::stdin()
+        let n = io::stdin()
             .read_line(&mut line)
             .expect("Failed to read line"); 
+        if n == 0 { // EOF
+            println!();
+            break;
+        }
 
         let cmds: Vec<&str> = line.split_whitespace().collect();
-        if cmds.len() > 0 {
+        if !cmds.is_empty() {
+            match debugger.command(&cmds, &module, &mut store) {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    continue;
+                },
+            }
             match cmds[0] {
-                "exec" => {
-                    match exec::exec_func(&cmds[1..], &module, &mut store) {
-                        Err(e) => eprintln!("error: {}", e),
-                        Ok(_) => (),
+                "trace" => {
+                    if let Err(e) = set_trace(&cmds[1..], &module, &mut store) {
+                        eprintln!("error: {}", e);
                     }
                 },
+                "fuel" => match cmds.get(1) {
+                    None => match store.fuel() {
+                        Some(fuel) => println!("fuel: {}", fuel),
+                        None => println!("fuel: unlimited"),
+                    },
+                    Some(&"off") => store.set_fuel(None),
+                    Some(n) => match n.parse() {
+                        Ok(n) => store.set_fuel(Some(n)),
+                        Err(_) => eprintln!("error: bad fuel '{}'", n),
+                    },
+                },
+                "exports" => module.show_exports(),
+                "imports" => module.show_imports(),
+                "funcs" => module.show_funcs(),
                 "help" => print_help(),
                 "exit" => break,
                 _ => {
@@ -95,8 +479,43 @@ fn main() {
     process::exit(0);
 }
 
+// trace on|json [func..] or trace off
+fn set_trace(args: &[&str], module: &module::Module, store: &mut exec::Store) -> Result<(), String> {
+    let json = match args.first() {
+        Some(&"on") => false,
+        Some(&"json") => true,
+        Some(&"off") => {
+            store.set_trace(None);
+            return Ok(());
+        },
+        _ => return Err("trace on|json [func..] or trace off".to_string()),
+    };
+    let funcs = resolve_funcs(&args[1..], module)?;
+    store.set_trace(Some(exec::Trace {json, funcs}));
+    Ok(())
+}
+
 fn print_help() {
-    println!("exec funcidx [args..]");
+    println!("exec funcidx|name [args..]");
+    println!("break|b [func [ip]]    set a breakpoint (list breakpoints without args)");
+    println!("delete [func [ip]]     delete a breakpoint (all without args)");
+    println!("step|s [n]             execute an instruction (enter calls)");
+    println!("next|n                 execute an instruction (step over calls)");
+    println!("finish                 run until the current function returns");
+    println!("continue|c             run until a breakpoint or the end");
+    println!("abort                  abort the paused execution");
+    println!("list|l                 show instructions around the current one");
+    println!("locals");
+    println!("stack");
+    println!("backtrace|bt");
+    println!("memory|x addr [len]    dump memory");
+    println!("global [idx]");
+    println!("trace on|json [func..] log executed instructions (to stderr)");
+    println!("trace off");
+    println!("fuel [n|off]           show or set the remaining fuel (off: unlimited)");
+    println!("exports");
+    println!("imports");
+    println!("funcs");
     println!("help");
     println!("exit");
 }
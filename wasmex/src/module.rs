@@ -10,11 +10,17 @@ use std::fmt;
 use crate::bytecode::*;
 use crate::inst::*;
 use crate::exec::*;
+use crate::names::*;
+use crate::validate::*;
 
 pub struct Module {
     sec_summary: Vec<SectionSummary>,
     sections: HashMap<u8, Section>,
+    customs: Vec<Customsec>, // in order of appearance
     funcs: Vec<Function>,
+    names: Names, // from the name section
+    validated: bool,
+    relaxed_simd: bool, // accept the relaxed SIMD instructions
 }
 
 enum SummaryItem {
@@ -63,8 +69,12 @@ struct Typesec {
 }
 
 impl Typesec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Typesec {ft: get_vector::<Functype>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Typesec {ft: get_vector::<Functype>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.ft, buf);
     }
 
     fn show(&self) {
@@ -75,32 +85,72 @@ impl Typesec {
     }
 }
 
-enum Importdesc {
+pub enum Importdesc {
     Func(u32), // type index
     Table(Tabletype),
     Mem(Limits), // memtype == limits
     Global(Globaltype),
 }
 
-struct Import {
+pub struct Import {
     module: String,
     name: String,
     desc: Importdesc,
 }
 
+impl PutType for Import {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_name(&self.module);
+        buf.put_name(&self.name);
+        match &self.desc {
+            Importdesc::Func(idx) => {
+                buf.put_byte(0);
+                buf.put_u32(*idx);
+            },
+            Importdesc::Table(table) => {
+                buf.put_byte(1);
+                table.put(buf);
+            },
+            Importdesc::Mem(lm) => {
+                buf.put_byte(2);
+                lm.put(buf);
+            },
+            Importdesc::Global(gt) => {
+                buf.put_byte(3);
+                gt.put(buf);
+            },
+        }
+    }
+}
+
+impl Import {
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn desc(&self) -> &Importdesc {
+        &self.desc
+    }
+}
+
 impl GetType for Import {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        let module = buf.get_name();
-        let name = buf.get_name();
-        let d = buf.get_byte();
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let module = buf.get_name()?;
+        let name = buf.get_name()?;
+        let cur = buf.get_cur();
+        let d = buf.get_byte()?;
         let desc = match d {
-            0 => Importdesc::Func(buf.get_u32()),
-            1 => Importdesc::Table(Tabletype::get(buf)),
-            2 => Importdesc::Mem(Limits::get(buf)),
-            3 => Importdesc::Global(Globaltype::get(buf)),
-            _ => panic!("unknown import desc"),
+            0 => Importdesc::Func(buf.get_u32()?),
+            1 => Importdesc::Table(Tabletype::get(buf)?),
+            2 => Importdesc::Mem(Limits::get(buf)?),
+            3 => Importdesc::Global(Globaltype::get(buf)?),
+            _ => return Err(buf.err_unexpected(cur, "import desc", d as u32)),
         };
-        Import {module, name, desc,}
+        Ok(Import {module, name, desc,})
     }
 }
 
@@ -109,8 +159,12 @@ struct Importsec {
 }
 
 impl Importsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Importsec {import: get_vector::<Import>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Importsec {import: get_vector::<Import>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.import, buf);
     }
 
     fn show(&self) {
@@ -132,8 +186,12 @@ struct Funcsec {
 }
 
 impl Funcsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Funcsec {typeidx: get_vector::<u32>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Funcsec {typeidx: get_vector::<u32>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.typeidx, buf);
     }
 
     fn show(&self) {
@@ -148,8 +206,12 @@ struct Tablesec {
 }
 
 impl Tablesec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Tablesec {table: get_vector::<Tabletype>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Tablesec {table: get_vector::<Tabletype>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.table, buf);
     }
 
     fn show(&self) {
@@ -164,8 +226,12 @@ struct Memsec {
 }
 
 impl Memsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Memsec {mem: get_vector::<Limits>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Memsec {mem: get_vector::<Limits>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.mem, buf);
     }
 
     fn show(&self) {
@@ -175,17 +241,34 @@ impl Memsec {
     }
 }
 
-struct Global {
+pub struct Global {
     globaltype: Globaltype,
     expr: Expr,
 }
 
+impl PutType for Global {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        self.globaltype.put(buf);
+        self.expr.put(buf);
+    }
+}
+
+impl Global {
+    pub fn globaltype(&self) -> &Globaltype {
+        &self.globaltype
+    }
+
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+}
+
 impl GetType for Global {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Global {
-            globaltype: Globaltype::get(buf),
-            expr: Expr::get(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Global {
+            globaltype: Globaltype::get(buf)?,
+            expr: Expr::get(buf)?,
+        })
     }
 }
 
@@ -194,8 +277,12 @@ struct Globalsec {
 }
 
 impl Globalsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Globalsec {global: get_vector::<Global>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Globalsec {global: get_vector::<Global>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.global, buf);
     }
 
     fn show(&self) {
@@ -206,31 +293,56 @@ impl Globalsec {
     }
 }
 
-enum Exportdesc {
+pub enum Exportdesc {
     Func(u32),
     Table(u32),
     Mem(u32),
     Global(u32),
 }
 
-struct Export {
+pub struct Export {
     name: String,
     desc: Exportdesc,
 }
 
+impl PutType for Export {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_name(&self.name);
+        let (d, idx) = match &self.desc {
+            Exportdesc::Func(idx) => (0, idx),
+            Exportdesc::Table(idx) => (1, idx),
+            Exportdesc::Mem(idx) => (2, idx),
+            Exportdesc::Global(idx) => (3, idx),
+        };
+        buf.put_byte(d);
+        buf.put_u32(*idx);
+    }
+}
+
+impl Export {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn desc(&self) -> &Exportdesc {
+        &self.desc
+    }
+}
+
 impl GetType for Export {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        let name = buf.get_name();
-        let d = buf.get_byte();
-        let idx = buf.get_u32();
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let name = buf.get_name()?;
+        let cur = buf.get_cur();
+        let d = buf.get_byte()?;
+        let idx = buf.get_u32()?;
         let desc = match d {
             0 => Exportdesc::Func(idx),
             1 => Exportdesc::Table(idx),
             2 => Exportdesc::Mem(idx),
             3 => Exportdesc::Global(idx),
-            _ => panic!("unknown exportdesc"),
+            _ => return Err(buf.err_unexpected(cur, "export desc", d as u32)),
         };
-        Export {name, desc,}
+        Ok(Export {name, desc,})
     }
 }
 
@@ -239,8 +351,12 @@ struct Exportsec {
 }
 
 impl Exportsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Exportsec {export: get_vector::<Export>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Exportsec {export: get_vector::<Export>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.export, buf);
     }
 
     fn show(&self) {
@@ -261,8 +377,12 @@ struct Startsec {
 }
 
 impl Startsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Startsec {idx: buf.get_u32(),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Startsec {idx: buf.get_u32()?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_u32(self.idx);
     }
 
     fn show(&self) {
@@ -270,29 +390,39 @@ impl Startsec {
     }
 }
 
-struct Elem0 {
+pub struct Elem0 {
     expr: Expr,
     funcidx: Vec<u32>,
 }
 
-fn format_funcidx(idx: &Vec<u32>) -> String {
+fn format_funcidx(idx: &[u32]) -> String {
     let mut s = "[".to_string();
     for (i, v) in idx.iter().enumerate() {
         s.push_str(&format!("{}", v));
         if i != idx.len() - 1 {
-            s.push_str(" ");
+            s.push(' ');
         }
     }
-    s.push_str("]");
+    s.push(']');
     s
 }
 
+// elemkind must be 0x00 (funcref)
+fn get_elemkind(buf: &mut ByteCodeBuff) -> Result<u8, DecodeError> {
+    let cur = buf.get_cur();
+    let kind = buf.get_byte()?;
+    if kind != 0 {
+        return Err(buf.err_unexpected(cur, "elemkind (0x00)", kind as u32));
+    }
+    Ok(kind)
+}
+
 impl Elem0 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem0 {
-            expr: Expr::get(buf),
-            funcidx: get_vector::<u32>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem0 {
+            expr: Expr::get(buf)?,
+            funcidx: get_vector::<u32>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
@@ -302,17 +432,17 @@ impl Elem0 {
     }
 }
 
-struct Elem1 {
+pub struct Elem1 {
     elemkind: u8,
     funcidx: Vec<u32>,
 }
 
 impl Elem1 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem1 {
-            elemkind: buf.get_byte(),
-            funcidx: get_vector::<u32>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem1 {
+            elemkind: get_elemkind(buf)?,
+            funcidx: get_vector::<u32>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
@@ -321,7 +451,7 @@ impl Elem1 {
     }
 }
 
-struct Elem2 {
+pub struct Elem2 {
     tableidx: u32,
     expr: Expr,
     elemkind: u8,
@@ -329,13 +459,13 @@ struct Elem2 {
 }
 
 impl Elem2 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem2 {
-            tableidx: buf.get_u32(),
-            expr: Expr::get(buf),
-            elemkind: buf.get_byte(),
-            funcidx: get_vector::<u32>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem2 {
+            tableidx: buf.get_u32()?,
+            expr: Expr::get(buf)?,
+            elemkind: get_elemkind(buf)?,
+            funcidx: get_vector::<u32>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
@@ -345,17 +475,17 @@ impl Elem2 {
     }
 }
 
-struct Elem3 {
+pub struct Elem3 {
     elemkind: u8,
     funcidx: Vec<u32>,
 }
 
 impl Elem3 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem3 {
-            elemkind: buf.get_byte(),
-            funcidx: get_vector::<u32>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem3 {
+            elemkind: get_elemkind(buf)?,
+            funcidx: get_vector::<u32>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
@@ -364,45 +494,43 @@ impl Elem3 {
     }
 }
 
-struct Elem4 {
+pub struct Elem4 {
     expr: Expr,
     el: Vec<Expr>,
 }
 
 impl Elem4 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem4 {
-            expr: Expr::get(buf),
-            el: get_vector::<Expr>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem4 {
+            expr: Expr::get(buf)?,
+            el: get_vector::<Expr>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
-        let s = format!("type 4: todo");
-        s
+        "type 4: todo".to_string()
     }
 }
 
-struct Elem5 {
+pub struct Elem5 {
     reftype: Valtype,
     el: Vec<Expr>,
 }
 
 impl Elem5 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem5 {
-            reftype: Valtype::get(buf),
-            el: get_vector::<Expr>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem5 {
+            reftype: Valtype::get(buf)?,
+            el: get_vector::<Expr>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
-        let s = format!("type 5: todo");
-        s
+        "type 5: todo".to_string()
     }
 }
 
-struct Elem6 {
+pub struct Elem6 {
     tableidx: u32,
     expr: Expr,
     reftype: Valtype,
@@ -410,41 +538,39 @@ struct Elem6 {
 }
 
 impl Elem6 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem6 {
-            tableidx: buf.get_u32(),
-            expr: Expr::get(buf),
-            reftype: Valtype::get(buf),
-            el: get_vector::<Expr>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem6 {
+            tableidx: buf.get_u32()?,
+            expr: Expr::get(buf)?,
+            reftype: Valtype::get(buf)?,
+            el: get_vector::<Expr>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
-        let s = format!("type 6: todo");
-        s
+        "type 6: todo".to_string()
     }
 }
 
-struct Elem7 {
+pub struct Elem7 {
     reftype: Valtype,
     el: Vec<Expr>,
 }
 
 impl Elem7 {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elem7 {
-            reftype: Valtype::get(buf),
-            el: get_vector::<Expr>(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elem7 {
+            reftype: Valtype::get(buf)?,
+            el: get_vector::<Expr>(buf)?,
+        })
     }
 
     fn format(&self) -> String {
-        let s = format!("type 7: todo");
-        s
+        "type 7: todo".to_string()
     }
 }
 
-enum Elem {
+pub enum Elem {
     Elem0(Elem0),
     Elem1(Elem1),
     Elem2(Elem2),
@@ -456,20 +582,133 @@ enum Elem {
 }
 
 impl GetType for Elem {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        let elem_id = buf.get_u32();
-        match elem_id {
-            0 => Elem::Elem0(Elem0::get(buf)),
-            1 => Elem::Elem1(Elem1::get(buf)),
-            2 => Elem::Elem2(Elem2::get(buf)),
-            3 => Elem::Elem3(Elem3::get(buf)),
-            4 => Elem::Elem4(Elem4::get(buf)),
-            5 => Elem::Elem5(Elem5::get(buf)),
-            6 => Elem::Elem6(Elem6::get(buf)),
-            7 => Elem::Elem7(Elem7::get(buf)),
-            _ => panic!("unknown element type"),
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let cur = buf.get_cur();
+        let elem_id = buf.get_u32()?;
+        let elem = match elem_id {
+            0 => Elem::Elem0(Elem0::get(buf)?),
+            1 => Elem::Elem1(Elem1::get(buf)?),
+            2 => Elem::Elem2(Elem2::get(buf)?),
+            3 => Elem::Elem3(Elem3::get(buf)?),
+            4 => Elem::Elem4(Elem4::get(buf)?),
+            5 => Elem::Elem5(Elem5::get(buf)?),
+            6 => Elem::Elem6(Elem6::get(buf)?),
+            7 => Elem::Elem7(Elem7::get(buf)?),
+            _ => return Err(buf.err_unexpected(cur, "element type", elem_id)),
+        };
+        Ok(elem)
+    }
+}
+
+// common view of the eight element segment encodings
+pub enum ElemMode<'a> {
+    Active(u32, &'a Expr), // tableidx, offset
+    Passive,
+    Declarative,
+}
+
+pub enum ElemInit<'a> {
+    Funcs(&'a [u32]),
+    Exprs(&'a [Expr]),
+}
+
+impl PutType for Elem {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        match self {
+            Elem::Elem0(el) => {
+                buf.put_u32(0);
+                el.expr.put(buf);
+                put_vector(&el.funcidx, buf);
+            },
+            Elem::Elem1(el) => {
+                buf.put_u32(1);
+                buf.put_byte(el.elemkind);
+                put_vector(&el.funcidx, buf);
+            },
+            Elem::Elem2(el) => {
+                buf.put_u32(2);
+                buf.put_u32(el.tableidx);
+                el.expr.put(buf);
+                buf.put_byte(el.elemkind);
+                put_vector(&el.funcidx, buf);
+            },
+            Elem::Elem3(el) => {
+                buf.put_u32(3);
+                buf.put_byte(el.elemkind);
+                put_vector(&el.funcidx, buf);
+            },
+            Elem::Elem4(el) => {
+                buf.put_u32(4);
+                el.expr.put(buf);
+                put_vector(&el.el, buf);
+            },
+            Elem::Elem5(el) => {
+                buf.put_u32(5);
+                el.reftype.put(buf);
+                put_vector(&el.el, buf);
+            },
+            Elem::Elem6(el) => {
+                buf.put_u32(6);
+                buf.put_u32(el.tableidx);
+                el.expr.put(buf);
+                el.reftype.put(buf);
+                put_vector(&el.el, buf);
+            },
+            Elem::Elem7(el) => {
+                buf.put_u32(7);
+                el.reftype.put(buf);
+                put_vector(&el.el, buf);
+            },
+        }
+    }
+}
+
+impl Elem {
+    pub fn mode(&self) -> ElemMode<'_> {
+        match self {
+            Elem::Elem0(el) => ElemMode::Active(0, &el.expr),
+            Elem::Elem1(_) => ElemMode::Passive,
+            Elem::Elem2(el) => ElemMode::Active(el.tableidx, &el.expr),
+            Elem::Elem3(_) => ElemMode::Declarative,
+            Elem::Elem4(el) => ElemMode::Active(0, &el.expr),
+            Elem::Elem5(_) => ElemMode::Passive,
+            Elem::Elem6(el) => ElemMode::Active(el.tableidx, &el.expr),
+            Elem::Elem7(_) => ElemMode::Declarative,
+        }
+    }
+
+    pub fn reftype(&self) -> u8 {
+        match self {
+            Elem::Elem5(el) => el.reftype.0,
+            Elem::Elem6(el) => el.reftype.0,
+            Elem::Elem7(el) => el.reftype.0,
+            _ => 0x70, // funcref
+        }
+    }
+
+    pub fn init(&self) -> ElemInit<'_> {
+        match self {
+            Elem::Elem0(el) => ElemInit::Funcs(&el.funcidx),
+            Elem::Elem1(el) => ElemInit::Funcs(&el.funcidx),
+            Elem::Elem2(el) => ElemInit::Funcs(&el.funcidx),
+            Elem::Elem3(el) => ElemInit::Funcs(&el.funcidx),
+            Elem::Elem4(el) => ElemInit::Exprs(&el.el),
+            Elem::Elem5(el) => ElemInit::Exprs(&el.el),
+            Elem::Elem6(el) => ElemInit::Exprs(&el.el),
+            Elem::Elem7(el) => ElemInit::Exprs(&el.el),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.init() {
+            ElemInit::Funcs(idx) => idx.len(),
+            ElemInit::Exprs(el) => el.len(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 struct Elemsec {
@@ -477,8 +716,12 @@ struct Elemsec {
 }
 
 impl Elemsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Elemsec {elem: get_vector::<Elem>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Elemsec {elem: get_vector::<Elem>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.elem, buf);
     }
 
     fn show(&self) {
@@ -505,46 +748,64 @@ pub struct Locals {
 }
 
 impl GetType for Locals {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Locals {
-            num: buf.get_u32(),
-            valtype: Valtype::get(buf),
-        }
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Locals {
+            num: buf.get_u32()?,
+            valtype: Valtype::get(buf)?,
+        })
     }
 }
 
 struct Code {
     size: u32,
     start: usize,
+    end: usize,
     locals: Vec<Valtype>,
 }
 
+// limit of the number of locals in a function, same as other engines.
+// locals are expanded one by one, so an absurd count is rejected here.
+const MAX_LOCALS: u64 = 50000;
+
 impl GetType for Code {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        let len = buf.get_u32();
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let len = buf.get_u32()?;
         let end = buf.get_cur() + len as usize;
-        let vec_locals = get_vector::<Locals>(buf);
+        if end > buf.get_end() {
+            return Err(buf.err_end());
+        }
+        let vec_locals = get_vector::<Locals>(buf)?;
         let start = buf.get_cur();
+        if start > end {
+            return Err(buf.err_malformed(start, "function body size mismatch"));
+        }
         buf.set_cur(end);
+        let mut total: u64 = 0;
+        for l in vec_locals.iter() {
+            total += l.num as u64;
+        }
+        if total > MAX_LOCALS {
+            return Err(buf.err_malformed(start, "too many locals"));
+        }
         let mut locals = Vec::new();
         for l in vec_locals.iter() {
             for _ in 0..l.num {
                 locals.push(l.valtype.clone());
             }
         }
-        Code {size: len, start, locals,}
+        Ok(Code {size: len, start, end, locals,})
     }
 }
 
-fn format_locals(locals: &Vec<Valtype>) -> String {
+fn format_locals(locals: &[Valtype]) -> String {
     let mut s = "[".to_string();
     for (i, l) in locals.iter().enumerate() {
         s.push_str(&format!("{}", &l));
         if i != locals.len() - 1 {
-            s.push_str(" ");
+            s.push(' ');
         }
     }
-    s.push_str("]");
+    s.push(']');
     s
 }
 
@@ -553,8 +814,8 @@ struct Codesec {
 }
 
 impl Codesec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Codesec {code: get_vector::<Code>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Codesec {code: get_vector::<Code>(buf)?,})
     }
 
     fn show(&self) {
@@ -565,37 +826,72 @@ impl Codesec {
     }
 }
 
-struct Data {
+pub struct Data {
     id: u32,
     expr: Expr,
     data: Vec<u8>,
     memidx: u32,
 }
 
+pub enum DataMode<'a> {
+    Active(u32, &'a Expr), // memidx, offset
+    Passive,
+}
+
+impl PutType for Data {
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_u32(self.id);
+        match self.id {
+            0 => self.expr.put(buf),
+            2 => {
+                buf.put_u32(self.memidx);
+                self.expr.put(buf);
+            },
+            _ => (),
+        }
+        buf.put_data(&self.data);
+    }
+}
+
+impl Data {
+    pub fn mode(&self) -> DataMode<'_> {
+        match self.id {
+            1 => DataMode::Passive,
+            _ => DataMode::Active(self.memidx, &self.expr),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
 impl GetType for Data {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        let t = buf.get_u32();
-        match t {
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        let cur = buf.get_cur();
+        let t = buf.get_u32()?;
+        let data = match t {
             0 => Data {
                     id: t,
-                    expr: Expr::get(buf),
-                    data: buf.get_data(),
+                    expr: Expr::get(buf)?,
+                    data: buf.get_data()?,
                     memidx: 0
                 },
             1 => Data {
                     id: t,
                     expr: Expr(vec![]),
-                    data: buf.get_data(),
+                    data: buf.get_data()?,
                     memidx: 0
                 },
             2 => Data {
                     id: t,
-                    memidx: buf.get_u32(),
-                    expr: Expr::get(buf),
-                    data: buf.get_data(),
+                    memidx: buf.get_u32()?,
+                    expr: Expr::get(buf)?,
+                    data: buf.get_data()?,
                 },
-            _ => panic!("unknown data type"),
-        }
+            _ => return Err(buf.err_unexpected(cur, "data type", t)),
+        };
+        Ok(data)
     }
 }
 
@@ -604,8 +900,12 @@ struct Datasec {
 }
 
 impl Datasec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        Datasec {data: get_vector::<Data>(buf),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(Datasec {data: get_vector::<Data>(buf)?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        put_vector(&self.data, buf);
     }
 
     fn show(&self) {
@@ -626,8 +926,12 @@ struct DataCountsec {
 }
 
 impl DataCountsec {
-    fn get(buf: &mut ByteCodeBuff) -> Self {
-        DataCountsec {count: buf.get_u32(),}
+    fn get(buf: &mut ByteCodeBuff) -> Result<Self, DecodeError> {
+        Ok(DataCountsec {count: buf.get_u32()?,})
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_u32(self.count);
     }
 
     fn show(&self) {
@@ -637,11 +941,17 @@ impl DataCountsec {
 
 struct Customsec {
     name: String,
+    data: Vec<u8>, // contents after the name
 }
 
 impl Customsec {
     fn show(&self) {
-        println!("to do");
+        println!("custom: name({}) size({})", self.name, self.data.len());
+    }
+
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        buf.put_name(&self.name);
+        buf.put_bytes(&self.data);
     }
 }
 
@@ -662,28 +972,28 @@ enum Section {
 }
 
 impl Section {
-    pub fn get_section(sec_id: u8, size: u32, buf: &mut ByteCodeBuff) -> Section {
-        match sec_id {
+    pub fn get_section(sec_id: u8, buf: &mut ByteCodeBuff) -> Result<Section, DecodeError> {
+        let section = match sec_id {
             0 => {
-                let end = buf.get_cur() + size as usize;
-                let name = buf.get_name();
-                buf.set_cur(end);
-                Section::Custom(Customsec{name,})
+                let name = buf.get_name()?;
+                let data = buf.get_bytes(buf.get_end() - buf.get_cur())?;
+                Section::Custom(Customsec{name, data,})
             },
-            1 => Section::Type(Typesec::get(buf)),
-            2 => Section::Import(Importsec::get(buf)),
-            3 => Section::Function(Funcsec::get(buf)),
-            4 => Section::Table(Tablesec::get(buf)),
-            5 => Section::Memory(Memsec::get(buf)),
-            6 => Section::Global(Globalsec::get(buf)),
-            7 => Section::Export(Exportsec::get(buf)),
-            8 => Section::Start(Startsec::get(buf)),
-            9 => Section::Element(Elemsec::get(buf)),
-            10 => Section::Code(Codesec::get(buf)),
-            11 => Section::Data(Datasec::get(buf)),
-            12 => Section::DataCount(DataCountsec::get(buf)),
-            _ => panic!("unknown section id"),
-        }
+            1 => Section::Type(Typesec::get(buf)?),
+            2 => Section::Import(Importsec::get(buf)?),
+            3 => Section::Function(Funcsec::get(buf)?),
+            4 => Section::Table(Tablesec::get(buf)?),
+            5 => Section::Memory(Memsec::get(buf)?),
+            6 => Section::Global(Globalsec::get(buf)?),
+            7 => Section::Export(Exportsec::get(buf)?),
+            8 => Section::Start(Startsec::get(buf)?),
+            9 => Section::Element(Elemsec::get(buf)?),
+            10 => Section::Code(Codesec::get(buf)?),
+            11 => Section::Data(Datasec::get(buf)?),
+            12 => Section::DataCount(DataCountsec::get(buf)?),
+            _ => return Err(buf.err_unexpected(buf.get_cur(), "section id", sec_id as u32)),
+        };
+        Ok(section)
     }
 
     fn summary_item(&self) -> SummaryItem {
@@ -704,6 +1014,24 @@ impl Section {
         }
     }
 
+    fn put(&self, buf: &mut ByteCodeBuff) {
+        match self {
+            Section::Custom(sec) => sec.put(buf),
+            Section::Type(sec) => sec.put(buf),
+            Section::Import(sec) => sec.put(buf),
+            Section::Function(sec) => sec.put(buf),
+            Section::Table(sec) => sec.put(buf),
+            Section::Memory(sec) => sec.put(buf),
+            Section::Global(sec) => sec.put(buf),
+            Section::Export(sec) => sec.put(buf),
+            Section::Start(sec) => sec.put(buf),
+            Section::Element(sec) => sec.put(buf),
+            Section::Code(_) => (), // encoded from funcs by Module::encode
+            Section::Data(sec) => sec.put(buf),
+            Section::DataCount(sec) => sec.put(buf),
+        }
+    }
+
     fn show(&self) {
         match self {
             Section::Custom(sec) => sec.show(),
@@ -731,8 +1059,16 @@ impl Module {
     }
 
     pub fn show_section(&self) {
+        let mut customs = self.customs.iter();
         for sec_s in &self.sec_summary {
-            if sec_s.id == 0 || sec_s.id == 12 {
+            if sec_s.id == 0 {
+                if let Some(custom) = customs.next() {
+                    custom.show();
+                    if custom.name == "name" {
+                        self.names.show();
+                    }
+                }
+            } else if sec_s.id == 12 {
                 println!("{}", sec_s);
             } else {
                 if let Some(sec) = self.sections.get(&sec_s.id) {
@@ -746,12 +1082,151 @@ impl Module {
         if let Some(Section::Code(sec)) = self.sections.get(&10) {
             let num = self.num_import_func();
             for i in 0..sec.code.len() {
-                println!("code[{}]: size({}) locals{}", i, sec.code[i].size,
+                let name = match self.names.func((num + i) as u32) {
+                    Some(name) => format!(" <{}>", name),
+                    None => "".to_string(),
+                };
+                println!("code[{}]{}: size({}) locals{}", i, name, sec.code[i].size,
                     &format_locals(&sec.code[i].locals));
                 let func = self.get_local_func(num + i);
-                func.show_insts();
-                println!("");
+                func.show_insts(&self.names, (num + i) as u32);
+                println!();
+            }
+        }
+    }
+
+    // signature of function idx. "(i32, i32) -> (i32)"
+    fn func_sig(&self, idx: usize) -> String {
+        match self.funcs.get(idx) {
+            Some(Function::Import(func)) => func.ft.to_string(),
+            Some(Function::Local(func)) => func.ft.to_string(),
+            None => "?".to_string(),
+        }
+    }
+
+    // type of global idx (imported globals come first)
+    pub(crate) fn global_type(&self, idx: usize) -> Option<&Globaltype> {
+        let imports = self.imports().iter().filter_map(|i| match i.desc() {
+            Importdesc::Global(gt) => Some(gt),
+            _ => None,
+        });
+        imports.chain(self.globals().iter().map(|g| g.globaltype())).nth(idx)
+    }
+
+    fn table_type(&self, idx: usize) -> Option<&Tabletype> {
+        let imports = self.imports().iter().filter_map(|i| match i.desc() {
+            Importdesc::Table(tt) => Some(tt),
+            _ => None,
+        });
+        imports.chain(self.tables().iter()).nth(idx)
+    }
+
+    fn mem_type(&self, idx: usize) -> Option<&Limits> {
+        let imports = self.imports().iter().filter_map(|i| match i.desc() {
+            Importdesc::Mem(limits) => Some(limits),
+            _ => None,
+        });
+        imports.chain(self.mems().iter()).nth(idx)
+    }
+
+    // exports with their types
+    pub fn show_exports(&self) {
+        for export in self.exports() {
+            let desc = match export.desc() {
+                Exportdesc::Func(idx) => format!("func[{}] {}", idx, self.func_sig(*idx as usize)),
+                Exportdesc::Table(idx) => match self.table_type(*idx as usize) {
+                    Some(tt) => format!("table[{}] {}", idx, tt),
+                    None => format!("table[{}]", idx),
+                },
+                Exportdesc::Mem(idx) => match self.mem_type(*idx as usize) {
+                    Some(limits) => format!("memory[{}] {}", idx, limits),
+                    None => format!("memory[{}]", idx),
+                },
+                Exportdesc::Global(idx) => match self.global_type(*idx as usize) {
+                    Some(gt) => format!("global[{}] {}", idx, gt),
+                    None => format!("global[{}]", idx),
+                },
+            };
+            println!("{}: {}", export.name(), desc);
+        }
+    }
+
+    // imports with their types
+    pub fn show_imports(&self) {
+        for import in self.imports() {
+            let desc = match import.desc() {
+                Importdesc::Func(typeidx) => match self.types().get(*typeidx as usize) {
+                    Some(ft) => format!("func {}", ft),
+                    None => format!("func type={}", typeidx),
+                },
+                Importdesc::Table(tt) => format!("table {}", tt),
+                Importdesc::Mem(limits) => format!("memory {}", limits),
+                Importdesc::Global(gt) => format!("global {}", gt),
+            };
+            println!("{}.{}: {}", import.module(), import.name(), desc);
+        }
+    }
+
+    // all functions (imports first) with their signatures
+    pub fn show_funcs(&self) {
+        for (idx, func) in self.funcs.iter().enumerate() {
+            let mut line = format!("{}: {}", self.func_desc(idx), self.func_sig(idx));
+            if let Function::Import(func) = func {
+                line += &format!(" import {}", func.name);
+            }
+            for export in self.exports() {
+                if matches!(export.desc(), Exportdesc::Func(i) if *i as usize == idx) {
+                    line += &format!(" export \"{}\"", export.name());
+                }
+            }
+            println!("{}", line);
+        }
+    }
+
+    // serialize back to the binary format. sections are emitted in the
+    // order they were read, so canonical input round-trips byte-identically.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = ByteCodeBuff::new(Vec::new());
+        buf.put_bytes(&[0, b'a', b's', b'm', 1, 0, 0, 0]);
+        let mut customs = self.customs.iter();
+        for sec_s in &self.sec_summary {
+            let mut body = ByteCodeBuff::new(Vec::new());
+            if sec_s.id == 0 {
+                if let Some(custom) = customs.next() {
+                    custom.put(&mut body);
+                }
+            } else if sec_s.id == 10 {
+                self.put_code(&mut body);
+            } else if let Some(sec) = self.sections.get(&sec_s.id) {
+                sec.put(&mut body);
+            }
+            buf.put_byte(sec_s.id);
+            buf.put_data(&body.into_vec());
+        }
+        buf.into_vec()
+    }
+
+    fn put_code(&self, buf: &mut ByteCodeBuff) {
+        let num = self.num_import_func();
+        buf.put_u32((self.funcs.len() - num) as u32);
+        for i in num..self.funcs.len() {
+            let func = self.get_local_func(i);
+            let mut body = ByteCodeBuff::new(Vec::new());
+            // locals: compress runs of the same type
+            let mut runs: Vec<(u32, u8)> = Vec::new();
+            for l in &func.locals {
+                match runs.last_mut() {
+                    Some((n, t)) if *t == l.0 => *n += 1,
+                    _ => runs.push((1, l.0)),
+                }
             }
+            body.put_u32(runs.len() as u32);
+            for (n, t) in runs {
+                body.put_u32(n);
+                body.put_byte(t);
+            }
+            put_insts(&func.insts, &mut body);
+            buf.put_data(&body.into_vec());
         }
     }
 
@@ -759,17 +1234,146 @@ impl Module {
         self.funcs.len()
     }
 
+    pub fn funcs(&self) -> &[Function] {
+        &self.funcs
+    }
+
+    pub fn types(&self) -> &[Functype] {
+        match self.sections.get(&1) {
+            Some(Section::Type(sec)) => &sec.ft,
+            _ => &[],
+        }
+    }
+
+    pub fn imports(&self) -> &[Import] {
+        match self.sections.get(&2) {
+            Some(Section::Import(sec)) => &sec.import,
+            _ => &[],
+        }
+    }
+
+    // type index of each local (non-import) function
+    pub fn func_typeidx(&self) -> &[u32] {
+        match self.sections.get(&3) {
+            Some(Section::Function(sec)) => &sec.typeidx,
+            _ => &[],
+        }
+    }
+
+    pub fn tables(&self) -> &[Tabletype] {
+        match self.sections.get(&4) {
+            Some(Section::Table(sec)) => &sec.table,
+            _ => &[],
+        }
+    }
+
+    pub fn mems(&self) -> &[Limits] {
+        match self.sections.get(&5) {
+            Some(Section::Memory(sec)) => &sec.mem,
+            _ => &[],
+        }
+    }
+
+    pub fn globals(&self) -> &[Global] {
+        match self.sections.get(&6) {
+            Some(Section::Global(sec)) => &sec.global,
+            _ => &[],
+        }
+    }
+
+    pub fn exports(&self) -> &[Export] {
+        match self.sections.get(&7) {
+            Some(Section::Export(sec)) => &sec.export,
+            _ => &[],
+        }
+    }
+
+    // function index of an exported function
+    pub fn export_func(&self, name: &str) -> Option<usize> {
+        self.exports().iter().find_map(|e| match e.desc() {
+            Exportdesc::Func(idx) if e.name() == name => Some(*idx as usize),
+            _ => None,
+        })
+    }
+
+    pub fn start(&self) -> Option<u32> {
+        match self.sections.get(&8) {
+            Some(Section::Start(sec)) => Some(sec.idx),
+            _ => None,
+        }
+    }
+
+    pub fn elems(&self) -> &[Elem] {
+        match self.sections.get(&9) {
+            Some(Section::Element(sec)) => &sec.elem,
+            _ => &[],
+        }
+    }
+
+    pub fn datas(&self) -> &[Data] {
+        match self.sections.get(&11) {
+            Some(Section::Data(sec)) => &sec.data,
+            _ => &[],
+        }
+    }
+
+    pub fn data_count(&self) -> Option<u32> {
+        match self.sections.get(&12) {
+            Some(Section::DataCount(sec)) => Some(sec.count),
+            _ => None,
+        }
+    }
+
+    pub fn is_validated(&self) -> bool {
+        self.validated
+    }
+
+    // the relaxed SIMD instructions are rejected by validate() unless this is set
+    pub fn set_relaxed_simd(&mut self, enabled: bool) {
+        self.relaxed_simd = enabled;
+    }
+
+    pub fn relaxed_simd(&self) -> bool {
+        self.relaxed_simd
+    }
+
+    pub fn validate(&mut self) -> Result<(), Vec<ValidateError>> {
+        validate_module(self)?;
+        self.validated = true;
+        Ok(())
+    }
+
     fn num_import_func(&self) -> usize {
         let mut num: usize = 0;
         for item in &self.funcs {
-            match item {
-                Function::Import(_) => num += 1,
-                _ => (),
+            if let Function::Import(_) = item {
+                num += 1;
             }
         }
         num
     }
 
+    pub fn names(&self) -> &Names {
+        &self.names
+    }
+
+    // "function[idx]" with the name if it has one (for messages)
+    pub fn func_desc(&self, idx: usize) -> String {
+        match self.names.func(idx as u32) {
+            Some(name) => format!("function[{}] <{}>", idx, name),
+            None => format!("function[{}]", idx),
+        }
+    }
+
+    // function index by the name in the name section
+    pub fn func_index(&self, name: &str) -> Option<usize> {
+        self.names.func_index(name).map(|idx| idx as usize)
+    }
+
+    pub fn get_func(&self, idx: usize) -> Option<&Function> {
+        self.funcs.get(idx)
+    }
+
     pub fn is_import_func(&self, idx: usize) -> bool {
         let item = self.funcs.get(idx);
         if let Some(Function::Import(_)) = item {
@@ -781,81 +1385,321 @@ impl Module {
     pub fn get_local_func(&self, idx: usize) -> &LocalFunc {
         let item = self.funcs.get(idx);
         if let Some(Function::Local(lc_func)) = item {
-            return lc_func
+            lc_func
         } else {
             panic!("illegale index");
         }
     }
 }
 
-fn check_magic(buf: &[u8]) -> Result<(), String> {
+fn check_magic(buf: &[u8]) -> Result<(), DecodeError> {
     if buf.len() < 8 || buf[0..4] != [0, b'a', b's', b'm'] {
-        return Err("invalid format".to_string());
+        return Err(DecodeError::Magic);
     }
 
     let ver = u32::from_le_bytes(buf[4..8].try_into().unwrap());
     if ver == 1 {
         Ok(())
     } else {
-        Err(format!("version {} is invalid", ver))
+        Err(DecodeError::Version(ver))
+    }
+}
+
+// position of each section id in the order the spec requires.
+// datacount(12) comes between element(9) and code(10).
+fn section_order(sec_id: u8) -> u8 {
+    match sec_id {
+        12 => 10,
+        10 | 11 => sec_id + 1,
+        _ => sec_id,
     }
 }
 
-pub fn init_module(buf: Vec<u8>) -> Result<Module, String> {
+fn sec_start(sec_summary: &[SectionSummary], sec_id: u8) -> usize {
+    for sec_s in sec_summary {
+        if sec_s.id == sec_id {
+            return sec_s.start;
+        }
+    }
+    0
+}
+
+pub fn init_module(buf: Vec<u8>) -> Result<Module, DecodeError> {
     check_magic(&buf)?;
 
     let mut buf = ByteCodeBuff::new(buf);
     let mut sec_summary = Vec::new();
     let mut sections = HashMap::new();
+    let mut customs = Vec::new();
     let mut funcs = Vec::new();
 
     buf.add_cur(8);
+    let mut last_order = 0;
     loop {
         if !buf.more() {
             break;
         }
-        let sec_id = buf.get_byte();
-        let size = buf.get_u32();
+        let id_cur = buf.get_cur();
+        let sec_id = buf.get_byte()?;
+        buf.set_section(sec_id, buf.len());
+        let size = buf.get_u32()?;
         let start = buf.get_cur();
-        let section = Section::get_section(sec_id, size, &mut buf);
+        let end = start + size as usize;
+        if end > buf.len() {
+            return Err(DecodeError::UnexpectedEnd {sec_id, offset: buf.len()});
+        }
+        if sec_id != 0 && sec_id <= 12 {
+            let order = section_order(sec_id);
+            if order <= last_order {
+                return Err(buf.err_unexpected(id_cur, "section in order", sec_id as u32));
+            }
+            last_order = order;
+        }
+        buf.set_section(sec_id, end);
+        let section = Section::get_section(sec_id, &mut buf)?;
+        if buf.get_cur() != end {
+            return Err(buf.err_malformed(buf.get_cur(), "section size mismatch"));
+        }
+        buf.set_section(0, buf.len());
         let item = section.summary_item();
         let summary = SectionSummary {id: sec_id, size, start, item,};
         sec_summary.push(summary);
-        if sec_id != 0 {
+        if let Section::Custom(custom) = section {
+            customs.push(custom);
+        } else {
             sections.insert(sec_id, section);
         }
     }
 
+    let no_types = Vec::new();
+    let types = match sections.get(&1) {
+        Some(Section::Type(type_sec)) => &type_sec.ft,
+        _ => &no_types,
+    };
+
     if let Some(Section::Import(import_sec)) = sections.get(&2) {
         for im in &import_sec.import {
             if let Importdesc::Func(idx) = &im.desc {
-                if let Some(Section::Type(type_sec)) = sections.get(&1) {
-                    let im_func = ImportFunc {
-                        name: format!("{}.{}", im.module, im.name),
-                        ft: type_sec.ft[*idx as usize].clone(),
-                    };
-                    funcs.push(Function::Import(im_func));
-                }
+                let ft = match types.get(*idx as usize) {
+                    Some(ft) => ft,
+                    None => return Err(DecodeError::Malformed {sec_id: 2,
+                        offset: sec_start(&sec_summary, 2), reason: "unknown type"}),
+                };
+                let im_func = ImportFunc {
+                    name: format!("{}.{}", im.module, im.name),
+                    ft: ft.clone(),
+                };
+                funcs.push(Function::Import(im_func));
             }
         }
     }
 
-    if let Some(Section::Code(code_sec)) = sections.get(&10) {
-        if let Some(Section::Function(func_sec)) = sections.get(&3) {
-            for (i, idx) in func_sec.typeidx.iter().enumerate() {
-                let code = &code_sec.code[i];
-                if let Some(Section::Type(type_sec)) = sections.get(&1) {
-                    buf.set_cur(code.start);
-                    let lc_func = LocalFunc {
-                        ft: type_sec.ft[*idx as usize].clone(),
-                        locals: code.locals.clone(),
-                        insts: get_insts(&mut buf),
-                    };
-                    funcs.push(Function::Local(lc_func));
-                }
+    let typeidx = match sections.get(&3) {
+        Some(Section::Function(func_sec)) => func_sec.typeidx.as_slice(),
+        _ => &[],
+    };
+    let codes = match sections.get(&10) {
+        Some(Section::Code(code_sec)) => code_sec.code.as_slice(),
+        _ => &[],
+    };
+    if typeidx.len() != codes.len() {
+        return Err(DecodeError::Malformed {sec_id: 10, offset: sec_start(&sec_summary, 10),
+            reason: "function and code section have inconsistent lengths"});
+    }
+    for (i, idx) in typeidx.iter().enumerate() {
+        let code = &codes[i];
+        let ft = match types.get(*idx as usize) {
+            Some(ft) => ft,
+            None => return Err(DecodeError::Malformed {sec_id: 3,
+                offset: sec_start(&sec_summary, 3), reason: "unknown type"}),
+        };
+        buf.set_cur(code.start);
+        buf.set_section(10, code.end);
+        let insts = get_insts(&mut buf)?;
+        let lc_func = LocalFunc {
+            ft: ft.clone(),
+            locals: code.locals.clone(),
+            jumps: compile_jumps(&insts, ft, types),
+            insts,
+        };
+        if buf.get_cur() != code.end {
+            return Err(buf.err_malformed(buf.get_cur(), "function body size mismatch"));
+        }
+        funcs.push(Function::Local(lc_func));
+    }
+
+    // custom sections do not affect the validity of a module,
+    // so a malformed name section is just ignored.
+    let names = customs.iter()
+        .find(|c| c.name == "name")
+        .and_then(|c| Names::decode(&c.data).ok())
+        .unwrap_or_default();
+
+    Ok(Module{sec_summary, sections, customs, funcs, names, validated: false, relaxed_simd: false,})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leb(mut n: usize) -> Vec<u8> {
+        let mut v = Vec::new();
+        loop {
+            let b = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                v.push(b);
+                return v;
             }
+            v.push(b | 0x80);
         }
     }
 
-    Ok(Module{sec_summary, sections, funcs,})
+    fn module(sections: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = vec![0, b'a', b's', b'm', 1, 0, 0, 0];
+        for (id, body) in sections {
+            buf.push(*id);
+            buf.extend(leb(body.len()));
+            buf.extend(body);
+        }
+        buf
+    }
+
+    // a module with a function () -> () of the body (locals and instructions)
+    fn func_module(body: &[u8]) -> Vec<u8> {
+        let mut code = vec![1];
+        code.extend(leb(body.len()));
+        code.extend(body);
+        module(&[(1, vec![1, 0x60, 0, 0]), (3, vec![1, 0]), (10, code)])
+    }
+
+    #[test]
+    fn header() {
+        assert!(init_module(module(&[])).is_ok());
+        assert!(matches!(init_module(b"\0asn\x01\0\0\0".to_vec()), Err(DecodeError::Magic)));
+        assert!(matches!(init_module(b"\0asm\x01".to_vec()), Err(DecodeError::Magic)));
+        assert!(matches!(init_module(b"\0asm\x02\0\0\0".to_vec()), Err(DecodeError::Version(2))));
+    }
+
+    #[test]
+    fn truncated() {
+        // the section size is beyond the end
+        let mut buf = module(&[(1, vec![1, 0x60, 0, 0])]);
+        buf.truncate(buf.len() - 1);
+        let len = buf.len();
+        assert!(matches!(init_module(buf), Err(DecodeError::UnexpectedEnd {sec_id: 1, offset}) if offset == len));
+        // the contents are shorter than the vector count
+        let buf = module(&[(1, vec![2, 0x60, 0, 0])]);
+        let len = buf.len();
+        assert!(matches!(init_module(buf), Err(DecodeError::UnexpectedEnd {sec_id: 1, offset}) if offset == len));
+        // no end of the function body
+        let buf = func_module(&[0, 0x01]);
+        let len = buf.len();
+        assert!(matches!(init_module(buf), Err(DecodeError::UnexpectedEnd {sec_id: 10, offset}) if offset == len));
+    }
+
+    #[test]
+    fn overlong_leb128() {
+        // the count of the type section at offset 10 in 6 bytes
+        let buf = module(&[(1, vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x00])]);
+        assert!(matches!(init_module(buf), Err(DecodeError::Malformed {sec_id: 1, offset: 10, reason})
+                         if reason == "integer representation too long"));
+        let buf = module(&[(1, vec![0xff, 0xff, 0xff, 0xff, 0x7f])]);
+        assert!(matches!(init_module(buf), Err(DecodeError::Malformed {sec_id: 1, offset: 10, reason})
+                         if reason == "integer too large"));
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let depth = 100000;
+        let mut body = vec![0];
+        for _ in 0..depth {
+            body.extend([0x02, 0x40]);
+        }
+        let open = body.clone();
+        body.extend(vec![0x0b; depth + 1]);
+        let module = init_module(func_module(&body)).unwrap();
+        assert_eq!(module.get_local_func(0).insts.len(), depth * 2 + 1);
+        // the ends are missing
+        let buf = func_module(&open);
+        let len = buf.len();
+        assert!(matches!(init_module(buf), Err(DecodeError::UnexpectedEnd {sec_id: 10, offset}) if offset == len));
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let mut body = vec![1];
+        body.extend(leb(MAX_LOCALS as usize + 1));
+        body.extend([0x7f, 0x0b]);
+        let buf = func_module(&body);
+        assert!(matches!(init_module(buf), Err(DecodeError::Malformed {sec_id: 10, reason, ..})
+                         if reason == "too many locals"));
+        // the total overflows u32
+        let body = [2, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x7f, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x7e, 0x0b];
+        assert!(matches!(init_module(func_module(&body)), Err(DecodeError::Malformed {sec_id: 10, reason, ..})
+                         if reason == "too many locals"));
+    }
+
+    fn section(id: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = ByteCodeBuff::new(Vec::new());
+        buf.put_byte(id);
+        buf.put_u32(body.len() as u32);
+        let mut v = buf.into_vec();
+        v.extend_from_slice(body);
+        v
+    }
+
+    fn code(body: &[u8]) -> Vec<u8> {
+        let mut v = vec![body.len() as u8];
+        v.extend_from_slice(body);
+        v
+    }
+
+    // a module with every kind of section, including a custom section
+    // before the type section and one after the data section
+    fn all_sections() -> Vec<u8> {
+        let mut m = b"\0asm\x01\0\0\0".to_vec();
+        m.extend(section(0, b"\x04metaabc"));
+        m.extend(section(1, b"\x02\x60\x02\x7f\x7f\x01\x7f\x60\x00\x00"));
+        m.extend(section(2, b"\x02\x03env\x01f\x00\x01\x03env\x01g\x03\x7f\x00"));
+        m.extend(section(3, b"\x02\x00\x01"));
+        m.extend(section(4, b"\x01\x70\x00\x02"));
+        m.extend(section(5, b"\x01\x01\x01\x02"));
+        m.extend(section(6, b"\x01\x7f\x01\x41\x2a\x0b"));
+        m.extend(section(7, b"\x02\x03add\x00\x01\x03mem\x02\x00"));
+        m.extend(section(8, b"\x02"));
+        m.extend(section(9, b"\x02\x00\x41\x00\x0b\x02\x01\x02\x01\x00\x01\x01"));
+        m.extend(section(12, b"\x02"));
+        let mut codes = vec![2];
+        codes.extend(code(b"\x01\x01\x7f\x20\x00\x20\x01\x6a\x0b"));
+        codes.extend(code(b"\x00\x02\x40\x0b\x0b"));
+        m.extend(section(10, &codes));
+        m.extend(section(11, b"\x02\x00\x41\x00\x0b\x03abc\x01\x02hi"));
+        m.extend(section(0, b"\x04tail\x01\x02\x03"));
+        m
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let buf = all_sections();
+        let module = init_module(buf.clone()).unwrap();
+        assert_eq!(module.data_count(), Some(2));
+        assert_eq!(module.start(), Some(2));
+        assert_eq!(module.encode(), buf);
+    }
+
+    #[test]
+    fn encode_round_trip_wat() {
+        let src = r#"(module
+          (memory 1)
+          (global $g (mut i64) (i64.const -1))
+          (func $fac (export "fac") (param i32) (result i32)
+            (if (result i32) (i32.eqz (local.get 0))
+              (then (i32.const 1))
+              (else (i32.mul (local.get 0) (call $fac (i32.sub (local.get 0) (i32.const 1)))))))
+          (data (i32.const 8) "\01\02"))"#;
+        let buf = crate::parse_wat(src).unwrap().encode();
+        let module = init_module(buf.clone()).unwrap();
+        assert_eq!(module.encode(), buf);
+    }
 }
@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// the "name" custom section. besides module, function and local names
+// (spec appendix), the subsections of the extended name section proposal
+// (label, type, table, memory, global, elem and data names) are supported.
+
+use std::collections::HashMap;
+
+use crate::bytecode::*;
+
+pub type NameMap = HashMap<u32, String>;
+pub type IndirectNameMap = HashMap<u32, NameMap>;
+
+#[derive(Default)]
+pub struct Names {
+    pub module: Option<String>,
+    pub funcs: NameMap,
+    pub locals: IndirectNameMap,
+    pub labels: IndirectNameMap,
+    pub types: NameMap,
+    pub tables: NameMap,
+    pub mems: NameMap,
+    pub globals: NameMap,
+    pub elems: NameMap,
+    pub datas: NameMap,
+}
+
+fn get_namemap(buf: &mut ByteCodeBuff) -> Result<NameMap, DecodeError> {
+    let mut map = HashMap::new();
+    let n = buf.get_u32()?;
+    for _ in 0..n {
+        let idx = buf.get_u32()?;
+        let name = buf.get_name()?;
+        map.insert(idx, name);
+    }
+    Ok(map)
+}
+
+fn get_indirect_namemap(buf: &mut ByteCodeBuff) -> Result<IndirectNameMap, DecodeError> {
+    let mut map = HashMap::new();
+    let n = buf.get_u32()?;
+    for _ in 0..n {
+        let idx = buf.get_u32()?;
+        map.insert(idx, get_namemap(buf)?);
+    }
+    Ok(map)
+}
+
+// entries in index order (namemaps must be sorted in the binary)
+fn sorted(map: &NameMap) -> Vec<(&u32, &String)> {
+    let mut v: Vec<_> = map.iter().collect();
+    v.sort();
+    v
+}
+
+fn put_namemap(map: &NameMap, buf: &mut ByteCodeBuff) {
+    buf.put_u32(map.len() as u32);
+    for (idx, name) in sorted(map) {
+        buf.put_u32(*idx);
+        buf.put_name(name);
+    }
+}
+
+fn put_indirect_namemap(map: &IndirectNameMap, buf: &mut ByteCodeBuff) {
+    let mut v: Vec<_> = map.iter().collect();
+    v.sort_by_key(|(idx, _)| **idx);
+    buf.put_u32(v.len() as u32);
+    for (idx, m) in v {
+        buf.put_u32(*idx);
+        put_namemap(m, buf);
+    }
+}
+
+impl Names {
+    // decode the contents of the name section (following the section name)
+    pub fn decode(data: &[u8]) -> Result<Names, DecodeError> {
+        let mut buf = ByteCodeBuff::new(data.to_vec());
+        let mut names = Names::default();
+        while buf.more() {
+            let id = buf.get_byte()?;
+            let size = buf.get_u32()? as usize;
+            let end = buf.get_cur() + size;
+            if end > buf.len() {
+                return Err(buf.err_end());
+            }
+            buf.set_section(0, end);
+            match id {
+                0 => names.module = Some(buf.get_name()?),
+                1 => names.funcs = get_namemap(&mut buf)?,
+                2 => names.locals = get_indirect_namemap(&mut buf)?,
+                3 => names.labels = get_indirect_namemap(&mut buf)?,
+                4 => names.types = get_namemap(&mut buf)?,
+                5 => names.tables = get_namemap(&mut buf)?,
+                6 => names.mems = get_namemap(&mut buf)?,
+                7 => names.globals = get_namemap(&mut buf)?,
+                8 => names.elems = get_namemap(&mut buf)?,
+                9 => names.datas = get_namemap(&mut buf)?,
+                _ => buf.set_cur(end), // unknown subsection
+            }
+            if buf.get_cur() != end {
+                return Err(buf.err_malformed(buf.get_cur(), "name subsection size mismatch"));
+            }
+            buf.set_section(0, buf.len());
+        }
+        Ok(names)
+    }
+
+    // contents of the name section (without the section name)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = ByteCodeBuff::new(Vec::new());
+        let mut subsection = |id: u8, body: ByteCodeBuff| {
+            buf.put_byte(id);
+            buf.put_data(&body.into_vec());
+        };
+        if let Some(name) = &self.module {
+            let mut body = ByteCodeBuff::new(Vec::new());
+            body.put_name(name);
+            subsection(0, body);
+        }
+        if !self.funcs.is_empty() {
+            let mut body = ByteCodeBuff::new(Vec::new());
+            put_namemap(&self.funcs, &mut body);
+            subsection(1, body);
+        }
+        for (id, map) in [(2, &self.locals), (3, &self.labels)] {
+            if !map.is_empty() {
+                let mut body = ByteCodeBuff::new(Vec::new());
+                put_indirect_namemap(map, &mut body);
+                subsection(id, body);
+            }
+        }
+        let maps = [(4, &self.types), (5, &self.tables), (6, &self.mems), (7, &self.globals),
+            (8, &self.elems), (9, &self.datas)];
+        for (id, map) in maps {
+            if !map.is_empty() {
+                let mut body = ByteCodeBuff::new(Vec::new());
+                put_namemap(map, &mut body);
+                subsection(id, body);
+            }
+        }
+        buf.into_vec()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.module.is_none() && self.funcs.is_empty() && self.locals.is_empty()
+            && self.labels.is_empty() && self.types.is_empty() && self.tables.is_empty()
+            && self.mems.is_empty() && self.globals.is_empty() && self.elems.is_empty()
+            && self.datas.is_empty()
+    }
+
+    pub fn func(&self, idx: u32) -> Option<&str> {
+        self.funcs.get(&idx).map(|s| s.as_str())
+    }
+
+    pub fn local(&self, func_idx: u32, idx: u32) -> Option<&str> {
+        self.locals.get(&func_idx)?.get(&idx).map(|s| s.as_str())
+    }
+
+    pub fn label(&self, func_idx: u32, idx: u32) -> Option<&str> {
+        self.labels.get(&func_idx)?.get(&idx).map(|s| s.as_str())
+    }
+
+    pub fn global(&self, idx: u32) -> Option<&str> {
+        self.globals.get(&idx).map(|s| s.as_str())
+    }
+
+    // function index of a name
+    pub fn func_index(&self, name: &str) -> Option<u32> {
+        self.funcs.iter().find(|(_, n)| *n == name).map(|(idx, _)| *idx)
+    }
+
+    pub fn show(&self) {
+        if let Some(name) = &self.module {
+            println!("  module: {}", name);
+        }
+        for (idx, name) in sorted(&self.funcs) {
+            println!("  func[{}]: {}", idx, name);
+        }
+        let mut v: Vec<_> = self.locals.iter().collect();
+        v.sort_by_key(|(idx, _)| **idx);
+        for (func_idx, map) in v {
+            for (idx, name) in sorted(map) {
+                println!("  func[{}] local[{}]: {}", func_idx, idx, name);
+            }
+        }
+        let mut v: Vec<_> = self.labels.iter().collect();
+        v.sort_by_key(|(idx, _)| **idx);
+        for (func_idx, map) in v {
+            for (idx, name) in sorted(map) {
+                println!("  func[{}] label[{}]: {}", func_idx, idx, name);
+            }
+        }
+        let maps = [("type", &self.types), ("table", &self.tables), ("memory", &self.mems),
+            ("global", &self.globals), ("elem", &self.elems), ("data", &self.datas)];
+        for (kind, map) in maps {
+            for (idx, name) in sorted(map) {
+                println!("  {}[{}]: {}", kind, idx, name);
+            }
+        }
+    }
+}
@@ -0,0 +1,484 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// SIMD (0xfd) instructions: operand types and lane-wise operations.
+// a v128 is held in a u128 (lane 0 is the least significant bits).
+// the relaxed SIMD instructions (0x100..) give the same results as their
+// deterministic counterparts where the proposal allows it.
+
+use crate::exec::Value;
+use crate::inst::{lanes_fd, natural_align_fd};
+
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const F32: u8 = 0x7d;
+const F64: u8 = 0x7c;
+const V128: u8 = 0x7b;
+
+pub fn is_relaxed(sub_op: u32) -> bool {
+    sub_op >= 0x100
+}
+
+// (params, results) of a 0xfd instruction. None if sub_op is unknown
+pub fn signature(sub_op: u32) -> Option<(&'static [u8], &'static [u8])> {
+    let sig: (&[u8], &[u8]) = match sub_op {
+        0x00..=0x0a | 0x5c | 0x5d => (&[I32], &[V128]), // load
+        0x0b | 0x58..=0x5b => (&[I32, V128], &[]), // store, store_lane
+        0x54..=0x57 => (&[I32, V128], &[V128]), // load_lane
+        0x0c => (&[], &[V128]), // v128.const
+        0x0f..=0x11 => (&[I32], &[V128]), // splat
+        0x12 => (&[I64], &[V128]),
+        0x13 => (&[F32], &[V128]),
+        0x14 => (&[F64], &[V128]),
+        0x15 | 0x16 | 0x18 | 0x19 | 0x1b => (&[V128], &[I32]), // extract_lane
+        0x1d => (&[V128], &[I64]),
+        0x1f => (&[V128], &[F32]),
+        0x21 => (&[V128], &[F64]),
+        0x17 | 0x1a | 0x1c => (&[V128, I32], &[V128]), // replace_lane
+        0x1e => (&[V128, I64], &[V128]),
+        0x20 => (&[V128, F32], &[V128]),
+        0x22 => (&[V128, F64], &[V128]),
+        // any_true, all_true, bitmask
+        0x53 | 0x63 | 0x64 | 0x83 | 0x84 | 0xa3 | 0xa4 | 0xc3 | 0xc4 => (&[V128], &[I32]),
+        // shifts
+        0x6b..=0x6d | 0x8b..=0x8d | 0xab..=0xad | 0xcb..=0xcd => (&[V128, I32], &[V128]),
+        // bitselect, relaxed madd, laneselect, dot_add
+        0x52 | 0x105..=0x10c | 0x113 => (&[V128, V128, V128], &[V128]),
+        0x4d | 0x5e..=0x62 | 0x67..=0x6a | 0x74 | 0x75 | 0x7a | 0x7c..=0x81 | 0x87..=0x8a | 0x94 |
+        0xa0 | 0xa1 | 0xa7..=0xaa | 0xc0 | 0xc1 | 0xc7..=0xca | 0xe0 | 0xe1 | 0xe3 | 0xec | 0xed |
+        0xef | 0xf8..=0xff | 0x101..=0x104 => (&[V128], &[V128]),
+        // shuffle, swizzle, comparisons, bitwise, narrow, arithmetic, dot, extmul and relaxed swizzle,
+        // min, max, q15mulr, dot
+        0x0d | 0x0e | 0x23..=0x4c | 0x4e..=0x51 | 0x65 | 0x66 | 0x6e..=0x73 | 0x76..=0x79 | 0x7b | 0x82 |
+        0x85 | 0x86 | 0x8e..=0x93 | 0x95..=0x99 | 0x9b..=0x9f | 0xae | 0xb1 | 0xb5..=0xba | 0xbc..=0xbf |
+        0xce | 0xd1 | 0xd5..=0xdf | 0xe4..=0xeb | 0xf0..=0xf7 | 0x100 | 0x10d..=0x112 => (&[V128, V128], &[V128]),
+        _ => return None,
+    };
+    Some(sig)
+}
+
+fn mask(w: usize) -> u64 {
+    if w == 64 { u64::MAX } else { (1 << w) - 1 }
+}
+
+// sign extension of a lane of w bits
+fn signed(x: u64, w: usize) -> i64 {
+    ((x << (64 - w)) as i64) >> (64 - w)
+}
+
+// saturate to a signed integer of w bits
+fn sat_s(x: i64, w: usize) -> u64 {
+    x.clamp(-(1 << (w - 1)), (1 << (w - 1)) - 1) as u64
+}
+
+// n lanes of 128 / n bits
+fn lanes(v: u128, n: usize) -> [u64; 16] {
+    let w = 128 / n;
+    let mut l = [0; 16];
+    for (i, x) in l.iter_mut().take(n).enumerate() {
+        *x = (v >> (w * i)) as u64 & mask(w);
+    }
+    l
+}
+
+fn pack(l: &[u64], n: usize) -> u128 {
+    let w = 128 / n;
+    let mut v = 0;
+    for (i, x) in l.iter().take(n).enumerate() {
+        v |= ((x & mask(w)) as u128) << (w * i);
+    }
+    v
+}
+
+fn map(a: u128, n: usize, f: impl Fn(u64) -> u64) -> u128 {
+    pack(&lanes(a, n).map(f), n)
+}
+
+fn zip(a: u128, b: u128, n: usize, f: impl Fn(u64, u64) -> u64) -> u128 {
+    let (a, b) = (lanes(a, n), lanes(b, n));
+    let l: [u64; 16] = std::array::from_fn(|i| f(a[i], b[i]));
+    pack(&l, n)
+}
+
+fn mask_of(c: bool) -> u64 {
+    if c { u64::MAX } else { 0 }
+}
+
+fn f32_map(a: u128, f: impl Fn(f32) -> f32) -> u128 {
+    map(a, 4, |x| f(f32::from_bits(x as u32)).to_bits() as u64)
+}
+
+fn f64_map(a: u128, f: impl Fn(f64) -> f64) -> u128 {
+    map(a, 2, |x| f(f64::from_bits(x)).to_bits())
+}
+
+fn f32_zip(a: u128, b: u128, f: impl Fn(f32, f32) -> f32) -> u128 {
+    zip(a, b, 4, |x, y| f(f32::from_bits(x as u32), f32::from_bits(y as u32)).to_bits() as u64)
+}
+
+fn f64_zip(a: u128, b: u128, f: impl Fn(f64, f64) -> f64) -> u128 {
+    zip(a, b, 2, |x, y| f(f64::from_bits(x), f64::from_bits(y)).to_bits())
+}
+
+fn f32_cmp(a: u128, b: u128, f: impl Fn(f32, f32) -> bool) -> u128 {
+    zip(a, b, 4, |x, y| mask_of(f(f32::from_bits(x as u32), f32::from_bits(y as u32))))
+}
+
+fn f64_cmp(a: u128, b: u128, f: impl Fn(f64, f64) -> bool) -> u128 {
+    zip(a, b, 2, |x, y| mask_of(f(f64::from_bits(x), f64::from_bits(y))))
+}
+
+// low or high half of the 2n lanes of a extended to n lanes of double width
+fn extend(a: u128, n: usize, high: bool, signed_ext: bool) -> [u64; 16] {
+    let src = lanes(a, n * 2);
+    let w = 64 / n;
+    let base = if high { n } else { 0 };
+    std::array::from_fn(|i| {
+        let x = if i < n { src[base + i] } else { 0 };
+        if signed_ext { signed(x, w) as u64 } else { x }
+    })
+}
+
+// number of lanes of the integer instructions 0x60..=0xdf
+fn int_lanes(sub_op: u32) -> usize {
+    match sub_op {
+        0x60..=0x7f => 16,
+        0x80..=0x9f => 8,
+        0xa0..=0xbf => 4,
+        _ => 2,
+    }
+}
+
+// integer lane comparison. k: eq, ne, lt_s, lt_u, gt_s, gt_u, le_s, le_u, ge_s, ge_u
+fn int_cmp(k: u32, a: u128, b: u128, n: usize) -> u128 {
+    let w = 128 / n;
+    zip(a, b, n, |x, y| {
+        let (sx, sy) = (signed(x, w), signed(y, w));
+        mask_of(match k {
+            0 => x == y,
+            1 => x != y,
+            2 => sx < sy,
+            3 => x < y,
+            4 => sx > sy,
+            5 => x > y,
+            6 => sx <= sy,
+            7 => x <= y,
+            8 => sx >= sy,
+            _ => x >= y,
+        })
+    })
+}
+
+// a and b with signed lanes of double width narrowed to 2n lanes with saturation
+fn narrow(a: u128, b: u128, n: usize, signed_sat: bool) -> u128 {
+    let (la, lb) = (lanes(a, n / 2), lanes(b, n / 2));
+    let w = 128 / n;
+    let l: [u64; 16] = std::array::from_fn(|i| {
+        if i >= n {
+            return 0;
+        }
+        let x = signed(if i < n / 2 { la[i] } else { lb[i - n / 2] }, w * 2);
+        if signed_sat { sat_s(x, w) } else { x.clamp(0, mask(w) as i64) as u64 }
+    });
+    pack(&l, n)
+}
+
+fn q15mulr(x: u64, y: u64) -> u64 {
+    sat_s((signed(x, 16) * signed(y, 16) + 0x4000) >> 15, 16)
+}
+
+// i16 lanes of the sums of the products of adjacent signed i8 lanes (saturated)
+fn dot_i8x16(a: u128, b: u128) -> [u64; 16] {
+    let (a, b) = (lanes(a, 16), lanes(b, 16));
+    std::array::from_fn(|i| {
+        if i >= 8 {
+            return 0;
+        }
+        let p = |j: usize| signed(a[j], 8) * signed(b[j], 8);
+        sat_s(p(2 * i) + p(2 * i + 1), 16)
+    })
+}
+
+fn swizzle(a: u128, b: u128) -> u128 {
+    let (a, b) = (lanes(a, 16), lanes(b, 16));
+    let l: [u64; 16] = std::array::from_fn(|i| if b[i] < 16 { a[b[i] as usize] } else { 0 });
+    pack(&l, 16)
+}
+
+pub fn shuffle(a: u128, b: u128, idx: &[u8; 16]) -> u128 {
+    let (a, b) = (lanes(a, 16), lanes(b, 16));
+    let l: [u64; 16] = std::array::from_fn(|i| {
+        let j = idx[i] as usize;
+        if j < 16 { a[j] } else { b[j - 16] }
+    });
+    pack(&l, 16)
+}
+
+fn bitselect(a: u128, b: u128, c: u128) -> u128 {
+    (a & c) | (b & !c)
+}
+
+// lane bits of a scalar value
+fn bits(v: &Value) -> u64 {
+    match v {
+        Value::I32(n) => *n as u32 as u64,
+        Value::I64(n) => *n as u64,
+        Value::F32(z) => z.to_bits() as u64,
+        Value::F64(z) => z.to_bits(),
+        _ => 0,
+    }
+}
+
+// splat (0x0f..=0x14)
+pub fn splat(sub_op: u32, v: &Value) -> u128 {
+    let n = [16, 8, 4, 2, 4, 2][(sub_op - 0x0f) as usize];
+    pack(&[bits(v); 16], n)
+}
+
+pub fn extract_lane(sub_op: u32, a: u128, lane: u8) -> Value {
+    let n = lanes_fd(sub_op) as usize;
+    let x = lanes(a, n)[lane as usize];
+    match sub_op {
+        0x15 | 0x18 => Value::I32(signed(x, 128 / n) as i32),
+        0x16 | 0x19 | 0x1b => Value::I32(x as i32),
+        0x1d => Value::I64(x as i64),
+        0x1f => Value::F32(f32::from_bits(x as u32)),
+        _ => Value::F64(f64::from_bits(x)),
+    }
+}
+
+pub fn replace_lane(sub_op: u32, a: u128, lane: u8, v: &Value) -> u128 {
+    let n = lanes_fd(sub_op) as usize;
+    let mut l = lanes(a, n);
+    l[lane as usize] = bits(v);
+    pack(&l, n)
+}
+
+// v128 loaded from the bytes of the load instructions (0x00..=0x0a, 0x5c, 0x5d)
+pub fn load(sub_op: u32, bytes: &[u8]) -> u128 {
+    let mut buf = [0; 16];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let v = u128::from_le_bytes(buf);
+    match sub_op {
+        0x01..=0x06 => {
+            let n = [8, 4, 2][((sub_op - 1) / 2) as usize];
+            pack(&extend(v, n, false, sub_op & 1 == 1), n)
+        },
+        0x07..=0x0a => pack(&[v as u64; 16], 16 >> natural_align_fd(sub_op)),
+        _ => v,
+    }
+}
+
+// any_true, all_true and bitmask
+pub fn test(sub_op: u32, a: u128) -> i32 {
+    if sub_op == 0x53 {
+        return (a != 0) as i32;
+    }
+    let n = int_lanes(sub_op);
+    let l = lanes(a, n);
+    if sub_op & 0x1f == 0x03 {
+        l.iter().take(n).all(|x| *x != 0) as i32
+    } else {
+        let w = 128 / n;
+        l.iter().take(n).enumerate().fold(0, |m, (i, x)| m | ((x >> (w - 1)) as i32) << i)
+    }
+}
+
+// shl, shr_s, shr_u. the count is taken modulo the lane width
+pub fn shift(sub_op: u32, a: u128, count: i32) -> u128 {
+    let n = int_lanes(sub_op);
+    let w = 128 / n;
+    let s = count as u32 as usize % w;
+    match sub_op & 0x1f {
+        0x0b => map(a, n, |x| x << s),
+        0x0c => map(a, n, |x| (signed(x, w) >> s) as u64),
+        _ => map(a, n, |x| x >> s),
+    }
+}
+
+pub fn unop(sub_op: u32, a: u128) -> u128 {
+    match sub_op {
+        0x4d => !a, // v128.not
+        0x5e => { // f32x4.demote_f64x2_zero
+            let l = lanes(a, 2);
+            let z = |x: u64| (f64::from_bits(x) as f32).to_bits() as u64;
+            pack(&[z(l[0]), z(l[1]), 0, 0], 4)
+        },
+        0x5f => { // f64x2.promote_low_f32x4
+            let l = lanes(a, 4);
+            let z = |x: u64| (f32::from_bits(x as u32) as f64).to_bits();
+            pack(&[z(l[0]), z(l[1])], 2)
+        },
+        0x62 => map(a, 16, |x| x.count_ones() as u64), // i8x16.popcnt
+        0x67 => f32_map(a, f32::ceil),
+        0x68 => f32_map(a, f32::floor),
+        0x69 => f32_map(a, f32::trunc),
+        0x6a => f32_map(a, f32::round_ties_even),
+        0x74 => f64_map(a, f64::ceil),
+        0x75 => f64_map(a, f64::floor),
+        0x7a => f64_map(a, f64::trunc),
+        0x94 => f64_map(a, f64::round_ties_even),
+        0x7c..=0x7f => { // extadd_pairwise
+            let n = if sub_op < 0x7e { 8 } else { 4 };
+            let w = 64 / n;
+            let src = lanes(a, n * 2);
+            let ext = |x: u64| if sub_op & 1 == 0 { signed(x, w) as u64 } else { x };
+            let l: [u64; 16] = std::array::from_fn(|i| {
+                if i < n { ext(src[2 * i]).wrapping_add(ext(src[2 * i + 1])) } else { 0 }
+            });
+            pack(&l, n)
+        },
+        0xe0 => f32_map(a, f32::abs),
+        0xe1 => f32_map(a, |z| -z),
+        0xe3 => f32_map(a, f32::sqrt),
+        0xec => f64_map(a, f64::abs),
+        0xed => f64_map(a, |z| -z),
+        0xef => f64_map(a, f64::sqrt),
+        // saturating truncation. "as" saturates and maps NaN to 0
+        0xf8 | 0x101 => map(a, 4, |x| f32::from_bits(x as u32) as i32 as u32 as u64),
+        0xf9 | 0x102 => map(a, 4, |x| f32::from_bits(x as u32) as u32 as u64),
+        0xfa => map(a, 4, |x| (x as u32 as i32 as f32).to_bits() as u64),
+        0xfb => map(a, 4, |x| (x as u32 as f32).to_bits() as u64),
+        0xfc | 0xfd | 0x103 | 0x104 => {
+            let l = lanes(a, 2);
+            let t = |x: u64| {
+                let z = f64::from_bits(x);
+                if matches!(sub_op, 0xfc | 0x103) { z as i32 as u32 as u64 } else { z as u32 as u64 }
+            };
+            pack(&[t(l[0]), t(l[1]), 0, 0], 4)
+        },
+        0xfe | 0xff => { // f64x2.convert_low_i32x4
+            let l = lanes(a, 4);
+            let z = |x: u64| if sub_op == 0xfe { x as u32 as i32 as f64 } else { x as u32 as f64 };
+            pack(&[z(l[0]).to_bits(), z(l[1]).to_bits()], 2)
+        },
+        _ => { // integer unops
+            let n = int_lanes(sub_op);
+            let w = 128 / n;
+            match sub_op & 0x1f {
+                0x00 => map(a, n, |x| signed(x, w).wrapping_abs() as u64), // abs
+                0x01 => map(a, n, |x| x.wrapping_neg()), // neg
+                k => pack(&extend(a, n, k & 1 == 0, k < 0x09), n), // extend_low/high_s/u
+            }
+        },
+    }
+}
+
+pub fn binop(sub_op: u32, a: u128, b: u128) -> u128 {
+    match sub_op {
+        0x0e | 0x100 => swizzle(a, b),
+        0x23..=0x40 => {
+            let k = sub_op - 0x23;
+            int_cmp(k % 10, a, b, [16, 8, 4][(k / 10) as usize])
+        },
+        0x41 => f32_cmp(a, b, |x, y| x == y),
+        0x42 => f32_cmp(a, b, |x, y| x != y),
+        0x43 => f32_cmp(a, b, |x, y| x < y),
+        0x44 => f32_cmp(a, b, |x, y| x > y),
+        0x45 => f32_cmp(a, b, |x, y| x <= y),
+        0x46 => f32_cmp(a, b, |x, y| x >= y),
+        0x47 => f64_cmp(a, b, |x, y| x == y),
+        0x48 => f64_cmp(a, b, |x, y| x != y),
+        0x49 => f64_cmp(a, b, |x, y| x < y),
+        0x4a => f64_cmp(a, b, |x, y| x > y),
+        0x4b => f64_cmp(a, b, |x, y| x <= y),
+        0x4c => f64_cmp(a, b, |x, y| x >= y),
+        0x4e => a & b,
+        0x4f => a & !b,
+        0x50 => a | b,
+        0x51 => a ^ b,
+        0x65 | 0x66 => narrow(a, b, 16, sub_op == 0x65),
+        0x85 | 0x86 => narrow(a, b, 8, sub_op == 0x85),
+        0x82 | 0x111 => zip(a, b, 8, q15mulr),
+        0xba => { // i32x4.dot_i16x8_s
+            let (a, b) = (lanes(a, 8), lanes(b, 8));
+            let p = |j: usize| signed(a[j], 16) * signed(b[j], 16);
+            let l: [u64; 16] = std::array::from_fn(|i| if i < 4 { (p(2 * i) + p(2 * i + 1)) as u64 } else { 0 });
+            pack(&l, 4)
+        },
+        0xd6 => int_cmp(0, a, b, 2),
+        0xd7 => int_cmp(1, a, b, 2),
+        0xd8 => int_cmp(2, a, b, 2),
+        0xd9 => int_cmp(4, a, b, 2),
+        0xda => int_cmp(6, a, b, 2),
+        0xdb => int_cmp(8, a, b, 2),
+        0xe4 => f32_zip(a, b, |x, y| x + y),
+        0xe5 => f32_zip(a, b, |x, y| x - y),
+        0xe6 => f32_zip(a, b, |x, y| x * y),
+        0xe7 => f32_zip(a, b, |x, y| x / y),
+        0xe8 | 0x10d => f32_zip(a, b, crate::exec::f32_min),
+        0xe9 | 0x10e => f32_zip(a, b, crate::exec::f32_max),
+        0xea => f32_zip(a, b, |x, y| if y < x { y } else { x }), // pmin
+        0xeb => f32_zip(a, b, |x, y| if x < y { y } else { x }), // pmax
+        0xf0 => f64_zip(a, b, |x, y| x + y),
+        0xf1 => f64_zip(a, b, |x, y| x - y),
+        0xf2 => f64_zip(a, b, |x, y| x * y),
+        0xf3 => f64_zip(a, b, |x, y| x / y),
+        0xf4 | 0x10f => f64_zip(a, b, crate::exec::f64_min),
+        0xf5 | 0x110 => f64_zip(a, b, crate::exec::f64_max),
+        0xf6 => f64_zip(a, b, |x, y| if y < x { y } else { x }),
+        0xf7 => f64_zip(a, b, |x, y| if x < y { y } else { x }),
+        0x112 => pack(&dot_i8x16(a, b), 8), // i16x8.relaxed_dot_i8x16_i7x16_s
+        _ => { // integer binops
+            let n = int_lanes(sub_op);
+            let w = 128 / n;
+            let s = |x: u64| signed(x, w);
+            match sub_op & 0x1f {
+                0x0e => zip(a, b, n, |x, y| x.wrapping_add(y)),
+                0x0f => zip(a, b, n, |x, y| sat_s(s(x) + s(y), w)), // add_sat_s
+                0x10 => zip(a, b, n, |x, y| (x + y).min(mask(w))), // add_sat_u
+                0x11 => zip(a, b, n, |x, y| x.wrapping_sub(y)),
+                0x12 => zip(a, b, n, |x, y| sat_s(s(x) - s(y), w)), // sub_sat_s
+                0x13 => zip(a, b, n, |x, y| x.saturating_sub(y)), // sub_sat_u
+                0x15 => zip(a, b, n, |x, y| x.wrapping_mul(y)),
+                0x16 => zip(a, b, n, |x, y| if s(x) < s(y) { x } else { y }), // min_s
+                0x17 => zip(a, b, n, |x, y| x.min(y)),
+                0x18 => zip(a, b, n, |x, y| if s(x) > s(y) { x } else { y }), // max_s
+                0x19 => zip(a, b, n, |x, y| x.max(y)),
+                0x1b => zip(a, b, n, |x, y| (x + y).div_ceil(2)), // avgr_u
+                k => { // extmul_low/high_s/u
+                    let (high, signed_ext) = (k & 1 == 1, k < 0x1e);
+                    let (x, y) = (extend(a, n, high, signed_ext), extend(b, n, high, signed_ext));
+                    let l: [u64; 16] = std::array::from_fn(|i| x[i].wrapping_mul(y[i]));
+                    pack(&l, n)
+                },
+            }
+        },
+    }
+}
+
+// bitselect and the relaxed SIMD instructions with 3 operands
+pub fn ternop(sub_op: u32, a: u128, b: u128, c: u128) -> u128 {
+    match sub_op {
+        0x105 => {
+            let ab = f32_zip(a, b, |x, y| x * y);
+            f32_zip(ab, c, |x, y| x + y)
+        },
+        0x106 => {
+            let ab = f32_zip(a, b, |x, y| -(x * y));
+            f32_zip(ab, c, |x, y| x + y)
+        },
+        0x107 => {
+            let ab = f64_zip(a, b, |x, y| x * y);
+            f64_zip(ab, c, |x, y| x + y)
+        },
+        0x108 => {
+            let ab = f64_zip(a, b, |x, y| -(x * y));
+            f64_zip(ab, c, |x, y| x + y)
+        },
+        0x113 => { // i32x4.relaxed_dot_i8x16_i7x16_add_s
+            let d = dot_i8x16(a, b);
+            let c = lanes(c, 4);
+            let l: [u64; 16] = std::array::from_fn(|i| {
+                if i < 4 {
+                    (signed(d[2 * i], 16) + signed(d[2 * i + 1], 16)).wrapping_add(c[i] as i64) as u64
+                } else {
+                    0
+                }
+            });
+            pack(&l, 4)
+        },
+        _ => bitselect(a, b, c), // v128.bitselect, relaxed_laneselect
+    }
+}
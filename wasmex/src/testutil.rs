@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// setup shared by the exec, ir and jit tests.
+
+use crate::exec::{make_store, resolve_func, Store, Value};
+use crate::module::Module;
+
+// every test module has $fib besides its own functions
+const FIB: &str = r#"
+  (func $fib (export "fib") (param i32) (result i32)
+    (if (result i32) (i32.lt_u (local.get 0) (i32.const 2))
+      (then (local.get 0))
+      (else (i32.add (call $fib (i32.sub (local.get 0) (i32.const 1)))
+                     (call $fib (i32.sub (local.get 0) (i32.const 2)))))))"#;
+
+pub type Runner<'a> = &'a dyn Fn(usize, &[Value], &Module, &mut Store) -> Result<Vec<Value>, String>;
+
+// parse and validate a module made of fields plus $fib.
+pub fn load(fields: &str) -> Module {
+    let mut module = crate::parse_wat(&format!("(module {} {})", FIB, fields)).unwrap();
+    module.validate().unwrap();
+    module
+}
+
+// call an exported function on a fresh store with the given fuel.
+// returns the results and the fuel left.
+pub fn call(module: &Module, name: &str, args: &[Value], fuel: Option<u64>, run: Runner)
+            -> (Result<Vec<Value>, String>, Option<u64>) {
+    let mut store = make_store(module).unwrap();
+    store.set_fuel(fuel);
+    let idx = resolve_func(name, module).unwrap();
+    let result = run(idx, args, module, &mut store);
+    (result, store.fuel())
+}
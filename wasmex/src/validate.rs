@@ -0,0 +1,1123 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+#![allow(dead_code)]
+
+// module validation.
+// function bodies are checked with the operand stack / control stack
+// algorithm described in the appendix of the spec.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::bytecode::*;
+use crate::exec::*;
+use crate::inst::*;
+use crate::module::*;
+use crate::simd;
+
+#[derive(Debug)]
+pub enum ValidateError {
+    // what: "table[0]", "export[2]" etc.
+    Module {
+        what: String,
+        msg: String,
+    },
+    Func {
+        func_idx: usize,
+        inst_idx: usize,
+        mnemonic: String,
+        msg: String,
+    },
+}
+
+impl fmt::Display for ValidateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidateError::Module {what, msg} => write!(f, "{}: {}", what, msg),
+            ValidateError::Func {func_idx, inst_idx, mnemonic, msg} =>
+                write!(f, "func[{}] inst[{}] {}: {}", func_idx, inst_idx, mnemonic, msg),
+        }
+    }
+}
+
+impl ValidateError {
+    pub fn msg(&self) -> &str {
+        match self {
+            ValidateError::Module {msg, ..} => msg,
+            ValidateError::Func {msg, ..} => msg,
+        }
+    }
+}
+
+fn module_err(what: String, msg: &str) -> ValidateError {
+    ValidateError::Module {what, msg: msg.to_string()}
+}
+
+// operand type. UNKNOWN is used for the polymorphic stack after
+// unconditional branches.
+const UNKNOWN: u8 = 0;
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const F32: u8 = 0x7d;
+const F64: u8 = 0x7c;
+const V128: u8 = 0x7b;
+const FUNCREF: u8 = 0x70;
+const EXTERNREF: u8 = 0x6f;
+
+fn type_name(t: u8) -> String {
+    if t == UNKNOWN {
+        "unknown".to_string()
+    } else {
+        format!("{}", Valtype(t))
+    }
+}
+
+fn is_num(t: u8) -> bool {
+    matches!(t, I32 | I64 | F32 | F64 | UNKNOWN)
+}
+
+fn is_vec(t: u8) -> bool {
+    t == V128 || t == UNKNOWN
+}
+
+fn is_ref(t: u8) -> bool {
+    t == FUNCREF || t == EXTERNREF || t == UNKNOWN
+}
+
+fn valtypes(rt: &Resulttype) -> Vec<u8> {
+    rt.0.iter().map(|v| v.0).collect()
+}
+
+struct Context<'a> {
+    types: &'a [Functype],
+    funcs: Vec<&'a Functype>,
+    tables: Vec<u8>, // reftype
+    mems: usize,
+    globals: Vec<(u8, bool)>, // valtype, mutable
+    num_import_globals: usize,
+    elems: Vec<u8>, // reftype
+    datas: usize,
+    refs: HashSet<u32>,
+    relaxed_simd: bool,
+}
+
+impl<'a> Context<'a> {
+    fn new(module: &'a Module) -> Context<'a> {
+        let mut funcs = Vec::new();
+        for func in module.funcs() {
+            match func {
+                Function::Import(f) => funcs.push(&f.ft),
+                Function::Local(f) => funcs.push(&f.ft),
+            }
+        }
+        let mut tables = Vec::new();
+        let mut mems = 0;
+        let mut globals = Vec::new();
+        for im in module.imports() {
+            match im.desc() {
+                Importdesc::Table(tt) => tables.push(tt.reftype()),
+                Importdesc::Mem(_) => mems += 1,
+                Importdesc::Global(gt) => globals.push((gt.valtype(), gt.is_mutable())),
+                _ => (),
+            }
+        }
+        let num_import_globals = globals.len();
+        for tt in module.tables() {
+            tables.push(tt.reftype());
+        }
+        mems += module.mems().len();
+        for g in module.globals() {
+            globals.push((g.globaltype().valtype(), g.globaltype().is_mutable()));
+        }
+        let elems = module.elems().iter().map(|el| el.reftype()).collect();
+
+        // function indices which may be referenced by ref.func in code
+        let mut refs = HashSet::new();
+        for g in module.globals() {
+            collect_refs(g.expr(), &mut refs);
+        }
+        for el in module.elems() {
+            match el.init() {
+                ElemInit::Funcs(idx) => refs.extend(idx.iter()),
+                ElemInit::Exprs(exprs) => {
+                    for e in exprs {
+                        collect_refs(e, &mut refs);
+                    }
+                },
+            }
+        }
+        for ex in module.exports() {
+            if let Exportdesc::Func(idx) = ex.desc() {
+                refs.insert(*idx);
+            }
+        }
+
+        Context {
+            types: module.types(),
+            funcs,
+            tables,
+            mems,
+            globals,
+            num_import_globals,
+            elems,
+            datas: module.datas().len(),
+            refs,
+            relaxed_simd: module.relaxed_simd(),
+        }
+    }
+}
+
+fn collect_refs(expr: &Expr, refs: &mut HashSet<u32>) {
+    if let Ok(insts) = expr.get_insts() {
+        for inst in insts {
+            if let (0xd2, Operand::Index(idx)) = (inst.op_code, &inst.operand) {
+                refs.insert(*idx);
+            }
+        }
+    }
+}
+
+fn check_limits(limits: &Limits, k: u64) -> Result<(), &'static str> {
+    if limits.min() as u64 > k {
+        return Err("size out of range");
+    }
+    if let Some(max) = limits.max() {
+        if max as u64 > k {
+            return Err("size out of range");
+        }
+        if limits.min() > max {
+            return Err("size minimum must not be greater than maximum");
+        }
+    }
+    Ok(())
+}
+
+fn check_memtype(limits: &Limits) -> Result<(), &'static str> {
+    match check_limits(limits, 65536) {
+        Err("size out of range") => Err("memory size must be at most 65536 pages (4GiB)"),
+        r => r,
+    }
+}
+
+// constant expression. returns its (single) result type.
+fn check_const_expr(ctx: &Context, expr: &Expr, expect: u8) -> Result<(), String> {
+    let insts = match expr.get_insts() {
+        Ok(insts) => insts,
+        Err(e) => return Err(format!("{}", e)),
+    };
+    let mut stack: Vec<u8> = Vec::new();
+    for inst in &insts {
+        match (inst.op_code, &inst.operand) {
+            (0x41, _) => stack.push(I32),
+            (0x42, _) => stack.push(I64),
+            (0x43, _) => stack.push(F32),
+            (0x44, _) => stack.push(F64),
+            (0xfd, Operand::V128(_)) => stack.push(V128),
+            (0xd0, Operand::Valtype(t)) => stack.push(*t),
+            (0xd2, Operand::Index(idx)) => {
+                if *idx as usize >= ctx.funcs.len() {
+                    return Err(format!("unknown function {}", idx));
+                }
+                stack.push(FUNCREF);
+            },
+            (0x23, Operand::Index(idx)) => {
+                // only imported globals are visible here
+                if *idx as usize >= ctx.num_import_globals {
+                    return Err(format!("unknown global {}", idx));
+                }
+                let (t, mutable) = ctx.globals[*idx as usize];
+                if mutable {
+                    return Err("constant expression required".to_string());
+                }
+                stack.push(t);
+            },
+            // extended-const
+            (0x6a..=0x6c | 0x7c..=0x7e, _) => {
+                let t = if inst.op_code < 0x7c { I32 } else { I64 };
+                if stack.len() < 2 || stack.pop() != Some(t) || stack.pop() != Some(t) {
+                    return Err("type mismatch".to_string());
+                }
+                stack.push(t);
+            },
+            (0x0b, _) => (),
+            _ => return Err("constant expression required".to_string()),
+        }
+    }
+    if stack.len() != 1 || stack[0] != expect {
+        return Err("type mismatch".to_string());
+    }
+    Ok(())
+}
+
+pub fn validate_module(module: &Module) -> Result<(), Vec<ValidateError>> {
+    let ctx = Context::new(module);
+    let mut errs = Vec::new();
+
+    for (i, im) in module.imports().iter().enumerate() {
+        let what = format!("import[{}]", i);
+        match im.desc() {
+            Importdesc::Func(idx) => {
+                if *idx as usize >= ctx.types.len() {
+                    errs.push(module_err(what, &format!("unknown type {}", idx)));
+                }
+            },
+            Importdesc::Table(tt) => {
+                if let Err(e) = check_limits(tt.limits(), u32::MAX as u64) {
+                    errs.push(module_err(what, e));
+                }
+            },
+            Importdesc::Mem(limits) => {
+                if let Err(e) = check_memtype(limits) {
+                    errs.push(module_err(what, e));
+                }
+            },
+            Importdesc::Global(_) => (),
+        }
+    }
+
+    for (i, tt) in module.tables().iter().enumerate() {
+        if let Err(e) = check_limits(tt.limits(), u32::MAX as u64) {
+            errs.push(module_err(format!("table[{}]", i), e));
+        }
+    }
+
+    for (i, limits) in module.mems().iter().enumerate() {
+        if let Err(e) = check_memtype(limits) {
+            errs.push(module_err(format!("memory[{}]", i), e));
+        }
+    }
+    if ctx.mems > 1 {
+        errs.push(module_err("memory".to_string(), "multiple memories"));
+    }
+
+    for (i, g) in module.globals().iter().enumerate() {
+        if let Err(e) = check_const_expr(&ctx, g.expr(), g.globaltype().valtype()) {
+            errs.push(module_err(format!("global[{}]", i), &e));
+        }
+    }
+
+    let mut names = HashSet::new();
+    for (i, ex) in module.exports().iter().enumerate() {
+        let what = format!("export[{}]", i);
+        if !names.insert(ex.name()) {
+            errs.push(module_err(what.clone(), "duplicate export name"));
+        }
+        let (idx, num, kind) = match ex.desc() {
+            Exportdesc::Func(idx) => (idx, ctx.funcs.len(), "function"),
+            Exportdesc::Table(idx) => (idx, ctx.tables.len(), "table"),
+            Exportdesc::Mem(idx) => (idx, ctx.mems, "memory"),
+            Exportdesc::Global(idx) => (idx, ctx.globals.len(), "global"),
+        };
+        if *idx as usize >= num {
+            errs.push(module_err(what, &format!("unknown {} {}", kind, idx)));
+        }
+    }
+
+    if let Some(idx) = module.start() {
+        match ctx.funcs.get(idx as usize) {
+            None => errs.push(module_err("start".to_string(),
+                &format!("unknown function {}", idx))),
+            Some(ft) => {
+                if !ft.input.0.is_empty() || !ft.output.0.is_empty() {
+                    errs.push(module_err("start".to_string(), "start function"));
+                }
+            },
+        }
+    }
+
+    for (i, el) in module.elems().iter().enumerate() {
+        let what = format!("element[{}]", i);
+        if let ElemMode::Active(tableidx, offset) = el.mode() {
+            match ctx.tables.get(tableidx as usize) {
+                None => errs.push(module_err(what.clone(),
+                    &format!("unknown table {}", tableidx))),
+                Some(t) => {
+                    if *t != el.reftype() {
+                        errs.push(module_err(what.clone(), "type mismatch"));
+                    }
+                },
+            }
+            if let Err(e) = check_const_expr(&ctx, offset, I32) {
+                errs.push(module_err(what.clone(), &e));
+            }
+        }
+        match el.init() {
+            ElemInit::Funcs(idx) => {
+                for f in idx {
+                    if *f as usize >= ctx.funcs.len() {
+                        errs.push(module_err(what.clone(), &format!("unknown function {}", f)));
+                    }
+                }
+            },
+            ElemInit::Exprs(exprs) => {
+                for e in exprs {
+                    if let Err(e) = check_const_expr(&ctx, e, el.reftype()) {
+                        errs.push(module_err(what.clone(), &e));
+                    }
+                }
+            },
+        }
+    }
+
+    for (i, data) in module.datas().iter().enumerate() {
+        if let DataMode::Active(memidx, offset) = data.mode() {
+            let what = format!("data[{}]", i);
+            if memidx as usize >= ctx.mems {
+                errs.push(module_err(what.clone(), &format!("unknown memory {}", memidx)));
+            }
+            if let Err(e) = check_const_expr(&ctx, offset, I32) {
+                errs.push(module_err(what, &e));
+            }
+        }
+    }
+
+    for (idx, func) in module.funcs().iter().enumerate() {
+        if let Function::Local(func) = func {
+            if let Err(e) = validate_func(&ctx, idx, func) {
+                errs.push(e);
+            }
+        }
+    }
+
+    if errs.is_empty() {
+        Ok(())
+    } else {
+        Err(errs)
+    }
+}
+
+struct CtrlFrame {
+    op_code: u8,
+    start_types: Vec<u8>,
+    end_types: Vec<u8>,
+    height: usize,
+    unreachable: bool,
+}
+
+impl CtrlFrame {
+    fn label_types(&self) -> &[u8] {
+        if self.op_code == 0x03 {
+            &self.start_types
+        } else {
+            &self.end_types
+        }
+    }
+}
+
+struct Checker<'a> {
+    ctx: &'a Context<'a>,
+    locals: Vec<u8>,
+    vals: Vec<u8>,
+    ctrls: Vec<CtrlFrame>,
+}
+
+type CheckResult<T> = Result<T, String>;
+
+fn mismatch(expect: u8, actual: Option<u8>) -> String {
+    match actual {
+        Some(t) => format!("type mismatch: expected {}, found {}",
+            type_name(expect), type_name(t)),
+        None => format!("type mismatch: expected {}, but stack is empty", type_name(expect)),
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn push_val(&mut self, t: u8) {
+        self.vals.push(t);
+    }
+
+    fn pop_val(&mut self) -> CheckResult<Option<u8>> {
+        let frame = self.ctrls.last().unwrap();
+        if self.vals.len() == frame.height {
+            if frame.unreachable {
+                return Ok(Some(UNKNOWN));
+            }
+            return Ok(None);
+        }
+        Ok(self.vals.pop())
+    }
+
+    fn pop_any(&mut self) -> CheckResult<u8> {
+        match self.pop_val()? {
+            Some(t) => Ok(t),
+            None => Err("type mismatch: stack is empty".to_string()),
+        }
+    }
+
+    fn pop_expect(&mut self, expect: u8) -> CheckResult<u8> {
+        let actual = self.pop_val()?;
+        match actual {
+            Some(t) if t == expect || t == UNKNOWN || expect == UNKNOWN => Ok(t),
+            _ => Err(mismatch(expect, actual)),
+        }
+    }
+
+    fn push_vals(&mut self, types: &[u8]) {
+        self.vals.extend_from_slice(types);
+    }
+
+    fn pop_vals(&mut self, types: &[u8]) -> CheckResult<Vec<u8>> {
+        let mut popped = Vec::new();
+        for t in types.iter().rev() {
+            popped.insert(0, self.pop_expect(*t)?);
+        }
+        Ok(popped)
+    }
+
+    fn push_ctrl(&mut self, op_code: u8, start_types: Vec<u8>, end_types: Vec<u8>) {
+        let frame = CtrlFrame {
+            op_code,
+            start_types: start_types.clone(),
+            end_types,
+            height: self.vals.len(),
+            unreachable: false,
+        };
+        self.ctrls.push(frame);
+        self.push_vals(&start_types);
+    }
+
+    fn pop_ctrl(&mut self) -> CheckResult<CtrlFrame> {
+        if self.ctrls.is_empty() {
+            return Err("unexpected end".to_string());
+        }
+        let end_types = self.ctrls.last().unwrap().end_types.clone();
+        self.pop_vals(&end_types)?;
+        let frame = self.ctrls.pop().unwrap();
+        if self.vals.len() != frame.height {
+            self.ctrls.push(frame);
+            return Err("type mismatch: values remaining on stack at end of block".to_string());
+        }
+        Ok(frame)
+    }
+
+    fn unreachable(&mut self) {
+        let frame = self.ctrls.last_mut().unwrap();
+        self.vals.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn label(&self, n: u32) -> CheckResult<Vec<u8>> {
+        let n = n as usize;
+        if n >= self.ctrls.len() {
+            return Err(format!("unknown label {}", n));
+        }
+        Ok(self.ctrls[self.ctrls.len() - 1 - n].label_types().to_vec())
+    }
+
+    fn block_type(&self, bt: &BlockType) -> CheckResult<(Vec<u8>, Vec<u8>)> {
+        match bt {
+            BlockType::Empty => Ok((vec![], vec![])),
+            BlockType::Valtype(t) => Ok((vec![], vec![*t])),
+            BlockType::TypeIndex(idx) => match self.ctx.types.get(*idx as usize) {
+                Some(ft) => Ok((valtypes(&ft.input), valtypes(&ft.output))),
+                None => Err(format!("unknown type {}", idx)),
+            },
+        }
+    }
+
+    fn unop(&mut self, t_in: u8, t_out: u8) -> CheckResult<()> {
+        self.pop_expect(t_in)?;
+        self.push_val(t_out);
+        Ok(())
+    }
+
+    fn binop(&mut self, t_in: u8, t_out: u8) -> CheckResult<()> {
+        self.pop_expect(t_in)?;
+        self.pop_expect(t_in)?;
+        self.push_val(t_out);
+        Ok(())
+    }
+
+    fn local(&self, idx: u32) -> CheckResult<u8> {
+        match self.locals.get(idx as usize) {
+            Some(t) => Ok(*t),
+            None => Err(format!("unknown local {}", idx)),
+        }
+    }
+
+    fn global(&self, idx: u32) -> CheckResult<(u8, bool)> {
+        match self.ctx.globals.get(idx as usize) {
+            Some(g) => Ok(*g),
+            None => Err(format!("unknown global {}", idx)),
+        }
+    }
+
+    fn table(&self, idx: u32) -> CheckResult<u8> {
+        match self.ctx.tables.get(idx as usize) {
+            Some(t) => Ok(*t),
+            None => Err(format!("unknown table {}", idx)),
+        }
+    }
+
+    fn memory(&self, idx: u32) -> CheckResult<()> {
+        if idx as usize >= self.ctx.mems {
+            return Err(format!("unknown memory {}", idx));
+        }
+        Ok(())
+    }
+
+    fn elem(&self, idx: u32) -> CheckResult<u8> {
+        match self.ctx.elems.get(idx as usize) {
+            Some(t) => Ok(*t),
+            None => Err(format!("unknown elem segment {}", idx)),
+        }
+    }
+
+    fn data(&self, idx: u32) -> CheckResult<()> {
+        if idx as usize >= self.ctx.datas {
+            return Err(format!("unknown data segment {}", idx));
+        }
+        Ok(())
+    }
+
+    fn func_type(&self, idx: u32) -> CheckResult<(Vec<u8>, Vec<u8>)> {
+        match self.ctx.funcs.get(idx as usize) {
+            Some(ft) => Ok((valtypes(&ft.input), valtypes(&ft.output))),
+            None => Err(format!("unknown function {}", idx)),
+        }
+    }
+
+    fn memarg(&self, memarg: &Memarg, width: u32) -> CheckResult<()> {
+        self.memory(0)?;
+        if memarg.align >= 32 || (1u64 << memarg.align) > width as u64 {
+            return Err("alignment must not be larger than natural".to_string());
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, memarg: &Memarg, width: u32, t: u8) -> CheckResult<()> {
+        self.memarg(memarg, width)?;
+        self.unop(I32, t)
+    }
+
+    fn store(&mut self, memarg: &Memarg, width: u32, t: u8) -> CheckResult<()> {
+        self.memarg(memarg, width)?;
+        self.pop_expect(t)?;
+        self.pop_expect(I32)?;
+        Ok(())
+    }
+
+    fn inst(&mut self, inst: &Inst) -> CheckResult<()> {
+        match (inst.op_code, &inst.operand) {
+            (0x00, _) => self.unreachable(), // unreachable
+            (0x01, _) => (), // nop
+            (0x02..=0x04, Operand::BlockType(bt)) => { // block, loop, if
+                let (t1, t2) = self.block_type(bt)?;
+                if inst.op_code == 0x04 {
+                    self.pop_expect(I32)?;
+                }
+                self.pop_vals(&t1)?;
+                self.push_ctrl(inst.op_code, t1, t2);
+            },
+            (0x05, _) => { // else
+                let frame = self.pop_ctrl()?;
+                if frame.op_code != 0x04 {
+                    return Err("else without if".to_string());
+                }
+                self.push_ctrl(0x05, frame.start_types, frame.end_types);
+            },
+            (0x0b, _) => { // end
+                let frame = self.pop_ctrl()?;
+                if frame.op_code == 0x04 && frame.start_types != frame.end_types {
+                    return Err("type mismatch: if without else must not change the stack".to_string());
+                }
+                self.push_vals(&frame.end_types);
+            },
+            (0x0c, Operand::Index(n)) => { // br
+                let types = self.label(*n)?;
+                self.pop_vals(&types)?;
+                self.unreachable();
+            },
+            (0x0d, Operand::Index(n)) => { // br_if
+                self.pop_expect(I32)?;
+                let types = self.label(*n)?;
+                self.pop_vals(&types)?;
+                self.push_vals(&types);
+            },
+            (0x0e, Operand::BrTable(br_table)) => { // br_table
+                self.pop_expect(I32)?;
+                let default = self.label(br_table.default)?;
+                let arity = default.len();
+                for l in &br_table.labels {
+                    let types = self.label(*l)?;
+                    if types.len() != arity {
+                        return Err("type mismatch: br_table labels have inconsistent arity".to_string());
+                    }
+                    let popped = self.pop_vals(&types)?;
+                    self.push_vals(&popped);
+                }
+                self.pop_vals(&default)?;
+                self.unreachable();
+            },
+            (0x0f, _) => { // return
+                let types = self.ctrls[0].end_types.clone();
+                self.pop_vals(&types)?;
+                self.unreachable();
+            },
+            (0x10, Operand::Index(idx)) => { // call
+                let (t1, t2) = self.func_type(*idx)?;
+                self.pop_vals(&t1)?;
+                self.push_vals(&t2);
+            },
+            (0x11, Operand::Index2(tableidx, typeidx)) => { // call_indirect
+                if self.table(*tableidx)? != FUNCREF {
+                    return Err("type mismatch: call_indirect requires a funcref table".to_string());
+                }
+                let (t1, t2) = self.block_type(&BlockType::TypeIndex(*typeidx))?;
+                self.pop_expect(I32)?;
+                self.pop_vals(&t1)?;
+                self.push_vals(&t2);
+            },
+            (0x1a, _) => { // drop
+                self.pop_any()?;
+            },
+            (0x1b, _) => { // select
+                self.pop_expect(I32)?;
+                let t1 = self.pop_any()?;
+                let t2 = self.pop_any()?;
+                if !(is_num(t1) && is_num(t2) || is_vec(t1) && is_vec(t2)) {
+                    return Err("type mismatch: select operands must be numeric".to_string());
+                }
+                if t1 != t2 && t1 != UNKNOWN && t2 != UNKNOWN {
+                    return Err(mismatch(t1, Some(t2)));
+                }
+                self.push_val(if t1 == UNKNOWN {t2} else {t1});
+            },
+            (0x1c, Operand::VecValtype(types)) => { // select t
+                if types.len() != 1 {
+                    return Err("invalid result arity".to_string());
+                }
+                let t = types[0];
+                self.pop_expect(I32)?;
+                self.pop_expect(t)?;
+                self.pop_expect(t)?;
+                self.push_val(t);
+            },
+            (0x20, Operand::Index(idx)) => { // local.get
+                let t = self.local(*idx)?;
+                self.push_val(t);
+            },
+            (0x21, Operand::Index(idx)) => { // local.set
+                let t = self.local(*idx)?;
+                self.pop_expect(t)?;
+            },
+            (0x22, Operand::Index(idx)) => { // local.tee
+                let t = self.local(*idx)?;
+                self.unop(t, t)?;
+            },
+            (0x23, Operand::Index(idx)) => { // global.get
+                let (t, _) = self.global(*idx)?;
+                self.push_val(t);
+            },
+            (0x24, Operand::Index(idx)) => { // global.set
+                let (t, mutable) = self.global(*idx)?;
+                if !mutable {
+                    return Err("global is immutable".to_string());
+                }
+                self.pop_expect(t)?;
+            },
+            (0x25, Operand::Index(idx)) => { // table.get
+                let t = self.table(*idx)?;
+                self.unop(I32, t)?;
+            },
+            (0x26, Operand::Index(idx)) => { // table.set
+                let t = self.table(*idx)?;
+                self.pop_expect(t)?;
+                self.pop_expect(I32)?;
+            },
+            (0x28, Operand::Memarg(m)) => self.load(m, 4, I32)?,
+            (0x29, Operand::Memarg(m)) => self.load(m, 8, I64)?,
+            (0x2a, Operand::Memarg(m)) => self.load(m, 4, F32)?,
+            (0x2b, Operand::Memarg(m)) => self.load(m, 8, F64)?,
+            (0x2c | 0x2d, Operand::Memarg(m)) => self.load(m, 1, I32)?,
+            (0x2e | 0x2f, Operand::Memarg(m)) => self.load(m, 2, I32)?,
+            (0x30 | 0x31, Operand::Memarg(m)) => self.load(m, 1, I64)?,
+            (0x32 | 0x33, Operand::Memarg(m)) => self.load(m, 2, I64)?,
+            (0x34 | 0x35, Operand::Memarg(m)) => self.load(m, 4, I64)?,
+            (0x36, Operand::Memarg(m)) => self.store(m, 4, I32)?,
+            (0x37, Operand::Memarg(m)) => self.store(m, 8, I64)?,
+            (0x38, Operand::Memarg(m)) => self.store(m, 4, F32)?,
+            (0x39, Operand::Memarg(m)) => self.store(m, 8, F64)?,
+            (0x3a, Operand::Memarg(m)) => self.store(m, 1, I32)?,
+            (0x3b, Operand::Memarg(m)) => self.store(m, 2, I32)?,
+            (0x3c, Operand::Memarg(m)) => self.store(m, 1, I64)?,
+            (0x3d, Operand::Memarg(m)) => self.store(m, 2, I64)?,
+            (0x3e, Operand::Memarg(m)) => self.store(m, 4, I64)?,
+            (0x3f, _) => { // memory.size
+                self.memory(0)?;
+                self.push_val(I32);
+            },
+            (0x40, _) => { // memory.grow
+                self.memory(0)?;
+                self.unop(I32, I32)?;
+            },
+            (0x41, _) => self.push_val(I32),
+            (0x42, _) => self.push_val(I64),
+            (0x43, _) => self.push_val(F32),
+            (0x44, _) => self.push_val(F64),
+            (0x45, _) => self.unop(I32, I32)?, // i32.eqz
+            (0x46..=0x4f, _) => self.binop(I32, I32)?, // i32 compare
+            (0x50, _) => self.unop(I64, I32)?, // i64.eqz
+            (0x51..=0x5a, _) => self.binop(I64, I32)?, // i64 compare
+            (0x5b..=0x60, _) => self.binop(F32, I32)?, // f32 compare
+            (0x61..=0x66, _) => self.binop(F64, I32)?, // f64 compare
+            (0x67..=0x69, _) => self.unop(I32, I32)?,
+            (0x6a..=0x78, _) => self.binop(I32, I32)?,
+            (0x79..=0x7b, _) => self.unop(I64, I64)?,
+            (0x7c..=0x8a, _) => self.binop(I64, I64)?,
+            (0x8b..=0x91, _) => self.unop(F32, F32)?,
+            (0x92..=0x98, _) => self.binop(F32, F32)?,
+            (0x99..=0x9f, _) => self.unop(F64, F64)?,
+            (0xa0..=0xa6, _) => self.binop(F64, F64)?,
+            (0xa7..=0xc4, _) => {
+                let (t_in, t_out) = CONVERT_TYPES[(inst.op_code - 0xa7) as usize];
+                self.unop(t_in, t_out)?;
+            },
+            (0xd0, Operand::Valtype(t)) => self.push_val(*t), // ref.null
+            (0xd1, _) => { // ref.is_null
+                let t = self.pop_any()?;
+                if !is_ref(t) {
+                    return Err(format!("type mismatch: expected reference, found {}",
+                        type_name(t)));
+                }
+                self.push_val(I32);
+            },
+            (0xd2, Operand::Index(idx)) => { // ref.func
+                self.func_type(*idx)?;
+                if !self.ctx.refs.contains(idx) {
+                    return Err("undeclared function reference".to_string());
+                }
+                self.push_val(FUNCREF);
+            },
+            (0xfc, _) => self.inst_fc(inst)?,
+            (0xfd, _) => self.inst_fd(inst)?,
+            _ => return Err("unknown instruction".to_string()),
+        }
+        Ok(())
+    }
+
+    fn inst_fc(&mut self, inst: &Inst) -> CheckResult<()> {
+        match (inst.sub_op, &inst.operand) {
+            (0 | 1, _) => self.unop(F32, I32)?, // i32.trunc_sat_f32
+            (2 | 3, _) => self.unop(F64, I32)?, // i32.trunc_sat_f64
+            (4 | 5, _) => self.unop(F32, I64)?, // i64.trunc_sat_f32
+            (6 | 7, _) => self.unop(F64, I64)?, // i64.trunc_sat_f64
+            (8, Operand::Index2(dataidx, _)) => { // memory.init
+                self.memory(0)?;
+                self.data(*dataidx)?;
+                self.pop_vals(&[I32, I32, I32])?;
+            },
+            (9, Operand::Index(dataidx)) => self.data(*dataidx)?, // data.drop
+            (10 | 11, _) => { // memory.copy, memory.fill
+                self.memory(0)?;
+                self.pop_vals(&[I32, I32, I32])?;
+            },
+            (12, Operand::Index2(elemidx, tableidx)) => { // table.init
+                let t1 = self.table(*tableidx)?;
+                let t2 = self.elem(*elemidx)?;
+                if t1 != t2 {
+                    return Err(mismatch(t1, Some(t2)));
+                }
+                self.pop_vals(&[I32, I32, I32])?;
+            },
+            (13, Operand::Index(elemidx)) => { // elem.drop
+                self.elem(*elemidx)?;
+            },
+            (14, Operand::Index2(dst, src)) => { // table.copy
+                let t1 = self.table(*dst)?;
+                let t2 = self.table(*src)?;
+                if t1 != t2 {
+                    return Err(mismatch(t1, Some(t2)));
+                }
+                self.pop_vals(&[I32, I32, I32])?;
+            },
+            (15, Operand::Index(idx)) => { // table.grow
+                let t = self.table(*idx)?;
+                self.pop_vals(&[t, I32])?;
+                self.push_val(I32);
+            },
+            (16, Operand::Index(idx)) => { // table.size
+                self.table(*idx)?;
+                self.push_val(I32);
+            },
+            (17, Operand::Index(idx)) => { // table.fill
+                let t = self.table(*idx)?;
+                self.pop_vals(&[I32, t, I32])?;
+            },
+            _ => return Err("unknown instruction".to_string()),
+        }
+        Ok(())
+    }
+
+    fn inst_fd(&mut self, inst: &Inst) -> CheckResult<()> {
+        if simd::is_relaxed(inst.sub_op) && !self.ctx.relaxed_simd {
+            return Err("relaxed SIMD is not enabled".to_string());
+        }
+        let lane = |lane: u8| -> CheckResult<()> {
+            if lane >= lanes_fd(inst.sub_op) {
+                return Err("invalid lane index".to_string());
+            }
+            Ok(())
+        };
+        match &inst.operand {
+            Operand::Memarg(m) => self.memarg(m, 1 << natural_align_fd(inst.sub_op))?,
+            Operand::MemargLane(m, l) => {
+                self.memarg(m, 1 << natural_align_fd(inst.sub_op))?;
+                lane(*l)?;
+            },
+            Operand::Lane(l) => lane(*l)?,
+            Operand::Shuffle(lanes) if lanes.iter().any(|l| *l >= 32) => {
+                return Err("invalid lane index".to_string());
+            },
+            _ => (),
+        }
+        let (params, results) = simd::signature(inst.sub_op).ok_or("unknown SIMD instruction")?;
+        self.pop_vals(params)?;
+        self.push_vals(results);
+        Ok(())
+    }
+}
+
+// conversion instructions 0xa7..=0xc4: (input, output)
+const CONVERT_TYPES: [(u8, u8); 30] = [
+/*0xa7*/ (I64, I32), // i32.wrap_i64
+/*0xa8*/ (F32, I32), // i32.trunc_f32_s
+/*0xa9*/ (F32, I32), // i32.trunc_f32_u
+/*0xaa*/ (F64, I32), // i32.trunc_f64_s
+/*0xab*/ (F64, I32), // i32.trunc_f64_u
+/*0xac*/ (I32, I64), // i64.extend_i32_s
+/*0xad*/ (I32, I64), // i64.extend_i32_u
+/*0xae*/ (F32, I64), // i64.trunc_f32_s
+/*0xaf*/ (F32, I64), // i64.trunc_f32_u
+/*0xb0*/ (F64, I64), // i64.trunc_f64_s
+/*0xb1*/ (F64, I64), // i64.trunc_f64_u
+/*0xb2*/ (I32, F32), // f32.convert_i32_s
+/*0xb3*/ (I32, F32), // f32.convert_i32_u
+/*0xb4*/ (I64, F32), // f32.convert_i64_s
+/*0xb5*/ (I64, F32), // f32.convert_i64_u
+/*0xb6*/ (F64, F32), // f32.demote_f64
+/*0xb7*/ (I32, F64), // f64.convert_i32_s
+/*0xb8*/ (I32, F64), // f64.convert_i32_u
+/*0xb9*/ (I64, F64), // f64.convert_i64_s
+/*0xba*/ (I64, F64), // f64.convert_i64_u
+/*0xbb*/ (F32, F64), // f64.promote_f32
+/*0xbc*/ (F32, I32), // i32.reinterpret_f32
+/*0xbd*/ (F64, I64), // i64.reinterpret_f64
+/*0xbe*/ (I32, F32), // f32.reinterpret_i32
+/*0xbf*/ (I64, F64), // f64.reinterpret_i64
+/*0xc0*/ (I32, I32), // i32.extend8_s
+/*0xc1*/ (I32, I32), // i32.extend16_s
+/*0xc2*/ (I64, I64), // i64.extend8_s
+/*0xc3*/ (I64, I64), // i64.extend16_s
+/*0xc4*/ (I64, I64), // i64.extend32_s
+];
+
+fn validate_func(ctx: &Context, func_idx: usize, func: &LocalFunc) -> Result<(), ValidateError> {
+    let mut locals = valtypes(&func.ft.input);
+    locals.extend(func.locals.iter().map(|v| v.0));
+
+    let mut checker = Checker {
+        ctx,
+        locals,
+        vals: Vec::new(),
+        ctrls: Vec::new(),
+    };
+    checker.push_ctrl(0x02, vec![], valtypes(&func.ft.output));
+
+    for (i, inst) in func.insts.iter().enumerate() {
+        if checker.ctrls.is_empty() {
+            // the function's own end has already been seen
+            return Err(ValidateError::Func {func_idx, inst_idx: i,
+                mnemonic: inst.get_mnemonic(), msg: "unexpected instruction after end".to_string()});
+        }
+        if let Err(msg) = checker.inst(inst) {
+            return Err(ValidateError::Func {func_idx, inst_idx: i,
+                mnemonic: inst.get_mnemonic(), msg});
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_errors(src: &str, relaxed: bool) -> Vec<String> {
+        let mut module = crate::parse_wat(src).unwrap();
+        module.set_relaxed_simd(relaxed);
+        match module.validate() {
+            Ok(()) => Vec::new(),
+            Err(errs) => errs.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    // validation errors of a function with the body
+    fn errors(body: &str, relaxed: bool) -> Vec<String> {
+        module_errors(&format!("(module (memory 1) (func (param v128 i32) (result v128) {}))", body), relaxed)
+    }
+
+    // check the functions (func ...) in a module with a memory and globals.
+    // error is a part of the expected message or "" if it is valid
+    fn check_funcs(cases: &[(&str, &str)]) {
+        for (func, error) in cases {
+            let src = format!(r#"(module (memory 1) (global $c i32 (i32.const 0)) (global $m (mut i32) (i32.const 0))
+                                 {})"#, func);
+            let errs = module_errors(&src, false);
+            if error.is_empty() {
+                assert!(errs.is_empty(), "{}: {:?}", func, errs);
+            } else {
+                assert_eq!(errs.len(), 1, "{}: {:?}", func, errs);
+                assert!(errs[0].contains(error), "{}: {}", func, errs[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn type_mismatch() {
+        check_funcs(&[
+            ("(func (result i32) (i32.add (i32.const 1) (i32.const 2)))", ""),
+            ("(func (result i32) (i32.add (i32.const 1) (i64.const 2)))", "type mismatch"),
+            ("(func (result i32) (i64.const 1))", "type mismatch"),
+            ("(func (result i32))", "type mismatch"),
+            ("(func (i32.const 1))", "type mismatch"),
+            ("(func (param f32) (result i32) (i32.eqz (local.get 0)))", "type mismatch"),
+            ("(func (result i32) (select (i32.const 1) (i64.const 2) (i32.const 0)))", "type mismatch"),
+            ("(func (drop))", "type mismatch"),
+        ]);
+    }
+
+    #[test]
+    fn unreachable_stack() {
+        // the stack is polymorphic after unreachable, br, return and br_table
+        check_funcs(&[
+            ("(func (result i32) (unreachable))", ""),
+            ("(func (result i32) (unreachable) (i32.add))", ""),
+            ("(func (result i64) (return (i64.const 1)) (i64.add))", ""),
+            ("(func (result i32) (block (result i32) (br 0 (i32.const 1)) (i32.add)))", ""),
+            ("(func (result i32) (unreachable) (select))", ""),
+            // the known values are still checked
+            ("(func (result i32) (unreachable) (i64.const 0))", "type mismatch"),
+            ("(func (result i32) (unreachable) (i32.add (f32.const 0)))", "type mismatch"),
+            ("(func (unreachable) (i32.const 1) (i32.const 2))", "type mismatch"),
+            ("(func (result i32) (block (result i32) (br 0 (i32.const 1)) (f32.add)))", "type mismatch"),
+        ]);
+    }
+
+    #[test]
+    fn block_results() {
+        check_funcs(&[
+            ("(func (result i32 i64) (block (result i32 i64) (i32.const 1) (i64.const 2)))", ""),
+            ("(func (result i32) (block (result i32) (i64.const 1)))", "type mismatch"),
+            ("(func (result i32) (block (result i32)) )", "type mismatch"),
+            ("(func (block (i32.const 1)))", "type mismatch"),
+            ("(func (result i32) (if (result i32) (i32.const 1) (then (i32.const 1))))", "type mismatch"),
+            ("(func (result i32) (if (result i32) (i32.const 1) (then (i32.const 1)) (else (i32.const 2))))", ""),
+            ("(func (result i32) (loop (result i32) (br 0)))", ""),
+            ("(func (result i32) (block (result i32) (br_if 0 (i32.const 1) (i32.const 0))))", ""),
+            ("(func (block (result i32) (br_if 0 (i32.const 1))) (drop))", "type mismatch"),
+        ]);
+    }
+
+    #[test]
+    fn branch_depth() {
+        check_funcs(&[
+            ("(func (block (br 1)))", ""),
+            ("(func (block (br 2)))", "unknown label 2"),
+            ("(func (br_if 1 (i32.const 0)))", "unknown label 1"),
+            ("(func (block (br_table 0 1 2 (i32.const 0))))", "unknown label 2"),
+            ("(func (block (result i32) (block (br_table 0 1 (i32.const 0) (i32.const 0))) (i32.const 1)))",
+             "inconsistent arity"),
+        ]);
+    }
+
+    #[test]
+    fn indices() {
+        check_funcs(&[
+            ("(func (param i32) (result i64) (local i64) (drop (local.get 0)) (local.get 1))", ""),
+            ("(func (param i32) (drop (local.get 1)))", "unknown local 1"),
+            ("(func (local.set 0 (i32.const 0)))", "unknown local 0"),
+            ("(func (drop (global.get 2)))", "unknown global 2"),
+            ("(func (global.set 2 (i32.const 0)))", "unknown global 2"),
+            ("(func (call 5))", "unknown function 5"),
+        ]);
+    }
+
+    #[test]
+    fn immutable_global() {
+        check_funcs(&[
+            ("(func (global.set $m (i32.const 1)))", ""),
+            ("(func (global.set $c (i32.const 1)))", "global is immutable"),
+            ("(func (global.set $m (i64.const 1)))", "type mismatch"),
+        ]);
+    }
+
+    #[test]
+    fn alignment() {
+        check_funcs(&[
+            ("(func (drop (i32.load align=4 (i32.const 0))))", ""),
+            ("(func (drop (i32.load align=8 (i32.const 0))))", "alignment must not be larger than natural"),
+            ("(func (drop (i64.load8_u align=2 (i32.const 0))))", "alignment must not be larger than natural"),
+            ("(func (i64.store32 align=4 (i32.const 0) (i64.const 0)))", ""),
+            ("(func (i64.store32 align=8 (i32.const 0) (i64.const 0)))", "alignment must not be larger than natural"),
+        ]);
+        let errs = module_errors("(module (func (drop (i32.load (i32.const 0)))))", false);
+        assert!(errs.len() == 1 && errs[0].ends_with("unknown memory 0"), "{:?}", errs);
+    }
+
+    #[test]
+    fn lane_index() {
+        let cases = [
+            ("(i32x4.replace_lane 3 (local.get 0) (local.get 1))", true),
+            ("(i32x4.replace_lane 4 (local.get 0) (local.get 1))", false),
+            ("(i8x16.splat (i8x16.extract_lane_u 15 (local.get 0)))", true),
+            ("(i8x16.splat (i8x16.extract_lane_u 16 (local.get 0)))", false),
+            ("(i64x2.splat (i64x2.extract_lane 2 (local.get 0)))", false),
+            ("(v128.load16_lane 7 (local.get 1) (local.get 0))", true),
+            ("(v128.load16_lane 8 (local.get 1) (local.get 0))", false),
+            ("(v128.store64_lane 1 (local.get 1) (local.get 0)) (local.get 0)", true),
+            ("(v128.store64_lane 2 (local.get 1) (local.get 0)) (local.get 0)", false),
+            ("(i8x16.shuffle 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 31 (local.get 0) (local.get 0))", true),
+            ("(i8x16.shuffle 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 32 (local.get 0) (local.get 0))", false),
+        ];
+        for (body, ok) in cases {
+            let errs = errors(body, false);
+            if ok {
+                assert!(errs.is_empty(), "{}: {:?}", body, errs);
+            } else {
+                assert_eq!(errs.len(), 1, "{}", body);
+                assert!(errs[0].ends_with("invalid lane index"), "{}: {}", body, errs[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn relaxed_simd() {
+        let bodies = [
+            "(i8x16.relaxed_swizzle (local.get 0) (local.get 0))",
+            "(i32x4.relaxed_trunc_f32x4_s (local.get 0))",
+            "(f32x4.relaxed_madd (local.get 0) (local.get 0) (local.get 0))",
+            "(i16x8.relaxed_dot_i8x16_i7x16_s (local.get 0) (local.get 0))",
+        ];
+        for body in bodies {
+            let errs = errors(body, false);
+            assert_eq!(errs.len(), 1, "{}", body);
+            assert!(errs[0].ends_with("relaxed SIMD is not enabled"), "{}: {}", body, errs[0]);
+            assert!(errors(body, true).is_empty(), "{}", body);
+        }
+        // the gate is checked before the operands
+        let errs = errors("(f32x4.relaxed_madd (local.get 0) (local.get 0))", false);
+        assert!(errs[0].ends_with("relaxed SIMD is not enabled"), "{}", errs[0]);
+        assert!(errors("(v128.not (local.get 0))", false).is_empty());
+    }
+
+    #[test]
+    fn simd_signature() {
+        assert_eq!(simd::signature(0x0d), Some((&[0x7b, 0x7b][..], &[0x7b][..])));
+        assert_eq!(simd::signature(0x4d), Some((&[0x7b][..], &[0x7b][..])));
+        for sub_op in [0x9a, 0xbb, 0xd2, 0x114, 0x200] {
+            assert_eq!(simd::signature(sub_op), None, "{:#x}", sub_op);
+        }
+    }
+}
@@ -0,0 +1,544 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// WASI preview1 (wasi_snapshot_preview1) host functions.
+// args/environ, fd_*, path_open with preopened directories, clock_time_get,
+// random_get and proc_exit are supported. other functions return ENOSYS.
+
+use std::cell::{RefCell, RefMut};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::exec::*;
+use crate::linker::*;
+use crate::module::*;
+
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+// errno
+const SUCCESS: i32 = 0;
+const EACCES: i32 = 2;
+const EBADF: i32 = 8;
+const EEXIST: i32 = 20;
+const EFAULT: i32 = 21;
+const EINVAL: i32 = 28;
+const EIO: i32 = 29;
+const EISDIR: i32 = 31;
+const ELOOP: i32 = 32;
+const ENOENT: i32 = 44;
+const ENOSYS: i32 = 52;
+const ENOTDIR: i32 = 54;
+const ENOTSUP: i32 = 58;
+const ESPIPE: i32 = 70;
+
+// filetype
+const CHARACTER_DEVICE: u8 = 2;
+const DIRECTORY: u8 = 3;
+const REGULAR_FILE: u8 = 4;
+
+// lookupflags
+const LOOKUP_SYMLINK_FOLLOW: i32 = 1;
+
+// oflags
+const O_CREAT: i32 = 1;
+const O_DIRECTORY: i32 = 2;
+const O_EXCL: i32 = 4;
+const O_TRUNC: i32 = 8;
+
+// rights
+const RIGHT_FD_READ: i64 = 1 << 1;
+const RIGHT_FD_WRITE: i64 = 1 << 6;
+
+// fdflags
+const FDFLAGS_APPEND: i32 = 1;
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    Dir(PathBuf, PathBuf, Option<String>), // host path, preopened root (canonical), guest name if preopened
+    File(File),
+}
+
+pub struct Wasi {
+    args: Vec<String>,
+    env: Vec<String>, // "KEY=VALUE"
+    fds: Vec<Option<Fd>>,
+    exit_code: Option<i32>,
+    start: Instant,
+}
+
+impl Wasi {
+    pub fn new(args: Vec<String>, env: Vec<String>) -> Wasi {
+        Wasi {
+            args,
+            env,
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            exit_code: None,
+            start: Instant::now(),
+        }
+    }
+
+    // make the host directory visible to the module as guest
+    pub fn preopen_dir(&mut self, host: &str, guest: &str) -> Result<(), String> {
+        if !Path::new(host).is_dir() {
+            return Err(format!("'{}' is not a directory", host));
+        }
+        let root = Path::new(host).canonicalize().map_err(|e| format!("'{}': {}", host, e))?;
+        self.fds.push(Some(Fd::Dir(root.clone(), root, Some(guest.to_string()))));
+        Ok(())
+    }
+
+    // exit code given by proc_exit
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    fn new_fd(&mut self, fd: Fd) -> u32 {
+        match self.fds.iter().position(|f| f.is_none()) {
+            Some(i) => {
+                self.fds[i] = Some(fd);
+                i as u32
+            },
+            None => {
+                self.fds.push(Some(fd));
+                (self.fds.len() - 1) as u32
+            },
+        }
+    }
+
+    fn fd(&mut self, fd: i32) -> Option<&mut Fd> {
+        self.fds.get_mut(fd as u32 as usize)?.as_mut()
+    }
+}
+
+// memory access of the host functions. out of range is EFAULT.
+fn mem_slice(store: &mut Store, ptr: i32, len: usize) -> Result<RefMut<'_, [u8]>, i32> {
+    let start = ptr as u32 as usize;
+    RefMut::filter_map(store.memory_mut(), |mem| mem.get_mut(start..start + len)).map_err(|_| EFAULT)
+}
+
+fn read_u32(store: &mut Store, ptr: i32) -> Result<u32, i32> {
+    let bytes = mem_slice(store, ptr, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn write_bytes(store: &mut Store, ptr: i32, data: &[u8]) -> Result<(), i32> {
+    mem_slice(store, ptr, data.len())?.copy_from_slice(data);
+    Ok(())
+}
+
+fn write_u32(store: &mut Store, ptr: i32, n: u32) -> Result<(), i32> {
+    write_bytes(store, ptr, &n.to_le_bytes())
+}
+
+fn write_u64(store: &mut Store, ptr: i32, n: u64) -> Result<(), i32> {
+    write_bytes(store, ptr, &n.to_le_bytes())
+}
+
+// (ptr, len) of each iovec
+fn iovecs(store: &mut Store, iovs: i32, iovs_len: i32) -> Result<Vec<(i32, usize)>, i32> {
+    let mut v = Vec::new();
+    for i in 0..iovs_len {
+        let p = iovs.wrapping_add(i * 8);
+        let buf = read_u32(store, p)? as i32;
+        let len = read_u32(store, p.wrapping_add(4))? as usize;
+        v.push((buf, len));
+    }
+    Ok(v)
+}
+
+fn io_errno(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        _ => EIO,
+    }
+}
+
+fn i32_arg(args: &[Value], i: usize) -> i32 {
+    match args.get(i) {
+        Some(Value::I32(n)) => *n,
+        _ => 0,
+    }
+}
+
+fn i64_arg(args: &[Value], i: usize) -> i64 {
+    match args.get(i) {
+        Some(Value::I64(n)) => *n,
+        _ => 0,
+    }
+}
+
+// write a list of strings for args_get and environ_get
+fn put_strings(store: &mut Store, list: &[String], ptrs: i32, buf: i32) -> Result<(), i32> {
+    let mut p = buf;
+    for (i, s) in list.iter().enumerate() {
+        write_u32(store, ptrs.wrapping_add(i as i32 * 4), p as u32)?;
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        write_bytes(store, p, &bytes)?;
+        p = p.wrapping_add(bytes.len() as i32);
+    }
+    Ok(())
+}
+
+fn put_sizes(store: &mut Store, list: &[String], count: i32, size: i32) -> Result<(), i32> {
+    write_u32(store, count, list.len() as u32)?;
+    let total: usize = list.iter().map(|s| s.len() + 1).sum();
+    write_u32(store, size, total as u32)
+}
+
+fn fd_write(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let iovs = iovecs(store, i32_arg(args, 1), i32_arg(args, 2))?;
+    let mut data = Vec::new();
+    for (buf, len) in iovs {
+        data.extend_from_slice(&mem_slice(store, buf, len)?);
+    }
+    let result = match wasi.fd(i32_arg(args, 0)) {
+        Some(Fd::Stdout) => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()),
+        Some(Fd::Stderr) => io::stderr().write_all(&data),
+        Some(Fd::File(file)) => file.write_all(&data),
+        Some(_) => return Err(EBADF),
+        None => return Err(EBADF),
+    };
+    result.map_err(|e| io_errno(&e))?;
+    write_u32(store, i32_arg(args, 3), data.len() as u32)
+}
+
+fn fd_read(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let iovs = iovecs(store, i32_arg(args, 1), i32_arg(args, 2))?;
+    let mut total = 0;
+    for (buf, len) in iovs {
+        let mut data = vec![0; len];
+        let n = match wasi.fd(i32_arg(args, 0)) {
+            Some(Fd::Stdin) => io::stdin().read(&mut data),
+            Some(Fd::File(file)) => file.read(&mut data),
+            Some(Fd::Dir(..)) => return Err(EISDIR),
+            _ => return Err(EBADF),
+        }.map_err(|e| io_errno(&e))?;
+        write_bytes(store, buf, &data[..n])?;
+        total += n;
+        if n < len {
+            break;
+        }
+    }
+    write_u32(store, i32_arg(args, 3), total as u32)
+}
+
+fn fd_seek(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let offset = i64_arg(args, 1);
+    let pos = match i32_arg(args, 2) {
+        0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return Err(EINVAL),
+    };
+    let n = match wasi.fd(i32_arg(args, 0)) {
+        Some(Fd::File(file)) => file.seek(pos).map_err(|_| EINVAL)?,
+        Some(Fd::Dir(..)) => return Err(EISDIR),
+        Some(_) => return Err(ESPIPE),
+        None => return Err(EBADF),
+    };
+    write_u64(store, i32_arg(args, 3), n)
+}
+
+fn fd_close(wasi: &mut Wasi, _store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let fd = i32_arg(args, 0) as u32 as usize;
+    match wasi.fds.get_mut(fd) {
+        Some(f) if f.is_some() => {
+            *f = None;
+            Ok(())
+        },
+        _ => Err(EBADF),
+    }
+}
+
+fn fd_fdstat_get(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let filetype = match wasi.fd(i32_arg(args, 0)) {
+        Some(Fd::Stdin | Fd::Stdout | Fd::Stderr) => CHARACTER_DEVICE,
+        Some(Fd::Dir(..)) => DIRECTORY,
+        Some(Fd::File(_)) => REGULAR_FILE,
+        None => return Err(EBADF),
+    };
+    let mut stat = [0u8; 24];
+    stat[0] = filetype;
+    stat[8..16].copy_from_slice(&u64::MAX.to_le_bytes()); // rights base
+    stat[16..24].copy_from_slice(&u64::MAX.to_le_bytes()); // rights inheriting
+    write_bytes(store, i32_arg(args, 1), &stat)
+}
+
+fn fd_prestat_get(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let len = match wasi.fd(i32_arg(args, 0)) {
+        Some(Fd::Dir(_, _, Some(name))) => name.len(),
+        _ => return Err(EBADF),
+    };
+    let mut prestat = [0u8; 8]; // tag 0: dir
+    prestat[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+    write_bytes(store, i32_arg(args, 1), &prestat)
+}
+
+fn fd_prestat_dir_name(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let name = match wasi.fd(i32_arg(args, 0)) {
+        Some(Fd::Dir(_, _, Some(name))) => name.clone(),
+        _ => return Err(EBADF),
+    };
+    let len = i32_arg(args, 2) as u32 as usize;
+    if len < name.len() {
+        return Err(EINVAL);
+    }
+    write_bytes(store, i32_arg(args, 1), name.as_bytes())
+}
+
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false)
+}
+
+// canonical parent directory joined with the last component (not resolved)
+fn canonical_parent(path: &Path) -> Result<PathBuf, i32> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok(parent.canonicalize().map_err(|e| io_errno(&e))?.join(name)),
+        _ => Err(ENOENT),
+    }
+}
+
+// the path must stay in the preopened directory (root). symlinks are resolved
+// by canonicalizing the path and the result is checked again, so a link can't
+// point out of the root. the last component is resolved only if follow is set.
+fn resolve_path(dir: &Path, root: &Path, path: &str, follow: bool) -> Result<PathBuf, i32> {
+    let mut depth = 0;
+    for c in Path::new(path).components() {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => {
+                if depth == 0 {
+                    return Err(EACCES);
+                }
+                depth -= 1;
+            },
+            Component::CurDir => (),
+            _ => return Err(EACCES), // absolute path
+        }
+    }
+    let joined = dir.join(path);
+    let link = is_symlink(&joined);
+    let resolved = if follow || !link {
+        match joined.canonicalize() {
+            Ok(p) => p,
+            // not created yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !link => canonical_parent(&joined)?,
+            Err(e) => return Err(io_errno(&e)),
+        }
+    } else {
+        canonical_parent(&joined)?
+    };
+    if !resolved.starts_with(root) {
+        return Err(EACCES);
+    }
+    Ok(resolved)
+}
+
+fn path_open(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let (dir, root) = match wasi.fd(i32_arg(args, 0)) {
+        Some(Fd::Dir(dir, root, _)) => (dir.clone(), root.clone()),
+        Some(_) => return Err(ENOTDIR),
+        None => return Err(EBADF),
+    };
+    let path_len = i32_arg(args, 3) as u32 as usize;
+    let path = mem_slice(store, i32_arg(args, 2), path_len)?.to_vec();
+    let path = String::from_utf8(path).map_err(|_| EINVAL)?;
+    let follow = i32_arg(args, 1) & LOOKUP_SYMLINK_FOLLOW != 0;
+    let host_path = resolve_path(&dir, &root, &path, follow)?;
+    if !follow && is_symlink(&host_path) {
+        return Err(ELOOP);
+    }
+    let oflags = i32_arg(args, 4);
+    let rights = i64_arg(args, 5);
+    let fdflags = i32_arg(args, 7);
+
+    let fd = if host_path.is_dir() {
+        if oflags & (O_CREAT | O_TRUNC) != 0 || rights & RIGHT_FD_WRITE != 0 && oflags & O_DIRECTORY == 0 {
+            return Err(EISDIR);
+        }
+        Fd::Dir(host_path, root, None)
+    } else {
+        if oflags & O_DIRECTORY != 0 {
+            return Err(if host_path.exists() { ENOTDIR } else { ENOENT });
+        }
+        let write = rights & RIGHT_FD_WRITE != 0;
+        let mut options = OpenOptions::new();
+        options.read(rights & RIGHT_FD_READ != 0 || !write)
+            .write(write)
+            .append(fdflags & FDFLAGS_APPEND != 0)
+            .truncate(oflags & O_TRUNC != 0);
+        if oflags & O_EXCL != 0 {
+            options.create_new(true);
+        } else if oflags & O_CREAT != 0 {
+            options.create(true);
+        }
+        Fd::File(options.open(&host_path).map_err(|e| io_errno(&e))?)
+    };
+    let n = wasi.new_fd(fd);
+    write_u32(store, i32_arg(args, 8), n)
+}
+
+fn clock_time_get(wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let ns = match i32_arg(args, 0) {
+        0 => SystemTime::now().duration_since(UNIX_EPOCH).map_err(|_| EIO)?.as_nanos(),
+        1 => wasi.start.elapsed().as_nanos(), // monotonic
+        2 | 3 => return Err(ENOTSUP), // process and thread cputime
+        _ => return Err(EINVAL),
+    };
+    write_u64(store, i32_arg(args, 2), ns as u64)
+}
+
+fn random_get(_wasi: &mut Wasi, store: &mut Store, args: &[Value]) -> Result<(), i32> {
+    let len = i32_arg(args, 1) as u32 as usize;
+    let mut buf = mem_slice(store, i32_arg(args, 0), len)?;
+    read_random("/dev/urandom", &mut buf)
+}
+
+// fill buf from the random source. there is no weaker fallback: EIO if it can't be read.
+fn read_random(path: &str, buf: &mut [u8]) -> Result<(), i32> {
+    let mut f = File::open(path).map_err(|_| EIO)?;
+    f.read_exact(buf).map_err(|_| EIO)
+}
+
+type WasiFunc = fn(&mut Wasi, &mut Store, &[Value]) -> Result<(), i32>;
+
+// name, params, function. all of them return errno (i32).
+const WASI_FUNCS: [(&str, &[u8], WasiFunc); 14] = [
+    ("args_get", &[0x7f, 0x7f], |w, s, a| put_strings(s, &w.args, i32_arg(a, 0), i32_arg(a, 1))),
+    ("args_sizes_get", &[0x7f, 0x7f], |w, s, a| put_sizes(s, &w.args, i32_arg(a, 0), i32_arg(a, 1))),
+    ("environ_get", &[0x7f, 0x7f], |w, s, a| put_strings(s, &w.env, i32_arg(a, 0), i32_arg(a, 1))),
+    ("environ_sizes_get", &[0x7f, 0x7f], |w, s, a| put_sizes(s, &w.env, i32_arg(a, 0), i32_arg(a, 1))),
+    ("fd_write", &[0x7f, 0x7f, 0x7f, 0x7f], fd_write),
+    ("fd_read", &[0x7f, 0x7f, 0x7f, 0x7f], fd_read),
+    ("fd_seek", &[0x7f, 0x7e, 0x7f, 0x7f], fd_seek),
+    ("fd_close", &[0x7f], fd_close),
+    ("fd_fdstat_get", &[0x7f, 0x7f], fd_fdstat_get),
+    ("fd_prestat_get", &[0x7f, 0x7f], fd_prestat_get),
+    ("fd_prestat_dir_name", &[0x7f, 0x7f, 0x7f], fd_prestat_dir_name),
+    ("path_open", &[0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7e, 0x7e, 0x7f, 0x7f], path_open),
+    ("clock_time_get", &[0x7f, 0x7e, 0x7f], clock_time_get),
+    ("random_get", &[0x7f, 0x7f], random_get),
+];
+
+// register the WASI functions to the linker. the other wasi_snapshot_preview1
+// functions imported by the module return ENOSYS (if they return errno).
+pub fn add_to_linker(wasi: &Rc<RefCell<Wasi>>, linker: &mut Linker, module: &Module) {
+    for (name, params, f) in WASI_FUNCS {
+        let wasi = wasi.clone();
+        linker.func(WASI_MODULE, name, params, &[0x7f], move |store, args| {
+            let errno = match f(&mut wasi.borrow_mut(), store, args) {
+                Ok(()) => SUCCESS,
+                Err(errno) => errno,
+            };
+            Ok(vec![Value::I32(errno)])
+        });
+    }
+
+    let w = wasi.clone();
+    linker.func(WASI_MODULE, "proc_exit", &[0x7f], &[], move |_store, args| {
+        let code = i32_arg(args, 0);
+        w.borrow_mut().exit_code = Some(code);
+        Err(format!("proc_exit({})", code))
+    });
+
+    for import in module.imports() {
+        if import.module() != WASI_MODULE || linker.has_func(WASI_MODULE, import.name()) {
+            continue;
+        }
+        if let Importdesc::Func(typeidx) = import.desc() {
+            if let Some(ft) = module.types().get(*typeidx as usize) {
+                let params: Vec<u8> = ft.input.0.iter().map(|v| v.0).collect();
+                let results: Vec<u8> = ft.output.0.iter().map(|v| v.0).collect();
+                let name = import.name().to_string();
+                let ret = results.clone();
+                linker.func(WASI_MODULE, import.name(), &params, &results, move |_store, _args| {
+                    if ret == [0x7f] {
+                        Ok(vec![Value::I32(ENOSYS)])
+                    } else {
+                        Err(format!("trap: {} is not supported", name))
+                    }
+                });
+            }
+        }
+    }
+}
+
+// run a WASI command module: call _start and return the exit code.
+pub fn run(module: &Module, args: Vec<String>, env: Vec<String>, dirs: &[String],
+           limits: StoreLimits, fuel: Option<u64>, trace: Option<Trace>) -> Result<i32, String> {
+    let mut wasi = Wasi::new(args, env);
+    for dir in dirs {
+        wasi.preopen_dir(dir, dir)?;
+    }
+    let wasi = Rc::new(RefCell::new(wasi));
+    let mut linker = Linker::new();
+    add_to_linker(&wasi, &mut linker, module);
+    linker.limits(limits);
+    let mut store = linker.instantiate(module)?;
+    store.set_fuel(fuel);
+    store.set_trace(trace);
+
+    let start = match module.export_func("_start") {
+        Some(idx) => idx,
+        None => return Err("_start is not exported".to_string()),
+    };
+    let result = invoke(start, &[], module, &mut store);
+    if let Some(code) = wasi.borrow().exit_code() {
+        return Ok(code);
+    }
+    result.map(|_| 0)
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_path_sandbox() {
+        let tmp = std::env::temp_dir().join(format!("wasmex-wasi-{}", std::process::id()));
+        let root = tmp.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("ok.txt"), b"ok").unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("escape")).unwrap();
+        std::os::unix::fs::symlink("ok.txt", root.join("link")).unwrap();
+        std::os::unix::fs::symlink(tmp.join("outside"), root.join("dangling")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(resolve_path(&root, &root, "ok.txt", true), Ok(root.join("ok.txt")));
+        assert_eq!(resolve_path(&root, &root, "sub/../ok.txt", true), Ok(root.join("ok.txt")));
+        assert_eq!(resolve_path(&root, &root, "new.txt", true), Ok(root.join("new.txt")));
+        assert_eq!(resolve_path(&root, &root, "link", true), Ok(root.join("ok.txt")));
+        assert_eq!(resolve_path(&root, &root, "link", false), Ok(root.join("link")));
+        assert_eq!(resolve_path(&root, &root, "../ok.txt", true), Err(EACCES));
+        assert_eq!(resolve_path(&root, &root, "/etc/passwd", true), Err(EACCES));
+        assert_eq!(resolve_path(&root, &root, "escape/passwd", true), Err(EACCES));
+        assert_eq!(resolve_path(&root, &root, "escape", true), Err(EACCES));
+        assert_eq!(resolve_path(&root, &root, "dangling", true), Err(ENOENT));
+        assert_eq!(resolve_path(&root.join("sub"), &root, "../escape", true), Err(EACCES));
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn random_source() {
+        let mut buf = [0u8; 32];
+        assert_eq!(read_random("/dev/urandom", &mut buf), Ok(()));
+        assert!(buf.iter().any(|b| *b != 0));
+        assert_eq!(read_random("/nonexistent/urandom", &mut buf), Err(EIO));
+        // a short read is an error, too
+        let short = std::env::temp_dir().join(format!("wasmex-random-{}", std::process::id()));
+        std::fs::write(&short, b"ab").unwrap();
+        assert_eq!(read_random(short.to_str().unwrap(), &mut buf), Err(EIO));
+        std::fs::remove_file(&short).unwrap();
+    }
+}
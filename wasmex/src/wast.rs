@@ -0,0 +1,623 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// runner of .wast scripts (the format of the spec testsuite).
+// supported commands are module (text, binary and quote), register, invoke,
+// get, assert_return, assert_trap, assert_exhaustion, assert_invalid,
+// assert_malformed and assert_unlinkable. other commands are skipped.
+//
+// imports are resolved by the "spectest" module and the registered modules.
+// memories, tables and globals of a registered module are shared with the
+// importer, so changes by either module are seen by the other.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::bytecode::*;
+use crate::exec::*;
+use crate::linker::*;
+use crate::module::*;
+use crate::watparse::*;
+
+// s-expression of the script. start and end are char positions in the source.
+struct Sexp {
+    kind: SexpKind,
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+enum SexpKind {
+    List(Vec<Sexp>),
+    Atom(String),
+    Id(String),
+    Str(Vec<u8>),
+}
+
+impl Sexp {
+    fn list(&self) -> &[Sexp] {
+        match &self.kind {
+            SexpKind::List(items) => items,
+            _ => &[],
+        }
+    }
+
+    fn atom(&self) -> Option<&str> {
+        match &self.kind {
+            SexpKind::Atom(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> Option<&str> {
+        match &self.kind {
+            SexpKind::Id(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn string(&self) -> Option<String> {
+        match &self.kind {
+            SexpKind::Str(s) => Some(String::from_utf8_lossy(s).to_string()),
+            _ => None,
+        }
+    }
+
+    // keyword of the list: (keyword ...)
+    fn head(&self) -> &str {
+        self.list().first().and_then(|s| s.atom()).unwrap_or("")
+    }
+}
+
+fn parse_sexps(toks: &[Token]) -> Result<Vec<Sexp>, String> {
+    let mut stack: Vec<(Vec<Sexp>, usize, usize)> = Vec::new(); // items, line, start
+    let mut top = Vec::new();
+    for t in toks {
+        let kind = match &t.tok {
+            Tok::LParen => {
+                stack.push((Vec::new(), t.line, t.pos));
+                continue;
+            },
+            Tok::RParen => {
+                let (items, line, start) = match stack.pop() {
+                    Some(list) => list,
+                    None => return Err(format!("{}:{}: unexpected ')'", t.line, t.col)),
+                };
+                let sexp = Sexp {kind: SexpKind::List(items), line, start, end: t.pos + 1};
+                match stack.last_mut() {
+                    Some((items, _, _)) => items.push(sexp),
+                    None => top.push(sexp),
+                }
+                continue;
+            },
+            Tok::Atom(s) => SexpKind::Atom(s.clone()),
+            Tok::Id(s) => SexpKind::Id(s.clone()),
+            Tok::Str(s) => SexpKind::Str(s.clone()),
+            Tok::Eof => break,
+        };
+        let sexp = Sexp {kind, line: t.line, start: t.pos, end: t.pos};
+        match stack.last_mut() {
+            Some((items, _, _)) => items.push(sexp),
+            None => return Err(format!("{}:{}: unexpected token at top level", t.line, t.col)),
+        }
+    }
+    if let Some((_, line, _)) = stack.last() {
+        return Err(format!("{}: unclosed '('", line));
+    }
+    Ok(top)
+}
+
+// expected result of assert_return
+enum Expected {
+    Value(Value),
+    CanonicalNan(u8), // f32 or f64
+    ArithmeticNan(u8),
+    RefFunc, // any non-null funcref
+    RefExtern,
+    Either(Vec<Expected>),
+    Lanes(Vec<Expected>), // v128 of f32x4 or f64x2 with NaN patterns
+}
+
+impl Expected {
+    fn matches(&self, v: &Value) -> bool {
+        match (self, v) {
+            (Expected::Value(e), v) => e == v,
+            (Expected::CanonicalNan(0x7d), Value::F32(f)) => f.to_bits() & 0x7fff_ffff == 0x7fc0_0000,
+            (Expected::CanonicalNan(0x7c), Value::F64(f)) =>
+                f.to_bits() & 0x7fff_ffff_ffff_ffff == 0x7ff8_0000_0000_0000,
+            (Expected::ArithmeticNan(0x7d), Value::F32(f)) => f.to_bits() & 0x7fc0_0000 == 0x7fc0_0000,
+            (Expected::ArithmeticNan(0x7c), Value::F64(f)) =>
+                f.to_bits() & 0x7ff8_0000_0000_0000 == 0x7ff8_0000_0000_0000,
+            (Expected::RefFunc, Value::FuncRef(Some(_))) => true,
+            (Expected::RefExtern, Value::ExternRef(Some(_))) => true,
+            (Expected::Either(es), v) => es.iter().any(|e| e.matches(v)),
+            (Expected::Lanes(es), Value::V128(v)) => es.iter().enumerate().all(|(i, e)| {
+                let lane = if es.len() == 4 {
+                    Value::F32(f32::from_bits((v >> (32 * i)) as u32))
+                } else {
+                    Value::F64(f64::from_bits((v >> (64 * i)) as u64))
+                };
+                e.matches(&lane)
+            }),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Expected::Value(v) => write!(f, "{}", show_value(v)),
+            Expected::CanonicalNan(t) => write!(f, "{}:nan:canonical", type_name(*t)),
+            Expected::ArithmeticNan(t) => write!(f, "{}:nan:arithmetic", type_name(*t)),
+            Expected::RefFunc => write!(f, "ref.func"),
+            Expected::RefExtern => write!(f, "ref.extern"),
+            Expected::Either(es) => {
+                let v: Vec<String> = es.iter().map(|e| e.to_string()).collect();
+                write!(f, "either({})", v.join(" | "))
+            },
+            Expected::Lanes(es) => {
+                let v: Vec<String> = es.iter().map(|e| e.to_string()).collect();
+                write!(f, "v128({})", v.join(" "))
+            },
+        }
+    }
+}
+
+// floats are compared by the bit pattern
+fn type_name(t: u8) -> &'static str {
+    match t {
+        0x7f => "i32",
+        0x7e => "i64",
+        0x7d => "f32",
+        0x7c => "f64",
+        0x7b => "v128",
+        _ => "?",
+    }
+}
+
+fn show_value(v: &Value) -> String {
+    match v {
+        Value::F32(f) if f.is_nan() => format!("f32:nan({:#x})", f.to_bits()),
+        Value::F64(f) if f.is_nan() => format!("f64:nan({:#x})", f.to_bits()),
+        Value::FuncRef(_) | Value::ExternRef(_) => v.to_string(),
+        _ => format!("{}:{}", type_name(v.valtype().unwrap_or(0)), v),
+    }
+}
+
+fn show_values(vs: &[Value]) -> String {
+    let v: Vec<String> = vs.iter().map(show_value).collect();
+    format!("[{}]", v.join(" "))
+}
+
+// (i32.const 1), (ref.null func) etc. in an action or a result
+fn parse_const(sexp: &Sexp) -> Result<Expected, String> {
+    let items = sexp.list();
+    let arg = items.get(1).and_then(|s| s.atom());
+    let need = || format!("line {}: missing value of {}", sexp.line, sexp.head());
+    let bad = |s: &str| format!("line {}: bad value '{}' of {}", sexp.line, s, sexp.head());
+    let value = match sexp.head() {
+        "i32.const" => {
+            let s = arg.ok_or_else(need)?;
+            Value::I32(parse_int(s, 32).ok_or_else(|| bad(s))? as u32 as i32)
+        },
+        "i64.const" => {
+            let s = arg.ok_or_else(need)?;
+            Value::I64(parse_int(s, 64).ok_or_else(|| bad(s))? as i64)
+        },
+        "f32.const" => match arg.ok_or_else(need)? {
+            "nan:canonical" => return Ok(Expected::CanonicalNan(0x7d)),
+            "nan:arithmetic" => return Ok(Expected::ArithmeticNan(0x7d)),
+            s => Value::F32(f32::from_bits(parse_float(s, 23, 8).ok_or_else(|| bad(s))? as u32)),
+        },
+        "f64.const" => match arg.ok_or_else(need)? {
+            "nan:canonical" => return Ok(Expected::CanonicalNan(0x7c)),
+            "nan:arithmetic" => return Ok(Expected::ArithmeticNan(0x7c)),
+            s => Value::F64(f64::from_bits(parse_float(s, 52, 11).ok_or_else(|| bad(s))?)),
+        },
+        "v128.const" => {
+            let shape = arg.ok_or_else(need)?;
+            let lanes: Vec<&str> = items[2..].iter().filter_map(|s| s.atom()).collect();
+            if lanes.iter().any(|s| s.starts_with("nan:")) && (shape == "f32x4" || shape == "f64x2") {
+                let (t, mant, exp) = if shape == "f32x4" { (0x7d, 23, 8) } else { (0x7c, 52, 11) };
+                let mut es = Vec::new();
+                for s in lanes {
+                    es.push(match s {
+                        "nan:canonical" => Expected::CanonicalNan(t),
+                        "nan:arithmetic" => Expected::ArithmeticNan(t),
+                        s => {
+                            let bits = parse_float(s, mant, exp).ok_or_else(|| bad(s))?;
+                            Expected::Value(if t == 0x7d {
+                                Value::F32(f32::from_bits(bits as u32))
+                            } else {
+                                Value::F64(f64::from_bits(bits))
+                            })
+                        },
+                    });
+                }
+                return Ok(Expected::Lanes(es));
+            }
+            Value::V128(parse_v128(shape, &lanes).ok_or_else(|| bad(&lanes.join(" ")))?)
+        },
+        "ref.null" => match arg {
+            Some("func") | Some("funcref") => Value::FuncRef(None),
+            Some("extern") | Some("externref") => Value::ExternRef(None),
+            _ => return Err(format!("line {}: bad ref.null", sexp.line)),
+        },
+        "ref.extern" => match arg {
+            Some(s) => Value::ExternRef(Some(parse_int(s, 32).ok_or_else(|| bad(s))? as u32)),
+            None => return Ok(Expected::RefExtern),
+        },
+        "ref.func" => match arg {
+            Some(s) => Value::FuncRef(Some(parse_int(s, 32).ok_or_else(|| bad(s))? as u32)),
+            None => return Ok(Expected::RefFunc),
+        },
+        "either" => {
+            let es: Result<Vec<Expected>, String> = items[1..].iter().map(parse_const).collect();
+            return Ok(Expected::Either(es?));
+        },
+        s => return Err(format!("line {}: '{}' not supported", sexp.line, s)),
+    };
+    Ok(Expected::Value(value))
+}
+
+fn parse_arg(sexp: &Sexp) -> Result<Value, String> {
+    match parse_const(sexp)? {
+        Expected::Value(v) => Ok(v),
+        _ => Err(format!("line {}: not a value", sexp.line)),
+    }
+}
+
+struct Instance {
+    module: Module,
+    store: Store,
+}
+
+// how a module in the script failed
+enum ModuleError {
+    Malformed(String), // parse or decode error
+    Invalid(String),
+    Unlinkable(String), // instantiation error (including traps of the start function)
+}
+
+impl ModuleError {
+    fn msg(&self) -> &str {
+        match self {
+            ModuleError::Malformed(msg) | ModuleError::Invalid(msg) | ModuleError::Unlinkable(msg) => msg,
+        }
+    }
+}
+
+// result of a script
+pub struct WastSummary {
+    pub passed: usize,
+    pub skipped: usize,
+    pub failed: Vec<(usize, String)>, // line and message
+}
+
+impl WastSummary {
+    pub fn total(&self) -> usize {
+        self.passed + self.failed.len()
+    }
+}
+
+struct Runner {
+    chars: Vec<char>,
+    current: Option<Rc<RefCell<Instance>>>,
+    instances: HashMap<String, Rc<RefCell<Instance>>>, // by $id
+    registered: HashMap<String, Rc<RefCell<Instance>>>, // by the name of register
+}
+
+// run the script and return the summary.
+// Err is returned only if the script itself can't be parsed.
+pub fn run_wast(src: &str) -> Result<WastSummary, String> {
+    let toks = tokenize(src).map_err(|e| e.to_string())?;
+    let cmds = parse_sexps(&toks)?;
+    let mut runner = Runner {
+        chars: src.chars().collect(),
+        current: None,
+        instances: HashMap::new(),
+        registered: HashMap::new(),
+    };
+    let mut summary = WastSummary {passed: 0, skipped: 0, failed: Vec::new()};
+    for cmd in &cmds {
+        match runner.command(cmd) {
+            Ok(true) => summary.passed += 1,
+            Ok(false) => summary.skipped += 1,
+            Err(msg) => summary.failed.push((cmd.line, msg)),
+        }
+    }
+    Ok(summary)
+}
+
+impl Runner {
+    // Ok(false) if the command is skipped
+    fn command(&mut self, cmd: &Sexp) -> Result<bool, String> {
+        let items = cmd.list();
+        match cmd.head() {
+            "module" => {
+                if matches!(items.get(1).and_then(|s| s.atom()), Some("definition") | Some("instance")) {
+                    return Ok(false);
+                }
+                let (id, module) = self.load_module(cmd).map_err(|e| e.msg().to_string())?;
+                let inst = self.instantiate(module).map_err(|e| e.msg().to_string())?;
+                let inst = Rc::new(RefCell::new(inst));
+                if let Some(id) = id {
+                    self.instances.insert(id, inst.clone());
+                }
+                self.current = Some(inst);
+            },
+            "register" => {
+                let name = items.get(1).and_then(|s| s.string()).ok_or("register: need a name")?;
+                let inst = self.instance(items.get(2).and_then(|s| s.id()))?;
+                self.registered.insert(name, inst);
+            },
+            "invoke" | "get" => {
+                self.action(cmd)?;
+            },
+            "assert_return" => {
+                let action = items.get(1).ok_or("assert_return: need an action")?;
+                let expected: Result<Vec<Expected>, String> = items[2..].iter().map(parse_const).collect();
+                let expected = expected?;
+                let results = self.action(action)?;
+                if results.len() != expected.len()
+                    || !expected.iter().zip(&results).all(|(e, r)| e.matches(r)) {
+                    let e: Vec<String> = expected.iter().map(|e| e.to_string()).collect();
+                    return Err(format!("assert_return: expected [{}], got {}", e.join(" "),
+                                       show_values(&results)));
+                }
+            },
+            "assert_trap" | "assert_exhaustion" | "assert_uninstantiable" => {
+                let target = items.get(1).ok_or("assert_trap: need an action or a module")?;
+                let msg = items.get(2).and_then(|s| s.string()).unwrap_or_default();
+                let result = if target.head() == "module" {
+                    match self.load_module(target) {
+                        Ok((_, module)) => self.instantiate(module).map(|_| Vec::new())
+                            .map_err(|e| e.msg().to_string()),
+                        Err(e) => return Err(format!("{}: {}", cmd.head(), e.msg())),
+                    }
+                } else {
+                    self.action(target)
+                };
+                match result {
+                    Ok(results) => return Err(format!("{}: expected '{}', got {}", cmd.head(), msg,
+                                                      show_values(&results))),
+                    Err(err) if !err.starts_with("trap:") || !err.contains(&msg) =>
+                        return Err(format!("{}: expected '{}', got '{}'", cmd.head(), msg, err)),
+                    Err(_) => (),
+                }
+            },
+            "assert_invalid" | "assert_malformed" | "assert_unlinkable" => {
+                let target = items.get(1).ok_or("need a module")?;
+                let err = match self.load_module(target) {
+                    Ok((_, module)) => self.instantiate(module).err(),
+                    Err(e) => Some(e),
+                };
+                let ok = matches!((cmd.head(), &err),
+                                  ("assert_malformed", Some(ModuleError::Malformed(_)))
+                                  | ("assert_invalid", Some(ModuleError::Invalid(_)))
+                                  | ("assert_unlinkable", Some(ModuleError::Unlinkable(_))));
+                if !ok {
+                    let msg = items.get(2).and_then(|s| s.string()).unwrap_or_default();
+                    let got = err.as_ref().map_or("no error", |e| e.msg());
+                    return Err(format!("{}: expected '{}', got '{}'", cmd.head(), msg, got));
+                }
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn instance(&self, id: Option<&str>) -> Result<Rc<RefCell<Instance>>, String> {
+        match id {
+            Some(id) => self.instances.get(id).cloned().ok_or(format!("unknown module ${}", id)),
+            None => self.current.clone().ok_or("no module".to_string()),
+        }
+    }
+
+    // (module $id? ...), (module $id? binary "..."*) or (module $id? quote "..."*)
+    fn load_module(&self, sexp: &Sexp) -> Result<(Option<String>, Module), ModuleError> {
+        let items = sexp.list();
+        let id = items.get(1).and_then(|s| s.id()).map(|s| s.to_string());
+        let rest = &items[1 + id.is_some() as usize..];
+        let strings = || {
+            let mut buf = Vec::new();
+            for s in &rest[1..] {
+                if let SexpKind::Str(bytes) = &s.kind {
+                    buf.extend_from_slice(bytes);
+                }
+            }
+            buf
+        };
+        let mut module = match rest.first().and_then(|s| s.atom()) {
+            Some("binary") => init_module(strings()).map_err(|e| ModuleError::Malformed(e.to_string()))?,
+            Some("quote") => {
+                let text = String::from_utf8_lossy(&strings()).to_string();
+                let text = if text.trim_start().starts_with("(module") {
+                    text
+                } else {
+                    format!("(module {})", text)
+                };
+                parse_wat(&text).map_err(|e| ModuleError::Malformed(e.to_string()))?
+            },
+            _ => {
+                let text: String = self.chars[sexp.start..sexp.end].iter().collect();
+                parse_wat(&text).map_err(|e| ModuleError::Malformed(e.to_string()))?
+            },
+        };
+        if let Err(errs) = module.validate() {
+            let msg = errs.first().map_or(String::new(), |e| e.to_string());
+            return Err(ModuleError::Invalid(msg));
+        }
+        Ok((id, module))
+    }
+
+    fn instantiate(&self, module: Module) -> Result<Instance, ModuleError> {
+        let linker = self.linker(&module);
+        match linker.instantiate(&module) {
+            Ok(store) => Ok(Instance {module, store}),
+            Err(err) => Err(ModuleError::Unlinkable(err)),
+        }
+    }
+
+    // imports of the module from spectest and the registered modules
+    fn linker(&self, module: &Module) -> Linker {
+        let mut linker = Linker::new();
+        for import in module.imports() {
+            let (m, name) = (import.module(), import.name());
+            if m == "spectest" {
+                spectest(&mut linker, name);
+                continue;
+            }
+            let inst = match self.registered.get(m) {
+                Some(inst) => inst,
+                None => continue,
+            };
+            let i = inst.borrow();
+            let export = match i.module.exports().iter().find(|e| e.name() == name) {
+                Some(export) => export,
+                None => continue,
+            };
+            match export.desc() {
+                Exportdesc::Func(idx) => {
+                    let idx = *idx as usize;
+                    let ft = match i.module.get_func(idx) {
+                        Some(Function::Import(f)) => &f.ft,
+                        Some(Function::Local(f)) => &f.ft,
+                        None => continue,
+                    };
+                    let params: Vec<u8> = ft.input.0.iter().map(|v| v.0).collect();
+                    let results: Vec<u8> = ft.output.0.iter().map(|v| v.0).collect();
+                    let inst = inst.clone();
+                    linker.func(m, name, &params, &results, move |_store, args| {
+                        let mut inst = match inst.try_borrow_mut() {
+                            Ok(inst) => inst,
+                            Err(_) => return Err("trap: re-entrant call to a registered module".to_string()),
+                        };
+                        let Instance {module, store} = &mut *inst;
+                        invoke(idx, args, module, store)
+                    });
+                },
+                Exportdesc::Global(idx) => {
+                    if let Some(global) = i.store.global_ref(*idx as usize) {
+                        linker.shared_global(m, name, global);
+                    }
+                },
+                Exportdesc::Mem(_) => {
+                    linker.shared_memory(m, name, i.store.memory_ref());
+                },
+                Exportdesc::Table(idx) => {
+                    if let Some(table) = i.store.table_ref(*idx as usize) {
+                        linker.shared_table(m, name, table);
+                    }
+                },
+            }
+        }
+        linker
+    }
+
+    // (invoke $id? "name" arg*) or (get $id? "name")
+    fn action(&self, sexp: &Sexp) -> Result<Vec<Value>, String> {
+        let items = sexp.list();
+        let id = items.get(1).and_then(|s| s.id());
+        let rest = &items[1 + id.is_some() as usize..];
+        let name = rest.first().and_then(|s| s.string()).ok_or(format!("{}: need a name", sexp.head()))?;
+        let inst = self.instance(id)?;
+        let mut inst = inst.borrow_mut();
+        let Instance {module, store} = &mut *inst;
+        match sexp.head() {
+            "invoke" => {
+                let args: Result<Vec<Value>, String> = rest[1..].iter().map(parse_arg).collect();
+                let idx = module.export_func(&name).ok_or(format!("unknown function \"{}\"", name))?;
+                invoke(idx, &args?, module, store)
+            },
+            "get" => {
+                let idx = module.exports().iter().find_map(|e| match (e.name() == name, e.desc()) {
+                    (true, Exportdesc::Global(idx)) => Some(*idx as usize),
+                    _ => None,
+                });
+                let idx = idx.ok_or(format!("unknown global \"{}\"", name))?;
+                Ok(vec![store.global(idx).ok_or("global out of range")?])
+            },
+            s => Err(format!("unknown action '{}'", s)),
+        }
+    }
+}
+
+// the "spectest" module of the testsuite
+fn spectest(linker: &mut Linker, name: &str) {
+    let params: &[u8] = match name {
+        "global_i32" => {
+            linker.global("spectest", name, Value::I32(666), false);
+            return;
+        },
+        "global_i64" => {
+            linker.global("spectest", name, Value::I64(666), false);
+            return;
+        },
+        "global_f32" => {
+            linker.global("spectest", name, Value::F32(666.6), false);
+            return;
+        },
+        "global_f64" => {
+            linker.global("spectest", name, Value::F64(666.6), false);
+            return;
+        },
+        "table" => {
+            linker.table("spectest", name, 0x70, Limits::Minmax(10, 20));
+            return;
+        },
+        "memory" => {
+            linker.memory("spectest", name, Limits::Minmax(1, 2));
+            return;
+        },
+        "print" => &[],
+        "print_i32" => &[0x7f],
+        "print_i64" => &[0x7e],
+        "print_f32" => &[0x7d],
+        "print_f64" => &[0x7c],
+        "print_i32_f32" => &[0x7f, 0x7d],
+        "print_f64_f64" => &[0x7c, 0x7c],
+        _ => return,
+    };
+    linker.func("spectest", name, params, &[], |_store, _args| Ok(vec![]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_instances_are_shared() {
+        let summary = run_wast(r#"
+          (module $M
+            (memory (export "mem") 1 4)
+            (global (export "g") (mut i32) (i32.const 0))
+            (table (export "t") 2 externref)
+            (func (export "load") (result i32) (i32.load (i32.const 100)))
+            (func (export "pages") (result i32) (memory.size))
+            (func (export "elem") (result externref) (table.get (i32.const 1))))
+          (register "M" $M)
+          (module $N
+            (import "M" "mem" (memory 1))
+            (import "M" "g" (global $g (mut i32)))
+            (import "M" "t" (table 1 externref))
+            (func (export "change") (param externref)
+              (i32.store (i32.const 100) (i32.const 42))
+              (drop (memory.grow (i32.const 2)))
+              (global.set $g (i32.const 9))
+              (table.set (i32.const 1) (local.get 0))))
+          (assert_return (invoke $N "change" (ref.extern 3)))
+          (assert_return (invoke $M "load") (i32.const 42))
+          (assert_return (invoke $M "pages") (i32.const 3))
+          (assert_return (get $M "g") (i32.const 9))
+          (assert_return (invoke $M "elem") (ref.extern 3))
+          ;; the memory has grown, so it doesn't match the import of min 4
+          (assert_unlinkable (module (import "M" "mem" (memory 4 4))) "incompatible import type")
+          (module (import "M" "mem" (memory 3 4)))"#).unwrap();
+        assert_eq!(summary.failed, []);
+        assert_eq!(summary.passed, 10);
+    }
+}
@@ -0,0 +1,819 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+#![allow(dead_code)]
+
+// print a whole module in the WebAssembly text format (like wasm2wat).
+// function bodies are printed as flat instructions, or folded into
+// s-expressions when requested. names in the name section are used as
+// $ids when they are valid identifiers. blocks without a name have no label
+// and branches to them use the depth (flat blocks are commented with
+// ";; label = @n" as wasm-tools does).
+
+use std::collections::HashMap;
+
+use crate::bytecode::*;
+use crate::exec::*;
+use crate::inst::*;
+use crate::module::*;
+use crate::names::*;
+use crate::simd;
+
+pub fn module_to_wat(module: &Module, folded: bool) -> String {
+    let names = module.names();
+    let mut p = WatPrinter {
+        module,
+        folded,
+        out: String::new(),
+        ids: [&names.types, &names.funcs, &names.tables, &names.mems, &names.globals,
+            &names.elems, &names.datas].map(usable_ids),
+        local_ids: HashMap::new(),
+    };
+    p.print_module();
+    p.out
+}
+
+#[derive(Clone, Copy)]
+enum Space {
+    Type,
+    Func,
+    Table,
+    Mem,
+    Global,
+    Elem,
+    Data,
+}
+
+fn is_id(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-./:<=>?@\\^_`|~".contains(c))
+}
+
+// names which can be printed as $id: made of identifier characters and unique
+fn usable_ids(map: &NameMap) -> HashMap<u32, String> {
+    let mut count: HashMap<&str, usize> = HashMap::new();
+    for name in map.values() {
+        *count.entry(name).or_default() += 1;
+    }
+    map.iter()
+        .filter(|(_, name)| is_id(name) && count[name.as_str()] == 1)
+        .map(|(idx, name)| (*idx, format!("${}", name)))
+        .collect()
+}
+
+// " offset=8 align=4" (omitted if default)
+fn memarg_text(memarg: &Memarg, natural: u32) -> String {
+    let mut s = String::new();
+    if memarg.offset != 0 {
+        s.push_str(&format!(" offset={}", memarg.offset));
+    }
+    if memarg.align != natural {
+        s.push_str(&format!(" align={}", 1u64 << memarg.align.min(63)));
+    }
+    s
+}
+
+fn valtype_text(t: u8) -> String {
+    format!("{}", Valtype(t))
+}
+
+fn heaptype_text(t: u8) -> &'static str {
+    match t {
+        0x70 => "func",
+        0x6f => "extern",
+        _ => "?",
+    }
+}
+
+fn limits_text(limits: &Limits) -> String {
+    match limits {
+        Limits::Min(min) => format!("{}", min),
+        Limits::Minmax(min, max) => format!("{} {}", min, max),
+    }
+}
+
+fn globaltype_text(gt: &Globaltype) -> String {
+    if gt.is_mutable() {
+        format!("(mut {})", valtype_text(gt.valtype()))
+    } else {
+        valtype_text(gt.valtype())
+    }
+}
+
+// " (param i32 i32) (result i32)"
+fn functype_text(ft: &Functype) -> String {
+    let mut s = String::new();
+    if !ft.input.0.is_empty() {
+        s.push_str(" (param");
+        for v in &ft.input.0 {
+            s.push_str(&format!(" {}", v));
+        }
+        s.push(')');
+    }
+    if !ft.output.0.is_empty() {
+        s.push_str(" (result");
+        for v in &ft.output.0 {
+            s.push_str(&format!(" {}", v));
+        }
+        s.push(')');
+    }
+    s
+}
+
+pub fn string_text(bytes: &[u8]) -> String {
+    let mut s = "\"".to_string();
+    for b in bytes {
+        match b {
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            0x20..=0x7e => s.push(*b as char),
+            _ => s.push_str(&format!("\\{:02x}", b)),
+        }
+    }
+    s.push('"');
+    s
+}
+
+pub fn f32_text(n: f32) -> String {
+    if n.is_nan() {
+        let bits = n.to_bits();
+        let sign = if bits >> 31 != 0 {"-"} else {""};
+        let payload = bits & 0x7fffff;
+        if payload == 0x400000 {
+            format!("{}nan", sign)
+        } else {
+            format!("{}nan:{:#x}", sign, payload)
+        }
+    } else if n.is_infinite() {
+        if n < 0.0 {"-inf".to_string()} else {"inf".to_string()}
+    } else {
+        // Debug format is the shortest representation that round-trips
+        format!("{:?}", n)
+    }
+}
+
+pub fn f64_text(n: f64) -> String {
+    if n.is_nan() {
+        let bits = n.to_bits();
+        let sign = if bits >> 63 != 0 {"-"} else {""};
+        let payload = bits & 0xfffffffffffff;
+        if payload == 0x8000000000000 {
+            format!("{}nan", sign)
+        } else {
+            format!("{}nan:{:#x}", sign, payload)
+        }
+    } else if n.is_infinite() {
+        if n < 0.0 {"-inf".to_string()} else {"inf".to_string()}
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+// folded instruction tree
+enum Node {
+    Plain(String, Vec<Node>),
+    Block {
+        head: String,
+        cond: Option<Box<Node>>,
+        body: Vec<Node>,
+        else_body: Option<Vec<Node>>,
+    },
+}
+
+// label name (None if it has no name) and its arity
+struct Label {
+    name: Option<String>,
+    arity: usize,
+}
+
+struct FuncCtx {
+    labels: Vec<Label>,
+    num_labels: u32, // for looking up the names
+    num_results: usize,
+    label_ids: HashMap<u32, String>,
+}
+
+struct WatPrinter<'a> {
+    module: &'a Module,
+    folded: bool,
+    out: String,
+    ids: [HashMap<u32, String>; 7], // by Space
+    local_ids: HashMap<u32, String>, // of the current function
+}
+
+impl<'a> WatPrinter<'a> {
+    fn line(&mut self, indent: usize, s: &str) {
+        for _ in 0..indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    // $id or the index
+    fn idx_text(&self, space: Space, idx: u32) -> String {
+        match self.ids[space as usize].get(&idx) {
+            Some(id) => id.clone(),
+            None => format!("{}", idx),
+        }
+    }
+
+    // "$id (;idx;)" for definitions
+    fn def_text(&self, space: Space, idx: usize) -> String {
+        match self.ids[space as usize].get(&(idx as u32)) {
+            Some(id) => format!("{} (;{};)", id, idx),
+            None => format!("(;{};)", idx),
+        }
+    }
+
+    // params with their $ids, and results
+    fn signature_text(&self, ft: &Functype) -> String {
+        if !(0..ft.input.0.len()).any(|i| self.local_ids.contains_key(&(i as u32))) {
+            return functype_text(ft);
+        }
+        let mut s = String::new();
+        for (i, v) in ft.input.0.iter().enumerate() {
+            match self.local_ids.get(&(i as u32)) {
+                Some(id) => s.push_str(&format!(" (param {} {})", id, v)),
+                None => s.push_str(&format!(" (param {})", v)),
+            }
+        }
+        let results = Functype {input: Resulttype(Vec::new()), output: ft.output.clone()};
+        s + &functype_text(&results)
+    }
+
+    // append ")" to the last line
+    fn close(&mut self) {
+        self.out.pop();
+        self.out.push_str(")\n");
+    }
+
+    fn print_module(&mut self) {
+        let module = self.module;
+        match &module.names().module {
+            Some(name) if is_id(name) => self.line(0, &format!("(module ${}", name)),
+            _ => self.line(0, "(module"),
+        }
+
+        for (i, ft) in module.types().iter().enumerate() {
+            self.line(1, &format!("(type {} (func{}))", self.def_text(Space::Type, i),
+                functype_text(ft)));
+        }
+
+        let mut num_funcs = 0;
+        let mut num_tables = 0;
+        let mut num_mems = 0;
+        let mut num_globals = 0;
+        for im in module.imports() {
+            let desc = match im.desc() {
+                Importdesc::Func(idx) => {
+                    num_funcs += 1;
+                    format!("(func {} (type {}))", self.def_text(Space::Func, num_funcs - 1),
+                        self.idx_text(Space::Type, *idx))
+                },
+                Importdesc::Table(tt) => {
+                    num_tables += 1;
+                    format!("(table {} {} {})", self.def_text(Space::Table, num_tables - 1),
+                        limits_text(tt.limits()),
+                        valtype_text(tt.reftype()))
+                },
+                Importdesc::Mem(limits) => {
+                    num_mems += 1;
+                    format!("(memory {} {})", self.def_text(Space::Mem, num_mems - 1),
+                        limits_text(limits))
+                },
+                Importdesc::Global(gt) => {
+                    num_globals += 1;
+                    format!("(global {} {})", self.def_text(Space::Global, num_globals - 1),
+                        globaltype_text(gt))
+                },
+            };
+            self.line(1, &format!("(import {} {} {})", string_text(im.module().as_bytes()),
+                string_text(im.name().as_bytes()), desc));
+        }
+
+        for (i, typeidx) in module.func_typeidx().iter().enumerate() {
+            let idx = num_funcs + i;
+            let func = module.get_local_func(idx);
+            let names = module.names();
+            self.local_ids = names.locals.get(&(idx as u32)).map(usable_ids).unwrap_or_default();
+            self.line(1, &format!("(func {} (type {}){}", self.def_text(Space::Func, idx),
+                self.idx_text(Space::Type, *typeidx), self.signature_text(&func.ft)));
+            if !func.locals.is_empty() {
+                let num_params = func.ft.input.0.len();
+                let named = (0..func.locals.len())
+                    .any(|i| self.local_ids.contains_key(&((num_params + i) as u32)));
+                if named {
+                    for (i, l) in func.locals.iter().enumerate() {
+                        match self.local_ids.get(&((num_params + i) as u32)) {
+                            Some(id) => self.line(2, &format!("(local {} {})", id, l)),
+                            None => self.line(2, &format!("(local {})", l)),
+                        }
+                    }
+                } else {
+                    let mut s = "(local".to_string();
+                    for l in &func.locals {
+                        s.push_str(&format!(" {}", l));
+                    }
+                    s.push(')');
+                    self.line(2, &s);
+                }
+            }
+            let mut ctx = FuncCtx {
+                labels: Vec::new(),
+                num_labels: 0,
+                num_results: func.ft.output.0.len(),
+                label_ids: names.labels.get(&(idx as u32)).map(usable_ids).unwrap_or_default(),
+            };
+            if self.folded {
+                let mut pos = 0;
+                let nodes = self.fold(&func.insts, &mut pos, &mut ctx);
+                for node in &nodes {
+                    self.print_node(node, 2);
+                }
+            } else {
+                self.print_flat(&func.insts, &mut ctx);
+            }
+            self.close();
+        }
+        self.local_ids.clear();
+
+        for (i, tt) in module.tables().iter().enumerate() {
+            self.line(1, &format!("(table {} {} {})", self.def_text(Space::Table, num_tables + i),
+                limits_text(tt.limits()), valtype_text(tt.reftype())));
+        }
+
+        for (i, limits) in module.mems().iter().enumerate() {
+            self.line(1, &format!("(memory {} {})", self.def_text(Space::Mem, num_mems + i),
+                limits_text(limits)));
+        }
+
+        for (i, g) in module.globals().iter().enumerate() {
+            self.line(1, &format!("(global {} {} {})", self.def_text(Space::Global, num_globals + i),
+                globaltype_text(g.globaltype()), self.expr_text(g.expr())));
+        }
+
+        for ex in module.exports() {
+            let desc = match ex.desc() {
+                Exportdesc::Func(idx) => format!("(func {})", self.idx_text(Space::Func, *idx)),
+                Exportdesc::Table(idx) => format!("(table {})", self.idx_text(Space::Table, *idx)),
+                Exportdesc::Mem(idx) => format!("(memory {})", self.idx_text(Space::Mem, *idx)),
+                Exportdesc::Global(idx) => format!("(global {})", self.idx_text(Space::Global, *idx)),
+            };
+            self.line(1, &format!("(export {} {})", string_text(ex.name().as_bytes()), desc));
+        }
+
+        if let Some(idx) = module.start() {
+            self.line(1, &format!("(start {})", self.idx_text(Space::Func, idx)));
+        }
+
+        for (i, el) in module.elems().iter().enumerate() {
+            let mut s = format!("(elem {}", self.def_text(Space::Elem, i));
+            match el.mode() {
+                ElemMode::Active(tableidx, offset) => {
+                    if tableidx != 0 || matches!(el, Elem::Elem2(_) | Elem::Elem6(_)) {
+                        s.push_str(&format!(" (table {})", self.idx_text(Space::Table, tableidx)));
+                    }
+                    s.push_str(&format!(" {}", self.offset_text(offset)));
+                },
+                ElemMode::Passive => (),
+                ElemMode::Declarative => s.push_str(" declare"),
+            }
+            match el.init() {
+                ElemInit::Funcs(idx) => {
+                    s.push_str(" func");
+                    for f in idx {
+                        s.push_str(&format!(" {}", self.idx_text(Space::Func, *f)));
+                    }
+                },
+                ElemInit::Exprs(exprs) => {
+                    s.push_str(&format!(" {}", valtype_text(el.reftype())));
+                    for e in exprs {
+                        s.push_str(&format!(" (item {})", self.expr_text(e)));
+                    }
+                },
+            }
+            s.push(')');
+            self.line(1, &s);
+        }
+
+        for (i, data) in module.datas().iter().enumerate() {
+            let mut s = format!("(data {}", self.def_text(Space::Data, i));
+            if let DataMode::Active(memidx, offset) = data.mode() {
+                if memidx != 0 {
+                    s.push_str(&format!(" (memory {})", self.idx_text(Space::Mem, memidx)));
+                }
+                s.push_str(&format!(" {}", self.offset_text(offset)));
+            }
+            s.push_str(&format!(" {})", string_text(data.data())));
+            self.line(1, &s);
+        }
+
+        self.close();
+    }
+
+    // constant expression as folded instructions without the final end
+    fn expr_text(&self, expr: &Expr) -> String {
+        let insts = expr.get_insts().unwrap_or_default();
+        let mut v = Vec::new();
+        for inst in insts.iter().filter(|inst| inst.op_code != 0x0b) {
+            v.push(format!("({})", self.inst_text(inst, &[])));
+        }
+        v.join(" ")
+    }
+
+    fn offset_text(&self, expr: &Expr) -> String {
+        let n = expr.get_insts().map(|insts| insts.len()).unwrap_or(0);
+        if n == 2 {
+            self.expr_text(expr)
+        } else {
+            format!("(offset {})", self.expr_text(expr))
+        }
+    }
+
+    fn blocktype_text(&self, bt: &BlockType) -> String {
+        match bt {
+            BlockType::Empty => "".to_string(),
+            BlockType::Valtype(t) => format!(" (result {})", valtype_text(*t)),
+            BlockType::TypeIndex(idx) => format!(" (type {})", self.idx_text(Space::Type, *idx)),
+        }
+    }
+
+    // (params, results) of a block type
+    fn blocktype_arity(&self, bt: &BlockType) -> (usize, usize) {
+        match bt {
+            BlockType::Empty => (0, 0),
+            BlockType::Valtype(_) => (0, 1),
+            BlockType::TypeIndex(idx) => match self.module.types().get(*idx as usize) {
+                Some(ft) => (ft.input.0.len(), ft.output.0.len()),
+                None => (0, 0),
+            },
+        }
+    }
+
+    fn label_text(labels: &[Label], n: u32) -> String {
+        let n = n as usize;
+        match labels.get(labels.len().wrapping_sub(n + 1)) {
+            Some(Label {name: Some(name), ..}) => name.clone(),
+            _ => format!("{}", n),
+        }
+    }
+
+    fn inst_text(&self, inst: &Inst, labels: &[Label]) -> String {
+        let mut s = inst.get_mnemonic();
+        match (inst.op_code, &inst.operand) {
+            (0x0c | 0x0d, Operand::Index(n)) => {
+                s.push_str(&format!(" {}", Self::label_text(labels, *n)));
+            },
+            (0x0e, Operand::BrTable(br_table)) => {
+                for l in &br_table.labels {
+                    s.push_str(&format!(" {}", Self::label_text(labels, *l)));
+                }
+                s.push_str(&format!(" {}", Self::label_text(labels, br_table.default)));
+            },
+            (0x11, Operand::Index2(tableidx, typeidx)) => {
+                if *tableidx != 0 {
+                    s.push_str(&format!(" {}", self.idx_text(Space::Table, *tableidx)));
+                }
+                s.push_str(&format!(" (type {})", self.idx_text(Space::Type, *typeidx)));
+            },
+            (0x1c, Operand::VecValtype(types)) => {
+                s.push_str(" (result");
+                for t in types {
+                    s.push_str(&format!(" {}", valtype_text(*t)));
+                }
+                s.push(')');
+            },
+            (0x28..=0x3e, Operand::Memarg(memarg)) => {
+                s.push_str(&memarg_text(memarg, natural_align(inst.op_code)));
+            },
+            (0xfd, Operand::Memarg(memarg)) => {
+                s.push_str(&memarg_text(memarg, natural_align_fd(inst.sub_op)));
+            },
+            (0xfd, Operand::MemargLane(memarg, lane)) => {
+                s.push_str(&memarg_text(memarg, natural_align_fd(inst.sub_op)));
+                s.push_str(&format!(" {}", lane));
+            },
+            (0xfd, Operand::V128(v)) => s.push_str(&format!(" {}", v128_text(*v))),
+            (0xfd, Operand::Lane(lane)) => s.push_str(&format!(" {}", lane)),
+            (0xfd, Operand::Shuffle(lanes)) => {
+                for l in lanes {
+                    s.push_str(&format!(" {}", l));
+                }
+            },
+            (0x3f | 0x40, _) => (),
+            (0x41, Operand::I32(n)) => s.push_str(&format!(" {}", n)),
+            (0x42, Operand::I64(n)) => s.push_str(&format!(" {}", n)),
+            (0x43, Operand::F32(n)) => s.push_str(&format!(" {}", f32_text(*n))),
+            (0x44, Operand::F64(n)) => s.push_str(&format!(" {}", f64_text(*n))),
+            (0xd0, Operand::Valtype(t)) => s.push_str(&format!(" {}", heaptype_text(*t))),
+            (0xfc, Operand::Index2(idx1, idx2)) => {
+                match inst.sub_op {
+                    8 => s.push_str(&format!(" {}", self.idx_text(Space::Data, *idx1))), // memory.init
+                    12 => s.push_str(&format!(" {} {}", self.idx_text(Space::Table, *idx2),
+                        self.idx_text(Space::Elem, *idx1))), // table.init tableidx elemidx
+                    14 => s.push_str(&format!(" {} {}", self.idx_text(Space::Table, *idx1),
+                        self.idx_text(Space::Table, *idx2))), // table.copy
+                    _ => (),
+                }
+            },
+            (0xfc, Operand::Index(_)) if inst.sub_op == 11 => (), // memory.fill
+            (0xfc, Operand::Index(idx)) => {
+                let space = match inst.sub_op {
+                    9 => Space::Data,
+                    13 => Space::Elem,
+                    _ => Space::Table,
+                };
+                s.push_str(&format!(" {}", self.idx_text(space, *idx)));
+            },
+            (0x10 | 0xd2, Operand::Index(idx)) => {
+                s.push_str(&format!(" {}", self.idx_text(Space::Func, *idx)));
+            },
+            (0x20..=0x22, Operand::Index(idx)) => {
+                match self.local_ids.get(idx) {
+                    Some(id) => s.push_str(&format!(" {}", id)),
+                    None => s.push_str(&format!(" {}", idx)),
+                }
+            },
+            (0x23 | 0x24, Operand::Index(idx)) => {
+                s.push_str(&format!(" {}", self.idx_text(Space::Global, *idx)));
+            },
+            (0x25 | 0x26, Operand::Index(idx)) => {
+                s.push_str(&format!(" {}", self.idx_text(Space::Table, *idx)));
+            },
+            (_, Operand::Index(idx)) => s.push_str(&format!(" {}", idx)),
+            _ => (),
+        }
+        s
+    }
+
+    fn block_head(&self, inst: &Inst, ctx: &mut FuncCtx) -> String {
+        let name = ctx.label_ids.get(&ctx.num_labels).cloned();
+        ctx.num_labels += 1;
+        let mut head = inst.get_mnemonic();
+        if let Some(name) = &name {
+            head.push_str(&format!(" {}", name));
+        }
+        if let Operand::BlockType(bt) = &inst.operand {
+            head.push_str(&self.blocktype_text(bt));
+            let (params, results) = self.blocktype_arity(bt);
+            let arity = if inst.op_code == 0x03 {params} else {results};
+            if name.is_none() && !self.folded {
+                head.push_str(&format!("  ;; label = @{}", ctx.labels.len() + 1));
+            }
+            ctx.labels.push(Label {name, arity});
+        }
+        head
+    }
+
+    fn print_flat(&mut self, insts: &[Inst], ctx: &mut FuncCtx) {
+        let mut depth = 2;
+        for (i, inst) in insts.iter().enumerate() {
+            match inst.op_code {
+                0x02..=0x04 => {
+                    let head = self.block_head(inst, ctx);
+                    self.line(depth, &head);
+                    depth += 1;
+                },
+                0x05 => self.line(depth - 1, "else"),
+                0x0b => {
+                    if i == insts.len() - 1 {
+                        break; // end of the function
+                    }
+                    ctx.labels.pop();
+                    depth -= 1;
+                    self.line(depth, "end");
+                },
+                _ => {
+                    let s = self.inst_text(inst, &ctx.labels);
+                    self.line(depth, &s);
+                },
+            }
+        }
+    }
+
+    // (params, results) of a plain instruction, used for folding
+    fn arity(&self, inst: &Inst, ctx: &FuncCtx) -> (usize, usize) {
+        let label_arity = |n: u32| -> usize {
+            let n = n as usize;
+            if n < ctx.labels.len() {
+                ctx.labels[ctx.labels.len() - 1 - n].arity
+            } else {
+                ctx.num_results
+            }
+        };
+        let func_arity = |idx: usize| -> (usize, usize) {
+            match self.module.get_func(idx) {
+                Some(Function::Import(f)) => (f.ft.input.0.len(), f.ft.output.0.len()),
+                Some(Function::Local(f)) => (f.ft.input.0.len(), f.ft.output.0.len()),
+                None => (0, 0),
+            }
+        };
+        match (inst.op_code, &inst.operand) {
+            (0x00 | 0x01, _) => (0, 0),
+            (0x0c, Operand::Index(n)) => (label_arity(*n), 0),
+            (0x0d, Operand::Index(n)) => (label_arity(*n) + 1, label_arity(*n)),
+            (0x0e, Operand::BrTable(br_table)) => (label_arity(br_table.default) + 1, 0),
+            (0x0f, _) => (ctx.num_results, 0),
+            (0x10, Operand::Index(idx)) => func_arity(*idx as usize),
+            (0x11, Operand::Index2(_, typeidx)) => {
+                let (p, r) = self.blocktype_arity(&BlockType::TypeIndex(*typeidx));
+                (p + 1, r)
+            },
+            (0x1a, _) => (1, 0),
+            (0x1b | 0x1c, _) => (3, 1),
+            (0x20, _) => (0, 1),
+            (0x21, _) => (1, 0),
+            (0x22, _) => (1, 1),
+            (0x23, _) => (0, 1),
+            (0x24, _) => (1, 0),
+            (0x25, _) => (1, 1),
+            (0x26, _) => (2, 0),
+            (0x28..=0x35, _) => (1, 1),
+            (0x36..=0x3e, _) => (2, 0),
+            (0x3f, _) => (0, 1),
+            (0x40, _) => (1, 1),
+            (0x41..=0x44, _) => (0, 1),
+            (0x45, _) | (0x50, _) => (1, 1),
+            (0x46..=0x66, _) => (2, 1),
+            (0x67..=0x69, _) | (0x79..=0x7b, _) => (1, 1),
+            (0x6a..=0x78, _) | (0x7c..=0x8a, _) => (2, 1),
+            (0x8b..=0x91, _) | (0x99..=0x9f, _) => (1, 1),
+            (0x92..=0x98, _) | (0xa0..=0xa6, _) => (2, 1),
+            (0xa7..=0xc4, _) => (1, 1),
+            (0xd0, _) => (0, 1),
+            (0xd1, _) => (1, 1),
+            (0xd2, _) => (0, 1),
+            (0xfc, _) => match inst.sub_op {
+                0..=7 => (1, 1),
+                9 | 13 => (0, 0),
+                15 => (2, 1),
+                16 => (0, 1),
+                _ => (3, 0),
+            },
+            (0xfd, _) => {
+                simd::signature(inst.sub_op).map_or((0, 0), |(params, results)| (params.len(), results.len()))
+            },
+            _ => (0, 0),
+        }
+    }
+
+    // fold instructions up to (not including) the next else/end at this level
+    fn fold(&self, insts: &[Inst], pos: &mut usize, ctx: &mut FuncCtx) -> Vec<Node> {
+        let mut stack: Vec<(Node, usize)> = Vec::new();
+
+        // take n operands if all of them are single-value expressions
+        fn take(stack: &mut Vec<(Node, usize)>, n: usize) -> Vec<Node> {
+            if n == 0 || stack.len() < n || stack[stack.len() - n..].iter().any(|(_, r)| *r != 1) {
+                return Vec::new();
+            }
+            stack.drain(stack.len() - n..).map(|(node, _)| node).collect()
+        }
+
+        while *pos < insts.len() {
+            let inst = &insts[*pos];
+            match inst.op_code {
+                0x05 | 0x0b => break,
+                0x02..=0x04 => {
+                    let head = self.block_head(inst, ctx);
+                    let results = match &inst.operand {
+                        Operand::BlockType(bt) => self.blocktype_arity(bt).1,
+                        _ => 0,
+                    };
+                    let cond = if inst.op_code == 0x04 {
+                        take(&mut stack, 1).pop().map(Box::new)
+                    } else {
+                        None
+                    };
+                    *pos += 1;
+                    let body = self.fold(insts, pos, ctx);
+                    let mut else_body = None;
+                    if *pos < insts.len() && insts[*pos].op_code == 0x05 {
+                        *pos += 1;
+                        else_body = Some(self.fold(insts, pos, ctx));
+                    }
+                    *pos += 1; // end
+                    ctx.labels.pop();
+                    if inst.op_code == 0x04 && else_body.is_none() {
+                        else_body = Some(Vec::new());
+                    }
+                    let node = Node::Block {head, cond, body, else_body};
+                    stack.push((node, results));
+                },
+                _ => {
+                    let (params, results) = self.arity(inst, ctx);
+                    let children = take(&mut stack, params);
+                    let node = Node::Plain(self.inst_text(inst, &ctx.labels), children);
+                    stack.push((node, results));
+                    *pos += 1;
+                },
+            }
+        }
+
+        stack.into_iter().map(|(node, _)| node).collect()
+    }
+
+    fn print_node(&mut self, node: &Node, indent: usize) {
+        match node {
+            Node::Plain(text, children) => {
+                if children.is_empty() {
+                    self.line(indent, &format!("({})", text));
+                } else {
+                    self.line(indent, &format!("({}", text));
+                    for c in children {
+                        self.print_node(c, indent + 1);
+                    }
+                    self.close();
+                }
+            },
+            Node::Block {head, cond, body, else_body} => {
+                self.line(indent, &format!("({}", head));
+                if let Some(cond) = cond {
+                    self.print_node(cond, indent + 1);
+                }
+                match else_body {
+                    Some(else_body) => { // if
+                        self.line(indent + 1, "(then");
+                        for n in body {
+                            self.print_node(n, indent + 2);
+                        }
+                        self.close();
+                        if !else_body.is_empty() {
+                            self.line(indent + 1, "(else");
+                            for n in else_body {
+                                self.print_node(n, indent + 2);
+                            }
+                            self.close();
+                        }
+                    },
+                    None => {
+                        for n in body {
+                            self.print_node(n, indent + 1);
+                        }
+                    },
+                }
+                self.close();
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // wasm -> wat -> wasm must give the same binary
+    fn round_trip(src: &str) -> String {
+        let buf = crate::parse_wat(src).unwrap().encode();
+        let module = init_module(buf.clone()).unwrap();
+        for folded in [false, true] {
+            let text = module_to_wat(&module, folded);
+            let again = crate::parse_wat(&text).unwrap_or_else(|e| panic!("{}\n{}", e, text));
+            assert_eq!(again.encode(), buf, "{}", text);
+        }
+        module_to_wat(&module, false)
+    }
+
+    #[test]
+    fn module_name() {
+        let text = round_trip("(module $calc (func $add (param i32 i32) (result i32) (i32.add (local.get 0) (local.get 1))))");
+        assert!(text.starts_with("(module $calc\n"), "{}", text);
+        let module = crate::parse_wat(&text).unwrap();
+        assert_eq!(module.names().module.as_deref(), Some("calc"));
+        assert_eq!(module.func_index("add"), Some(0));
+        // "a b" is not a valid id
+        let mut buf = crate::parse_wat("(module (func))").unwrap().encode();
+        buf.extend(b"\x00\x0b\x04name\x00\x04\x03a b");
+        let module = init_module(buf).unwrap();
+        assert_eq!(module.names().module.as_deref(), Some("a b"));
+        assert!(module_to_wat(&module, false).starts_with("(module\n"));
+    }
+
+    #[test]
+    fn unnamed_labels() {
+        let text = round_trip(r#"(module
+          (func (param i32) (result i32)
+            (block (result i32)
+              (loop
+                (br_if 1 (i32.const 5) (local.get 0))
+                (block (br_table 0 1 2 (local.get 0)))
+                (if (local.get 0) (then (br 2)) (else (br 1))))
+              (i32.const 3))))"#);
+        assert!(!text.contains('$'), "{}", text);
+        assert!(text.contains("block  ;; label = @3"), "{}", text);
+        assert!(text.contains("br_table 0 1 2"), "{}", text);
+    }
+
+    #[test]
+    fn named_labels() {
+        let text = round_trip(r#"(module
+          (func $f (param i32)
+            (block $out
+              (loop $top
+                (block (br_table $out $top 0 (local.get 0)))
+                (br $top)))))"#);
+        assert!(text.contains("block $out"), "{}", text);
+        assert!(text.contains("br_table $out $top 0"), "{}", text);
+    }
+}
@@ -0,0 +1,1978 @@
+// SPDX-License-Identifier: MIT
+// Copyright(c) 2023 Itsuro Oda
+// https://opensource.org/license/mit/
+
+// parse the WebAssembly text format and assemble it into the binary format
+// (like wat2wasm). the binary is then decoded by init_module, so a .wat file
+// ends up in exactly the same Module as the equivalent .wasm file.
+//
+// supported: flat and folded instructions, symbolic $names of all index
+// spaces, locals and labels, inline import/export, inline elem/data of
+// table/memory and type use abbreviations.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::bytecode::*;
+use crate::inst::*;
+use crate::module::*;
+use crate::names::*;
+
+#[derive(Debug, Clone)]
+pub struct WatError {
+    pub line: usize,
+    pub col: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for WatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.msg)
+    }
+}
+
+impl std::error::Error for WatError {}
+
+pub fn wat2wasm(src: &str) -> Result<Vec<u8>, WatError> {
+    let toks = tokenize(src)?;
+    let mut p = Parser::new(toks);
+    p.parse_module()?;
+    Ok(p.finish())
+}
+
+pub fn parse_wat(src: &str) -> Result<Module, WatError> {
+    let buf = wat2wasm(src)?;
+    init_module(buf).map_err(|e| WatError {
+        line: 0,
+        col: 0,
+        msg: format!("assembled module is malformed: {}", e),
+    })
+}
+
+// ---- lexer ----
+
+#[derive(Clone, PartialEq)]
+pub(crate) enum Tok {
+    LParen,
+    RParen,
+    Atom(String), // keyword, number and other reserved words
+    Id(String), // $name (without '$')
+    Str(Vec<u8>),
+    Eof,
+}
+
+pub(crate) struct Token {
+    pub tok: Tok,
+    pub line: usize,
+    pub col: usize,
+    pub pos: usize, // in chars
+}
+
+// tokens of the source (used by the .wast script runner as well)
+pub(crate) fn tokenize(src: &str) -> Result<Vec<Token>, WatError> {
+    Lexer::new(src).tokens()
+}
+
+fn is_idchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-./:<=>?@\\^_`|~".contains(c)
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    i: usize,
+    line: usize,
+    col: usize,
+}
+
+impl Lexer {
+    fn new(src: &str) -> Self {
+        Lexer {
+            chars: src.chars().collect(),
+            i: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn peek(&self, n: usize) -> Option<char> {
+        self.chars.get(self.i + n).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.i += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn err(&self, msg: &str) -> WatError {
+        WatError {line: self.line, col: self.col, msg: msg.to_string()}
+    }
+
+    fn tokens(mut self) -> Result<Vec<Token>, WatError> {
+        let mut toks = Vec::new();
+        loop {
+            let (line, col, pos) = (self.line, self.col, self.i);
+            let c = match self.peek(0) {
+                Some(c) => c,
+                None => {
+                    toks.push(Token {tok: Tok::Eof, line, col, pos});
+                    break;
+                },
+            };
+            let tok = match c {
+                ' ' | '\t' | '\n' | '\r' => {
+                    self.bump();
+                    continue;
+                },
+                ';' if self.peek(1) == Some(';') => {
+                    while let Some(c) = self.bump() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                    continue;
+                },
+                '(' if self.peek(1) == Some(';') => {
+                    self.block_comment()?;
+                    continue;
+                },
+                '(' => {
+                    self.bump();
+                    Tok::LParen
+                },
+                ')' => {
+                    self.bump();
+                    Tok::RParen
+                },
+                '"' => {
+                    self.bump();
+                    Tok::Str(self.string()?)
+                },
+                c if is_idchar(c) => {
+                    let mut s = String::new();
+                    while let Some(c) = self.peek(0) {
+                        if !is_idchar(c) {
+                            break;
+                        }
+                        s.push(c);
+                        self.bump();
+                    }
+                    match s.strip_prefix('$') {
+                        Some("") => return Err(WatError {line, col, msg: "empty identifier".to_string()}),
+                        Some(id) => Tok::Id(id.to_string()),
+                        None => Tok::Atom(s),
+                    }
+                },
+                _ => return Err(self.err(&format!("unexpected character '{}'", c))),
+            };
+            toks.push(Token {tok, line, col, pos});
+        }
+        Ok(toks)
+    }
+
+    // block comments nest
+    fn block_comment(&mut self) -> Result<(), WatError> {
+        self.bump();
+        self.bump();
+        let mut depth = 1;
+        while depth > 0 {
+            match self.bump() {
+                None => return Err(self.err("unterminated block comment")),
+                Some('(') if self.peek(0) == Some(';') => {
+                    self.bump();
+                    depth += 1;
+                },
+                Some(';') if self.peek(0) == Some(')') => {
+                    self.bump();
+                    depth -= 1;
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn hex_digit(&mut self) -> Result<u32, WatError> {
+        match self.bump().and_then(|c| c.to_digit(16)) {
+            Some(d) => Ok(d),
+            None => Err(self.err("invalid escape")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, WatError> {
+        let mut s = Vec::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.err("unterminated string")),
+                Some('"') => break,
+                Some('\\') => {
+                    match self.peek(0) {
+                        Some('t') => s.push(b'\t'),
+                        Some('n') => s.push(b'\n'),
+                        Some('r') => s.push(b'\r'),
+                        Some('"') => s.push(b'"'),
+                        Some('\'') => s.push(b'\''),
+                        Some('\\') => s.push(b'\\'),
+                        Some('u') => {
+                            self.bump();
+                            if self.bump() != Some('{') {
+                                return Err(self.err("invalid escape"));
+                            }
+                            let mut n: u32 = 0;
+                            while self.peek(0) != Some('}') {
+                                n = n.saturating_mul(16).saturating_add(self.hex_digit()?);
+                            }
+                            let c = char::from_u32(n).ok_or_else(|| self.err("invalid escape"))?;
+                            let mut b = [0; 4];
+                            s.extend_from_slice(c.encode_utf8(&mut b).as_bytes());
+                        },
+                        Some(c) if c.is_ascii_hexdigit() => {
+                            let hi = self.hex_digit()?;
+                            let lo = self.hex_digit()?;
+                            s.push((hi * 16 + lo) as u8);
+                            continue;
+                        },
+                        _ => return Err(self.err("invalid escape")),
+                    }
+                    self.bump();
+                },
+                Some(c) => {
+                    let mut b = [0; 4];
+                    s.extend_from_slice(c.encode_utf8(&mut b).as_bytes());
+                },
+            }
+        }
+        Ok(s)
+    }
+}
+
+// ---- numbers ----
+
+fn split_sign(s: &str) -> (bool, &str) {
+    if let Some(rest) = s.strip_prefix('-') {
+        (true, rest)
+    } else if let Some(rest) = s.strip_prefix('+') {
+        (false, rest)
+    } else {
+        (false, s)
+    }
+}
+
+// '_' is allowed only between digits
+fn check_underscore(s: &str) -> bool {
+    !(s.starts_with('_') || s.ends_with('_') || s.contains("__"))
+}
+
+// unsigned decimal or hexadecimal (0x) number
+fn parse_digits(s: &str) -> Option<u128> {
+    let (digits, radix) = match s.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None => (s, 10),
+    };
+    if digits.is_empty() || !check_underscore(digits) {
+        return None;
+    }
+    let mut n: u128 = 0;
+    for c in digits.chars().filter(|c| *c != '_') {
+        let d = c.to_digit(radix)?;
+        n = n.checked_mul(radix as u128)?.checked_add(d as u128)?;
+    }
+    Some(n)
+}
+
+fn parse_u32(s: &str) -> Option<u32> {
+    parse_digits(s).and_then(|n| u32::try_from(n).ok())
+}
+
+// integer literal of the given width. both signed and unsigned ranges
+// are accepted and the result is the bit pattern.
+pub(crate) fn parse_int(s: &str, bits: u32) -> Option<u64> {
+    let (neg, digits) = split_sign(s);
+    let n = parse_digits(digits)?;
+    let mask = ((1u128 << bits) - 1) as u64;
+    if neg {
+        if n > 1u128 << (bits - 1) {
+            return None;
+        }
+        Some((n as u64).wrapping_neg() & mask)
+    } else {
+        if n > mask as u128 {
+            return None;
+        }
+        Some(n as u64)
+    }
+}
+
+// number of lanes of a v128.const shape
+pub(crate) fn shape_lanes(shape: &str) -> Option<usize> {
+    match shape {
+        "i8x16" => Some(16),
+        "i16x8" => Some(8),
+        "i32x4" | "f32x4" => Some(4),
+        "i64x2" | "f64x2" => Some(2),
+        _ => None,
+    }
+}
+
+// v128.const literal: the shape and the lanes like "i32x4" ["1", "2", "3", "4"]
+pub(crate) fn parse_v128(shape: &str, lanes: &[&str]) -> Option<u128> {
+    let n = shape_lanes(shape)?;
+    if lanes.len() != n {
+        return None;
+    }
+    let w = 128 / n;
+    let mut v = 0;
+    for (i, s) in lanes.iter().enumerate() {
+        let x = match shape {
+            "f32x4" => parse_float(s, 23, 8)?,
+            "f64x2" => parse_float(s, 52, 11)?,
+            _ => parse_int(s, w as u32)?,
+        };
+        v |= (x as u128) << (w * i);
+    }
+    Some(v)
+}
+
+// float literal. returns the bit pattern of a float which has mant_bits
+// of fraction and exp_bits of exponent.
+pub(crate) fn parse_float(s: &str, mant_bits: u32, exp_bits: u32) -> Option<u64> {
+    let (neg, body) = split_sign(s);
+    let sign = (neg as u64) << (mant_bits + exp_bits);
+    let exp_all = ((1u64 << exp_bits) - 1) << mant_bits;
+    let bits = if body == "inf" {
+        exp_all
+    } else if body == "nan" {
+        exp_all | 1u64 << (mant_bits - 1)
+    } else if let Some(payload) = body.strip_prefix("nan:") {
+        if !payload.starts_with("0x") {
+            return None;
+        }
+        let p = parse_digits(payload)?;
+        if p == 0 || p >= 1u128 << mant_bits {
+            return None;
+        }
+        exp_all | p as u64
+    } else if let Some(hex) = body.strip_prefix("0x") {
+        hex_float(hex, mant_bits, exp_bits)?
+    } else {
+        if !body.starts_with(|c: char| c.is_ascii_digit()) || !check_underscore(body)
+            || !body.chars().all(|c| c.is_ascii_digit() || "._eE+-".contains(c)) {
+            return None;
+        }
+        let t: String = body.chars().filter(|c| *c != '_').collect();
+        // rust's parser rounds correctly
+        if mant_bits == 23 {
+            let f: f32 = t.parse().ok()?;
+            if f.is_infinite() {
+                return None;
+            }
+            f.to_bits() as u64
+        } else {
+            let f: f64 = t.parse().ok()?;
+            if f.is_infinite() {
+                return None;
+            }
+            f.to_bits()
+        }
+    };
+    Some(sign | bits)
+}
+
+// hexadecimal float (without sign and "0x"), rounded to nearest even
+fn hex_float(s: &str, mant_bits: u32, exp_bits: u32) -> Option<u64> {
+    let (mant_str, exp_str) = match s.find(['p', 'P']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    if !check_underscore(mant_str) || mant_str.starts_with('.') {
+        return None;
+    }
+
+    // value = m * 2^e, digits which do not fit are kept as a sticky bit
+    let mut m: u128 = 0;
+    let mut e: i64 = 0;
+    let mut sticky = false;
+    let mut after_point = false;
+    for c in mant_str.chars() {
+        match c {
+            '_' => (),
+            '.' => {
+                if after_point {
+                    return None;
+                }
+                after_point = true;
+            },
+            _ => {
+                let d = c.to_digit(16)? as u128;
+                if m < 1u128 << 100 {
+                    m = m * 16 + d;
+                    if after_point {
+                        e -= 4;
+                    }
+                } else {
+                    sticky |= d != 0;
+                    if !after_point {
+                        e += 4;
+                    }
+                }
+            },
+        }
+    }
+    if let Some(exp_str) = exp_str {
+        let (neg, digits) = split_sign(exp_str);
+        if digits.is_empty() || !check_underscore(digits) {
+            return None;
+        }
+        let mut n: i64 = 0;
+        for c in digits.chars().filter(|c| *c != '_') {
+            n = n.saturating_mul(10).saturating_add(c.to_digit(10)? as i64);
+        }
+        n = n.min(1 << 20);
+        e += if neg {-n} else {n};
+    }
+    if m == 0 {
+        return Some(0);
+    }
+
+    let bias = (1i64 << (exp_bits - 1)) - 1;
+    let emin = 1 - bias;
+    let msb = 127 - m.leading_zeros() as i64;
+    let exp = msb + e; // value is in [2^exp, 2^(exp+1))
+    // the lowest bit of m which is kept
+    let low = if exp >= emin {
+        msb - mant_bits as i64
+    } else {
+        emin - mant_bits as i64 - e
+    };
+    let mut mant = if low <= 0 {
+        m << (-low)
+    } else if low > 128 {
+        0
+    } else {
+        let (mant, rem) = if low == 128 {(0, m)} else {(m >> low, m & ((1u128 << low) - 1))};
+        let half = 1u128 << (low - 1);
+        if rem > half || (rem == half && (sticky || mant & 1 == 1)) {
+            mant + 1
+        } else {
+            mant
+        }
+    };
+
+    if exp >= emin {
+        let mut exp = exp;
+        if mant >> (mant_bits + 1) != 0 {
+            mant >>= 1;
+            exp += 1;
+        }
+        if exp > bias {
+            return None;
+        }
+        Some(((exp + bias) as u64) << mant_bits | (mant as u64 & ((1u64 << mant_bits) - 1)))
+    } else {
+        // subnormal. a carry makes it the smallest normal number.
+        Some(mant as u64)
+    }
+}
+
+fn valtype_of(s: &str) -> Option<u8> {
+    match s {
+        "i32" => Some(0x7f),
+        "i64" => Some(0x7e),
+        "f32" => Some(0x7d),
+        "f64" => Some(0x7c),
+        "v128" => Some(0x7b),
+        "funcref" => Some(0x70),
+        "externref" => Some(0x6f),
+        _ => None,
+    }
+}
+
+fn new_inst(op_code: u8, sub_op: u32, operand: Operand) -> Inst {
+    Inst {
+        op_code,
+        sub_op,
+        operand,
+        level: 0,
+    }
+}
+
+fn encode_insts(insts: &[Inst]) -> Vec<u8> {
+    let mut buf = ByteCodeBuff::new(Vec::new());
+    put_insts(insts, &mut buf);
+    buf.into_vec()
+}
+
+// ---- parser ----
+
+#[derive(Clone, Copy)]
+enum Space {
+    Type,
+    Func,
+    Table,
+    Mem,
+    Global,
+    Elem,
+    Data,
+}
+
+const SPACE_NAMES: [&str; 7] = ["type", "func", "table", "memory", "global", "elem", "data"];
+
+// items of a vector section being assembled
+struct Items {
+    n: u32,
+    buf: ByteCodeBuff,
+}
+
+impl Items {
+    fn new() -> Self {
+        Items {
+            n: 0,
+            buf: ByteCodeBuff::new(Vec::new()),
+        }
+    }
+
+    fn put_section(self, sec_id: u8, out: &mut ByteCodeBuff) {
+        if self.n == 0 {
+            return;
+        }
+        let mut body = ByteCodeBuff::new(Vec::new());
+        body.put_u32(self.n);
+        body.put_bytes(&self.buf.into_vec());
+        out.put_byte(sec_id);
+        out.put_data(&body.into_vec());
+    }
+}
+
+enum SegMode {
+    Active(u32, Vec<u8>, bool), // index, offset expr, index written explicitly
+    Passive,
+    Declarative,
+}
+
+enum ElemList {
+    Funcs(Vec<u32>),
+    Exprs(u8, Vec<Vec<u8>>),
+}
+
+// params, results and names of the params
+type Signature = (Vec<u8>, Vec<u8>, Vec<Option<String>>);
+
+#[derive(Default)]
+struct FuncState {
+    locals: HashMap<String, u32>,
+    labels: Vec<Option<String>>,
+    num_labels: u32, // block/loop/if seen so far
+    label_names: NameMap, // for the name section
+}
+
+impl FuncState {
+    // enter a block/loop/if
+    fn push_label(&mut self, label: Option<String>) {
+        if let Some(name) = &label {
+            self.label_names.insert(self.num_labels, name.clone());
+        }
+        self.num_labels += 1;
+        self.labels.push(label);
+    }
+}
+
+struct Parser {
+    toks: Vec<Token>,
+    pos: usize,
+    names: [HashMap<String, u32>; 7],
+    counts: [u32; 7], // declared in the first pass
+    defined: [u32; 7], // assembled in the second pass
+    types: Vec<(Vec<u8>, Vec<u8>)>,
+    imports: Items,
+    funcs: Items,
+    tables: Items,
+    mems: Items,
+    globals: Items,
+    exports: Items,
+    start: Option<u32>,
+    elems: Items,
+    codes: Items,
+    datas: Items,
+    data_count: bool,
+    module_name: Option<String>,
+    local_names: IndirectNameMap,
+    label_names: IndirectNameMap,
+}
+
+impl Parser {
+    fn new(toks: Vec<Token>) -> Self {
+        Parser {
+            toks,
+            pos: 0,
+            names: Default::default(),
+            counts: [0; 7],
+            defined: [0; 7],
+            types: Vec::new(),
+            imports: Items::new(),
+            funcs: Items::new(),
+            tables: Items::new(),
+            mems: Items::new(),
+            globals: Items::new(),
+            exports: Items::new(),
+            start: None,
+            elems: Items::new(),
+            codes: Items::new(),
+            datas: Items::new(),
+            data_count: false,
+            module_name: None,
+            local_names: HashMap::new(),
+            label_names: HashMap::new(),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut buf = ByteCodeBuff::new(Vec::new());
+        buf.put_bytes(&[0, b'a', b's', b'm', 1, 0, 0, 0]);
+
+        let mut types = Items::new();
+        for (params, results) in &self.types {
+            types.n += 1;
+            types.buf.put_byte(0x60);
+            types.buf.put_data(params);
+            types.buf.put_data(results);
+        }
+        types.put_section(1, &mut buf);
+        self.imports.put_section(2, &mut buf);
+        self.funcs.put_section(3, &mut buf);
+        self.tables.put_section(4, &mut buf);
+        self.mems.put_section(5, &mut buf);
+        self.globals.put_section(6, &mut buf);
+        self.exports.put_section(7, &mut buf);
+        if let Some(idx) = self.start {
+            let mut body = ByteCodeBuff::new(Vec::new());
+            body.put_u32(idx);
+            buf.put_byte(8);
+            buf.put_data(&body.into_vec());
+        }
+        self.elems.put_section(9, &mut buf);
+        if self.data_count {
+            let mut body = ByteCodeBuff::new(Vec::new());
+            body.put_u32(self.datas.n);
+            buf.put_byte(12);
+            buf.put_data(&body.into_vec());
+        }
+        self.codes.put_section(10, &mut buf);
+        self.datas.put_section(11, &mut buf);
+
+        // $names go to the name section
+        let invert = |map: &HashMap<String, u32>| -> NameMap {
+            map.iter().map(|(name, idx)| (*idx, name.clone())).collect()
+        };
+        let names = Names {
+            module: self.module_name.clone(),
+            funcs: invert(&self.names[Space::Func as usize]),
+            locals: self.local_names.clone(),
+            labels: self.label_names.clone(),
+            types: invert(&self.names[Space::Type as usize]),
+            tables: invert(&self.names[Space::Table as usize]),
+            mems: invert(&self.names[Space::Mem as usize]),
+            globals: invert(&self.names[Space::Global as usize]),
+            elems: invert(&self.names[Space::Elem as usize]),
+            datas: invert(&self.names[Space::Data as usize]),
+        };
+        if !names.is_empty() {
+            let mut body = ByteCodeBuff::new(Vec::new());
+            body.put_name("name");
+            body.put_bytes(&names.encode());
+            buf.put_byte(0);
+            buf.put_data(&body.into_vec());
+        }
+        buf.into_vec()
+    }
+
+    // -- token helpers --
+
+    fn peek(&self) -> &Tok {
+        &self.toks[self.pos].tok
+    }
+
+    fn peek_at(&self, n: usize) -> &Tok {
+        match self.toks.get(self.pos + n) {
+            Some(t) => &t.tok,
+            None => &Tok::Eof,
+        }
+    }
+
+    fn next(&mut self) -> Tok {
+        let tok = self.toks[self.pos].tok.clone();
+        if tok != Tok::Eof {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn err(&self, msg: &str) -> WatError {
+        let t = &self.toks[self.pos];
+        WatError {line: t.line, col: t.col, msg: msg.to_string()}
+    }
+
+    fn lparen(&mut self) -> Result<(), WatError> {
+        match self.peek() {
+            Tok::LParen => {
+                self.next();
+                Ok(())
+            },
+            _ => Err(self.err("expected '('")),
+        }
+    }
+
+    fn rparen(&mut self) -> Result<(), WatError> {
+        match self.peek() {
+            Tok::RParen => {
+                self.next();
+                Ok(())
+            },
+            _ => Err(self.err("expected ')'")),
+        }
+    }
+
+    // "(kw" follows
+    fn at_field(&self, kw: &str) -> bool {
+        *self.peek() == Tok::LParen && matches!(self.peek_at(1), Tok::Atom(s) if s == kw)
+    }
+
+    // consume "(kw"
+    fn enter_field(&mut self, kw: &str) -> bool {
+        if self.at_field(kw) {
+            self.pos += 2;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn keyword(&mut self) -> Result<String, WatError> {
+        match self.peek().clone() {
+            Tok::Atom(s) => {
+                self.next();
+                Ok(s)
+            },
+            _ => Err(self.err("expected keyword")),
+        }
+    }
+
+    fn expect_keyword(&mut self, kw: &str) -> Result<(), WatError> {
+        if self.eat_keyword(kw) {
+            Ok(())
+        } else {
+            Err(self.err(&format!("expected '{}'", kw)))
+        }
+    }
+
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        if matches!(self.peek(), Tok::Atom(s) if s == kw) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn opt_id(&mut self) -> Option<String> {
+        match self.peek().clone() {
+            Tok::Id(id) => {
+                self.next();
+                Some(id)
+            },
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, WatError> {
+        match self.peek().clone() {
+            Tok::Str(s) => {
+                self.next();
+                Ok(s)
+            },
+            _ => Err(self.err("expected string")),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, WatError> {
+        let s = self.string()?;
+        String::from_utf8(s).map_err(|_| self.err("malformed UTF-8 encoding"))
+    }
+
+    fn u32(&mut self) -> Result<u32, WatError> {
+        let n = match self.peek() {
+            Tok::Atom(s) => parse_u32(s),
+            _ => None,
+        };
+        match n {
+            Some(n) => {
+                self.next();
+                Ok(n)
+            },
+            None => Err(self.err("expected unsigned integer")),
+        }
+    }
+
+    // skip a whole s-expression starting at '('
+    fn skip_sexpr(&mut self) -> Result<(), WatError> {
+        let mut depth = 0;
+        loop {
+            match self.next() {
+                Tok::LParen => depth += 1,
+                Tok::RParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                },
+                Tok::Eof => return Err(self.err("unexpected end of file")),
+                _ => (),
+            }
+        }
+    }
+
+    // -- indices --
+
+    fn is_index_at(&self, n: usize) -> bool {
+        match self.peek_at(n) {
+            Tok::Id(_) => true,
+            Tok::Atom(s) => s.starts_with(|c: char| c.is_ascii_digit()),
+            _ => false,
+        }
+    }
+
+    fn is_index(&self) -> bool {
+        self.is_index_at(0)
+    }
+
+    fn index(&mut self, space: Space) -> Result<u32, WatError> {
+        match self.peek().clone() {
+            Tok::Id(id) => match self.names[space as usize].get(&id) {
+                Some(idx) => {
+                    let idx = *idx;
+                    self.next();
+                    Ok(idx)
+                },
+                None => Err(self.err(&format!("unknown {} ${}", SPACE_NAMES[space as usize], id))),
+            },
+            Tok::Atom(_) => self.u32(),
+            _ => Err(self.err(&format!("expected {} index", SPACE_NAMES[space as usize]))),
+        }
+    }
+
+    // index which defaults to 0 when omitted
+    fn opt_index(&mut self, space: Space) -> Result<u32, WatError> {
+        if self.is_index() {
+            self.index(space)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn declare(&mut self, space: Space, id: Option<String>) -> Result<(), WatError> {
+        let idx = self.counts[space as usize];
+        if let Some(id) = id {
+            if self.names[space as usize].insert(id.clone(), idx).is_some() {
+                return Err(self.err(&format!("duplicate {} ${}", SPACE_NAMES[space as usize], id)));
+            }
+        }
+        self.counts[space as usize] += 1;
+        Ok(())
+    }
+
+    fn next_index(&mut self, space: Space) -> u32 {
+        self.defined[space as usize] += 1;
+        self.defined[space as usize] - 1
+    }
+
+    // -- types --
+
+    fn valtype(&mut self) -> Result<u8, WatError> {
+        let t = match self.peek() {
+            Tok::Atom(s) => valtype_of(s),
+            _ => None,
+        };
+        match t {
+            Some(t) => {
+                self.next();
+                Ok(t)
+            },
+            None => Err(self.err("expected value type")),
+        }
+    }
+
+    fn reftype(&mut self) -> Result<u8, WatError> {
+        if self.eat_keyword("funcref") {
+            Ok(0x70)
+        } else if self.eat_keyword("externref") {
+            Ok(0x6f)
+        } else {
+            Err(self.err("expected reference type"))
+        }
+    }
+
+    fn heaptype(&mut self) -> Result<u8, WatError> {
+        if self.eat_keyword("func") {
+            Ok(0x70)
+        } else if self.eat_keyword("extern") {
+            Ok(0x6f)
+        } else {
+            Err(self.err("expected heap type"))
+        }
+    }
+
+    fn limits(&mut self, buf: &mut ByteCodeBuff) -> Result<(), WatError> {
+        let min = self.u32()?;
+        if matches!(self.peek(), Tok::Atom(s) if s.starts_with(|c: char| c.is_ascii_digit())) {
+            let max = self.u32()?;
+            buf.put_byte(0x01);
+            buf.put_u32(min);
+            buf.put_u32(max);
+        } else {
+            buf.put_byte(0x00);
+            buf.put_u32(min);
+        }
+        Ok(())
+    }
+
+    fn tabletype(&mut self, buf: &mut ByteCodeBuff) -> Result<(), WatError> {
+        let mut limits = ByteCodeBuff::new(Vec::new());
+        self.limits(&mut limits)?;
+        buf.put_byte(self.reftype()?);
+        buf.put_bytes(&limits.into_vec());
+        Ok(())
+    }
+
+    fn globaltype(&mut self, buf: &mut ByteCodeBuff) -> Result<(), WatError> {
+        if self.enter_field("mut") {
+            buf.put_byte(self.valtype()?);
+            buf.put_byte(0x01);
+            self.rparen()
+        } else {
+            buf.put_byte(self.valtype()?);
+            buf.put_byte(0x00);
+            Ok(())
+        }
+    }
+
+    // (param ..)* (result ..)*
+    fn params_results(&mut self, allow_names: bool) -> Result<Signature, WatError> {
+        let mut params = Vec::new();
+        let mut names = Vec::new();
+        let mut results = Vec::new();
+        while self.enter_field("param") {
+            if let Some(id) = self.opt_id() {
+                if !allow_names {
+                    return Err(self.err("unexpected parameter name"));
+                }
+                params.push(self.valtype()?);
+                names.push(Some(id));
+            } else {
+                while *self.peek() != Tok::RParen {
+                    params.push(self.valtype()?);
+                    names.push(None);
+                }
+            }
+            self.rparen()?;
+        }
+        while self.enter_field("result") {
+            while *self.peek() != Tok::RParen {
+                results.push(self.valtype()?);
+            }
+            self.rparen()?;
+        }
+        Ok((params, results, names))
+    }
+
+    // index of the type, added at the end if it does not exist yet
+    fn find_type(&mut self, params: Vec<u8>, results: Vec<u8>) -> u32 {
+        let ft = (params, results);
+        match self.types.iter().position(|t| *t == ft) {
+            Some(idx) => idx as u32,
+            None => {
+                self.types.push(ft);
+                self.types.len() as u32 - 1
+            },
+        }
+    }
+
+    // (type x)? (param ..)* (result ..)*
+    // returns the type index and the names of the parameters
+    fn typeuse(&mut self) -> Result<(u32, Vec<Option<String>>), WatError> {
+        let mut typeidx = None;
+        if self.enter_field("type") {
+            typeidx = Some(self.index(Space::Type)?);
+            self.rparen()?;
+        }
+        let (params, results, names) = self.params_results(true)?;
+        match typeidx {
+            Some(idx) => {
+                let ft = match self.types.get(idx as usize) {
+                    Some(ft) => ft,
+                    None => return Err(self.err("unknown type")),
+                };
+                if params.is_empty() && results.is_empty() {
+                    Ok((idx, vec![None; ft.0.len()]))
+                } else if ft.0 != params || ft.1 != results {
+                    Err(self.err("inconsistent type"))
+                } else {
+                    Ok((idx, names))
+                }
+            },
+            None => Ok((self.find_type(params, results), names)),
+        }
+    }
+
+    fn blocktype(&mut self) -> Result<BlockType, WatError> {
+        if self.at_field("type") {
+            let (idx, _) = self.typeuse()?;
+            return Ok(BlockType::TypeIndex(idx));
+        }
+        let (params, results, _) = self.params_results(false)?;
+        if params.is_empty() && results.is_empty() {
+            Ok(BlockType::Empty)
+        } else if params.is_empty() && results.len() == 1 {
+            Ok(BlockType::Valtype(results[0]))
+        } else {
+            Ok(BlockType::TypeIndex(self.find_type(params, results)))
+        }
+    }
+
+    // -- module --
+
+    fn parse_module(&mut self) -> Result<(), WatError> {
+        // (module $id? field*) or just field*
+        let wrapped = self.at_field("module");
+        if wrapped {
+            self.pos += 2;
+            self.module_name = self.opt_id();
+        }
+        let start = self.pos;
+
+        // first pass: types and names of all index spaces
+        while *self.peek() == Tok::LParen {
+            self.declare_field()?;
+        }
+
+        // second pass: everything else
+        self.pos = start;
+        while *self.peek() == Tok::LParen {
+            if self.at_field("type") {
+                self.skip_sexpr()?;
+            } else {
+                self.field()?;
+            }
+        }
+
+        if wrapped {
+            self.rparen()?;
+        }
+        if *self.peek() != Tok::Eof {
+            return Err(self.err("unexpected token after module"));
+        }
+        Ok(())
+    }
+
+    // does the field from the current position have a (kw ..) directly
+    fn field_has(&self, kw: &str) -> bool {
+        let mut depth = 0;
+        let mut i = self.pos;
+        while let Some(t) = self.toks.get(i) {
+            match &t.tok {
+                Tok::LParen => {
+                    if depth == 0 && matches!(self.toks.get(i + 1).map(|t| &t.tok), Some(Tok::Atom(s)) if s == kw) {
+                        return true;
+                    }
+                    depth += 1;
+                },
+                Tok::RParen => {
+                    if depth == 0 {
+                        return false;
+                    }
+                    depth -= 1;
+                },
+                Tok::Eof => return false,
+                _ => (),
+            }
+            i += 1;
+        }
+        false
+    }
+
+    fn declare_field(&mut self) -> Result<(), WatError> {
+        let start = self.pos;
+        self.lparen()?;
+        let kw = self.keyword()?;
+        match kw.as_str() {
+            "type" => {
+                let id = self.opt_id();
+                self.lparen()?;
+                self.expect_keyword("func")?;
+                let (params, results, _) = self.params_results(true)?;
+                self.rparen()?;
+                self.declare(Space::Type, id)?;
+                self.types.push((params, results));
+            },
+            "import" => {
+                self.string()?;
+                self.string()?;
+                self.lparen()?;
+                let space = match self.keyword()?.as_str() {
+                    "func" => Space::Func,
+                    "table" => Space::Table,
+                    "memory" => Space::Mem,
+                    "global" => Space::Global,
+                    _ => return Err(self.err("unknown import kind")),
+                };
+                let id = self.opt_id();
+                self.declare(space, id)?;
+            },
+            "func" | "global" => {
+                let space = if kw == "func" {Space::Func} else {Space::Global};
+                let id = self.opt_id();
+                self.declare(space, id)?;
+            },
+            "table" => {
+                let id = self.opt_id();
+                self.declare(Space::Table, id)?;
+                if self.field_has("elem") {
+                    self.declare(Space::Elem, None)?;
+                }
+            },
+            "memory" => {
+                let id = self.opt_id();
+                self.declare(Space::Mem, id)?;
+                if self.field_has("data") {
+                    self.declare(Space::Data, None)?;
+                }
+            },
+            "elem" | "data" => {
+                let space = if kw == "elem" {Space::Elem} else {Space::Data};
+                let id = self.opt_id();
+                self.declare(space, id)?;
+            },
+            "export" | "start" => (),
+            _ => return Err(self.err(&format!("unknown module field '{}'", kw))),
+        }
+        self.pos = start;
+        self.skip_sexpr()
+    }
+
+    fn field(&mut self) -> Result<(), WatError> {
+        self.lparen()?;
+        let kw = self.keyword()?;
+        match kw.as_str() {
+            "import" => self.import_field()?,
+            "func" => self.func_field()?,
+            "table" => self.table_field()?,
+            "memory" => self.memory_field()?,
+            "global" => self.global_field()?,
+            "export" => self.export_field()?,
+            "start" => {
+                if self.start.is_some() {
+                    return Err(self.err("multiple start sections"));
+                }
+                self.start = Some(self.index(Space::Func)?);
+            },
+            "elem" => self.elem_field()?,
+            "data" => self.data_field()?,
+            _ => return Err(self.err(&format!("unknown module field '{}'", kw))),
+        }
+        self.rparen()
+    }
+
+    fn put_import(&mut self, module: &str, name: &str, kind: u8, desc: Vec<u8>) {
+        self.imports.n += 1;
+        self.imports.buf.put_name(module);
+        self.imports.buf.put_name(name);
+        self.imports.buf.put_byte(kind);
+        self.imports.buf.put_bytes(&desc);
+    }
+
+    // (export "name")*
+    fn inline_exports(&mut self, kind: u8, idx: u32) -> Result<(), WatError> {
+        while self.enter_field("export") {
+            let name = self.name()?;
+            self.rparen()?;
+            self.exports.n += 1;
+            self.exports.buf.put_name(&name);
+            self.exports.buf.put_byte(kind);
+            self.exports.buf.put_u32(idx);
+        }
+        Ok(())
+    }
+
+    // (import "module" "name")?
+    fn inline_import(&mut self) -> Result<Option<(String, String)>, WatError> {
+        if self.enter_field("import") {
+            let module = self.name()?;
+            let name = self.name()?;
+            self.rparen()?;
+            Ok(Some((module, name)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn import_field(&mut self) -> Result<(), WatError> {
+        let module = self.name()?;
+        let name = self.name()?;
+        self.lparen()?;
+        let kw = self.keyword()?;
+        self.opt_id();
+        let mut desc = ByteCodeBuff::new(Vec::new());
+        let kind = match kw.as_str() {
+            "func" => {
+                self.next_index(Space::Func);
+                let (typeidx, _) = self.typeuse()?;
+                desc.put_u32(typeidx);
+                0x00
+            },
+            "table" => {
+                self.next_index(Space::Table);
+                self.tabletype(&mut desc)?;
+                0x01
+            },
+            "memory" => {
+                self.next_index(Space::Mem);
+                self.limits(&mut desc)?;
+                0x02
+            },
+            _ => {
+                self.next_index(Space::Global);
+                self.globaltype(&mut desc)?;
+                0x03
+            },
+        };
+        self.rparen()?;
+        self.put_import(&module, &name, kind, desc.into_vec());
+        Ok(())
+    }
+
+    fn func_field(&mut self) -> Result<(), WatError> {
+        self.opt_id();
+        let idx = self.next_index(Space::Func);
+        self.inline_exports(0x00, idx)?;
+        if let Some((module, name)) = self.inline_import()? {
+            let (typeidx, _) = self.typeuse()?;
+            let mut desc = ByteCodeBuff::new(Vec::new());
+            desc.put_u32(typeidx);
+            self.put_import(&module, &name, 0x00, desc.into_vec());
+            return Ok(());
+        }
+
+        let (typeidx, param_names) = self.typeuse()?;
+        self.funcs.n += 1;
+        self.funcs.buf.put_u32(typeidx);
+
+        let mut fs = FuncState::default();
+        for (i, name) in param_names.iter().enumerate() {
+            if let Some(name) = name {
+                if fs.locals.insert(name.clone(), i as u32).is_some() {
+                    return Err(self.err(&format!("duplicate local ${}", name)));
+                }
+            }
+        }
+        let mut locals: Vec<u8> = Vec::new();
+        while self.enter_field("local") {
+            if let Some(id) = self.opt_id() {
+                let idx = (param_names.len() + locals.len()) as u32;
+                if fs.locals.insert(id.clone(), idx).is_some() {
+                    return Err(self.err(&format!("duplicate local ${}", id)));
+                }
+                locals.push(self.valtype()?);
+            } else {
+                while *self.peek() != Tok::RParen {
+                    locals.push(self.valtype()?);
+                }
+            }
+            self.rparen()?;
+        }
+
+        let mut insts = Vec::new();
+        self.instrs(&mut fs, &mut insts)?;
+        insts.push(new_inst(0x0b, 0, Operand::None));
+        if !fs.locals.is_empty() {
+            let names = fs.locals.iter().map(|(name, i)| (*i, name.clone())).collect();
+            self.local_names.insert(idx, names);
+        }
+        if !fs.label_names.is_empty() {
+            self.label_names.insert(idx, fs.label_names);
+        }
+
+        // locals: compress runs of the same type
+        let mut runs: Vec<(u32, u8)> = Vec::new();
+        for l in &locals {
+            match runs.last_mut() {
+                Some((n, t)) if t == l => *n += 1,
+                _ => runs.push((1, *l)),
+            }
+        }
+        let mut body = ByteCodeBuff::new(Vec::new());
+        body.put_u32(runs.len() as u32);
+        for (n, t) in runs {
+            body.put_u32(n);
+            body.put_byte(t);
+        }
+        put_insts(&insts, &mut body);
+        self.codes.n += 1;
+        self.codes.buf.put_data(&body.into_vec());
+        Ok(())
+    }
+
+    fn table_field(&mut self) -> Result<(), WatError> {
+        self.opt_id();
+        let idx = self.next_index(Space::Table);
+        self.inline_exports(0x01, idx)?;
+        if let Some((module, name)) = self.inline_import()? {
+            let mut desc = ByteCodeBuff::new(Vec::new());
+            self.tabletype(&mut desc)?;
+            self.put_import(&module, &name, 0x01, desc.into_vec());
+            return Ok(());
+        }
+
+        if matches!(self.peek(), Tok::Atom(s) if valtype_of(s).is_some()) {
+            // reftype (elem ..): table sized to fit the segment
+            let reftype = self.reftype()?;
+            self.lparen()?;
+            self.expect_keyword("elem")?;
+            let list = if self.is_index() || *self.peek() == Tok::RParen {
+                let mut funcs = Vec::new();
+                while self.is_index() {
+                    funcs.push(self.index(Space::Func)?);
+                }
+                ElemList::Funcs(funcs)
+            } else {
+                ElemList::Exprs(reftype, self.elem_exprs()?)
+            };
+            self.rparen()?;
+            let n = match &list {
+                ElemList::Funcs(funcs) => funcs.len(),
+                ElemList::Exprs(_, exprs) => exprs.len(),
+            } as u32;
+            self.tables.n += 1;
+            self.tables.buf.put_byte(reftype);
+            self.tables.buf.put_byte(0x01);
+            self.tables.buf.put_u32(n);
+            self.tables.buf.put_u32(n);
+            self.next_index(Space::Elem);
+            let offset = encode_insts(&[new_inst(0x41, 0, Operand::I32(0)),
+                new_inst(0x0b, 0, Operand::None)]);
+            self.put_elem(SegMode::Active(idx, offset, idx != 0), list);
+        } else {
+            self.tables.n += 1;
+            let mut buf = ByteCodeBuff::new(Vec::new());
+            self.tabletype(&mut buf)?;
+            self.tables.buf.put_bytes(&buf.into_vec());
+        }
+        Ok(())
+    }
+
+    fn memory_field(&mut self) -> Result<(), WatError> {
+        self.opt_id();
+        let idx = self.next_index(Space::Mem);
+        self.inline_exports(0x02, idx)?;
+        if let Some((module, name)) = self.inline_import()? {
+            let mut desc = ByteCodeBuff::new(Vec::new());
+            self.limits(&mut desc)?;
+            self.put_import(&module, &name, 0x02, desc.into_vec());
+            return Ok(());
+        }
+
+        self.mems.n += 1;
+        if self.enter_field("data") {
+            // memory sized to fit the data
+            let mut data = Vec::new();
+            while let Tok::Str(_) = self.peek() {
+                data.extend(self.string()?);
+            }
+            self.rparen()?;
+            let pages = data.len().div_ceil(65536) as u32;
+            self.mems.buf.put_byte(0x01);
+            self.mems.buf.put_u32(pages);
+            self.mems.buf.put_u32(pages);
+            self.next_index(Space::Data);
+            let offset = encode_insts(&[new_inst(0x41, 0, Operand::I32(0)),
+                new_inst(0x0b, 0, Operand::None)]);
+            self.put_data(SegMode::Active(idx, offset, idx != 0), &data);
+        } else {
+            let mut buf = ByteCodeBuff::new(Vec::new());
+            self.limits(&mut buf)?;
+            self.mems.buf.put_bytes(&buf.into_vec());
+        }
+        Ok(())
+    }
+
+    fn global_field(&mut self) -> Result<(), WatError> {
+        self.opt_id();
+        let idx = self.next_index(Space::Global);
+        self.inline_exports(0x03, idx)?;
+        let import = self.inline_import()?;
+        let mut buf = ByteCodeBuff::new(Vec::new());
+        self.globaltype(&mut buf)?;
+        if let Some((module, name)) = import {
+            self.put_import(&module, &name, 0x03, buf.into_vec());
+            return Ok(());
+        }
+        let expr = self.const_expr()?;
+        self.globals.n += 1;
+        self.globals.buf.put_bytes(&buf.into_vec());
+        self.globals.buf.put_bytes(&expr);
+        Ok(())
+    }
+
+    fn export_field(&mut self) -> Result<(), WatError> {
+        let name = self.name()?;
+        self.lparen()?;
+        let (kind, space) = match self.keyword()?.as_str() {
+            "func" => (0x00, Space::Func),
+            "table" => (0x01, Space::Table),
+            "memory" => (0x02, Space::Mem),
+            "global" => (0x03, Space::Global),
+            _ => return Err(self.err("unknown export kind")),
+        };
+        let idx = self.index(space)?;
+        self.rparen()?;
+        self.exports.n += 1;
+        self.exports.buf.put_name(&name);
+        self.exports.buf.put_byte(kind);
+        self.exports.buf.put_u32(idx);
+        Ok(())
+    }
+
+    // instructions up to ')' followed by end
+    fn const_expr(&mut self) -> Result<Vec<u8>, WatError> {
+        let mut fs = FuncState::default();
+        let mut insts = Vec::new();
+        self.instrs(&mut fs, &mut insts)?;
+        insts.push(new_inst(0x0b, 0, Operand::None));
+        Ok(encode_insts(&insts))
+    }
+
+    // (offset instr*) or a folded instruction
+    fn offset(&mut self) -> Result<Vec<u8>, WatError> {
+        if self.enter_field("offset") {
+            let expr = self.const_expr()?;
+            self.rparen()?;
+            Ok(expr)
+        } else {
+            self.item_expr()
+        }
+    }
+
+    // a folded instruction as an expression
+    fn item_expr(&mut self) -> Result<Vec<u8>, WatError> {
+        let mut fs = FuncState::default();
+        let mut insts = Vec::new();
+        self.folded_instr(&mut fs, &mut insts)?;
+        insts.push(new_inst(0x0b, 0, Operand::None));
+        Ok(encode_insts(&insts))
+    }
+
+    // ((item instr*) | (instr))*
+    fn elem_exprs(&mut self) -> Result<Vec<Vec<u8>>, WatError> {
+        let mut exprs = Vec::new();
+        while *self.peek() == Tok::LParen {
+            if self.enter_field("item") {
+                exprs.push(self.const_expr()?);
+                self.rparen()?;
+            } else {
+                exprs.push(self.item_expr()?);
+            }
+        }
+        Ok(exprs)
+    }
+
+    fn elem_field(&mut self) -> Result<(), WatError> {
+        self.opt_id();
+        self.next_index(Space::Elem);
+        let mode = if self.eat_keyword("declare") {
+            SegMode::Declarative
+        } else {
+            let table = if self.enter_field("table") {
+                let idx = self.index(Space::Table)?;
+                self.rparen()?;
+                Some(idx)
+            } else {
+                None
+            };
+            if table.is_some() || *self.peek() == Tok::LParen {
+                let offset = self.offset()?;
+                SegMode::Active(table.unwrap_or(0), offset, table.is_some())
+            } else {
+                SegMode::Passive
+            }
+        };
+
+        let list = if self.eat_keyword("func") || self.is_index() || *self.peek() == Tok::RParen {
+            let mut funcs = Vec::new();
+            while self.is_index() {
+                funcs.push(self.index(Space::Func)?);
+            }
+            ElemList::Funcs(funcs)
+        } else {
+            let reftype = self.reftype()?;
+            ElemList::Exprs(reftype, self.elem_exprs()?)
+        };
+        self.put_elem(mode, list);
+        Ok(())
+    }
+
+    fn put_elem(&mut self, mode: SegMode, list: ElemList) {
+        let buf = &mut self.elems.buf;
+        self.elems.n += 1;
+        match list {
+            ElemList::Funcs(funcs) => {
+                match mode {
+                    SegMode::Active(0, offset, false) => {
+                        buf.put_u32(0);
+                        buf.put_bytes(&offset);
+                    },
+                    SegMode::Active(table, offset, _) => {
+                        buf.put_u32(2);
+                        buf.put_u32(table);
+                        buf.put_bytes(&offset);
+                        buf.put_byte(0x00);
+                    },
+                    SegMode::Passive => {
+                        buf.put_u32(1);
+                        buf.put_byte(0x00);
+                    },
+                    SegMode::Declarative => {
+                        buf.put_u32(3);
+                        buf.put_byte(0x00);
+                    },
+                }
+                put_vector(&funcs, buf);
+            },
+            ElemList::Exprs(reftype, exprs) => {
+                match mode {
+                    SegMode::Active(0, offset, false) if reftype == 0x70 => {
+                        buf.put_u32(4);
+                        buf.put_bytes(&offset);
+                    },
+                    SegMode::Active(table, offset, _) => {
+                        buf.put_u32(6);
+                        buf.put_u32(table);
+                        buf.put_bytes(&offset);
+                        buf.put_byte(reftype);
+                    },
+                    SegMode::Passive => {
+                        buf.put_u32(5);
+                        buf.put_byte(reftype);
+                    },
+                    SegMode::Declarative => {
+                        buf.put_u32(7);
+                        buf.put_byte(reftype);
+                    },
+                }
+                buf.put_u32(exprs.len() as u32);
+                for expr in &exprs {
+                    buf.put_bytes(expr);
+                }
+            },
+        }
+    }
+
+    fn data_field(&mut self) -> Result<(), WatError> {
+        self.opt_id();
+        self.next_index(Space::Data);
+        let mem = if self.enter_field("memory") {
+            let idx = self.index(Space::Mem)?;
+            self.rparen()?;
+            Some(idx)
+        } else {
+            None
+        };
+        let mode = if mem.is_some() || *self.peek() == Tok::LParen {
+            let offset = self.offset()?;
+            SegMode::Active(mem.unwrap_or(0), offset, mem.is_some())
+        } else {
+            SegMode::Passive
+        };
+        let mut data = Vec::new();
+        while let Tok::Str(_) = self.peek() {
+            data.extend(self.string()?);
+        }
+        self.put_data(mode, &data);
+        Ok(())
+    }
+
+    fn put_data(&mut self, mode: SegMode, data: &[u8]) {
+        let buf = &mut self.datas.buf;
+        self.datas.n += 1;
+        match mode {
+            SegMode::Active(0, offset, _) => {
+                buf.put_u32(0);
+                buf.put_bytes(&offset);
+            },
+            SegMode::Active(mem, offset, _) => {
+                buf.put_u32(2);
+                buf.put_u32(mem);
+                buf.put_bytes(&offset);
+            },
+            _ => buf.put_u32(1),
+        }
+        buf.put_data(data);
+    }
+
+    // -- instructions --
+
+    // instructions up to ')', 'end' or 'else'
+    fn instrs(&mut self, fs: &mut FuncState, out: &mut Vec<Inst>) -> Result<(), WatError> {
+        loop {
+            match self.peek() {
+                Tok::LParen => self.folded_instr(fs, out)?,
+                Tok::Atom(kw) if kw != "end" && kw != "else" => self.plain_instr(fs, out)?,
+                Tok::RParen | Tok::Atom(_) => return Ok(()),
+                _ => return Err(self.err("expected instruction")),
+            }
+        }
+    }
+
+    fn block_head(&mut self, op_code: u8) -> Result<(Option<String>, Inst), WatError> {
+        let label = self.opt_id();
+        let bt = self.blocktype()?;
+        Ok((label, new_inst(op_code, 0, Operand::BlockType(bt))))
+    }
+
+    fn plain_instr(&mut self, fs: &mut FuncState, out: &mut Vec<Inst>) -> Result<(), WatError> {
+        let kw = self.keyword()?;
+        let op_code = match kw.as_str() {
+            "block" => 0x02,
+            "loop" => 0x03,
+            "if" => 0x04,
+            _ => {
+                let inst = self.op_inst(&kw, fs)?;
+                out.push(inst);
+                return Ok(());
+            },
+        };
+        let (label, inst) = self.block_head(op_code)?;
+        out.push(inst);
+        fs.push_label(label);
+        self.instrs(fs, out)?;
+        if op_code == 0x04 && self.eat_keyword("else") {
+            self.opt_id();
+            out.push(new_inst(0x05, 0, Operand::None));
+            self.instrs(fs, out)?;
+        }
+        self.expect_keyword("end")?;
+        self.opt_id();
+        fs.labels.pop();
+        out.push(new_inst(0x0b, 0, Operand::None));
+        Ok(())
+    }
+
+    fn folded_instr(&mut self, fs: &mut FuncState, out: &mut Vec<Inst>) -> Result<(), WatError> {
+        self.lparen()?;
+        let kw = self.keyword()?;
+        match kw.as_str() {
+            "block" | "loop" => {
+                let op_code = if kw == "block" {0x02} else {0x03};
+                let (label, inst) = self.block_head(op_code)?;
+                out.push(inst);
+                fs.push_label(label);
+                self.instrs(fs, out)?;
+                fs.labels.pop();
+                out.push(new_inst(0x0b, 0, Operand::None));
+            },
+            "if" => {
+                let (label, inst) = self.block_head(0x04)?;
+                // condition
+                while *self.peek() == Tok::LParen && !self.at_field("then") {
+                    self.folded_instr(fs, out)?;
+                }
+                out.push(inst);
+                fs.push_label(label);
+                if !self.enter_field("then") {
+                    return Err(self.err("expected '(then'"));
+                }
+                self.instrs(fs, out)?;
+                self.rparen()?;
+                if self.enter_field("else") {
+                    out.push(new_inst(0x05, 0, Operand::None));
+                    self.instrs(fs, out)?;
+                    self.rparen()?;
+                }
+                fs.labels.pop();
+                out.push(new_inst(0x0b, 0, Operand::None));
+            },
+            _ => {
+                let inst = self.op_inst(&kw, fs)?;
+                while *self.peek() == Tok::LParen {
+                    self.folded_instr(fs, out)?;
+                }
+                out.push(inst);
+            },
+        }
+        self.rparen()
+    }
+
+    fn label(&mut self, fs: &FuncState) -> Result<u32, WatError> {
+        if let Tok::Id(id) = self.peek().clone() {
+            match fs.labels.iter().rev().position(|l| l.as_deref() == Some(id.as_str())) {
+                Some(n) => {
+                    self.next();
+                    Ok(n as u32)
+                },
+                None => Err(self.err(&format!("unknown label ${}", id))),
+            }
+        } else {
+            self.u32()
+        }
+    }
+
+    fn local(&mut self, fs: &FuncState) -> Result<u32, WatError> {
+        if let Tok::Id(id) = self.peek().clone() {
+            match fs.locals.get(&id) {
+                Some(idx) => {
+                    let idx = *idx;
+                    self.next();
+                    Ok(idx)
+                },
+                None => Err(self.err(&format!("unknown local ${}", id))),
+            }
+        } else {
+            self.u32()
+        }
+    }
+
+    fn memarg(&mut self, natural: u32) -> Result<Memarg, WatError> {
+        let mut offset = 0;
+        let mut align = natural;
+        if let Tok::Atom(s) = self.peek().clone() {
+            if let Some(n) = s.strip_prefix("offset=") {
+                offset = parse_u32(n).ok_or_else(|| self.err("invalid offset"))?;
+                self.next();
+            }
+        }
+        if let Tok::Atom(s) = self.peek().clone() {
+            if let Some(n) = s.strip_prefix("align=") {
+                let n = parse_u32(n).ok_or_else(|| self.err("invalid alignment"))?;
+                if !n.is_power_of_two() {
+                    return Err(self.err("alignment must be a power of two"));
+                }
+                align = n.trailing_zeros();
+                self.next();
+            }
+        }
+        Ok(Memarg {align, offset})
+    }
+
+    fn lane(&mut self) -> Result<u8, WatError> {
+        let s = self.literal()?;
+        let n = parse_digits(&s).and_then(|n| u8::try_from(n).ok())
+            .ok_or_else(|| self.err("invalid lane index"))?;
+        self.next();
+        Ok(n)
+    }
+
+    fn literal(&mut self) -> Result<String, WatError> {
+        match self.peek().clone() {
+            Tok::Atom(s) => Ok(s),
+            _ => Err(self.err("expected constant")),
+        }
+    }
+
+    // an instruction other than block/loop/if with its immediates
+    fn op_inst(&mut self, kw: &str, fs: &FuncState) -> Result<Inst, WatError> {
+        let (op_code, sub_op) = match get_opcode(kw) {
+            Some(op) if op.0 != 0x05 && op.0 != 0x0b => op,
+            _ => return Err(self.err(&format!("unknown instruction '{}'", kw))),
+        };
+        let operand = match (op_code, sub_op) {
+            (0x02..=0x04, _) => return Err(self.err("unexpected block instruction")),
+            (0x0c | 0x0d, _) => Operand::Index(self.label(fs)?),
+            (0x0e, _) => {
+                let mut labels = vec![self.label(fs)?];
+                while self.is_index() {
+                    labels.push(self.label(fs)?);
+                }
+                let default = labels.pop().unwrap();
+                Operand::BrTable(BrTable {labels, default})
+            },
+            (0x10 | 0xd2, _) => Operand::Index(self.index(Space::Func)?),
+            (0x11, _) => {
+                let tableidx = self.opt_index(Space::Table)?;
+                let (typeidx, _) = self.typeuse()?;
+                Operand::Index2(tableidx, typeidx)
+            },
+            (0x1b, _) => {
+                if self.at_field("result") {
+                    let (_, results, _) = self.params_results(false)?;
+                    return Ok(new_inst(0x1c, 0, Operand::VecValtype(results)));
+                }
+                Operand::None
+            },
+            (0x20..=0x22, _) => Operand::Index(self.local(fs)?),
+            (0x23 | 0x24, _) => Operand::Index(self.index(Space::Global)?),
+            (0x25 | 0x26, _) | (0xfc, 15..=17) => Operand::Index(self.opt_index(Space::Table)?),
+            (0x28..=0x3e, _) => Operand::Memarg(self.memarg(natural_align(op_code))?),
+            (0x3f | 0x40, _) | (0xfc, 11) => Operand::Index(self.opt_index(Space::Mem)?),
+            (0x41, _) => {
+                let s = self.literal()?;
+                let n = parse_int(&s, 32).ok_or_else(|| self.err("invalid i32 constant"))?;
+                self.next();
+                Operand::I32(n as u32 as i32)
+            },
+            (0x42, _) => {
+                let s = self.literal()?;
+                let n = parse_int(&s, 64).ok_or_else(|| self.err("invalid i64 constant"))?;
+                self.next();
+                Operand::I64(n as i64)
+            },
+            (0x43, _) => {
+                let s = self.literal()?;
+                let n = parse_float(&s, 23, 8).ok_or_else(|| self.err("invalid f32 constant"))?;
+                self.next();
+                Operand::F32(f32::from_bits(n as u32))
+            },
+            (0x44, _) => {
+                let s = self.literal()?;
+                let n = parse_float(&s, 52, 11).ok_or_else(|| self.err("invalid f64 constant"))?;
+                self.next();
+                Operand::F64(f64::from_bits(n))
+            },
+            (0xd0, _) => Operand::Valtype(self.heaptype()?),
+            (0xfc, 8) => {
+                self.data_count = true;
+                Operand::Index2(self.index(Space::Data)?, 0)
+            },
+            (0xfc, 9) => {
+                self.data_count = true;
+                Operand::Index(self.index(Space::Data)?)
+            },
+            (0xfc, 10) => Operand::Index2(0, 0),
+            (0xfd, 0x00..=0x0b | 0x5c | 0x5d) => Operand::Memarg(self.memarg(natural_align_fd(sub_op))?),
+            (0xfd, 0x0c) => {
+                let shape = self.keyword()?;
+                let n = shape_lanes(&shape).ok_or_else(|| self.err("invalid v128 shape"))?;
+                let mut lanes = Vec::new();
+                for _ in 0..n {
+                    lanes.push(self.literal()?);
+                    self.next();
+                }
+                let lanes: Vec<&str> = lanes.iter().map(|s| s.as_str()).collect();
+                Operand::V128(parse_v128(&shape, &lanes).ok_or_else(|| self.err("invalid v128 constant"))?)
+            },
+            (0xfd, 0x0d) => {
+                let mut lanes = [0; 16];
+                for l in lanes.iter_mut() {
+                    *l = self.lane()?;
+                }
+                Operand::Shuffle(lanes)
+            },
+            (0xfd, 0x15..=0x22) => Operand::Lane(self.lane()?),
+            (0xfd, 0x54..=0x5b) => {
+                let memarg = self.memarg(natural_align_fd(sub_op))?;
+                Operand::MemargLane(memarg, self.lane()?)
+            },
+            (0xfc, 12) => {
+                // table.init tableidx? elemidx
+                let tableidx = if self.is_index_at(1) {self.index(Space::Table)?} else {0};
+                Operand::Index2(self.index(Space::Elem)?, tableidx)
+            },
+            (0xfc, 13) => Operand::Index(self.index(Space::Elem)?),
+            (0xfc, 14) => {
+                if self.is_index() {
+                    let dst = self.index(Space::Table)?;
+                    Operand::Index2(dst, self.index(Space::Table)?)
+                } else {
+                    Operand::Index2(0, 0)
+                }
+            },
+            _ => Operand::None,
+        };
+        Ok(new_inst(op_code, sub_op, operand))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_validate_encode() {
+        let src = r#"(module
+          (type $bin (func (param i32 i32) (result i32)))
+          (import "env" "log" (func $log (param i32)))
+          (memory (export "mem") 1 2)
+          (table 2 funcref)
+          (global $count (mut i32) (i32.const 0))
+          (elem (i32.const 0) $add $sum)
+          (data (i32.const 16) "hello\00")
+          (func $add (export "add") (type $bin)
+            local.get 0
+            local.get 1
+            i32.add)
+          (func $sum (param $n i32) (result i32) (local $acc i32)
+            (block $done
+              (loop $top
+                (br_if $done (i32.eqz (local.get $n)))
+                (local.set $acc (i32.add (local.get $acc) (local.get $n)))
+                (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+                (br $top)))
+            (global.set $count (i32.add (global.get $count) (i32.const 1)))
+            (call $log (local.get $acc))
+            (local.get $acc))
+          (func $pick (param i32) (result i32)
+            (block (block (block (br_table 0 1 2 (local.get 0)))
+                (return (i32.const 10)))
+              (return (i32.const 11)))
+            (i32.const 12))
+          (start $init)
+          (func $init (i32.store8 (i32.const 0) (i32.const 1))))"#;
+        let buf = wat2wasm(src).unwrap();
+        let mut module = parse_wat(src).unwrap();
+        module.validate().unwrap();
+        assert_eq!(module.encode(), buf);
+    }
+
+    #[test]
+    fn encode_simple() {
+        let buf = wat2wasm(r#"(module (func (export "f") (result i32) i32.const 42))"#).unwrap();
+        assert_eq!(buf, b"\0asm\x01\0\0\0\
+            \x01\x05\x01\x60\x00\x01\x7f\
+            \x03\x02\x01\x00\
+            \x07\x05\x01\x01f\x00\x00\
+            \x0a\x06\x01\x04\x00\x41\x2a\x0b");
+    }
+
+    #[test]
+    fn malformed_text() {
+        let srcs = [
+            ")",
+            "(module",
+            "(module (func (result i32) i32.const))",
+            "(module (func i32.const 99999999999))",
+            "(module (func i32.const 1.5))",
+            "(module (func $f) (func $f))",
+            "(module (func (call $nope)))",
+            "(module (func (local.get $x)))",
+            "(module (func (block $l (br $m))))",
+            "(module (func nop.nop))",
+            "(module (memory 1) (data (i32.const 0) \"\\zz\"))",
+            "(module (data (i32.const 0) \"abc))",
+            "(module (func (i32.load align=3 (i32.const 0))))",
+            "(module (type (func (param i32))) (func (type 0) (param i64)))",
+            "(module (func (export \"f\") (export \"f\")) (unknown))",
+            "(module (func (v128.const i32x4 1 2 3)))",
+            "(module (func (i8x16.shuffle 0 1 2 (v128.const i64x2 0 0) (v128.const i64x2 0 0))))",
+        ];
+        let accepted: Vec<&str> = srcs.iter().copied().filter(|src| wat2wasm(src).is_ok()).collect();
+        assert!(accepted.is_empty(), "accepted: {:?}", accepted);
+    }
+
+    #[test]
+    fn invalid_module() {
+        // well-formed text but the label is out of range
+        let mut module = parse_wat("(module (func (br 1)))").unwrap();
+        assert!(module.validate().is_err());
+    }
+}